@@ -0,0 +1,131 @@
+//! Reorders lines that mix left-to-right and right-to-left text (Arabic, Hebrew)
+//! into display order with the Unicode bidi algorithm, keeping span colors.
+
+use crate::config::TextDirection;
+use image::Rgb;
+use unicode_bidi::{BidiInfo, Level};
+
+/// Returns `spans` in visual order, left to right, ready to be drawn one after
+/// another. Lines without right-to-left text are returned unchanged.
+///
+/// Only reordering and bracket mirroring are done; joining Arabic letters into
+/// their contextual forms is left to the font.
+pub fn visual_spans(
+    spans: Vec<(String, Rgb<u8>)>,
+    direction: Option<TextDirection>,
+) -> Vec<(String, Rgb<u8>)> {
+    let text: String = spans.iter().map(|(text, _)| text.as_str()).collect();
+    let level = direction.map(|direction| match direction {
+        TextDirection::Ltr => Level::ltr(),
+        TextDirection::Rtl => Level::rtl(),
+    });
+    let info = BidiInfo::new(&text, level);
+    if !info.has_rtl() {
+        return spans;
+    }
+
+    // The color of every byte, so runs can be split at any character
+    let colors: Vec<Rgb<u8>> = spans
+        .iter()
+        .flat_map(|(text, color)| std::iter::repeat_n(*color, text.len()))
+        .collect();
+
+    let mut visual: Vec<(String, Rgb<u8>)> = Vec::new();
+    let mut push = |ch: char, color: Rgb<u8>| match visual.last_mut() {
+        Some((last, last_color)) if *last_color == color => last.push(ch),
+        _ => visual.push((ch.to_string(), color)),
+    };
+    for paragraph in &info.paragraphs {
+        let (levels, runs) = info.visual_runs(paragraph, paragraph.range.clone());
+        for run in runs {
+            let chars = text[run.clone()].char_indices();
+            if levels[run.start].is_rtl() {
+                for (offset, ch) in chars.rev() {
+                    push(mirror(ch), colors[run.start + offset]);
+                }
+            } else {
+                for (offset, ch) in chars {
+                    push(ch, colors[run.start + offset]);
+                }
+            }
+        }
+    }
+    visual
+}
+
+/// Brackets in right-to-left runs are drawn mirrored, so `(` still opens.
+fn mirror(ch: char) -> char {
+    match ch {
+        '(' => ')',
+        ')' => '(',
+        '[' => ']',
+        ']' => '[',
+        '{' => '}',
+        '}' => '{',
+        '<' => '>',
+        '>' => '<',
+        '«' => '»',
+        '»' => '«',
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: Rgb<u8> = Rgb([255, 255, 255]);
+    const RED: Rgb<u8> = Rgb([255, 0, 0]);
+
+    fn visual_text(spans: &[(String, Rgb<u8>)]) -> String {
+        spans.iter().map(|(text, _)| text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_mixed_arabic_and_number() {
+        let spans = vec![("درجة الحرارة: 22.5".to_string(), WHITE)];
+
+        let visual = visual_spans(spans, None);
+
+        // The Arabic reads right to left, the number stays in digit order on the left
+        assert_eq!(visual_text(&visual), "22.5 :ةرارحلا ةجرد");
+    }
+
+    #[test]
+    fn test_colors_follow_their_text() {
+        let spans = vec![
+            ("טמפ' (".to_string(), WHITE),
+            ("21".to_string(), RED),
+            (")".to_string(), WHITE),
+        ];
+
+        let visual = visual_spans(spans, None);
+
+        assert_eq!(
+            visual,
+            vec![
+                ("(".to_string(), WHITE),
+                ("21".to_string(), RED),
+                (") 'פמט".to_string(), WHITE),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_direction_override() {
+        let spans = || vec![("abc שלום".to_string(), WHITE)];
+
+        assert_eq!(visual_text(&visual_spans(spans(), None)), "abc םולש");
+        assert_eq!(
+            visual_text(&visual_spans(spans(), Some(TextDirection::Rtl))),
+            "םולש abc"
+        );
+    }
+
+    #[test]
+    fn test_ltr_is_unchanged() {
+        let spans = vec![("Temp ".to_string(), WHITE), ("21.5".to_string(), RED)];
+
+        assert_eq!(visual_spans(spans.clone(), None), spans);
+    }
+}
@@ -0,0 +1,99 @@
+//! `ha-sensor-streamer check`: fetches every watched entity once and prints the
+//! results, to validate the configuration in CI or during setup without starting
+//! a server.
+
+use crate::config::Config;
+use crate::ha_client::HaClient;
+use anyhow::{bail, Result};
+use std::fmt::Write;
+
+/// Whether the binary was started as `check` or `--check`.
+pub fn requested(mut args: impl Iterator<Item = String>) -> bool {
+    args.any(|arg| arg == "check" || arg == "--check")
+}
+
+/// Fetches each entity from `get_required_sensors` and prints a table. Returns
+/// whether every fetch succeeded.
+pub async fn run(config: &Config, ha_client: &HaClient) -> Result<bool> {
+    if config.demo {
+        bail!("DEMO mode never contacts Home Assistant, there is nothing to check");
+    }
+
+    let entities = config.get_required_sensors();
+    if entities.is_empty() {
+        println!("No sensors configured to watch.");
+        return Ok(true);
+    }
+
+    println!("Checking {} at {}", entities.len(), config.ha_base_url);
+    let mut results = Vec::with_capacity(entities.len());
+    for entity_id in entities {
+        let state = ha_client
+            .fetch_sensor_state(&entity_id)
+            .await
+            .map(|entity| entity.state);
+        results.push((entity_id, state));
+    }
+
+    print!("{}", format_report(&results));
+    Ok(results.iter().all(|(_, state)| state.is_ok()))
+}
+
+/// One row per entity: its id, `OK` or `FAILED`, and the state or error.
+fn format_report(results: &[(String, Result<String>)]) -> String {
+    let width = results
+        .iter()
+        .map(|(entity_id, _)| entity_id.len())
+        .chain(["ENTITY".len()])
+        .max()
+        .unwrap_or(0);
+
+    let mut report = String::new();
+    let _ = writeln!(report, "{:width$}  {:6}  STATE", "ENTITY", "RESULT");
+    for (entity_id, state) in results {
+        let _ = match state {
+            Ok(value) => writeln!(report, "{:width$}  {:6}  {}", entity_id, "OK", value),
+            Err(e) => writeln!(report, "{:width$}  {:6}  {:#}", entity_id, "FAILED", e),
+        };
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_requested() {
+        let args = |list: &[&str]| list.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert!(requested(
+            args(&["ha-sensor-streamer", "check"]).into_iter()
+        ));
+        assert!(requested(
+            args(&["ha-sensor-streamer", "--check"]).into_iter()
+        ));
+        assert!(!requested(args(&["ha-sensor-streamer"]).into_iter()));
+    }
+
+    #[test]
+    fn test_format_report() {
+        let results = vec![
+            (
+                "sensor.outdoor_temperature".to_string(),
+                Ok("21.5".to_string()),
+            ),
+            (
+                "sensor.gone".to_string(),
+                Err(anyhow!("HTTP 404 Not Found")),
+            ),
+        ];
+
+        assert_eq!(
+            format_report(&results),
+            "ENTITY                      RESULT  STATE\n\
+             sensor.outdoor_temperature  OK      21.5\n\
+             sensor.gone                 FAILED  HTTP 404 Not Found\n"
+        );
+    }
+}
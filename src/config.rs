@@ -1,30 +1,658 @@
-use anyhow::Result;
+use crate::expr;
+use crate::modifiers::{
+    parse_emoji_rules, parse_line_color_rules, parse_modifiers, parse_window, LineColorRule,
+    HISTORY_MODIFIERS,
+};
+use crate::smoothing::SmoothingMethod;
+use crate::transform::{parse_pipeline, TextTransform};
+use anyhow::{bail, Context, Result};
+use image::Rgb;
 use regex::Regex;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Matches a sensor token, capturing the entity's object id (without any
+/// `.attributes...` path) and the optional modifier list.
+const SENSOR_TOKEN_PATTERN: &str = r"\{sensor\.(\w+)(?:\.[\w\.]*)?(?::([^}]*))?\}";
+
+/// Matches a sensor token whose object id may interpolate other entities' states,
+/// e.g. `{sensor.${input_select.room}_temp:round=1}`. Captures the object id and
+/// the rest of the token (attribute path and modifiers).
+pub const TEMPLATED_TOKEN_PATTERN: &str =
+    r"\{sensor\.((?:\w|\$\{\w+\.\w+\})+)((?:\.[\w\.]*)?(?::[^}]*)?)\}";
+
+/// Matches a `{ticker:sensor.events|count=10}` line, capturing the entity id and
+/// the `|`-separated options.
+pub const TICKER_TOKEN_PATTERN: &str = r"\{ticker:(\w+\.\w+)((?:\|[^}|]*)*)\}";
+
+/// Matches an `{expr: sensor.power_a + sensor.power_b}` token, capturing the
+/// expression.
+pub const EXPR_TOKEN_PATTERN: &str = r"\{expr:([^}]*)\}";
+
+/// Matches an `{alias.temp:round=1}` token, capturing the alias name and the rest
+/// of the token (attribute path and modifiers).
+const ALIAS_TOKEN_PATTERN: &str = r"\{alias\.(\w+)([^}]*)\}";
+
+/// Number of recent values a ticker shows unless `count=` says otherwise.
+const DEFAULT_TICKER_COUNT: usize = 5;
+
+/// Matches a `${domain.object_id}` reference inside a templated entity id.
+pub const ENTITY_REF_PATTERN: &str = r"\$\{(\w+\.\w+)\}";
+
+/// Chroma subsampling used when encoding MJPEG frames.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JpegSubsampling {
+    Yuv444,
+    Yuv422,
+    Yuv420,
+}
+
+impl JpegSubsampling {
+    fn parse(value: &str) -> Result<Self> {
+        match value.trim() {
+            "444" | "4:4:4" => Ok(JpegSubsampling::Yuv444),
+            "422" | "4:2:2" => Ok(JpegSubsampling::Yuv422),
+            "420" | "4:2:0" => Ok(JpegSubsampling::Yuv420),
+            other => bail!(
+                "JPEG_SUBSAMPLING must be one of 444, 422 or 420 (got '{}')",
+                other
+            ),
+        }
+    }
+}
+
+/// Quantization range of the H.264 video sent over RTSP.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorRange {
+    /// Luma 16–235 ("TV" range), what most TVs and NVRs expect.
+    Limited,
+    /// Luma 0–255 ("PC" range).
+    Full,
+}
+
+impl ColorRange {
+    fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "limited" | "tv" => Ok(ColorRange::Limited),
+            "full" | "pc" => Ok(ColorRange::Full),
+            other => bail!(
+                "RTSP_COLOR_RANGE must be 'full' or 'limited' (got '{}')",
+                other
+            ),
+        }
+    }
+
+    /// GStreamer colorimetry (BT.709 primaries, matrix and transfer) with this range.
+    pub fn colorimetry(self) -> &'static str {
+        match self {
+            ColorRange::Limited => "bt709",
+            // range:matrix:transfer:primaries, i.e. bt709 with the 0-255 range
+            ColorRange::Full => "1:3:5:1",
+        }
+    }
+}
+
+/// Extra frame sink running next to the stream server (`OUTPUT`).
+#[derive(Clone, Debug, PartialEq)]
+pub enum FrameOutput {
+    /// The latest JPEG frame, replaced atomically.
+    File(PathBuf),
+    /// Raw RGB24 frames written back-to-back into a named pipe.
+    Pipe(PathBuf),
+}
+
+impl FrameOutput {
+    /// Parses `file:/path/frame.jpg` or `pipe:/path/fifo`.
+    fn parse(value: &str) -> Result<Self> {
+        let (kind, path) = value.trim().split_once(':').unwrap_or((value.trim(), ""));
+        if path.is_empty() {
+            bail!(
+                "OUTPUT must be file:/path/frame.jpg or pipe:/path/fifo (got '{}')",
+                value
+            );
+        }
+        match kind {
+            "file" => Ok(FrameOutput::File(PathBuf::from(path))),
+            "pipe" => Ok(FrameOutput::Pipe(PathBuf::from(path))),
+            _ => bail!(
+                "OUTPUT must be file:/path/frame.jpg or pipe:/path/fifo (got '{}')",
+                value
+            ),
+        }
+    }
+}
+
+/// How entity states are received from Home Assistant.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HaTransport {
+    /// Fetch every watched entity each `POLL_INTERVAL_SECS`.
+    Rest,
+    /// Receive state changes as server-sent events, polling only while the stream is down.
+    Sse,
+}
+
+impl HaTransport {
+    fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "" | "rest" => Ok(HaTransport::Rest),
+            "sse" => Ok(HaTransport::Sse),
+            other => bail!("HA_TRANSPORT must be 'rest' or 'sse' (got '{}')", other),
+        }
+    }
+}
+
+/// What a missing `LINE_n` between two configured lines becomes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LineGaps {
+    /// Skip it, so `LINE_1` and `LINE_3` are drawn as two adjacent lines.
+    Compact,
+    /// Keep an empty line in its place.
+    Spacer,
+}
+
+impl LineGaps {
+    fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "" | "compact" => Ok(LineGaps::Compact),
+            "spacer" => Ok(LineGaps::Spacer),
+            other => bail!("LINE_GAPS must be 'compact' or 'spacer' (got '{}')", other),
+        }
+    }
+}
+
+/// Frame corner a logo is drawn in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogoPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl LogoPosition {
+    fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().replace('_', "-").as_str() {
+            "top-left" => Ok(LogoPosition::TopLeft),
+            "top-right" => Ok(LogoPosition::TopRight),
+            "bottom-left" => Ok(LogoPosition::BottomLeft),
+            "bottom-right" => Ok(LogoPosition::BottomRight),
+            other => bail!(
+                "LOGO_POSITION must be top-left, top-right, bottom-left or bottom-right (got '{}')",
+                other
+            ),
+        }
+    }
+}
+
+/// `LOGO_IMAGE` and its placement.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Logo {
+    pub path: String,
+    pub position: LogoPosition,
+    /// Size relative to the image file, e.g. `0.5` for half size.
+    pub scale: f32,
+    /// Distance in pixels from the frame edges.
+    pub margin: u32,
+}
+
+/// A PEM certificate and key to terminate TLS with: `TLS_CERT_PATH` and
+/// `TLS_KEY_PATH` for HTTPS, `RTSP_TLS_CERT` and `RTSP_TLS_KEY` for RTSPS.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsFiles {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+/// How frames are reduced to pure black and white for e-paper panels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Monochrome {
+    /// Each pixel becomes black or white by its brightness. Crisp for text.
+    Threshold,
+    /// Floyd–Steinberg error diffusion, which keeps gradients and photos readable.
+    FloydSteinberg,
+}
+
+impl Monochrome {
+    /// `DITHER` picks the method once `MONOCHROME` is on.
+    fn parse_dither(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "" | "none" | "threshold" => Ok(Monochrome::Threshold),
+            "floyd-steinberg" | "floyd_steinberg" => Ok(Monochrome::FloydSteinberg),
+            other => bail!(
+                "DITHER must be 'none' or 'floyd-steinberg' (got '{}')",
+                other
+            ),
+        }
+    }
+}
+
+/// Output frame rate as an exact fraction, so rates like 7.5 or 30000/1001 fps work.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameRate {
+    pub num: u32,
+    pub den: u32,
+}
+
+impl FrameRate {
+    /// Parses `5`, `7.5` or `30000/1001`.
+    fn parse(value: &str) -> Result<Self> {
+        let value = value.trim();
+        let (num, den) = if let Some((num, den)) = value.split_once('/') {
+            (num.trim().parse::<u64>()?, den.trim().parse::<u64>()?)
+        } else if let Some((int, frac)) = value.split_once('.') {
+            let int = if int.is_empty() {
+                0
+            } else {
+                int.parse::<u64>()?
+            };
+            let den = 10u64
+                .checked_pow(frac.len() as u32)
+                .context("VIDEO_FPS has too many decimals")?;
+            let frac = frac.parse::<u64>()?;
+            let num = int
+                .checked_mul(den)
+                .and_then(|whole| whole.checked_add(frac))
+                .context("VIDEO_FPS has too many decimals")?;
+            (num, den)
+        } else {
+            (value.parse::<u64>()?, 1)
+        };
+
+        if num == 0 || den == 0 {
+            bail!("frame rate must be positive");
+        }
+
+        let divisor = gcd(num, den);
+        Ok(FrameRate {
+            num: u32::try_from(num / divisor)?,
+            den: u32::try_from(den / divisor)?,
+        })
+    }
+
+    /// Caps the rate at `max` frames per second, warning when it has to.
+    fn clamp_to(self, max: u32) -> Self {
+        if self.num as u64 <= max as u64 * self.den as u64 {
+            return self;
+        }
+        eprintln!(
+            "Warning: VIDEO_FPS {} is above MAX_FPS, using {} fps",
+            self, max
+        );
+        FrameRate { num: max, den: 1 }
+    }
+
+    /// Time between two frames.
+    pub fn frame_duration(&self) -> Duration {
+        Duration::from_nanos(1_000_000_000 * self.den as u64 / self.num as u64)
+    }
+}
+
+impl std::fmt::Display for FrameRate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.den == 1 {
+            write!(f, "{}", self.num)
+        } else {
+            write!(f, "{}/{}", self.num, self.den)
+        }
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Vertical position of an absolutely placed line.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinePosition {
+    Pixels(i32),
+    Percent(f32),
+}
+
+impl LinePosition {
+    fn parse(value: &str) -> Result<Self> {
+        let value = value.trim();
+        if let Some(percent) = value.strip_suffix('%') {
+            Ok(LinePosition::Percent(percent.trim().parse()?))
+        } else {
+            Ok(LinePosition::Pixels(value.parse()?))
+        }
+    }
+
+    /// Resolves to a pixel offset within a frame of the given height.
+    pub fn resolve(&self, height: u32) -> i32 {
+        match self {
+            LinePosition::Pixels(px) => *px,
+            LinePosition::Percent(pct) => (height as f32 * pct / 100.0).round() as i32,
+        }
+    }
+}
+
+/// Base direction of a line for the Unicode bidi algorithm.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextDirection {
+    Ltr,
+    Rtl,
+}
+
+impl TextDirection {
+    /// `auto` (the default) leaves the direction to the line's first strong character.
+    fn parse(value: &str) -> Result<Option<Self>> {
+        match value.trim().to_lowercase().as_str() {
+            "" | "auto" => Ok(None),
+            "ltr" => Ok(Some(TextDirection::Ltr)),
+            "rtl" => Ok(Some(TextDirection::Rtl)),
+            other => bail!("must be auto, ltr or rtl (got '{}')", other),
+        }
+    }
+}
+
+/// `BACKGROUND_GRADIENT`: a two-color background fading along one axis.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BackgroundGradient {
+    pub from: Rgb<u8>,
+    pub to: Rgb<u8>,
+    /// `true` fades from top to bottom, `false` from left to right.
+    pub vertical: bool,
+}
+
+impl BackgroundGradient {
+    /// Parses `FROM->TO` with an optional `,vertical` or `,horizontal`, e.g.
+    /// `#001020->#000000,vertical`. Colors can be names or `#rrggbb`.
+    fn parse(value: &str) -> Result<Self> {
+        let (colors, axis) = value.split_once(',').unwrap_or((value, "vertical"));
+        let vertical = match axis.trim().to_lowercase().as_str() {
+            "vertical" => true,
+            "horizontal" => false,
+            other => bail!(
+                "direction must be 'vertical' or 'horizontal' (got '{}')",
+                other
+            ),
+        };
+        let (from, to) = colors
+            .split_once("->")
+            .context("expected FROM->TO, e.g. #001020->#000000")?;
+        let color = |name: &str| {
+            parse_color(name.trim()).with_context(|| format!("unknown color '{}'", name.trim()))
+        };
+        Ok(BackgroundGradient {
+            from: color(from)?,
+            to: color(to)?,
+            vertical,
+        })
+    }
+
+    /// The color `pos` pixels along an axis of `len` pixels, `from` at the first
+    /// pixel and `to` at the last.
+    pub fn color_at(&self, pos: u32, len: u32) -> Rgb<u8> {
+        let t = pos as f32 / len.saturating_sub(1).max(1) as f32;
+        let mix = |from: u8, to: u8| (from as f32 + (to as f32 - from as f32) * t).round() as u8;
+        Rgb([
+            mix(self.from[0], self.to[0]),
+            mix(self.from[1], self.to[1]),
+            mix(self.from[2], self.to[2]),
+        ])
+    }
+}
+
+/// Slow drift of the rendered content to protect always-on OLED panels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AntiBurnin {
+    /// Maximum shift in pixels in each direction.
+    pub amplitude: u32,
+    /// Time for one full drift cycle.
+    pub period: Duration,
+}
+
+/// A `SENSOR_x_REGEX_REPLACE` rule, applied to the entity's state before display.
+#[derive(Clone, Debug)]
+pub struct ValueReplacement {
+    pub pattern: Regex,
+    /// Replacement text; may refer to capture groups as `$1` or `${name}`.
+    pub replacement: String,
+}
+
+impl ValueReplacement {
+    /// Parses `pattern=>replacement`.
+    fn parse(value: &str) -> Result<Self> {
+        let (pattern, replacement) = value
+            .split_once("=>")
+            .context("expected pattern=>replacement")?;
+        Ok(ValueReplacement {
+            pattern: Regex::new(pattern)?,
+            replacement: replacement.to_string(),
+        })
+    }
+}
+
+/// Per-line settings, kept in the same order as `Config::lines`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LineOptions {
+    /// Vertical center of the line. Unset lines take part in the centered flow.
+    pub y: Option<LinePosition>,
+    /// Blends the line toward the background (`0.0`–`1.0`). Unset means fully opaque.
+    pub opacity: Option<f32>,
+    /// `LINE_n_COLOR_WHEN` conditions on other entities, first match wins.
+    pub color_when: Vec<LineColorRule>,
+    /// `LINE_n_DIRECTION`; `None` detects it from the text.
+    pub direction: Option<TextDirection>,
+    /// `LINE_n_OUTLINE`; `None` follows `TEXT_OUTLINE`.
+    pub outline: Option<bool>,
+    /// `LINE_n_SIZE`; `None` uses `FONT_SIZE`.
+    pub font_size: Option<f32>,
+    /// `LINE_n_LAYER`; higher layers are drawn on top. `None` puts clocks and QR
+    /// codes on layer 0 and text on layer 1.
+    pub layer: Option<i32>,
+    /// `LINE_n_TRANSFORM` steps, applied in order to the resolved text.
+    pub transforms: Vec<TextTransform>,
+}
 
 #[derive(Clone)]
 pub struct Config {
+    /// `DEMO=on`: show synthetic values instead of polling Home Assistant.
+    pub demo: bool,
     pub ha_base_url: String,
     pub ha_token: String,
+    /// File the token was read from, re-read after an authentication failure.
+    pub ha_token_file: Option<String>,
+    /// Path of the REST API below the base URL, e.g. `/api`.
+    pub ha_api_prefix: String,
+    pub ha_transport: HaTransport,
+    pub ha_fallback_url: Option<String>,
+    pub ha_fallback_token: Option<String>,
     pub port: u16,
+    /// `SNAPSHOT_PORT`: in RTSP mode, also serve still frames over HTTP on this port.
+    pub snapshot_port: Option<u16>,
+    /// Serve HTTPS instead of plain HTTP.
+    pub tls: Option<TlsFiles>,
+    /// Serve RTSPS instead of plain RTSP.
+    pub rtsp_tls: Option<TlsFiles>,
+    pub http_timeout_secs: u64,
+    /// Longest a `/frame` long poll waits for a changed frame.
+    pub frame_max_wait: Duration,
+    /// `DEBUG_ENDPOINTS=on`: serve `/debug/frame` with the resolved lines.
+    pub debug_endpoints: bool,
+    /// `TRACE_FRAMES=on`: log the resolved lines whenever they change.
+    pub trace_frames: bool,
+    /// `SHARED_RENDERER=on`: render each frame once for all viewers.
+    pub shared_renderer: bool,
+    /// `SMART_FORMAT=on`: format plain tokens by the entity's `device_class`.
+    pub smart_format: bool,
+    /// `SUBPIXEL_TEXT=on`: place text at fractional pixel positions.
+    pub subpixel_text: bool,
+    /// Samples per pixel along each axis for the clock's anti-aliased lines and circles.
+    pub widget_supersampling: u32,
+    pub poll_interval_secs: u64,
+    /// Random variation of the poll interval as a fraction, e.g. `0.1` for ±10%.
+    pub poll_jitter: f64,
+    /// Spread the fetches of one cycle over the first half of the interval.
+    pub poll_stagger: bool,
     pub video_width: u32,
     pub video_height: u32,
-    pub video_fps: u64,
+    /// `RENDER_RESOLUTION`: draw at this size and scale down to the video size.
+    pub render_resolution: Option<(u32, u32)>,
+    pub video_fps: FrameRate,
     pub stream_format: String,
+    /// `RENDER_FRAMES=N`: render N frames, report the timing and exit.
+    pub render_frames: Option<u32>,
+    pub output: Option<FrameOutput>,
     pub lines: Vec<String>,
+    pub line_options: Vec<LineOptions>,
     pub font_size: f32,
+    /// TTF/OTF file to use instead of the embedded Lato.
+    pub font_path: Option<String>,
+    pub logo: Option<Logo>,
+    pub brightness: f32,
+    /// Scroll speed of `{ticker:...}` lines.
+    pub ticker_px_per_sec: f32,
+    /// Resolved lines longer than this many characters are truncated.
+    pub max_line_length: usize,
+    /// Number of columns the lines are laid out in.
+    pub grid_cols: u32,
+    pub text_color: Rgb<u8>,
+    pub background_color: Rgb<u8>,
+    /// Replaces the solid `background_color` fill when set.
+    pub background_gradient: Option<BackgroundGradient>,
+    /// `TEXT_OUTLINE=on`: outline text for readability over busy backgrounds.
+    pub text_outline: bool,
+    pub outline_color: Rgb<u8>,
+    /// `EMPTY_FRAME_TEXT`: shown while every line resolves to nothing.
+    pub empty_frame_text: Option<String>,
+    pub border_width: u32,
+    pub border_color: Rgb<u8>,
+    pub anti_burnin: Option<AntiBurnin>,
+    /// `IDLE_ANIMATION=on`: how long the values must stay unchanged before a
+    /// dot starts bouncing across the frame.
+    pub idle_animation: Option<Duration>,
+    /// Black-and-white output (`MONOCHROME`, `DITHER`); `None` keeps full color.
+    pub monochrome: Option<Monochrome>,
+    /// Named colors from `PALETTE`, as `(name, hex)` pairs in declaration order.
+    pub palette: Vec<(String, String)>,
     pub locale: String,
+    pub jpeg_subsampling: JpegSubsampling,
+    /// MCUs between JPEG restart markers, 0 for none.
+    pub jpeg_restart_interval: u16,
+    pub rtsp_color_range: ColorRange,
+    /// `RTSP_MAX_CLIENTS`: RTSP clients served at once, `None` for no limit.
+    pub rtsp_max_clients: Option<usize>,
+    pub sensor_grace_secs: u64,
+    pub fetch_concurrency: usize,
+    pub circuit_breaker_threshold: u32,
+    pub circuit_breaker_retry_secs: u64,
+    /// `ALERT_ON_DISCONNECT=on`: how long Home Assistant must be unreachable
+    /// before the background flashes red.
+    pub alert_after: Option<Duration>,
+    /// How often today's history is fetched for `max_today` / `min_today`.
+    pub history_refresh_secs: u64,
+    /// Smoothing applied to watched entities, keyed by entity id.
+    pub sensor_smoothing: HashMap<String, SmoothingMethod>,
+    /// Regex replacements applied in order to watched entities, keyed by entity id.
+    pub sensor_replacements: HashMap<String, Vec<ValueReplacement>>,
+    /// Attribute shown instead of the state, keyed by entity id.
+    pub sensor_primary_attrs: HashMap<String, String>,
 }
 
 impl Config {
     pub fn from_env() -> Result<Self> {
-        let ha_base_url = env::var("HA_BASE_URL").expect("HA_BASE_URL must be set");
-        let ha_token = env::var("HA_LONG_LIVED_TOKEN").expect("HA_LONG_LIVED_TOKEN must be set");
+        // Demo mode renders synthetic values and never talks to Home Assistant
+        let demo = matches!(
+            env::var("DEMO").unwrap_or_default().as_str(),
+            "on" | "true" | "1"
+        );
+        let ha_base_url = env::var("HA_BASE_URL")
+            .or_else(|e| if demo { Ok(String::new()) } else { Err(e) })
+            .expect("HA_BASE_URL must be set");
+        // A token file (e.g. a Docker secret) wins and is re-read when HA answers 401
+        let ha_token_file = env::var("HA_LONG_LIVED_TOKEN_FILE")
+            .ok()
+            .filter(|path| !path.is_empty());
+        let ha_token = match &ha_token_file {
+            Some(path) => read_token_file(Path::new(path))?,
+            None => env::var("HA_LONG_LIVED_TOKEN")
+                .or_else(|e| if demo { Ok(String::new()) } else { Err(e) })
+                .expect("HA_LONG_LIVED_TOKEN must be set"),
+        };
         let port = env::var("PORT")
             .unwrap_or_else(|_| "8080".to_string())
             .parse()
             .expect("PORT must be a number");
+        let snapshot_port = env::var("SNAPSHOT_PORT")
+            .ok()
+            .filter(|port| !port.is_empty())
+            .map(|port| port.parse().expect("SNAPSHOT_PORT must be a number"));
+        if snapshot_port == Some(port) {
+            bail!(
+                "SNAPSHOT_PORT must differ from PORT ({}), the stream listens there",
+                port
+            );
+        }
+        let tls = tls_files_from_env("TLS_CERT_PATH", "TLS_KEY_PATH", "HTTPS")?;
+        let rtsp_tls = tls_files_from_env("RTSP_TLS_CERT", "RTSP_TLS_KEY", "RTSPS")?;
+        let http_timeout_secs = env::var("HTTP_TIMEOUT_SECS")
+            .unwrap_or_else(|_| "10".to_string())
+            .parse()
+            .expect("HTTP_TIMEOUT_SECS must be a number");
+        let frame_max_wait = Duration::from_secs(
+            env::var("FRAME_MAX_WAIT_SECS")
+                .unwrap_or_else(|_| "30".to_string())
+                .parse()
+                .expect("FRAME_MAX_WAIT_SECS must be a number"),
+        );
+        let debug_endpoints = matches!(
+            env::var("DEBUG_ENDPOINTS").unwrap_or_default().as_str(),
+            "on" | "true" | "1"
+        );
+        let trace_frames = matches!(
+            env::var("TRACE_FRAMES").unwrap_or_default().as_str(),
+            "on" | "true" | "1"
+        );
+        let shared_renderer = matches!(
+            env::var("SHARED_RENDERER").unwrap_or_default().as_str(),
+            "on" | "true" | "1"
+        );
+        let smart_format = matches!(
+            env::var("SMART_FORMAT").unwrap_or_default().as_str(),
+            "on" | "true" | "1"
+        );
+        let subpixel_text = matches!(
+            env::var("SUBPIXEL_TEXT").unwrap_or_default().as_str(),
+            "on" | "true" | "1"
+        );
+        let widget_supersampling: u32 = env::var("WIDGET_SUPERSAMPLING")
+            .unwrap_or_else(|_| "4".to_string())
+            .parse()
+            .expect("WIDGET_SUPERSAMPLING must be a number");
+        if !(1..=16).contains(&widget_supersampling) {
+            bail!(
+                "WIDGET_SUPERSAMPLING must be between 1 and 16 (got {})",
+                widget_supersampling
+            );
+        }
+        let poll_interval_secs = env::var("POLL_INTERVAL_SECS")
+            .unwrap_or_else(|_| "10".to_string())
+            .parse::<u64>()
+            .expect("POLL_INTERVAL_SECS must be a number")
+            .max(1);
+        let poll_jitter: f64 = env::var("POLL_JITTER")
+            .unwrap_or_else(|_| "0.1".to_string())
+            .parse()
+            .expect("POLL_JITTER must be a number");
+        if !(0.0..=1.0).contains(&poll_jitter) {
+            bail!(
+                "POLL_JITTER must be between 0.0 and 1.0 (got {})",
+                poll_jitter
+            );
+        }
+        let poll_stagger = matches!(
+            env::var("POLL_STAGGER").unwrap_or_default().as_str(),
+            "on" | "true" | "1"
+        );
         let video_width = env::var("VIDEO_WIDTH")
             .unwrap_or_else(|_| "640".to_string())
             .parse()
@@ -33,18 +661,194 @@ impl Config {
             .unwrap_or_else(|_| "360".to_string())
             .parse()
             .expect("VIDEO_HEIGHT must be a number");
-        let video_fps = env::var("VIDEO_FPS")
-            .unwrap_or_else(|_| "5".to_string())
+        let render_resolution = match env::var("RENDER_RESOLUTION") {
+            Ok(value) if !value.is_empty() => Some(
+                parse_resolution(&value).context("RENDER_RESOLUTION must look like 1280x720")?,
+            ),
+            _ => None,
+        };
+        let video_fps = FrameRate::parse(&env::var("VIDEO_FPS").unwrap_or_else(|_| "5".into()))
+            .context("VIDEO_FPS must be a number or fraction like 30000/1001")?;
+        let max_fps: u32 = env::var("MAX_FPS")
+            .unwrap_or_else(|_| "60".to_string())
             .parse()
-            .expect("VIDEO_FPS must be a number");
+            .expect("MAX_FPS must be a number");
+        let video_fps = video_fps.clamp_to(max_fps.max(1));
         let stream_format = env::var("STREAM_FORMAT")
             .unwrap_or_else(|_| "mjpeg".to_string())
             .to_lowercase();
+        let render_frames = match env::var("RENDER_FRAMES").ok().filter(|n| !n.is_empty()) {
+            Some(n) => match n.parse::<u32>().expect("RENDER_FRAMES must be a number") {
+                0 => bail!("RENDER_FRAMES must be at least 1"),
+                n => Some(n),
+            },
+            None => None,
+        };
+        let output = match env::var("OUTPUT").ok().filter(|value| !value.is_empty()) {
+            Some(value) => Some(FrameOutput::parse(&value)?),
+            None => None,
+        };
         let font_size = env::var("FONT_SIZE")
             .unwrap_or_else(|_| "48.0".to_string())
             .parse()
             .expect("FONT_SIZE must be a number");
+        let font_path = env::var("FONT_PATH").ok().filter(|path| !path.is_empty());
+        let logo = match env::var("LOGO_IMAGE").ok().filter(|path| !path.is_empty()) {
+            Some(path) => {
+                let scale: f32 = env::var("LOGO_SCALE")
+                    .unwrap_or_else(|_| "1.0".to_string())
+                    .parse()
+                    .expect("LOGO_SCALE must be a number");
+                if !scale.is_finite() || scale <= 0.0 {
+                    bail!("LOGO_SCALE must be greater than 0 (got {})", scale);
+                }
+                Some(Logo {
+                    path,
+                    position: LogoPosition::parse(
+                        &env::var("LOGO_POSITION").unwrap_or_else(|_| "bottom-right".into()),
+                    )?,
+                    scale,
+                    margin: env::var("LOGO_MARGIN")
+                        .unwrap_or_else(|_| "8".to_string())
+                        .parse()
+                        .expect("LOGO_MARGIN must be a number"),
+                })
+            }
+            None => None,
+        };
+        let brightness: f32 = env::var("BRIGHTNESS")
+            .unwrap_or_else(|_| "1.0".to_string())
+            .parse()
+            .expect("BRIGHTNESS must be a number");
+        if !(0.0..=1.0).contains(&brightness) {
+            bail!(
+                "BRIGHTNESS must be between 0.0 and 1.0 (got {})",
+                brightness
+            );
+        }
+        let ticker_px_per_sec: f32 = env::var("TICKER_PX_PER_SEC")
+            .unwrap_or_else(|_| "60".to_string())
+            .parse()
+            .expect("TICKER_PX_PER_SEC must be a number");
+        if !ticker_px_per_sec.is_finite() || ticker_px_per_sec <= 0.0 {
+            bail!(
+                "TICKER_PX_PER_SEC must be greater than 0 (got {})",
+                ticker_px_per_sec
+            );
+        }
+        let max_line_length: usize = env::var("MAX_LINE_LENGTH")
+            .unwrap_or_else(|_| "512".to_string())
+            .parse()
+            .expect("MAX_LINE_LENGTH must be a number");
+        if max_line_length == 0 {
+            bail!("MAX_LINE_LENGTH must be at least 1");
+        }
+        let border_width = env::var("BORDER_WIDTH")
+            .unwrap_or_else(|_| "0".to_string())
+            .parse()
+            .expect("BORDER_WIDTH must be a number");
+        let border_color = color_from_env("BORDER_COLOR", "white")?;
+        let grid_cols = env::var("GRID_COLS")
+            .unwrap_or_else(|_| "1".to_string())
+            .parse::<u32>()
+            .expect("GRID_COLS must be a number")
+            .max(1);
+        let text_color = color_from_env("TEXT_COLOR", "white")?;
+        let background_color = color_from_env("BACKGROUND_COLOR", "black")?;
+        let background_gradient = match env::var("BACKGROUND_GRADIENT") {
+            Ok(value) if !value.is_empty() => {
+                Some(BackgroundGradient::parse(&value).context("Invalid BACKGROUND_GRADIENT")?)
+            }
+            _ => None,
+        };
+        let text_outline = matches!(
+            env::var("TEXT_OUTLINE").unwrap_or_default().as_str(),
+            "on" | "true" | "1"
+        );
+        let outline_color = color_from_env("OUTLINE_COLOR", "black")?;
+        let empty_frame_text = env::var("EMPTY_FRAME_TEXT")
+            .ok()
+            .filter(|text| !text.trim().is_empty());
+        let anti_burnin = match env::var("ANTI_BURNIN").unwrap_or_default().as_str() {
+            "on" | "true" | "1" => Some(AntiBurnin {
+                amplitude: env::var("ANTI_BURNIN_AMPLITUDE")
+                    .unwrap_or_else(|_| "4".to_string())
+                    .parse()
+                    .expect("ANTI_BURNIN_AMPLITUDE must be a number"),
+                period: Duration::from_secs(
+                    env::var("ANTI_BURNIN_PERIOD_SECS")
+                        .unwrap_or_else(|_| "600".to_string())
+                        .parse::<u64>()
+                        .expect("ANTI_BURNIN_PERIOD_SECS must be a number")
+                        .max(1),
+                ),
+            }),
+            _ => None,
+        };
+        let idle_animation = match env::var("IDLE_ANIMATION").unwrap_or_default().as_str() {
+            "on" | "true" | "1" => Some(Duration::from_secs(
+                env::var("IDLE_ANIMATION_AFTER_SECS")
+                    .unwrap_or_else(|_| "300".to_string())
+                    .parse()
+                    .expect("IDLE_ANIMATION_AFTER_SECS must be a number"),
+            )),
+            _ => None,
+        };
+        let monochrome = match env::var("MONOCHROME").unwrap_or_default().as_str() {
+            "on" | "true" | "1" => Some(Monochrome::parse_dither(
+                &env::var("DITHER").unwrap_or_default(),
+            )?),
+            _ => None,
+        };
+        let palette = parse_palette(&env::var("PALETTE").unwrap_or_default())?;
         let locale = env::var("LOCALE").unwrap_or_else(|_| "en_US".to_string());
+        let sensor_grace_secs = env::var("SENSOR_GRACE_SECS")
+            .unwrap_or_else(|_| "60".to_string())
+            .parse()
+            .expect("SENSOR_GRACE_SECS must be a number");
+        let fetch_concurrency = env::var("FETCH_CONCURRENCY")
+            .unwrap_or_else(|_| "4".to_string())
+            .parse()
+            .expect("FETCH_CONCURRENCY must be a number");
+        let circuit_breaker_threshold = env::var("CIRCUIT_BREAKER_THRESHOLD")
+            .unwrap_or_else(|_| "5".to_string())
+            .parse()
+            .expect("CIRCUIT_BREAKER_THRESHOLD must be a number");
+        let circuit_breaker_retry_secs = env::var("CIRCUIT_BREAKER_RETRY_SECS")
+            .unwrap_or_else(|_| "300".to_string())
+            .parse()
+            .expect("CIRCUIT_BREAKER_RETRY_SECS must be a number");
+        let alert_after = match env::var("ALERT_ON_DISCONNECT").unwrap_or_default().as_str() {
+            "on" | "true" | "1" => Some(Duration::from_secs(
+                env::var("ALERT_AFTER_SECS")
+                    .unwrap_or_else(|_| "60".to_string())
+                    .parse()
+                    .expect("ALERT_AFTER_SECS must be a number"),
+            )),
+            _ => None,
+        };
+        let history_refresh_secs = env::var("HISTORY_REFRESH_SECS")
+            .unwrap_or_else(|_| "900".to_string())
+            .parse()
+            .expect("HISTORY_REFRESH_SECS must be a number");
+        let jpeg_subsampling =
+            JpegSubsampling::parse(&env::var("JPEG_SUBSAMPLING").unwrap_or_else(|_| "420".into()))?;
+        let jpeg_restart_interval = env::var("JPEG_RESTART_INTERVAL")
+            .unwrap_or_else(|_| "0".to_string())
+            .parse()
+            .expect("JPEG_RESTART_INTERVAL must be a number between 0 and 65535");
+        let rtsp_color_range =
+            ColorRange::parse(&env::var("RTSP_COLOR_RANGE").unwrap_or_else(|_| "limited".into()))?;
+        let rtsp_max_clients = match env::var("RTSP_MAX_CLIENTS").ok().filter(|n| !n.is_empty()) {
+            Some(n) => match n
+                .parse::<usize>()
+                .expect("RTSP_MAX_CLIENTS must be a number")
+            {
+                0 => bail!("RTSP_MAX_CLIENTS must be at least 1"),
+                n => Some(n),
+            },
+            None => None,
+        };
 
         // Ensure base URL doesn't end with slash for cleaner path joining
         let ha_base_url = if ha_base_url.ends_with('/') {
@@ -53,21 +857,60 @@ impl Config {
             ha_base_url
         };
 
+        let ha_api_prefix =
+            normalize_api_prefix(&env::var("HA_API_PREFIX").unwrap_or_else(|_| "/api".into()));
+        let ha_transport = HaTransport::parse(&env::var("HA_TRANSPORT").unwrap_or_default())?;
+
+        // Optional secondary instance, tried when the primary is unreachable
+        let ha_fallback_url = env::var("HA_BASE_URL_FALLBACK")
+            .ok()
+            .filter(|url| !url.is_empty())
+            .map(|url| url.trim_end_matches('/').to_string());
+        let ha_fallback_token = env::var("HA_LONG_LIVED_TOKEN_FALLBACK")
+            .ok()
+            .filter(|token| !token.is_empty());
+
         // Parse Lines
         let mut lines = Vec::new();
+        let mut line_options = Vec::new();
         let mut has_line_config = false;
+        let line_gaps = LineGaps::parse(&env::var("LINE_GAPS").unwrap_or_default())?;
+        let mut missing = 0;
 
         for i in 1..=4 {
-            if let Ok(line) = env::var(format!("LINE_{}", i)) {
-                if !line.is_empty() {
+            match env::var(format!("LINE_{}", i)) {
+                Ok(line) if !line.is_empty() => {
+                    // Only gaps between configured lines, never before the first one
+                    if line_gaps == LineGaps::Spacer && has_line_config {
+                        lines.extend(std::iter::repeat_n(String::new(), missing));
+                        line_options.extend(std::iter::repeat_n(LineOptions::default(), missing));
+                    }
+                    missing = 0;
                     lines.push(line);
+                    line_options.push(Self::line_options_from_env(i)?);
                     has_line_config = true;
                 }
+                _ => missing += 1,
+            }
+        }
+
+        // A directory of line files takes precedence over LINE_n
+        if let Ok(dir) = env::var("LINES_DIR") {
+            if !dir.is_empty() {
+                lines = read_lines_dir(Path::new(&dir))?;
+                line_options = vec![LineOptions::default(); lines.len()];
+                has_line_config = true;
             }
         }
 
         // Fallback to old config if no lines are defined
-        if !has_line_config {
+        if !has_line_config && demo {
+            lines = crate::demo::DEMO_LINES
+                .iter()
+                .map(|l| l.to_string())
+                .collect();
+            line_options = vec![LineOptions::default(); lines.len()];
+        } else if !has_line_config {
             let date_format = env::var("DATE_FORMAT").unwrap_or_else(|_| "%Y-%m-%d".to_string());
             let time_format = env::var("TIME_FORMAT").unwrap_or_else(|_| "%H.%M".to_string());
             let sensor_entity_id = env::var("SENSOR_ENTITY_ID")
@@ -80,26 +923,260 @@ impl Config {
                 .strip_prefix("sensor.")
                 .unwrap_or(&sensor_entity_id);
             lines.push(format!("{{sensor.{}}}°", entity_id_part));
+            line_options = vec![LineOptions::default(); lines.len()];
         }
 
-        Ok(Config {
+        // `{alias.temp}` becomes the sensor token it stands for, so everything
+        // downstream only ever sees real entity ids
+        let aliases = aliases_from_env()?;
+        let lines = lines
+            .iter()
+            .map(|line| expand_aliases(line, &aliases))
+            .collect();
+
+        let mut config = Config {
+            demo,
             ha_base_url,
             ha_token,
+            ha_token_file,
+            ha_api_prefix,
+            ha_transport,
+            ha_fallback_url,
+            ha_fallback_token,
             port,
+            snapshot_port,
+            tls,
+            rtsp_tls,
+            http_timeout_secs,
+            frame_max_wait,
+            debug_endpoints,
+            trace_frames,
+            shared_renderer,
+            smart_format,
+            subpixel_text,
+            widget_supersampling,
+            poll_interval_secs,
+            poll_jitter,
+            poll_stagger,
             video_width,
             video_height,
+            render_resolution,
             video_fps,
             stream_format,
+            render_frames,
+            output,
             lines,
+            line_options,
             font_size,
+            font_path,
+            logo,
+            brightness,
+            ticker_px_per_sec,
+            max_line_length,
+            grid_cols,
+            text_color,
+            background_color,
+            background_gradient,
+            text_outline,
+            outline_color,
+            empty_frame_text,
+            border_width,
+            border_color,
+            anti_burnin,
+            idle_animation,
+            monochrome,
+            palette,
             locale,
+            jpeg_subsampling,
+            jpeg_restart_interval,
+            rtsp_color_range,
+            rtsp_max_clients,
+            sensor_grace_secs,
+            fetch_concurrency,
+            circuit_breaker_threshold,
+            circuit_breaker_retry_secs,
+            alert_after,
+            history_refresh_secs,
+            sensor_smoothing: HashMap::new(),
+            sensor_replacements: HashMap::new(),
+            sensor_primary_attrs: HashMap::new(),
+        };
+
+        // Per-entity options, e.g. SENSOR_OUTDOOR_TEMP_SMOOTH for sensor.outdoor_temp
+        for entity_id in config.get_required_sensors() {
+            let key = entity_env_var(&entity_id, "SMOOTH");
+            if let Ok(value) = env::var(&key) {
+                let method =
+                    SmoothingMethod::parse(&value).with_context(|| format!("Invalid {}", key))?;
+                config.sensor_smoothing.insert(entity_id.clone(), method);
+            }
+
+            let key = entity_env_var(&entity_id, "PRIMARY_ATTR");
+            if let Some(attribute) = env::var(&key).ok().filter(|name| !name.trim().is_empty()) {
+                config
+                    .sensor_primary_attrs
+                    .insert(entity_id.clone(), attribute.trim().to_string());
+            }
+
+            // SENSOR_X_REGEX_REPLACE, then _2, _3, ... for further rules
+            let mut replacements = Vec::new();
+            for n in 1.. {
+                let suffix = match n {
+                    1 => "REGEX_REPLACE".to_string(),
+                    n => format!("REGEX_REPLACE_{}", n),
+                };
+                let key = entity_env_var(&entity_id, &suffix);
+                let Ok(value) = env::var(&key) else { break };
+                replacements.push(
+                    ValueReplacement::parse(&value).with_context(|| format!("Invalid {}", key))?,
+                );
+            }
+            if !replacements.is_empty() {
+                config.sensor_replacements.insert(entity_id, replacements);
+            }
+        }
+
+        for (entity_id, window) in config.get_average_windows() {
+            if parse_window(&window).is_none() {
+                bail!(
+                    "Invalid avg window '{}' for {}, expected e.g. 60s, 5m or 1h",
+                    window,
+                    entity_id
+                );
+            }
+        }
+
+        for (entity_id, tolerance) in config.get_trend_tolerances() {
+            if !tolerance
+                .trim()
+                .parse::<f64>()
+                .is_ok_and(|t| t.is_finite() && t >= 0.0)
+            {
+                bail!(
+                    "Invalid trend tolerance '{}' for {}, expected a number such as 0.5",
+                    tolerance,
+                    entity_id
+                );
+            }
+        }
+
+        for (entity_id, rules) in config.modifier_args("emoji") {
+            parse_emoji_rules(&rules)
+                .with_context(|| format!("Invalid emoji rules for {}", entity_id))?;
+        }
+
+        Ok(config)
+    }
+
+    fn line_options_from_env(index: usize) -> Result<LineOptions> {
+        let y = match env::var(format!("LINE_{}_Y", index)) {
+            Ok(value) if !value.is_empty() => Some(
+                LinePosition::parse(&value)
+                    .with_context(|| format!("LINE_{}_Y must be pixels or a percentage", index))?,
+            ),
+            _ => None,
+        };
+
+        let opacity = match env::var(format!("LINE_{}_OPACITY", index)) {
+            Ok(value) if !value.is_empty() => {
+                let opacity: f32 = value
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("LINE_{}_OPACITY must be a number", index))?;
+                if !(0.0..=1.0).contains(&opacity) {
+                    bail!(
+                        "LINE_{}_OPACITY must be between 0.0 and 1.0 (got {})",
+                        index,
+                        opacity
+                    );
+                }
+                Some(opacity)
+            }
+            _ => None,
+        };
+
+        let font_size = match env::var(format!("LINE_{}_SIZE", index)) {
+            Ok(value) if !value.is_empty() => {
+                let size: f32 = value
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("LINE_{}_SIZE must be a number", index))?;
+                if !size.is_finite() || size <= 0.0 {
+                    bail!("LINE_{}_SIZE must be greater than 0 (got {})", index, size);
+                }
+                Some(size)
+            }
+            _ => None,
+        };
+
+        let color_when = match env::var(format!("LINE_{}_COLOR_WHEN", index)) {
+            Ok(value) => parse_line_color_rules(&value)
+                .with_context(|| format!("Invalid LINE_{}_COLOR_WHEN", index))?,
+            Err(_) => Vec::new(),
+        };
+
+        let direction = TextDirection::parse(
+            &env::var(format!("LINE_{}_DIRECTION", index)).unwrap_or_default(),
+        )
+        .with_context(|| format!("Invalid LINE_{}_DIRECTION", index))?;
+
+        let outline = match env::var(format!("LINE_{}_OUTLINE", index))
+            .unwrap_or_default()
+            .as_str()
+        {
+            "" => None,
+            "on" | "true" | "1" => Some(true),
+            "off" | "false" | "0" => Some(false),
+            other => bail!("LINE_{}_OUTLINE must be on or off (got '{}')", index, other),
+        };
+
+        let layer = match env::var(format!("LINE_{}_LAYER", index)) {
+            Ok(value) if !value.is_empty() => Some(
+                value
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("LINE_{}_LAYER must be a whole number", index))?,
+            ),
+            _ => None,
+        };
+
+        let transforms =
+            parse_pipeline(&env::var(format!("LINE_{}_TRANSFORM", index)).unwrap_or_default())
+                .with_context(|| format!("Invalid LINE_{}_TRANSFORM", index))?;
+
+        Ok(LineOptions {
+            y,
+            opacity,
+            color_when,
+            direction,
+            outline,
+            font_size,
+            layer,
+            transforms,
         })
     }
 
-    /// Extracts unique sensor entity IDs from the configured lines.
+    /// Extracts unique sensor entity IDs from the configured lines. Attribute paths
+    /// and modifiers are stripped, so `{sensor.temp.attributes.x}` and
+    /// `{sensor.temp:round=1}` both watch `sensor.temp`.
+    ///
+    /// Templated tokens like `{sensor.${input_select.room}_temp}` contribute the
+    /// entities they reference (`input_select.room`); the entity they resolve to
+    /// changes at runtime and comes from [`Config::get_templated_sensors`].
+    /// `{expr:...}` tokens contribute every entity in the expression.
     pub fn get_required_sensors(&self) -> Vec<String> {
-        let re = Regex::new(r"\{sensor\.([\w\.]+)\}").expect("Invalid regex");
-        let mut sensors = HashSet::new();
+        let re = Regex::new(SENSOR_TOKEN_PATTERN).expect("Invalid regex");
+        let expr_re = Regex::new(EXPR_TOKEN_PATTERN).expect("Invalid regex");
+        let templated_re = Regex::new(TEMPLATED_TOKEN_PATTERN).expect("Invalid regex");
+        let ref_re = Regex::new(ENTITY_REF_PATTERN).expect("Invalid regex");
+        let mut sensors: HashSet<String> = self
+            .get_ticker_sensors()
+            .into_iter()
+            .map(|(entity_id, _)| entity_id)
+            .collect();
+        for options in &self.line_options {
+            sensors.extend(options.color_when.iter().map(|r| r.entity_id.clone()));
+        }
 
         for line in &self.lines {
             for cap in re.captures_iter(line) {
@@ -107,97 +1184,1385 @@ impl Config {
                     sensors.insert(format!("sensor.{}", match_str.as_str()));
                 }
             }
+            for cap in templated_re.captures_iter(line) {
+                for reference in ref_re.captures_iter(&cap[1]) {
+                    sensors.insert(reference[1].to_string());
+                }
+            }
+            for cap in expr_re.captures_iter(line) {
+                sensors.extend(expr::entity_ids(&cap[1]));
+            }
         }
 
         let mut result: Vec<String> = sensors.into_iter().collect();
         result.sort(); // Sort for deterministic output
         result
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use serial_test::serial;
 
-    #[test]
-    #[serial]
-    fn test_config_from_env_defaults() {
-        // Set required env vars
-        env::set_var("HA_BASE_URL", "http://localhost:8123");
-        env::set_var("HA_LONG_LIVED_TOKEN", "test_token");
+    /// Entity ID templates of tokens that interpolate other entities' states, e.g.
+    /// `sensor.${input_select.room}_temp`.
+    pub fn get_templated_sensors(&self) -> Vec<String> {
+        let re = Regex::new(TEMPLATED_TOKEN_PATTERN).expect("Invalid regex");
+        let mut templates = HashSet::new();
 
-        // Clear optional ones to test defaults
-        env::remove_var("SENSOR_ENTITY_ID");
-        env::remove_var("PORT");
-        env::remove_var("DATE_FORMAT");
-        env::remove_var("TIME_FORMAT");
-        env::remove_var("VIDEO_WIDTH");
-        env::remove_var("VIDEO_HEIGHT");
-        env::remove_var("VIDEO_FPS");
-        env::remove_var("STREAM_FORMAT");
-        env::remove_var("FONT_SIZE");
-        env::remove_var("LOCALE");
-        for i in 1..=4 {
-            env::remove_var(format!("LINE_{}", i));
+        for line in &self.lines {
+            for cap in re.captures_iter(line) {
+                if cap[1].contains("${") {
+                    templates.insert(format!("sensor.{}", &cap[1]));
+                }
+            }
         }
 
-        let config = Config::from_env().unwrap();
+        let mut result: Vec<String> = templates.into_iter().collect();
+        result.sort();
+        result
+    }
 
-        assert_eq!(config.ha_base_url, "http://localhost:8123");
-        assert_eq!(config.ha_token, "test_token");
-        assert_eq!(config.port, 8080);
-        assert_eq!(config.video_width, 640);
-        assert_eq!(config.video_height, 360);
-        assert_eq!(config.video_fps, 5);
-        assert_eq!(config.stream_format, "mjpeg");
-        assert_eq!(config.font_size, 48.0);
-        assert_eq!(config.locale, "en_US");
+    /// `(entity id, count)` for every `{ticker:...}` line, keeping the largest count
+    /// when an entity has several tickers.
+    pub fn get_ticker_sensors(&self) -> Vec<(String, usize)> {
+        let re = Regex::new(TICKER_TOKEN_PATTERN).expect("Invalid regex");
+        let mut tickers: HashMap<String, usize> = HashMap::new();
 
-        // Check fallback lines
-        assert_eq!(config.lines.len(), 3);
-        assert_eq!(config.lines[0], "{time:%Y-%m-%d}");
-        assert_eq!(config.lines[1], "{time:%H.%M}");
-        assert_eq!(config.lines[2], "{sensor.ute_kombinerad}°"); // This now correctly matches the entity ID part
+        for line in &self.lines {
+            for cap in re.captures_iter(line) {
+                let count = cap[2]
+                    .split('|')
+                    .filter_map(|arg| arg.trim().strip_prefix("count="))
+                    .find_map(|count| count.parse().ok())
+                    .unwrap_or(DEFAULT_TICKER_COUNT)
+                    .max(1);
+                let entry = tickers.entry(cap[1].to_string()).or_default();
+                *entry = (*entry).max(count);
+            }
+        }
 
-        // Cleanup
-        env::remove_var("HA_BASE_URL");
-        env::remove_var("HA_LONG_LIVED_TOKEN");
+        let mut result: Vec<(String, usize)> = tickers.into_iter().collect();
+        result.sort();
+        result
     }
 
-    #[test]
+    /// Entity IDs used with a history modifier such as `{sensor.temp:max_today}`.
+    pub fn get_history_sensors(&self) -> Vec<String> {
+        let re = Regex::new(SENSOR_TOKEN_PATTERN).expect("Invalid regex");
+        let mut sensors = HashSet::new();
+
+        for line in &self.lines {
+            for cap in re.captures_iter(line) {
+                let Some(spec) = cap.get(2) else { continue };
+                let uses_history = parse_modifiers(spec.as_str())
+                    .iter()
+                    .any(|m| HISTORY_MODIFIERS.contains(&m.name.as_str()));
+                if uses_history {
+                    sensors.insert(format!("sensor.{}", &cap[1]));
+                }
+            }
+        }
+
+        let mut result: Vec<String> = sensors.into_iter().collect();
+        result.sort();
+        result
+    }
+
+    /// Entity IDs shown with the `trend` modifier, e.g. `{sensor.temp:trend=0.5}`.
+    pub fn get_trend_sensors(&self) -> Vec<String> {
+        let re = Regex::new(SENSOR_TOKEN_PATTERN).expect("Invalid regex");
+        let mut sensors = HashSet::new();
+
+        for line in &self.lines {
+            for cap in re.captures_iter(line) {
+                let Some(spec) = cap.get(2) else { continue };
+                if parse_modifiers(spec.as_str())
+                    .iter()
+                    .any(|m| m.name == "trend")
+                {
+                    sensors.insert(format!("sensor.{}", &cap[1]));
+                }
+            }
+        }
+
+        let mut result: Vec<String> = sensors.into_iter().collect();
+        result.sort();
+        result
+    }
+
+    /// `(entity id, window)` pairs for every `{sensor.x:avg=WINDOW}` token, e.g.
+    /// `("sensor.power", "60s")`.
+    pub fn get_average_windows(&self) -> Vec<(String, String)> {
+        let re = Regex::new(SENSOR_TOKEN_PATTERN).expect("Invalid regex");
+        let mut windows = HashSet::new();
+
+        for line in &self.lines {
+            for cap in re.captures_iter(line) {
+                let Some(spec) = cap.get(2) else { continue };
+                for modifier in parse_modifiers(spec.as_str()) {
+                    if modifier.name == "avg" {
+                        let window = modifier.arg.unwrap_or_default();
+                        windows.insert((format!("sensor.{}", &cap[1]), window));
+                    }
+                }
+            }
+        }
+
+        let mut result: Vec<(String, String)> = windows.into_iter().collect();
+        result.sort();
+        result
+    }
+
+    /// `(entity id, tolerance)` pairs for every `{sensor.x:trend=TOLERANCE}` token;
+    /// a bare `trend` has no tolerance to check and is left out.
+    pub fn get_trend_tolerances(&self) -> Vec<(String, String)> {
+        self.modifier_args("trend")
+    }
+
+    /// `(entity id, argument)` pairs for every `{sensor.x:NAME=ARG}` token.
+    fn modifier_args(&self, name: &str) -> Vec<(String, String)> {
+        let re = Regex::new(SENSOR_TOKEN_PATTERN).expect("Invalid regex");
+        let mut args = HashSet::new();
+
+        for line in &self.lines {
+            for cap in re.captures_iter(line) {
+                let Some(spec) = cap.get(2) else { continue };
+                for modifier in parse_modifiers(spec.as_str()) {
+                    if let Some(arg) = modifier.arg.filter(|_| modifier.name == name) {
+                        args.insert((format!("sensor.{}", &cap[1]), arg));
+                    }
+                }
+            }
+        }
+
+        let mut result: Vec<(String, String)> = args.into_iter().collect();
+        result.sort();
+        result
+    }
+}
+
+/// The certificate and key named by `cert_var` and `key_var`, `None` when neither
+/// is set. Setting only one of them is an error.
+fn tls_files_from_env(cert_var: &str, key_var: &str, protocol: &str) -> Result<Option<TlsFiles>> {
+    let path = |name| env::var(name).ok().filter(|path: &String| !path.is_empty());
+    match (path(cert_var), path(key_var)) {
+        (Some(cert_path), Some(key_path)) => Ok(Some(TlsFiles {
+            cert_path,
+            key_path,
+        })),
+        (None, None) => Ok(None),
+        (Some(_), None) => bail!(
+            "{} is set but {} is not, {} needs both",
+            cert_var,
+            key_var,
+            protocol
+        ),
+        (None, Some(_)) => bail!(
+            "{} is set but {} is not, {} needs both",
+            key_var,
+            cert_var,
+            protocol
+        ),
+    }
+}
+
+/// Reads an access token from a secret file, ignoring surrounding whitespace.
+pub fn read_token_file(path: &Path) -> Result<String> {
+    let token = fs::read_to_string(path)
+        .with_context(|| format!("Cannot read HA_LONG_LIVED_TOKEN_FILE {:?}", path))?;
+    let token = token.trim();
+    if token.is_empty() {
+        bail!("HA_LONG_LIVED_TOKEN_FILE {:?} is empty", path);
+    }
+    Ok(token.to_string())
+}
+
+/// Reads one line template per file, ordered by the numeric filename prefix
+/// (`10-title.txt` before `20-temp.txt`). Empty files become spacer lines.
+fn read_lines_dir(dir: &Path) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+
+    for entry in fs::read_dir(dir).with_context(|| format!("Cannot read LINES_DIR {:?}", dir))? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        // Skip hidden entries such as the `..data` links of Kubernetes ConfigMap mounts
+        if name.starts_with('.') || !fs::metadata(&path).map(|m| m.is_file()).unwrap_or(false) {
+            continue;
+        }
+
+        let digits: String = name.chars().take_while(|c| c.is_ascii_digit()).collect();
+        let order = digits.parse::<u64>().unwrap_or(u64::MAX);
+        files.push((order, name, path));
+    }
+
+    files.sort();
+
+    files
+        .into_iter()
+        .map(|(_, _, path)| {
+            let content = fs::read_to_string(&path)
+                .with_context(|| format!("Cannot read line file {:?}", path))?;
+            Ok(content.trim_end_matches(['\r', '\n']).to_string())
+        })
+        .collect()
+}
+
+/// Fills in settings from a whole config document in `CONFIG_JSON` or `CONFIG_TOML`,
+/// e.g. from a Kubernetes ConfigMap or secret. Env vars that are already set win.
+/// Call before `Config::from_env`, while no other threads read the environment.
+pub fn load_config_document() -> Result<()> {
+    let (name, settings) = match (env::var("CONFIG_JSON"), env::var("CONFIG_TOML")) {
+        (Ok(_), Ok(_)) => bail!("Set only one of CONFIG_JSON and CONFIG_TOML"),
+        (Ok(json), _) => ("CONFIG_JSON", parse_config_json(&json)?),
+        (_, Ok(toml)) => ("CONFIG_TOML", parse_config_toml(&toml)?),
+        _ => return Ok(()),
+    };
+
+    let mut applied = 0;
+    for (key, value) in settings {
+        if env::var_os(&key).is_none() {
+            env::set_var(&key, value);
+            applied += 1;
+        }
+    }
+    println!("Loaded {} settings from {}", applied, name);
+    Ok(())
+}
+
+fn parse_config_json(content: &str) -> Result<Vec<(String, String)>> {
+    let document: serde_json::Value =
+        serde_json::from_str(content).context("CONFIG_JSON is not valid JSON")?;
+    flatten_config_document(document).context("Invalid CONFIG_JSON")
+}
+
+fn parse_config_toml(content: &str) -> Result<Vec<(String, String)>> {
+    let document: serde_json::Value =
+        toml::from_str(content).context("CONFIG_TOML is not valid TOML")?;
+    flatten_config_document(document).context("Invalid CONFIG_TOML")
+}
+
+/// Turns a config document into env var settings. Keys are upper-cased, nested
+/// tables are joined with `_` and lists are numbered from 1, so
+/// `{"line": ["a", "b"], "line_2": {"size": 24}}` sets `LINE_1`, `LINE_2`
+/// and `LINE_2_SIZE`.
+fn flatten_config_document(document: serde_json::Value) -> Result<Vec<(String, String)>> {
+    fn flatten(key: String, value: serde_json::Value, out: &mut Vec<(String, String)>) {
+        match value {
+            serde_json::Value::Null => {}
+            serde_json::Value::String(s) => out.push((key, s)),
+            serde_json::Value::Array(items) => {
+                for (i, item) in items.into_iter().enumerate() {
+                    flatten(format!("{}_{}", key, i + 1), item, out);
+                }
+            }
+            serde_json::Value::Object(map) => {
+                for (child, item) in map {
+                    flatten(format!("{}_{}", key, child.to_uppercase()), item, out);
+                }
+            }
+            scalar => out.push((key, scalar.to_string())),
+        }
+    }
+
+    let serde_json::Value::Object(map) = document else {
+        bail!("the document must be a table of settings");
+    };
+    let mut settings = Vec::new();
+    for (key, value) in map {
+        flatten(key.to_uppercase(), value, &mut settings);
+    }
+    Ok(settings)
+}
+
+/// Ensures a leading slash and strips trailing ones: `homeassistant/api/` -> `/homeassistant/api`.
+/// An empty prefix stays empty.
+fn normalize_api_prefix(prefix: &str) -> String {
+    let trimmed = prefix.trim().trim_matches('/');
+    if trimmed.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", trimmed)
+    }
+}
+
+/// Parses a `WIDTHxHEIGHT` size such as `1280x720`.
+fn parse_resolution(value: &str) -> Result<(u32, u32)> {
+    let (width, height) = value
+        .trim()
+        .split_once(['x', 'X'])
+        .context("expected WIDTHxHEIGHT")?;
+    let width: u32 = width.trim().parse()?;
+    let height: u32 = height.trim().parse()?;
+    if width == 0 || height == 0 {
+        bail!("both sides must be at least 1 pixel");
+    }
+    Ok((width, height))
+}
+
+/// `ALIAS_name=sensor.entity_id` variables, keyed by the lowercased name so
+/// `ALIAS_TEMP` from a config document still matches `{alias.temp}`.
+fn aliases_from_env() -> Result<HashMap<String, String>> {
+    let entity_re = Regex::new(r"^sensor\.\w+$").expect("Invalid regex");
+    let mut aliases = HashMap::new();
+    for (key, value) in env::vars() {
+        let Some(name) = key.strip_prefix("ALIAS_") else {
+            continue;
+        };
+        let entity_id = value.trim();
+        if !entity_re.is_match(entity_id) {
+            bail!(
+                "{} must be a sensor entity id such as sensor.living_room_temperature (got '{}')",
+                key,
+                value
+            );
+        }
+        aliases.insert(name.to_lowercase(), entity_id.to_string());
+    }
+    Ok(aliases)
+}
+
+/// Replaces each `{alias.name...}` token in `line` with the sensor token of the
+/// aliased entity, keeping attribute paths and modifiers. Unknown aliases are
+/// reported and replaced with the `?` placeholder.
+fn expand_aliases(line: &str, aliases: &HashMap<String, String>) -> String {
+    let re = Regex::new(ALIAS_TOKEN_PATTERN).expect("Invalid regex");
+    re.replace_all(line, |caps: &regex::Captures| {
+        match aliases.get(&caps[1].to_lowercase()) {
+            Some(entity_id) => format!("{{{}{}}}", entity_id, &caps[2]),
+            None => {
+                eprintln!(
+                    "Warning: Unknown alias '{}' in line '{}', set ALIAS_{} to an entity id",
+                    &caps[1], line, &caps[1]
+                );
+                "?".to_string()
+            }
+        }
+    })
+    .into_owned()
+}
+
+/// CSS color names accepted wherever a color is configured.
+const CSS_COLORS: &[(&str, [u8; 3])] = &[
+    ("black", [0, 0, 0]),
+    ("white", [255, 255, 255]),
+    ("gray", [128, 128, 128]),
+    ("grey", [128, 128, 128]),
+    ("silver", [192, 192, 192]),
+    ("lightgray", [211, 211, 211]),
+    ("lightgrey", [211, 211, 211]),
+    ("darkgray", [169, 169, 169]),
+    ("darkgrey", [169, 169, 169]),
+    ("red", [255, 0, 0]),
+    ("darkred", [139, 0, 0]),
+    ("maroon", [128, 0, 0]),
+    ("crimson", [220, 20, 60]),
+    ("tomato", [255, 99, 71]),
+    ("coral", [255, 127, 80]),
+    ("salmon", [250, 128, 114]),
+    ("orange", [255, 165, 0]),
+    ("gold", [255, 215, 0]),
+    ("yellow", [255, 255, 0]),
+    ("khaki", [240, 230, 140]),
+    ("olive", [128, 128, 0]),
+    ("lime", [0, 255, 0]),
+    ("green", [0, 128, 0]),
+    ("darkgreen", [0, 100, 0]),
+    ("teal", [0, 128, 128]),
+    ("turquoise", [64, 224, 208]),
+    ("cyan", [0, 255, 255]),
+    ("aqua", [0, 255, 255]),
+    ("skyblue", [135, 206, 235]),
+    ("blue", [0, 0, 255]),
+    ("darkblue", [0, 0, 139]),
+    ("navy", [0, 0, 128]),
+    ("indigo", [75, 0, 130]),
+    ("purple", [128, 0, 128]),
+    ("violet", [238, 130, 238]),
+    ("magenta", [255, 0, 255]),
+    ("fuchsia", [255, 0, 255]),
+    ("pink", [255, 192, 203]),
+    ("brown", [165, 42, 42]),
+    ("chocolate", [210, 105, 30]),
+    ("tan", [210, 180, 140]),
+    ("beige", [245, 245, 220]),
+    ("ivory", [255, 255, 240]),
+];
+
+/// Parses a CSS color name (`navy`) or a `#rrggbb` hex code.
+pub fn parse_color(value: &str) -> Option<Rgb<u8>> {
+    let name = value.trim().to_lowercase();
+    CSS_COLORS
+        .iter()
+        .find(|(css, _)| *css == name)
+        .map(|(_, rgb)| Rgb(*rgb))
+        .or_else(|| parse_hex_color(value))
+}
+
+/// Reads a color variable, failing with the list of valid names if it can't be parsed.
+fn color_from_env(key: &str, default: &str) -> Result<Rgb<u8>> {
+    let value = env::var(key).unwrap_or_else(|_| default.to_string());
+    match parse_color(&value) {
+        Some(color) => Ok(color),
+        None => {
+            let names: Vec<&str> = CSS_COLORS.iter().map(|(name, _)| *name).collect();
+            bail!(
+                "{} must be #rrggbb or one of: {} (got {})",
+                key,
+                names.join(", "),
+                value
+            )
+        }
+    }
+}
+
+/// Parses a `#rrggbb` color.
+pub fn parse_hex_color(value: &str) -> Option<Rgb<u8>> {
+    let hex = value.trim().strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some(Rgb([channel(0)?, channel(2)?, channel(4)?]))
+}
+
+/// Builds the name of a per-entity env var: `sensor.outdoor_temp` + `SMOOTH`
+/// becomes `SENSOR_OUTDOOR_TEMP_SMOOTH`.
+pub fn entity_env_var(entity_id: &str, suffix: &str) -> String {
+    format!("{}_{}", entity_id.replace('.', "_"), suffix).to_uppercase()
+}
+
+/// Parses `PALETTE`, e.g. `warn:#ff0000,ok:#00ff00`. Colors are validated by `ImageGenerator`.
+fn parse_palette(value: &str) -> Result<Vec<(String, String)>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (name, color) = entry.split_once(':').with_context(|| {
+                format!("PALETTE entry '{}' must look like name:#rrggbb", entry)
+            })?;
+            Ok((name.trim().to_string(), color.trim().to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[test]
+    #[serial]
+    fn test_config_from_env_defaults() {
+        // Set required env vars
+        env::set_var("HA_BASE_URL", "http://localhost:8123");
+        env::set_var("HA_LONG_LIVED_TOKEN", "test_token");
+
+        // Clear optional ones to test defaults
+        env::remove_var("HA_BASE_URL_FALLBACK");
+        env::remove_var("HA_LONG_LIVED_TOKEN_FALLBACK");
+        env::remove_var("HA_API_PREFIX");
+        env::remove_var("HA_TRANSPORT");
+        env::remove_var("LINE_GAPS");
+        env::remove_var("DEMO");
+        env::remove_var("SENSOR_ENTITY_ID");
+        env::remove_var("PORT");
+        env::remove_var("SNAPSHOT_PORT");
+        env::remove_var("HTTP_TIMEOUT_SECS");
+        env::remove_var("FRAME_MAX_WAIT_SECS");
+        env::remove_var("DEBUG_ENDPOINTS");
+        env::remove_var("TRACE_FRAMES");
+        env::remove_var("TLS_CERT_PATH");
+        env::remove_var("TLS_KEY_PATH");
+        env::remove_var("RTSP_TLS_CERT");
+        env::remove_var("RTSP_TLS_KEY");
+        env::remove_var("SHARED_RENDERER");
+        env::remove_var("SMART_FORMAT");
+        env::remove_var("SUBPIXEL_TEXT");
+        env::remove_var("WIDGET_SUPERSAMPLING");
+        env::remove_var("POLL_INTERVAL_SECS");
+        env::remove_var("POLL_JITTER");
+        env::remove_var("POLL_STAGGER");
+        env::remove_var("DATE_FORMAT");
+        env::remove_var("TIME_FORMAT");
+        env::remove_var("VIDEO_WIDTH");
+        env::remove_var("RENDER_RESOLUTION");
+        env::remove_var("VIDEO_HEIGHT");
+        env::remove_var("VIDEO_FPS");
+        env::remove_var("MAX_FPS");
+        env::remove_var("STREAM_FORMAT");
+        env::remove_var("RENDER_FRAMES");
+        env::remove_var("FONT_SIZE");
+        env::remove_var("FONT_PATH");
+        env::remove_var("LOCALE");
+        env::remove_var("PALETTE");
+        env::remove_var("BORDER_WIDTH");
+        env::remove_var("BORDER_COLOR");
+        env::remove_var("TEXT_COLOR");
+        env::remove_var("GRID_COLS");
+        env::remove_var("BACKGROUND_COLOR");
+        env::remove_var("BACKGROUND_GRADIENT");
+        env::remove_var("TEXT_OUTLINE");
+        env::remove_var("OUTLINE_COLOR");
+        env::remove_var("EMPTY_FRAME_TEXT");
+        env::remove_var("ANTI_BURNIN");
+        env::remove_var("IDLE_ANIMATION");
+        env::remove_var("IDLE_ANIMATION_AFTER_SECS");
+        env::remove_var("MONOCHROME");
+        env::remove_var("LOGO_IMAGE");
+        env::remove_var("LOGO_POSITION");
+        env::remove_var("LOGO_SCALE");
+        env::remove_var("LOGO_MARGIN");
+        env::remove_var("DITHER");
+        env::remove_var("JPEG_SUBSAMPLING");
+        env::remove_var("JPEG_RESTART_INTERVAL");
+        env::remove_var("RTSP_COLOR_RANGE");
+        env::remove_var("RTSP_MAX_CLIENTS");
+        env::remove_var("LINES_DIR");
+        env::remove_var("SENSOR_GRACE_SECS");
+        env::remove_var("FETCH_CONCURRENCY");
+        env::remove_var("CIRCUIT_BREAKER_THRESHOLD");
+        env::remove_var("CIRCUIT_BREAKER_RETRY_SECS");
+        env::remove_var("ALERT_ON_DISCONNECT");
+        env::remove_var("HISTORY_REFRESH_SECS");
+        for i in 1..=4 {
+            env::remove_var(format!("LINE_{}", i));
+        }
+
+        let config = Config::from_env().unwrap();
+
+        assert!(!config.demo);
+        assert_eq!(config.ha_base_url, "http://localhost:8123");
+        assert_eq!(config.ha_token, "test_token");
+        assert_eq!(config.ha_api_prefix, "/api");
+        assert_eq!(config.ha_transport, HaTransport::Rest);
+        assert_eq!(config.ha_fallback_url, None);
+        assert_eq!(config.ha_fallback_token, None);
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.snapshot_port, None);
+        assert_eq!(config.tls, None);
+        assert_eq!(config.rtsp_tls, None);
+        assert_eq!(config.http_timeout_secs, 10);
+        assert_eq!(config.frame_max_wait, Duration::from_secs(30));
+        assert!(!config.debug_endpoints);
+        assert!(!config.trace_frames);
+        assert!(!config.shared_renderer);
+        assert!(!config.smart_format);
+        assert!(!config.subpixel_text);
+        assert_eq!(config.widget_supersampling, 4);
+        assert_eq!(config.poll_interval_secs, 10);
+        assert_eq!(config.poll_jitter, 0.1);
+        assert!(!config.poll_stagger);
+        assert_eq!(config.video_width, 640);
+        assert_eq!(config.render_resolution, None);
+        assert_eq!(config.video_height, 360);
+        assert_eq!(config.video_fps, FrameRate { num: 5, den: 1 });
+        assert_eq!(config.stream_format, "mjpeg");
+        assert_eq!(config.render_frames, None);
+        assert_eq!(config.output, None);
+        assert_eq!(config.font_size, 48.0);
+        assert_eq!(config.font_path, None);
+        assert_eq!(config.logo, None);
+        assert_eq!(config.brightness, 1.0);
+        assert_eq!(config.ticker_px_per_sec, 60.0);
+        assert_eq!(config.max_line_length, 512);
+        assert!(config.palette.is_empty());
+        assert_eq!(config.border_width, 0);
+        assert_eq!(config.border_color, Rgb([255, 255, 255]));
+        assert_eq!(config.text_color, Rgb([255, 255, 255]));
+        assert_eq!(config.grid_cols, 1);
+        assert_eq!(config.background_color, Rgb([0, 0, 0]));
+        assert_eq!(config.background_gradient, None);
+        assert!(!config.text_outline);
+        assert_eq!(config.empty_frame_text, None);
+        assert_eq!(config.outline_color, Rgb([0, 0, 0]));
+        assert_eq!(config.anti_burnin, None);
+        assert_eq!(config.idle_animation, None);
+        assert_eq!(config.monochrome, None);
+        assert_eq!(config.locale, "en_US");
+        assert_eq!(config.jpeg_subsampling, JpegSubsampling::Yuv420);
+        assert_eq!(config.jpeg_restart_interval, 0);
+        assert_eq!(config.rtsp_color_range, ColorRange::Limited);
+        assert_eq!(config.rtsp_max_clients, None);
+        assert_eq!(config.sensor_grace_secs, 60);
+        assert_eq!(config.fetch_concurrency, 4);
+        assert_eq!(config.circuit_breaker_threshold, 5);
+        assert_eq!(config.circuit_breaker_retry_secs, 300);
+        assert_eq!(config.alert_after, None);
+        assert_eq!(config.history_refresh_secs, 900);
+        assert!(config.get_history_sensors().is_empty());
+
+        // Check fallback lines
+        assert_eq!(config.lines.len(), 3);
+        assert_eq!(config.lines[0], "{time:%Y-%m-%d}");
+        assert_eq!(config.lines[1], "{time:%H.%M}");
+        assert_eq!(config.lines[2], "{sensor.ute_kombinerad}°"); // This now correctly matches the entity ID part
+
+        // Cleanup
+        env::remove_var("HA_BASE_URL");
+        env::remove_var("HA_LONG_LIVED_TOKEN");
+    }
+
+    #[test]
     #[serial]
     fn test_config_lines() {
         env::set_var("HA_BASE_URL", "http://localhost:8123");
         env::set_var("HA_LONG_LIVED_TOKEN", "test_token");
+        env::set_var("HA_BASE_URL_FALLBACK", "http://backup:8123/");
 
         // Set line config
         env::set_var("LINE_1", "Hello World");
-        env::set_var("LINE_2", "Temp: {sensor.temp}°C");
+        env::set_var("LINE_2", "Temp: {sensor.temp}°C ({sensor.temp:avg=5m})");
         env::set_var("LINE_3", "{time:%H:%M:%S}");
+        env::set_var("LINE_3_Y", "90%");
+        env::set_var("LINE_3_OPACITY", "0.5");
+        env::set_var("LINE_3_COLOR_WHEN", "binary_sensor.alarm==on:red");
+        env::set_var("LINE_3_DIRECTION", "RTL");
+        env::set_var("LINE_3_OUTLINE", "off");
+        env::set_var("LINE_3_SIZE", "24");
+        env::set_var("LINE_3_LAYER", "-1");
+        env::set_var("LINE_3_TRANSFORM", "trim|truncate:5");
+        env::set_var(
+            "LINE_4",
+            "H {sensor.out:max_today} L {sensor.out:min_today}",
+        );
         env::set_var("FONT_SIZE", "64");
         env::set_var("LOCALE", "sv_SE");
+        env::set_var("JPEG_SUBSAMPLING", "444");
+        env::set_var("JPEG_RESTART_INTERVAL", "4");
+        env::set_var("RTSP_COLOR_RANGE", "Full");
+        env::set_var("RTSP_MAX_CLIENTS", "3");
+        env::set_var("SENSOR_TEMP_SMOOTH", "median3");
+        env::set_var("SENSOR_OUT_REGEX_REPLACE", r"\s*\(.*\)=>");
+        env::set_var("SENSOR_OUT_REGEX_REPLACE_2", "^Playing (.*)=>▶ $1");
+        env::set_var("SENSOR_OUT_PRIMARY_ATTR", " temperature ");
+        env::set_var("ANTI_BURNIN", "on");
+        env::set_var("IDLE_ANIMATION", "on");
+        env::set_var("IDLE_ANIMATION_AFTER_SECS", "90");
+        env::set_var("MONOCHROME", "on");
+        env::set_var("LOGO_IMAGE", "/logo.png");
+        env::set_var("LOGO_POSITION", "top_left");
+        env::set_var("LOGO_SCALE", "0.5");
+        env::set_var("LOGO_MARGIN", "4");
+        env::set_var("DITHER", "floyd-steinberg");
 
         let config = Config::from_env().unwrap();
 
-        assert_eq!(config.lines.len(), 3);
+        assert_eq!(config.lines.len(), 4);
         assert_eq!(config.lines[0], "Hello World");
-        assert_eq!(config.lines[1], "Temp: {sensor.temp}°C");
+        assert_eq!(
+            config.lines[1],
+            "Temp: {sensor.temp}°C ({sensor.temp:avg=5m})"
+        );
         assert_eq!(config.lines[2], "{time:%H:%M:%S}");
+        assert_eq!(config.line_options[0].y, None);
+        assert_eq!(config.line_options[2].y, Some(LinePosition::Percent(90.0)));
+        assert_eq!(config.line_options[0].opacity, None);
+        assert_eq!(config.line_options[2].opacity, Some(0.5));
+        assert_eq!(config.line_options[0].direction, None);
+        assert_eq!(config.line_options[2].direction, Some(TextDirection::Rtl));
+        assert_eq!(config.line_options[0].outline, None);
+        assert_eq!(config.line_options[2].outline, Some(false));
+        assert_eq!(config.line_options[0].font_size, None);
+        assert_eq!(config.line_options[2].font_size, Some(24.0));
+        assert_eq!(config.line_options[0].layer, None);
+        assert_eq!(config.line_options[2].layer, Some(-1));
+        assert!(config.line_options[0].transforms.is_empty());
+        assert_eq!(
+            config.line_options[2].transforms,
+            vec![TextTransform::Trim, TextTransform::Truncate(5)]
+        );
         assert_eq!(config.font_size, 64.0);
         assert_eq!(config.locale, "sv_SE");
+        assert_eq!(config.jpeg_subsampling, JpegSubsampling::Yuv444);
+        assert_eq!(config.jpeg_restart_interval, 4);
+        assert_eq!(config.rtsp_color_range, ColorRange::Full);
+        assert_eq!(config.rtsp_max_clients, Some(3));
+        assert_eq!(
+            config.anti_burnin,
+            Some(AntiBurnin {
+                amplitude: 4,
+                period: Duration::from_secs(600)
+            })
+        );
+        assert_eq!(config.idle_animation, Some(Duration::from_secs(90)));
+        assert_eq!(config.monochrome, Some(Monochrome::FloydSteinberg));
+        assert_eq!(
+            config.logo,
+            Some(Logo {
+                path: "/logo.png".to_string(),
+                position: LogoPosition::TopLeft,
+                scale: 0.5,
+                margin: 4,
+            })
+        );
 
+        assert_eq!(
+            config.ha_fallback_url.as_deref(),
+            Some("http://backup:8123")
+        );
+        assert_eq!(config.ha_fallback_token, None);
+
+        assert_eq!(
+            config.line_options[2].color_when[0].entity_id,
+            "binary_sensor.alarm"
+        );
         let sensors = config.get_required_sensors();
-        assert_eq!(sensors.len(), 1);
-        assert_eq!(sensors[0], "sensor.temp");
+        assert_eq!(
+            sensors,
+            vec!["binary_sensor.alarm", "sensor.out", "sensor.temp"]
+        );
+        assert_eq!(config.get_history_sensors(), vec!["sensor.out"]);
+        assert_eq!(
+            config.get_average_windows(),
+            vec![("sensor.temp".to_string(), "5m".to_string())]
+        );
+        assert_eq!(
+            config.sensor_smoothing.get("sensor.temp"),
+            Some(&SmoothingMethod::Median(3))
+        );
+        let replacements = &config.sensor_replacements["sensor.out"];
+        assert_eq!(replacements.len(), 2);
+        assert_eq!(replacements[1].replacement, "▶ $1");
+        assert!(!config.sensor_replacements.contains_key("sensor.temp"));
+        assert_eq!(config.sensor_primary_attrs["sensor.out"], "temperature");
+        assert!(!config.sensor_primary_attrs.contains_key("sensor.temp"));
 
         // Cleanup
         env::remove_var("HA_BASE_URL");
         env::remove_var("HA_LONG_LIVED_TOKEN");
+        env::remove_var("HA_BASE_URL_FALLBACK");
         env::remove_var("LINE_1");
         env::remove_var("LINE_2");
         env::remove_var("LINE_3");
+        env::remove_var("LINE_3_Y");
+        env::remove_var("LINE_3_OPACITY");
+        env::remove_var("LINE_3_COLOR_WHEN");
+        env::remove_var("LINE_3_DIRECTION");
+        env::remove_var("LINE_3_OUTLINE");
+        env::remove_var("LINE_3_SIZE");
+        env::remove_var("LINE_3_LAYER");
+        env::remove_var("LINE_3_TRANSFORM");
+        env::remove_var("LINE_4");
         env::remove_var("FONT_SIZE");
         env::remove_var("LOCALE");
+        env::remove_var("JPEG_SUBSAMPLING");
+        env::remove_var("JPEG_RESTART_INTERVAL");
+        env::remove_var("RTSP_COLOR_RANGE");
+        env::remove_var("RTSP_MAX_CLIENTS");
+        env::remove_var("SENSOR_TEMP_SMOOTH");
+        env::remove_var("SENSOR_OUT_REGEX_REPLACE");
+        env::remove_var("SENSOR_OUT_REGEX_REPLACE_2");
+        env::remove_var("SENSOR_OUT_PRIMARY_ATTR");
+        env::remove_var("ANTI_BURNIN");
+        env::remove_var("IDLE_ANIMATION");
+        env::remove_var("IDLE_ANIMATION_AFTER_SECS");
+        env::remove_var("MONOCHROME");
+        env::remove_var("LOGO_IMAGE");
+        env::remove_var("LOGO_POSITION");
+        env::remove_var("LOGO_SCALE");
+        env::remove_var("LOGO_MARGIN");
+        env::remove_var("DITHER");
+    }
+
+    #[test]
+    fn test_frame_rate_parse() {
+        let rate = |s| FrameRate::parse(s).unwrap();
+        assert_eq!(rate("7.5"), FrameRate { num: 15, den: 2 });
+        assert_eq!(
+            rate("30000/1001"),
+            FrameRate {
+                num: 30000,
+                den: 1001
+            }
+        );
+        assert_eq!(rate("10/2"), FrameRate { num: 5, den: 1 });
+        assert_eq!(
+            rate("7.5").frame_duration(),
+            Duration::from_nanos(133_333_333)
+        );
+        assert_eq!(rate("1000").clamp_to(60), FrameRate { num: 60, den: 1 });
+        assert_eq!(rate("30000/1001").clamp_to(30), rate("30000/1001"));
+        assert!(FrameRate::parse("0").is_err());
+        assert!(FrameRate::parse("fast").is_err());
+        assert!(FrameRate::parse("7.50000000000000000000")
+            .unwrap_err()
+            .to_string()
+            .contains("too many decimals"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_demo_mode_needs_no_home_assistant() {
+        env::remove_var("HA_BASE_URL");
+        env::remove_var("HA_LONG_LIVED_TOKEN");
+        env::set_var("DEMO", "on");
+        let config = Config::from_env().unwrap();
+        env::remove_var("DEMO");
+
+        assert!(config.demo);
+        assert_eq!(config.lines[0], "DEMO MODE");
+        assert!(config
+            .get_required_sensors()
+            .contains(&crate::demo::DEMO_TEMPERATURE.to_string()));
+    }
+
+    #[test]
+    #[serial]
+    fn test_required_sensors_strip_modifiers_and_attributes() {
+        env::set_var("HA_BASE_URL", "http://localhost:8123");
+        env::set_var("HA_LONG_LIVED_TOKEN", "test_token");
+        let mut config = Config::from_env().unwrap();
+        env::remove_var("HA_BASE_URL");
+        env::remove_var("HA_LONG_LIVED_TOKEN");
+
+        config.lines = vec![
+            "{sensor.temp:round=1}".to_string(),
+            "{sensor.temp:unit} / {sensor.temp:relative:color=warn>25}".to_string(),
+            "{sensor.temp.attributes.unit_of_measurement}".to_string(),
+            "{sensor.humidity} {sensor.power_w:avg=60s}".to_string(),
+        ];
+
+        assert_eq!(
+            config.get_required_sensors(),
+            vec!["sensor.humidity", "sensor.power_w", "sensor.temp"]
+        );
+        assert_eq!(
+            config.get_average_windows(),
+            vec![("sensor.power_w".to_string(), "60s".to_string())]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_required_sensors_include_expr_operands() {
+        env::set_var("HA_BASE_URL", "http://localhost:8123");
+        env::set_var("HA_LONG_LIVED_TOKEN", "test_token");
+        let mut config = Config::from_env().unwrap();
+        env::remove_var("HA_BASE_URL");
+        env::remove_var("HA_LONG_LIVED_TOKEN");
+
+        config.lines = vec![
+            "{expr: sensor.power_a + sensor.power_b - input_number.offset}".to_string(),
+            "{expr: (sensor.power_a * 2)} of {sensor.limit}".to_string(),
+        ];
+
+        assert_eq!(
+            config.get_required_sensors(),
+            vec![
+                "input_number.offset",
+                "sensor.limit",
+                "sensor.power_a",
+                "sensor.power_b"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_value_replacement_parse() {
+        let rule = ValueReplacement::parse(r"\s*\(.*\)=>").unwrap();
+        assert_eq!(
+            rule.pattern
+                .replace_all("Playing (Spotify)", rule.replacement.as_str()),
+            "Playing"
+        );
+        assert!(ValueReplacement::parse("(unclosed=>x").is_err());
+        assert!(ValueReplacement::parse("no arrow").is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_named_colors() {
+        assert_eq!(parse_color("navy"), Some(Rgb([0, 0, 128])));
+        assert_eq!(parse_color(" White "), Some(Rgb([255, 255, 255])));
+        assert_eq!(parse_color("#102030"), Some(Rgb([16, 32, 48])));
+        assert_eq!(parse_color("nope"), None);
+
+        env::set_var("TEXT_COLOR", "blurple");
+        let err = color_from_env("TEXT_COLOR", "white").unwrap_err();
+        env::remove_var("TEXT_COLOR");
+        assert!(err.to_string().contains("navy"));
+    }
+
+    #[test]
+    fn test_normalize_api_prefix() {
+        assert_eq!(normalize_api_prefix("/api"), "/api");
+        assert_eq!(
+            normalize_api_prefix("homeassistant/api/"),
+            "/homeassistant/api"
+        );
+        assert_eq!(normalize_api_prefix("//ha//api//"), "/ha//api");
+        assert_eq!(normalize_api_prefix("/"), "");
+    }
+
+    #[test]
+    fn test_parse_palette() {
+        assert_eq!(
+            parse_palette("warn:#ff0000, ok:#00ff00").unwrap(),
+            vec![
+                ("warn".to_string(), "#ff0000".to_string()),
+                ("ok".to_string(), "#00ff00".to_string())
+            ]
+        );
+        assert!(parse_palette("").unwrap().is_empty());
+        assert!(parse_palette("warn").is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_lines_dir() {
+        let dir = env::temp_dir().join(format!("ha-streamer-lines-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("20-temp.txt"), "Temp: {sensor.temp}\n").unwrap();
+        fs::write(dir.join("10-title.txt"), "Living Room").unwrap();
+        fs::write(dir.join("100-footer.txt"), "{time:%H:%M}").unwrap();
+        fs::write(dir.join("15-spacer.txt"), "").unwrap();
+        fs::write(dir.join(".hidden"), "ignored").unwrap();
+
+        env::set_var("HA_BASE_URL", "http://localhost:8123");
+        env::set_var("HA_LONG_LIVED_TOKEN", "test_token");
+        env::set_var("LINE_1", "Overridden");
+        env::set_var("LINES_DIR", &dir);
+
+        let config = Config::from_env().unwrap();
+
+        assert_eq!(
+            config.lines,
+            vec!["Living Room", "", "Temp: {sensor.temp}", "{time:%H:%M}"]
+        );
+        assert_eq!(config.line_options.len(), 4);
+        assert_eq!(config.get_required_sensors(), vec!["sensor.temp"]);
+
+        env::remove_var("HA_BASE_URL");
+        env::remove_var("HA_LONG_LIVED_TOKEN");
+        env::remove_var("LINE_1");
+        env::remove_var("LINES_DIR");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_templated_sensors() {
+        env::set_var("HA_BASE_URL", "http://localhost:8123");
+        env::set_var("HA_LONG_LIVED_TOKEN", "test_token");
+        env::set_var("LINE_1", "Room: {sensor.${input_select.room}_temp:percent}");
+        env::set_var(
+            "LINE_2",
+            "{sensor.outdoor} {sensor.${input_select.floor}_${input_select.room}}",
+        );
+
+        let config = Config::from_env().unwrap();
+
+        assert_eq!(
+            config.get_required_sensors(),
+            vec!["input_select.floor", "input_select.room", "sensor.outdoor"]
+        );
+        assert_eq!(
+            config.get_templated_sensors(),
+            vec![
+                "sensor.${input_select.floor}_${input_select.room}",
+                "sensor.${input_select.room}_temp"
+            ]
+        );
+
+        env::remove_var("HA_BASE_URL");
+        env::remove_var("HA_LONG_LIVED_TOKEN");
+        env::remove_var("LINE_1");
+        env::remove_var("LINE_2");
+    }
+
+    #[test]
+    #[serial]
+    fn test_trend_tolerances() {
+        env::set_var("HA_BASE_URL", "http://localhost:8123");
+        env::set_var("HA_LONG_LIVED_TOKEN", "test_token");
+        env::set_var("LINE_1", "{sensor.out:trend=0.5} {sensor.in:trend}");
+
+        let config = Config::from_env().unwrap();
+        assert_eq!(
+            config.get_trend_tolerances(),
+            vec![("sensor.out".to_string(), "0.5".to_string())]
+        );
+
+        // A decimal comma would otherwise draw `?` instead of an arrow
+        env::set_var("LINE_1", "{sensor.out:trend=0,5}");
+        assert!(Config::from_env().is_err());
+        env::set_var("LINE_1", "{sensor.out:trend=-1}");
+        assert!(Config::from_env().is_err());
+
+        env::remove_var("HA_BASE_URL");
+        env::remove_var("HA_LONG_LIVED_TOKEN");
+        env::remove_var("LINE_1");
+    }
+
+    #[test]
+    #[serial]
+    fn test_emoji_rules() {
+        env::set_var("HA_BASE_URL", "http://localhost:8123");
+        env::set_var("HA_LONG_LIVED_TOKEN", "test_token");
+        env::set_var("LINE_1", "{sensor.out:emoji=<10:🥶,else:🥵}");
+        assert!(Config::from_env().is_ok());
+
+        // Broken rules stop startup instead of being skipped on every frame
+        env::set_var("LINE_1", "{sensor.out:emoji=:🥶}");
+        assert!(Config::from_env().is_err());
+        env::set_var("LINE_1", "{sensor.out:emoji=🥶:<10}");
+        assert!(Config::from_env().is_err());
+
+        env::remove_var("HA_BASE_URL");
+        env::remove_var("HA_LONG_LIVED_TOKEN");
+        env::remove_var("LINE_1");
+    }
+
+    #[test]
+    #[serial]
+    fn test_ticker_sensors() {
+        env::set_var("HA_BASE_URL", "http://localhost:8123");
+        env::set_var("HA_LONG_LIVED_TOKEN", "test_token");
+        env::set_var("LINE_1", "{ticker:sensor.events}");
+        env::set_var("LINE_2", "{ticker:input_text.log|count=3}");
+        env::set_var("LINE_3", "{ticker:sensor.events|count=8}");
+        env::set_var("TICKER_PX_PER_SEC", "120");
+
+        let config = Config::from_env().unwrap();
+        assert_eq!(config.ticker_px_per_sec, 120.0);
+        assert_eq!(
+            config.get_ticker_sensors(),
+            vec![
+                ("input_text.log".to_string(), 3),
+                ("sensor.events".to_string(), 8)
+            ]
+        );
+        assert_eq!(
+            config.get_required_sensors(),
+            vec!["input_text.log", "sensor.events"]
+        );
+
+        env::set_var("TICKER_PX_PER_SEC", "0");
+        assert!(Config::from_env().is_err());
+
+        env::remove_var("HA_BASE_URL");
+        env::remove_var("HA_LONG_LIVED_TOKEN");
+        env::remove_var("LINE_1");
+        env::remove_var("LINE_2");
+        env::remove_var("LINE_3");
+        env::remove_var("TICKER_PX_PER_SEC");
+    }
+
+    #[test]
+    #[serial]
+    fn test_token_file() {
+        let path = env::temp_dir().join(format!("ha-streamer-token-{}", std::process::id()));
+        fs::write(&path, "file_token\n").unwrap();
+        env::set_var("HA_BASE_URL", "http://localhost:8123");
+        env::set_var("HA_LONG_LIVED_TOKEN", "env_token");
+        env::set_var("HA_LONG_LIVED_TOKEN_FILE", &path);
+
+        let config = Config::from_env().unwrap();
+        assert_eq!(config.ha_token, "file_token");
+        assert_eq!(config.ha_token_file.as_deref(), path.to_str());
+
+        fs::write(&path, "  \n").unwrap();
+        assert!(Config::from_env().is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(Config::from_env().is_err());
+
+        env::remove_var("HA_BASE_URL");
+        env::remove_var("HA_LONG_LIVED_TOKEN");
+        env::remove_var("HA_LONG_LIVED_TOKEN_FILE");
+    }
+
+    #[test]
+    #[serial]
+    fn test_tls_files() {
+        env::set_var("HA_BASE_URL", "http://localhost:8123");
+        env::set_var("HA_LONG_LIVED_TOKEN", "test_token");
+        env::set_var("TLS_CERT_PATH", "/certs/cert.pem");
+
+        let err = Config::from_env().err().unwrap();
+        assert!(err.to_string().contains("TLS_KEY_PATH"));
+
+        env::set_var("TLS_KEY_PATH", "/certs/key.pem");
+        assert_eq!(
+            Config::from_env().unwrap().tls,
+            Some(TlsFiles {
+                cert_path: "/certs/cert.pem".to_string(),
+                key_path: "/certs/key.pem".to_string(),
+            })
+        );
+
+        env::remove_var("TLS_CERT_PATH");
+        let err = Config::from_env().err().unwrap();
+        assert!(err.to_string().contains("TLS_CERT_PATH"));
+
+        env::remove_var("HA_BASE_URL");
+        env::remove_var("HA_LONG_LIVED_TOKEN");
+        env::remove_var("TLS_KEY_PATH");
+    }
+
+    #[test]
+    #[serial]
+    fn test_rtsp_tls_files() {
+        env::set_var("HA_BASE_URL", "http://localhost:8123");
+        env::set_var("HA_LONG_LIVED_TOKEN", "test_token");
+        env::set_var("RTSP_TLS_KEY", "/certs/key.pem");
+
+        let err = Config::from_env().err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("RTSP_TLS_KEY is set but RTSP_TLS_CERT is not, RTSPS needs both"));
+
+        env::set_var("RTSP_TLS_CERT", "/certs/cert.pem");
+        let config = Config::from_env().unwrap();
+        assert_eq!(
+            config.rtsp_tls,
+            Some(TlsFiles {
+                cert_path: "/certs/cert.pem".to_string(),
+                key_path: "/certs/key.pem".to_string(),
+            })
+        );
+        // HTTPS stays off
+        assert_eq!(config.tls, None);
+
+        env::remove_var("HA_BASE_URL");
+        env::remove_var("HA_LONG_LIVED_TOKEN");
+        env::remove_var("RTSP_TLS_CERT");
+        env::remove_var("RTSP_TLS_KEY");
+    }
+
+    #[test]
+    #[serial]
+    fn test_snapshot_port() {
+        env::set_var("HA_BASE_URL", "http://localhost:8123");
+        env::set_var("HA_LONG_LIVED_TOKEN", "test_token");
+        env::set_var("SNAPSHOT_PORT", "8081");
+        assert_eq!(Config::from_env().unwrap().snapshot_port, Some(8081));
+
+        // Both servers can't listen on the same port
+        env::set_var("SNAPSHOT_PORT", "8080");
+        let err = Config::from_env().err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("SNAPSHOT_PORT must differ from PORT"));
+
+        env::remove_var("HA_BASE_URL");
+        env::remove_var("HA_LONG_LIVED_TOKEN");
+        env::remove_var("SNAPSHOT_PORT");
+    }
+
+    #[test]
+    #[serial]
+    fn test_aliases() {
+        env::set_var("HA_BASE_URL", "http://localhost:8123");
+        env::set_var("HA_LONG_LIVED_TOKEN", "test_token");
+        env::set_var("ALIAS_temp", "sensor.living_room_temperature_sensor_2");
+        // As set by a config document, whose keys are upper-cased
+        env::set_var("ALIAS_OUT", " sensor.outdoor ");
+        env::set_var(
+            "LINE_1",
+            "In {alias.temp:round=1} Out {alias.out.attributes.unit}",
+        );
+        env::set_var("LINE_2", "{alias.typo} {sensor.humidity}");
+
+        let config = Config::from_env().unwrap();
+        assert_eq!(
+            config.lines,
+            vec![
+                "In {sensor.living_room_temperature_sensor_2:round=1} Out {sensor.outdoor.attributes.unit}",
+                "? {sensor.humidity}",
+            ]
+        );
+        assert_eq!(
+            config.get_required_sensors(),
+            vec![
+                "sensor.humidity",
+                "sensor.living_room_temperature_sensor_2",
+                "sensor.outdoor",
+            ]
+        );
+
+        env::set_var("ALIAS_door", "binary_sensor.front door");
+        assert!(Config::from_env().is_err());
+
+        for key in [
+            "HA_BASE_URL",
+            "HA_LONG_LIVED_TOKEN",
+            "ALIAS_temp",
+            "ALIAS_OUT",
+            "ALIAS_door",
+            "LINE_1",
+            "LINE_2",
+        ] {
+            env::remove_var(key);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_line_gaps() {
+        env::set_var("HA_BASE_URL", "http://localhost:8123");
+        env::set_var("HA_LONG_LIVED_TOKEN", "test_token");
+        env::set_var("LINE_1", "First");
+        env::set_var("LINE_3", "Third");
+        env::set_var("LINE_3_OPACITY", "0.5");
+
+        // Missing lines are skipped by default
+        let config = Config::from_env().unwrap();
+        assert_eq!(config.lines, vec!["First", "Third"]);
+        assert_eq!(config.line_options[1].opacity, Some(0.5));
+
+        // The missing LINE_2 becomes a spacer, the missing LINE_4 is left out
+        env::set_var("LINE_GAPS", "spacer");
+        let config = Config::from_env().unwrap();
+        assert_eq!(config.lines, vec!["First", "", "Third"]);
+        assert_eq!(config.line_options.len(), 3);
+        assert_eq!(config.line_options[1], LineOptions::default());
+        assert_eq!(config.line_options[2].opacity, Some(0.5));
+
+        env::set_var("LINE_GAPS", "keep");
+        assert!(Config::from_env().is_err());
+
+        for key in [
+            "HA_BASE_URL",
+            "HA_LONG_LIVED_TOKEN",
+            "LINE_1",
+            "LINE_3",
+            "LINE_3_OPACITY",
+            "LINE_GAPS",
+        ] {
+            env::remove_var(key);
+        }
+    }
+
+    #[test]
+    fn test_background_gradient() {
+        let gradient = BackgroundGradient::parse("#001020->#000000,vertical").unwrap();
+        assert_eq!(
+            gradient,
+            BackgroundGradient {
+                from: Rgb([0, 16, 32]),
+                to: Rgb([0, 0, 0]),
+                vertical: true,
+            }
+        );
+        assert_eq!(gradient.color_at(0, 5), Rgb([0, 16, 32]));
+        assert_eq!(gradient.color_at(2, 5), Rgb([0, 8, 16]));
+        assert_eq!(gradient.color_at(4, 5), Rgb([0, 0, 0]));
+
+        let gradient = BackgroundGradient::parse("navy -> black").unwrap();
+        assert!(gradient.vertical);
+        assert!(
+            !BackgroundGradient::parse("red->blue, horizontal")
+                .unwrap()
+                .vertical
+        );
+
+        assert!(BackgroundGradient::parse("#001020").is_err());
+        assert!(BackgroundGradient::parse("#001020->nope").is_err());
+        assert!(BackgroundGradient::parse("red->blue,diagonal").is_err());
+    }
+
+    #[test]
+    fn test_parse_resolution() {
+        assert_eq!(parse_resolution("1280x720").unwrap(), (1280, 720));
+        assert_eq!(parse_resolution(" 1920 X 1080 ").unwrap(), (1920, 1080));
+        assert!(parse_resolution("1280").is_err());
+        assert!(parse_resolution("0x720").is_err());
+        assert!(parse_resolution("wide x tall").is_err());
+    }
+
+    #[test]
+    fn test_frame_output() {
+        assert_eq!(
+            FrameOutput::parse("file:/tmp/frame.jpg").unwrap(),
+            FrameOutput::File(PathBuf::from("/tmp/frame.jpg"))
+        );
+        assert_eq!(
+            FrameOutput::parse("pipe:/run/frames").unwrap(),
+            FrameOutput::Pipe(PathBuf::from("/run/frames"))
+        );
+        assert!(FrameOutput::parse("file:").is_err());
+        assert!(FrameOutput::parse("/tmp/frame.jpg").is_err());
+        assert!(FrameOutput::parse("http:/tmp/frame.jpg").is_err());
+    }
+
+    #[test]
+    fn test_parse_config_documents() {
+        let settings = |list: &[(&str, &str)]| {
+            let mut list: Vec<(String, String)> = list
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            list.sort();
+            list
+        };
+        let sorted = |mut list: Vec<(String, String)>| {
+            list.sort();
+            list
+        };
+        let expected = settings(&[
+            ("LINE_1", "Temp {sensor.temp}"),
+            ("LINE_2", "{time:%H:%M}"),
+            ("LINE_2_SIZE", "24"),
+            ("FONT_SIZE", "32.5"),
+            ("VIDEO_WIDTH", "640"),
+            ("SMART_FORMAT", "true"),
+        ]);
+
+        let json = r#"{
+            "line": ["Temp {sensor.temp}", "{time:%H:%M}"],
+            "line_2": {"size": 24},
+            "font_size": 32.5,
+            "VIDEO_WIDTH": 640,
+            "smart_format": true,
+            "logo_image": null
+        }"#;
+        assert_eq!(sorted(parse_config_json(json).unwrap()), expected);
+
+        let toml = r#"
+            line = ["Temp {sensor.temp}", "{time:%H:%M}"]
+            font_size = 32.5
+            VIDEO_WIDTH = 640
+            smart_format = true
+
+            [line_2]
+            size = 24
+        "#;
+        assert_eq!(sorted(parse_config_toml(toml).unwrap()), expected);
+
+        assert!(parse_config_json("{\"line\": [").is_err());
+        assert!(parse_config_json("[\"a\"]").is_err());
+        assert!(parse_config_toml("line = ").is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_document_is_overridden_by_env() {
+        env::set_var(
+            "CONFIG_JSON",
+            r#"{"ha_base_url": "http://doc:8123", "ha_long_lived_token": "doc_token", "port": 9000}"#,
+        );
+        env::set_var("PORT", "8081");
+        env::remove_var("HA_BASE_URL");
+        env::remove_var("HA_LONG_LIVED_TOKEN");
+
+        load_config_document().unwrap();
+        let config = Config::from_env().unwrap();
+        assert_eq!(config.ha_base_url, "http://doc:8123");
+        assert_eq!(config.ha_token, "doc_token");
+        assert_eq!(config.port, 8081);
+
+        env::set_var("CONFIG_TOML", "port = 9000");
+        assert!(load_config_document().is_err());
+
+        for key in [
+            "CONFIG_JSON",
+            "CONFIG_TOML",
+            "PORT",
+            "HA_BASE_URL",
+            "HA_LONG_LIVED_TOKEN",
+        ] {
+            env::remove_var(key);
+        }
     }
 }
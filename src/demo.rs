@@ -0,0 +1,59 @@
+//! `DEMO=on`: synthetic sensor values for checking the container, fonts and
+//! streaming before Home Assistant is configured.
+
+use crate::state::{AppState, STATUS_KEY};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+pub const DEMO_TEMPERATURE: &str = "sensor.demo_temperature";
+pub const DEMO_COUNTER: &str = "sensor.demo_counter";
+
+/// Lines shown in demo mode when no `LINE_n` / `LINES_DIR` is configured.
+pub const DEMO_LINES: &[&str] = &[
+    "DEMO MODE",
+    "{time:%H:%M:%S}",
+    "Temp: {sensor.demo_temperature}°C",
+    "Counter: {sensor.demo_counter}",
+];
+
+/// Values of the demo entities after `elapsed`: a temperature following a slow
+/// two-minute sine wave around 20 °C, and a counter of elapsed seconds.
+pub fn demo_values(elapsed: Duration) -> [(&'static str, String); 2] {
+    let secs = elapsed.as_secs_f64();
+    let temperature = 20.0 + 5.0 * (secs / 120.0 * std::f64::consts::TAU).sin();
+    [
+        (DEMO_TEMPERATURE, format!("{:.1}", temperature)),
+        (DEMO_COUNTER, elapsed.as_secs().to_string()),
+    ]
+}
+
+/// Replaces the poller in demo mode, updating the demo entities every second.
+pub async fn run(state: AppState) {
+    let start = Instant::now();
+    loop {
+        {
+            let mut values = state.sensor_values.write().unwrap();
+            for (entity_id, value) in demo_values(start.elapsed()) {
+                values.insert(entity_id.to_string(), value);
+            }
+            values.insert(STATUS_KEY.to_string(), "DEMO".to_string());
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_demo_values_change_over_time() {
+        let [(_, temp_start), (_, count_start)] = demo_values(Duration::ZERO);
+        assert_eq!(temp_start, "20.0");
+        assert_eq!(count_start, "0");
+
+        let [(_, temp), (_, count)] = demo_values(Duration::from_secs(30));
+        assert_eq!(temp, "25.0");
+        assert_eq!(count, "30");
+    }
+}
@@ -0,0 +1,210 @@
+//! `{expr: sensor.power_a + sensor.power_b}`: arithmetic over numeric entity
+//! states with `+`, `-`, `*`, `/` and parentheses.
+
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Entity(String),
+    Op(char),
+    Open,
+    Close,
+}
+
+/// Evaluates `expr` with each entity id replaced by its state. `None` when an
+/// entity has no numeric state, on division by zero, or when `expr` is malformed.
+pub fn evaluate(expr: &str, values: &HashMap<String, String>) -> Option<f64> {
+    let tokens = tokenize(expr)?;
+    let mut parser = Parser {
+        tokens: &tokens,
+        pos: 0,
+        values,
+    };
+    let value = parser.sum()?;
+    (parser.pos == tokens.len() && value.is_finite()).then_some(value)
+}
+
+/// The entity ids `expr` refers to, e.g. `sensor.power_a`.
+pub fn entity_ids(expr: &str) -> Vec<String> {
+    tokenize(expr)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|token| match token {
+            Token::Entity(entity_id) => Some(entity_id),
+            _ => None,
+        })
+        .collect()
+}
+
+fn tokenize(expr: &str) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let token = match c {
+            '+' | '-' | '*' | '/' => Token::Op(c),
+            '(' => Token::Open,
+            ')' => Token::Close,
+            c if c.is_ascii_digit() || c == '.' => {
+                let mut number = String::new();
+                while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit() || **c == '.') {
+                    number.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Number(number.parse().ok()?));
+                continue;
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut word = String::new();
+                while let Some(&c) = chars
+                    .peek()
+                    .filter(|c| c.is_alphanumeric() || **c == '_' || **c == '.')
+                {
+                    word.push(c);
+                    chars.next();
+                }
+                // Only `domain.object_id` names an entity
+                let (domain, object_id) = word.split_once('.')?;
+                if domain.is_empty() || object_id.is_empty() || object_id.contains('.') {
+                    return None;
+                }
+                tokens.push(Token::Entity(word));
+                continue;
+            }
+            _ => return None,
+        };
+        tokens.push(token);
+        chars.next();
+    }
+    Some(tokens)
+}
+
+/// Recursive descent over the usual precedence: `*` and `/` bind tighter than
+/// `+` and `-`, and a leading `-` negates.
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    values: &'a HashMap<String, String>,
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.pos)?;
+        self.pos += 1;
+        Some(token)
+    }
+
+    fn next_op(&mut self, ops: &[char]) -> Option<char> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) if ops.contains(op) => {
+                self.pos += 1;
+                Some(*op)
+            }
+            _ => None,
+        }
+    }
+
+    fn sum(&mut self) -> Option<f64> {
+        let mut value = self.product()?;
+        while let Some(op) = self.next_op(&['+', '-']) {
+            let rhs = self.product()?;
+            value = if op == '+' { value + rhs } else { value - rhs };
+        }
+        Some(value)
+    }
+
+    fn product(&mut self) -> Option<f64> {
+        let mut value = self.unary()?;
+        while let Some(op) = self.next_op(&['*', '/']) {
+            let rhs = self.unary()?;
+            value = match op {
+                '*' => value * rhs,
+                _ if rhs == 0.0 => return None,
+                _ => value / rhs,
+            };
+        }
+        Some(value)
+    }
+
+    fn unary(&mut self) -> Option<f64> {
+        if self.next_op(&['-']).is_some() {
+            return Some(-self.unary()?);
+        }
+        match self.next()? {
+            Token::Number(number) => Some(*number),
+            Token::Entity(entity_id) => self
+                .values
+                .get(entity_id)?
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|value| value.is_finite()),
+            Token::Open => {
+                let value = self.sum()?;
+                matches!(self.next()?, Token::Close).then_some(value)
+            }
+            Token::Op(_) | Token::Close => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> HashMap<String, String> {
+        let mut values = HashMap::new();
+        values.insert("sensor.power_a".to_string(), "120.5".to_string());
+        values.insert("sensor.power_b".to_string(), " 80 ".to_string());
+        values.insert("sensor.zero".to_string(), "0".to_string());
+        values.insert("sensor.mode".to_string(), "eco".to_string());
+        values
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let values = values();
+
+        assert_eq!(
+            evaluate(" sensor.power_a + sensor.power_b", &values),
+            Some(200.5)
+        );
+        assert_eq!(
+            evaluate("sensor.power_a - sensor.power_b * 2", &values),
+            Some(-39.5)
+        );
+        assert_eq!(
+            evaluate("(sensor.power_a - sensor.power_b) * 2", &values),
+            Some(81.0)
+        );
+        assert_eq!(evaluate("-sensor.power_b / 4 + 1.5", &values), Some(-18.5));
+    }
+
+    #[test]
+    fn test_evaluate_placeholders() {
+        let values = values();
+
+        // Non-numeric and missing states, division by zero, malformed expressions
+        assert_eq!(evaluate("sensor.power_a + sensor.mode", &values), None);
+        assert_eq!(evaluate("sensor.power_a + sensor.gone", &values), None);
+        assert_eq!(evaluate("sensor.power_a / sensor.zero", &values), None);
+        assert_eq!(evaluate("sensor.power_a +", &values), None);
+        assert_eq!(evaluate("(sensor.power_a", &values), None);
+        assert_eq!(evaluate("sensor.power_a sensor.power_b", &values), None);
+        assert_eq!(evaluate("power_a * 2", &values), None);
+        assert_eq!(evaluate("", &values), None);
+    }
+
+    #[test]
+    fn test_entity_ids() {
+        assert_eq!(
+            entity_ids("(sensor.power_a + input_number.offset) / 1000"),
+            vec!["sensor.power_a", "input_number.offset"]
+        );
+        assert!(entity_ids("2 * %").is_empty());
+    }
+}
@@ -1,42 +1,694 @@
-use crate::config::Config;
-use anyhow::Result;
-use reqwest::Client;
+use crate::config::{read_token_file, Config};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use reqwest::{Client, Response, StatusCode};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// How long to stay on the fallback instance before probing the primary again.
+const PRIMARY_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Back-off after a 429 without a usable `Retry-After` header.
+const DEFAULT_RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Longest `Retry-After` honored, so a bogus header can't stall an entity for days.
+const MAX_RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// An event stream without any data, not even a ping, for this long is treated as dead.
+const EVENT_STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Home Assistant (or a proxy in front of it) answered 429 Too Many Requests.
+#[derive(Debug)]
+pub struct RateLimited {
+    /// How long to wait before asking again, from `Retry-After`.
+    pub retry_after: Duration,
+}
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "rate limited (429 Too Many Requests), retry after {}s",
+            self.retry_after.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for RateLimited {}
 
 #[derive(Deserialize, Debug)]
 struct HaStateResponse {
     state: String,
-    // attributes: serde_json::Value, // We can add this if we need more data later
+    /// Missing from history entries, which are requested without attributes.
+    #[serde(default)]
+    attributes: HaAttributes,
+    last_changed: Option<String>,
+    last_updated: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct HaAttributes {
+    device_class: Option<String>,
+    unit_of_measurement: Option<String>,
+    #[serde(flatten)]
+    other: HashMap<String, Value>,
+}
+
+/// An entity's state plus the attributes used to format it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EntityState {
+    pub state: String,
+    pub device_class: Option<String>,
+    pub unit: Option<String>,
+    /// When the state last changed and when Home Assistant last wrote it, as
+    /// RFC 3339 timestamps.
+    pub last_changed: Option<String>,
+    pub last_updated: Option<String>,
+    /// The remaining attributes, e.g. for a `_PRIMARY_ATTR` shown instead of the state.
+    pub attributes: HashMap<String, Value>,
+}
+
+impl From<HaStateResponse> for EntityState {
+    fn from(response: HaStateResponse) -> Self {
+        EntityState {
+            state: response.state,
+            device_class: response.attributes.device_class,
+            unit: response.attributes.unit_of_measurement,
+            last_changed: response.last_changed,
+            last_updated: response.last_updated,
+            attributes: response.attributes.other,
+        }
+    }
+}
+
+/// A `state_changed` event from the event stream. `new_state` is `None` when the
+/// entity was removed.
+#[derive(Debug, PartialEq)]
+pub struct StateChange {
+    pub entity_id: String,
+    pub new_state: Option<EntityState>,
+}
+
+#[derive(Deserialize)]
+struct HaEvent {
+    event_type: String,
+    data: HaStateChangedData,
+}
+
+#[derive(Deserialize)]
+struct HaStateChangedData {
+    entity_id: String,
+    new_state: Option<HaStateResponse>,
+}
+
+/// Home Assistant's server-sent event stream (`/api/stream`), restricted to state
+/// changes.
+pub struct EventStream {
+    response: Response,
+    /// Received bytes not yet forming a complete event.
+    buffer: Vec<u8>,
+}
+
+impl EventStream {
+    /// Waits for the next state change. `Ok(None)` once the server closed the stream.
+    pub async fn next_change(&mut self) -> Result<Option<StateChange>> {
+        loop {
+            while let Some(end) = self.buffer.windows(2).position(|w| w == b"\n\n") {
+                let event: Vec<u8> = self.buffer.drain(..end + 2).collect();
+                if let Some(change) = parse_event(&String::from_utf8_lossy(&event)) {
+                    return Ok(Some(change));
+                }
+            }
+
+            // Home Assistant pings idle streams, so silence means the connection is gone
+            let chunk = tokio::time::timeout(EVENT_STREAM_IDLE_TIMEOUT, self.response.chunk())
+                .await
+                .context("no data on the event stream")??;
+            match chunk {
+                // Events may also be separated by CRLF
+                Some(bytes) => self.buffer.extend(bytes.iter().filter(|&&b| b != b'\r')),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+/// Parses one event from the stream. Pings and other event types give `None`.
+fn parse_event(event: &str) -> Option<StateChange> {
+    let data: Vec<&str> = event
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|data| data.strip_prefix(' ').unwrap_or(data))
+        .collect();
+    let event: HaEvent = serde_json::from_str(&data.join("\n")).ok()?;
+    if event.event_type != "state_changed" {
+        return None;
+    }
+    Some(StateChange {
+        entity_id: event.data.entity_id,
+        new_state: event.data.new_state.map(EntityState::from),
+    })
+}
+
+#[derive(Clone)]
+struct HaEndpoint {
+    base_url: String,
+    /// Shared with the fallback when it has no token of its own.
+    token: Arc<RwLock<String>>,
+    /// `HA_LONG_LIVED_TOKEN_FILE`, re-read after a 401 in case the token was rotated.
+    token_file: Option<PathBuf>,
+    /// The variable the token came from, named when Home Assistant rejects it.
+    token_var: &'static str,
+}
+
+impl HaEndpoint {
+    fn token(&self) -> String {
+        self.token.read().unwrap().clone()
+    }
+
+    /// Re-reads the token file. Returns true if it now holds a different token.
+    fn reload_token(&self) -> bool {
+        let Some(path) = &self.token_file else {
+            return false;
+        };
+        match read_token_file(path) {
+            Ok(token) if token != self.token() => {
+                println!("Re-read the access token from {}", path.display());
+                *self.token.write().unwrap() = token;
+                true
+            }
+            Ok(_) => false,
+            Err(e) => {
+                eprintln!("Error re-reading the access token: {:#}", e);
+                false
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct HaClient {
     client: Client,
-    base_url: String,
-    token: String,
+    primary: HaEndpoint,
+    fallback: Option<HaEndpoint>,
+    api_prefix: String,
+    /// Set while the primary is considered down and requests go to the fallback.
+    failed_over_at: Arc<Mutex<Option<Instant>>>,
+    /// Set while Home Assistant rejects the token, so the failure is logged once.
+    auth_failed: Arc<AtomicBool>,
 }
 
 impl HaClient {
     pub fn new(config: &Config) -> Self {
+        let primary = HaEndpoint {
+            base_url: config.ha_base_url.clone(),
+            token: Arc::new(RwLock::new(config.ha_token.clone())),
+            token_file: config.ha_token_file.as_ref().map(PathBuf::from),
+            token_var: match config.ha_token_file {
+                Some(_) => "HA_LONG_LIVED_TOKEN_FILE",
+                None => "HA_LONG_LIVED_TOKEN",
+            },
+        };
+        let fallback = config
+            .ha_fallback_url
+            .as_ref()
+            .map(|url| match &config.ha_fallback_token {
+                Some(token) => HaEndpoint {
+                    base_url: url.clone(),
+                    token: Arc::new(RwLock::new(token.clone())),
+                    token_file: None,
+                    token_var: "HA_LONG_LIVED_TOKEN_FALLBACK",
+                },
+                None => HaEndpoint {
+                    base_url: url.clone(),
+                    ..primary.clone()
+                },
+            });
+
         HaClient {
             client: Client::new(),
-            base_url: config.ha_base_url.clone(),
-            token: config.ha_token.clone(),
+            primary,
+            fallback,
+            api_prefix: config.ha_api_prefix.clone(),
+            failed_over_at: Arc::new(Mutex::new(None)),
+            auth_failed: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    pub async fn fetch_sensor_state(&self, entity_id: &str) -> Result<String> {
-        let url = format!("{}/api/states/{}", self.base_url, entity_id);
+    pub async fn fetch_sensor_state(&self, entity_id: &str) -> Result<EntityState> {
+        let path = format!("/states/{}", entity_id);
+        let json: HaStateResponse = self.get_json(&path).await?;
+        Ok(json.into())
+    }
 
-        let resp = self
+    /// Subscribes to state changes over server-sent events. Only the primary
+    /// instance is used; callers fall back to polling if this fails.
+    pub async fn open_event_stream(&self) -> Result<EventStream> {
+        let url = api_url(
+            &self.primary.base_url,
+            &self.api_prefix,
+            "/stream?restrict=state_changed",
+        );
+        let response = self
             .client
             .get(&url)
-            .bearer_auth(&self.token)
+            .bearer_auth(self.primary.token())
+            .header(reqwest::header::ACCEPT, "text/event-stream")
             .send()
             .await?
             .error_for_status()?;
+        Ok(EventStream {
+            response,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Fetches the recorded states of `entity_id` from `start` until now, oldest first.
+    pub async fn fetch_history(
+        &self,
+        entity_id: &str,
+        start: DateTime<Local>,
+    ) -> Result<Vec<String>> {
+        let path = format!(
+            "/history/period/{}?filter_entity_id={}&minimal_response&no_attributes",
+            start
+                .with_timezone(&Utc)
+                .to_rfc3339_opts(SecondsFormat::Secs, true),
+            entity_id
+        );
+        // One series per requested entity
+        let series: Vec<Vec<HaStateResponse>> = self.get_json(&path).await?;
+        Ok(series
+            .into_iter()
+            .flatten()
+            .map(|entry| entry.state)
+            .collect())
+    }
+
+    /// GETs `path` (relative to the API prefix) from the primary instance, failing over to the fallback if configured.
+    async fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let Some(fallback) = &self.fallback else {
+            return self.fetch_from(&self.primary, path).await;
+        };
+
+        // While failed over, skip the primary until it is time to probe it again.
+        let failed_over_at = *self.failed_over_at.lock().unwrap();
+        if let Some(since) = failed_over_at {
+            if since.elapsed() < PRIMARY_RETRY_INTERVAL {
+                return self.fetch_from(fallback, path).await;
+            }
+        }
+
+        match self.fetch_from(&self.primary, path).await {
+            Err(e) if is_connection_error(&e) => {
+                {
+                    let mut lock = self.failed_over_at.lock().unwrap();
+                    if lock.is_none() {
+                        eprintln!(
+                            "Primary Home Assistant unreachable ({}), switching to fallback {}",
+                            e, fallback.base_url
+                        );
+                    }
+                    *lock = Some(Instant::now());
+                }
+
+                self.fetch_from(fallback, path).await
+            }
+            result => {
+                if failed_over_at.is_some() {
+                    println!("Primary Home Assistant reachable again, switching back");
+                    *self.failed_over_at.lock().unwrap() = None;
+                }
+                result
+            }
+        }
+    }
+
+    async fn fetch_from<T: DeserializeOwned>(
+        &self,
+        endpoint: &HaEndpoint,
+        path: &str,
+    ) -> Result<T> {
+        let url = api_url(&endpoint.base_url, &self.api_prefix, path);
+
+        let token = endpoint.token();
+        let mut resp = self.send(&url, &token).await?;
+        if resp.status() == StatusCode::UNAUTHORIZED {
+            // Another request may already have picked up a rotated token
+            if endpoint.reload_token() || endpoint.token() != token {
+                resp = self.send(&url, &endpoint.token()).await?;
+            }
+        }
+
+        if resp.status() == StatusCode::UNAUTHORIZED {
+            if !self.auth_failed.swap(true, Ordering::Relaxed) {
+                eprintln!(
+                    "Authentication failed: Home Assistant at {} rejected the access token (401 Unauthorized). Check {}",
+                    endpoint.base_url, endpoint.token_var
+                );
+            }
+            bail!("authentication failed (401 Unauthorized), check the access token");
+        }
+        if self.auth_failed.swap(false, Ordering::Relaxed) {
+            println!("Home Assistant accepts the access token again");
+        }
+
+        if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = resp
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| parse_retry_after(value, Utc::now()))
+                .unwrap_or(DEFAULT_RATE_LIMIT_BACKOFF)
+                .min(MAX_RATE_LIMIT_BACKOFF);
+            return Err(RateLimited { retry_after }.into());
+        }
+
+        let resp = resp.error_for_status()?;
+        let is_html = resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.contains("html"));
+        parse_body(&resp.bytes().await?, is_html)
+    }
+
+    async fn send(&self, url: &str, token: &str) -> Result<Response> {
+        Ok(self.client.get(url).bearer_auth(token).send().await?)
+    }
+}
+
+/// Joins the base URL, the normalized API prefix and an endpoint path.
+fn api_url(base_url: &str, api_prefix: &str, path: &str) -> String {
+    format!("{}{}{}", base_url.trim_end_matches('/'), api_prefix, path)
+}
+
+/// Decodes a JSON response body. A body that isn't JSON at all (an HTML page from a
+/// proxy or the web UI, or binary data) is reported apart from JSON of the wrong
+/// shape, since the first usually means `HA_BASE_URL` points at the wrong place.
+fn parse_body<T: DeserializeOwned>(body: &[u8], is_html: bool) -> Result<T> {
+    let Ok(text) = std::str::from_utf8(body) else {
+        bail!(
+            "Home Assistant returned a non-UTF-8 body ({} bytes), check HA_BASE_URL and any proxy in front of it",
+            body.len()
+        );
+    };
+    if is_html || text.trim_start().starts_with('<') {
+        bail!(
+            "Home Assistant returned HTML instead of JSON ({}), check that HA_BASE_URL points at Home Assistant and not a proxy error page",
+            snippet(text)
+        );
+    }
+    serde_json::from_str(text).map_err(|e| match e.classify() {
+        serde_json::error::Category::Data => {
+            anyhow::anyhow!("Home Assistant returned unexpected JSON: {}", e)
+        }
+        _ => anyhow::anyhow!(
+            "Home Assistant returned non-JSON ({}: {}), check HA_BASE_URL and any proxy in front of it",
+            e,
+            snippet(text)
+        ),
+    })
+}
+
+/// The start of a response body on one line, for error messages.
+fn snippet(text: &str) -> String {
+    let line = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    match line.char_indices().nth(60) {
+        Some((cut, _)) => format!("{}…", &line[..cut]),
+        None => line,
+    }
+}
+
+/// Parses a `Retry-After` value, either delay seconds or an HTTP date.
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (at.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// True for errors where the instance itself could not be reached, as opposed to
+/// an HTTP error status (e.g. an unknown entity) returned by a healthy instance.
+fn is_connection_error(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| e.is_connect() || e.is_timeout())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::testing::test_config;
+    use axum::{http::HeaderMap, routing::get, Json, Router};
+    use serial_test::serial;
+
+    /// Serves `app` on a free local port and returns its base URL.
+    async fn serve(app: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        base_url
+    }
+
+    /// A client for the Home Assistant at `base_url`, without a fallback.
+    fn test_client(base_url: String) -> HaClient {
+        HaClient {
+            client: Client::new(),
+            primary: HaEndpoint {
+                base_url,
+                token: Arc::new(RwLock::new("token".to_string())),
+                token_file: None,
+                token_var: "HA_LONG_LIVED_TOKEN",
+            },
+            fallback: None,
+            api_prefix: "/api".to_string(),
+            failed_over_at: Arc::new(Mutex::new(None)),
+            auth_failed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    #[test]
+    fn test_api_url() {
+        assert_eq!(
+            api_url("http://ha:8123", "/api", "/states/sensor.temp"),
+            "http://ha:8123/api/states/sensor.temp"
+        );
+        assert_eq!(
+            api_url("https://host/", "/homeassistant/api", "/states/sensor.temp"),
+            "https://host/homeassistant/api/states/sensor.temp"
+        );
+        assert_eq!(
+            api_url("https://host", "", "/states/sensor.temp"),
+            "https://host/states/sensor.temp"
+        );
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 01 May 2024 12:00:45 GMT", now),
+            Some(Duration::from_secs(45))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 01 May 2024 11:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[tokio::test]
+    async fn test_rotated_token_is_reread_after_401() {
+        // A Home Assistant that only accepts the new token
+        let app = Router::new().route(
+            "/api/states/sensor.temp",
+            get(|headers: HeaderMap| async move {
+                if headers["authorization"] == "Bearer new_token" {
+                    Ok(Json(serde_json::json!({ "state": "21.5" })))
+                } else {
+                    Err(axum::http::StatusCode::UNAUTHORIZED)
+                }
+            }),
+        );
+
+        let token_file =
+            std::env::temp_dir().join(format!("ha-streamer-rotated-{}", std::process::id()));
+        std::fs::write(&token_file, "old_token").unwrap();
+        let mut client = test_client(serve(app).await);
+        *client.primary.token.write().unwrap() = "old_token".to_string();
+        client.primary.token_file = Some(token_file.clone());
+
+        let err = client.fetch_sensor_state("sensor.temp").await.unwrap_err();
+        assert!(err.to_string().contains("authentication failed"));
+        assert!(client.auth_failed.load(Ordering::Relaxed));
+
+        std::fs::write(&token_file, "new_token\n").unwrap();
+        assert_eq!(
+            client
+                .fetch_sensor_state("sensor.temp")
+                .await
+                .unwrap()
+                .state,
+            "21.5"
+        );
+        assert_eq!(client.primary.token(), "new_token");
+        assert!(!client.auth_failed.load(Ordering::Relaxed));
+
+        std::fs::remove_file(&token_file).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_rejected_token_names_its_variable() {
+        let fallback = [
+            ("HA_BASE_URL_FALLBACK", "http://backup:8123"),
+            ("HA_LONG_LIVED_TOKEN_FALLBACK", "backup_token"),
+        ];
+        let client = HaClient::new(&test_config(&fallback));
+        assert_eq!(client.primary.token_var, "HA_LONG_LIVED_TOKEN");
+        assert_eq!(
+            client.fallback.unwrap().token_var,
+            "HA_LONG_LIVED_TOKEN_FALLBACK"
+        );
+
+        // Without a token of its own the fallback uses the primary's
+        let client = HaClient::new(&test_config(&fallback[..1]));
+        assert_eq!(client.fallback.unwrap().token_var, "HA_LONG_LIVED_TOKEN");
+
+        let token_file =
+            std::env::temp_dir().join(format!("ha-streamer-token-var-{}", std::process::id()));
+        std::fs::write(&token_file, "file_token").unwrap();
+        let client = HaClient::new(&test_config(&[(
+            "HA_LONG_LIVED_TOKEN_FILE",
+            token_file.to_str().unwrap(),
+        )]));
+        assert_eq!(client.primary.token_var, "HA_LONG_LIVED_TOKEN_FILE");
+        std::fs::remove_file(&token_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_429_is_rate_limited() {
+        let app = Router::new().route(
+            "/api/states/sensor.temp",
+            get(|| async {
+                (
+                    axum::http::StatusCode::TOO_MANY_REQUESTS,
+                    [("Retry-After", "90")],
+                )
+            }),
+        );
+
+        let client = test_client(serve(app).await);
+
+        let err = client.fetch_sensor_state("sensor.temp").await.unwrap_err();
+        let limited = err.downcast_ref::<RateLimited>().unwrap();
+        assert_eq!(limited.retry_after, Duration::from_secs(90));
+    }
+
+    #[test]
+    fn test_parse_body() {
+        let state: HaStateResponse = parse_body(br#"{"state": "21.5"}"#, false).unwrap();
+        assert_eq!(state.state, "21.5");
+
+        // A proxy error page, with or without an HTML content type
+        let page = b"<!DOCTYPE html>\n<html><head><title>502 Bad Gateway</title></head></html>";
+        for is_html in [true, false] {
+            let err = parse_body::<HaStateResponse>(page, is_html).unwrap_err();
+            assert!(err.to_string().contains("returned HTML instead of JSON"));
+            assert!(err.to_string().contains("502 Bad Gateway"));
+        }
+
+        // Malformed JSON is not mistaken for a differently shaped body
+        let err = parse_body::<HaStateResponse>(br#"{"state": "21.5""#, false).unwrap_err();
+        assert!(err.to_string().contains("returned non-JSON"));
+        let err = parse_body::<HaStateResponse>(b"Service Unavailable", false).unwrap_err();
+        assert!(err.to_string().contains("returned non-JSON"));
+
+        let err = parse_body::<HaStateResponse>(br#"{"message": "Entity not found."}"#, false)
+            .unwrap_err();
+        assert!(err.to_string().contains("returned unexpected JSON"));
+        assert!(err.to_string().contains("missing field `state`"));
+
+        let err = parse_body::<HaStateResponse>(&[0xff, 0xfe, 0x00], false).unwrap_err();
+        assert!(err.to_string().contains("non-UTF-8 body (3 bytes)"));
+    }
+
+    #[tokio::test]
+    async fn test_html_response_is_reported() {
+        let app = Router::new().route(
+            "/api/states/sensor.temp",
+            get(|| async { axum::response::Html("<html><body>Home Assistant</body></html>") }),
+        );
+
+        let client = test_client(serve(app).await);
+
+        let err = client.fetch_sensor_state("sensor.temp").await.unwrap_err();
+        assert!(err.to_string().contains("returned HTML instead of JSON"));
+    }
+
+    #[test]
+    fn test_parse_event() {
+        let changed = r#"data: {"event_type": "state_changed", "data": {"entity_id": "sensor.temp", "new_state": {"state": "21.5", "attributes": {"unit_of_measurement": "°C", "battery": 80}, "last_changed": "2024-03-09T07:00:00+00:00", "last_updated": "2024-03-09T07:05:00+00:00"}}}"#;
+        assert_eq!(
+            parse_event(changed),
+            Some(StateChange {
+                entity_id: "sensor.temp".to_string(),
+                new_state: Some(EntityState {
+                    state: "21.5".to_string(),
+                    device_class: None,
+                    unit: Some("°C".to_string()),
+                    last_changed: Some("2024-03-09T07:00:00+00:00".to_string()),
+                    last_updated: Some("2024-03-09T07:05:00+00:00".to_string()),
+                    attributes: HashMap::from([("battery".to_string(), Value::from(80))]),
+                }),
+            })
+        );
+
+        let removed = r#"data: {"event_type": "state_changed", "data": {"entity_id": "sensor.temp", "new_state": null}}"#;
+        assert_eq!(parse_event(removed).unwrap().new_state, None);
+
+        assert_eq!(parse_event("data: ping"), None);
+        assert_eq!(
+            parse_event(r#"data: {"event_type": "call_service", "data": {}}"#),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_event_stream() {
+        // Events split over lines and separated by CRLF, with a ping in between
+        const BODY: &str = "data: ping\r\n\r\n\
+            data: {\"event_type\": \"state_changed\",\n\
+            data:  \"data\": {\"entity_id\": \"sensor.temp\", \"new_state\": {\"state\": \"22\"}}}\r\n\r\n";
+        let app = Router::new().route(
+            "/api/stream",
+            get(|| async { ([("content-type", "text/event-stream")], BODY) }),
+        );
+
+        let client = test_client(serve(app).await);
 
-        let json: HaStateResponse = resp.json().await?;
-        Ok(json.state)
+        let mut stream = client.open_event_stream().await.unwrap();
+        let change = stream.next_change().await.unwrap().unwrap();
+        assert_eq!(change.entity_id, "sensor.temp");
+        assert_eq!(change.new_state.unwrap().state, "22");
+        assert_eq!(stream.next_change().await.unwrap(), None);
     }
 }
@@ -1,12 +1,85 @@
-use anyhow::{Context, Result};
-use chrono::Local;
-use image::{ImageOutputFormat, Rgb, RgbImage};
-use imageproc::drawing::draw_text_mut;
+use crate::bidi;
+use crate::config::{
+    parse_color, AntiBurnin, BackgroundGradient, JpegSubsampling, LineOptions, Logo, LogoPosition,
+    Monochrome, ValueReplacement, EXPR_TOKEN_PATTERN, TEMPLATED_TOKEN_PATTERN,
+    TICKER_TOKEN_PATTERN,
+};
+use crate::expr;
+use crate::modifiers::{
+    format_currency, format_device_class, format_grouped, format_percent, humanize_relative,
+    pad_value, parse_color_rules, parse_modifiers, select_color, select_emoji, select_line_color,
+    trend_arrow, NumberFormat,
+};
+use crate::monochrome;
+use crate::state::{history_key, resolve_entity_refs, ALERT_KEY, STATUS_KEY};
+use crate::transform::{self, TextTransform};
+use crate::widgets::{draw_clock, draw_qr, Canvas};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local};
+use chrono_tz::Tz;
+use image::imageops::FilterType;
+use image::{ImageOutputFormat, Rgb, RgbImage, RgbaImage};
+use imageproc::rect::Rect;
+use jpeg_encoder::{ColorType, Encoder, SamplingFactor};
+use qrcode::EcLevel;
 use regex::Regex;
 use rusttype::{point, Font, Scale};
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::{Hash, Hasher};
 use std::io::Cursor;
-use std::sync::Arc;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use unicode_normalization::UnicodeNormalization;
+
+/// Source of the current time, replaceable so tests can render a fixed moment.
+type Clock = Arc<dyn Fn() -> DateTime<Local> + Send + Sync>;
+
+/// What a line renders as in the current frame.
+enum LineContent {
+    /// Runs from `text_spans` after the line's transforms, so tokens can carry
+    /// their own colors.
+    Text {
+        spans: Vec<(String, Rgb<u8>)>,
+    },
+    Clock {
+        size: u32,
+    },
+    Qr {
+        data: String,
+        size: u32,
+        ec_level: EcLevel,
+        /// The resolved line the payload was taken from.
+        line: String,
+    },
+    /// Recent values scrolling right to left.
+    Ticker {
+        text: String,
+    },
+    /// `{legend}`: a swatch and label per named threshold color.
+    Legend {
+        entries: Vec<(String, Rgb<u8>)>,
+    },
+}
+
+/// Token that turns a line into the color legend.
+const LEGEND_TOKEN: &str = "{legend}";
+
+/// Frame buffers kept for reuse, about one per thread rendering at the same time.
+const MAX_POOLED_FRAMES: usize = 4;
+
+/// Context of `generate_frame` errors that happened while encoding an already
+/// drawn frame, so callers can tell them from drawing errors with `is`.
+#[derive(Debug)]
+pub struct EncodeError;
+
+impl std::fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Failed to encode the frame")
+    }
+}
 
 pub struct ImageGenerator {
     font: Arc<Font<'static>>,
@@ -15,8 +88,67 @@ pub struct ImageGenerator {
     lines: Vec<String>,
     font_size: f32,
     decimal_separator: char,
+    number_format: NumberFormat,
     sensor_regex: Regex,
+    templated_regex: Regex,
     time_regex: Regex,
+    expr_regex: Regex,
+    clock_regex: Regex,
+    qr_regex: Regex,
+    ticker_regex: Regex,
+    ticker_px_per_sec: f32,
+    jpeg_subsampling: JpegSubsampling,
+    /// MCUs between JPEG restart markers; 0 writes none.
+    jpeg_restart_interval: u16,
+    line_options: Vec<LineOptions>,
+    text_color: Rgb<u8>,
+    background_color: Rgb<u8>,
+    grid_cols: u32,
+    border_width: u32,
+    border_color: Rgb<u8>,
+    anti_burnin: Option<AntiBurnin>,
+    /// Per-entity regex replacements, applied before modifiers.
+    value_replacements: HashMap<String, Vec<ValueReplacement>>,
+    /// Named colors usable in `color=` modifiers.
+    palette: HashMap<String, Rgb<u8>>,
+    /// Per-channel lookup table for `BRIGHTNESS`; `None` at full brightness.
+    brightness_lut: Option<[u8; 256]>,
+    monochrome: Option<Monochrome>,
+    /// `LOGO_IMAGE`, already scaled, with its corner and margin.
+    logo: Option<(RgbaImage, LogoPosition, u32)>,
+    max_line_length: usize,
+    /// `SMART_FORMAT`: round and label plain tokens by their `device_class`.
+    smart_format: bool,
+    /// `SUBPIXEL_TEXT`: keep fractional x positions when drawing text spans.
+    subpixel_text: bool,
+    /// `WIDGET_SUPERSAMPLING`: samples per pixel along each axis for widget shapes.
+    widget_supersampling: u32,
+    background_gradient: Option<BackgroundGradient>,
+    /// Size of the encoded frames, `width`×`height` unless `RENDER_RESOLUTION` differs.
+    output_size: (u32, u32),
+    /// `TEXT_OUTLINE`: the default for lines without `LINE_n_OUTLINE`.
+    outline: bool,
+    outline_color: Rgb<u8>,
+    /// `EMPTY_FRAME_TEXT`: drawn when no line has anything to show.
+    empty_frame_text: Option<String>,
+    /// `TRACE_FRAMES`: log the resolved lines of frames that differ from the last one.
+    trace_frames: bool,
+    /// Hash of the last traced lines.
+    traced_lines: Mutex<Option<u64>>,
+    /// `IDLE_ANIMATION`: how long the sensor values must stay the same before
+    /// a dot starts bouncing across the frame.
+    idle_animation: Option<Duration>,
+    /// Hash of the last sensor values and when they were first seen.
+    idle_since: Mutex<Option<(u64, DateTime<Local>)>>,
+    clock: Clock,
+    /// Templates already reported as too long, so each is only logged once.
+    truncated_lines: Mutex<HashSet<String>>,
+    /// `{time:FMT@ZONE}` zones that failed to parse, so each is only logged once.
+    unknown_time_zones: Mutex<HashSet<String>>,
+    /// Drawn frames handed back after encoding, so large frames aren't allocated
+    /// again every time. Frames may be drawn on several threads at once (MJPEG
+    /// viewers, the GStreamer callback), each takes its own buffer.
+    frame_pool: Mutex<Vec<RgbImage>>,
 }
 
 impl ImageGenerator {
@@ -30,9 +162,18 @@ impl ImageGenerator {
     ) -> Result<Self> {
         let font = Font::try_from_bytes(font_data).context("Error constructing Font from data")?;
         // Changed regex to capture just the entity ID part after "sensor."
-        let sensor_regex = Regex::new(r"\{sensor\.([\w\.]+)\}").expect("Invalid sensor regex");
+        // Optional modifiers follow the entity ID, e.g. {sensor.last_seen:relative}
+        let sensor_regex =
+            Regex::new(r"\{sensor\.([\w\.]+)(?::([^}]*))?\}").expect("Invalid sensor regex");
+        let templated_regex =
+            Regex::new(TEMPLATED_TOKEN_PATTERN).expect("Invalid templated sensor regex");
         let time_regex = Regex::new(r"\{time:([^}]+)\}").expect("Invalid time regex");
+        let expr_regex = Regex::new(EXPR_TOKEN_PATTERN).expect("Invalid expr regex");
+        let clock_regex = Regex::new(r"\{clock(?::([^}]*))?\}").expect("Invalid clock regex");
+        let qr_regex = Regex::new(r"\{qr:([^}|]*)((?:\|[^}|]*)*)\}").expect("Invalid qr regex");
+        let ticker_regex = Regex::new(TICKER_TOKEN_PATTERN).expect("Invalid ticker regex");
         let decimal_separator = Self::get_decimal_separator(locale);
+        let number_format = NumberFormat::for_locale(locale, decimal_separator);
 
         Ok(Self {
             font: Arc::new(font),
@@ -41,11 +182,302 @@ impl ImageGenerator {
             lines,
             font_size,
             decimal_separator,
+            number_format,
             sensor_regex,
+            templated_regex,
             time_regex,
+            expr_regex,
+            clock_regex,
+            qr_regex,
+            ticker_regex,
+            ticker_px_per_sec: 60.0,
+            jpeg_subsampling: JpegSubsampling::Yuv420,
+            jpeg_restart_interval: 0,
+            line_options: Vec::new(),
+            text_color: Rgb([255, 255, 255]),
+            background_color: Rgb([0, 0, 0]),
+            grid_cols: 1,
+            border_width: 0,
+            border_color: Rgb([255, 255, 255]),
+            anti_burnin: None,
+            value_replacements: HashMap::new(),
+            palette: HashMap::new(),
+            brightness_lut: None,
+            monochrome: None,
+            logo: None,
+            max_line_length: 512,
+            smart_format: false,
+            subpixel_text: false,
+            widget_supersampling: 4,
+            background_gradient: None,
+            output_size: (width, height),
+            outline: false,
+            outline_color: Rgb([0, 0, 0]),
+            empty_frame_text: None,
+            trace_frames: false,
+            traced_lines: Mutex::new(None),
+            idle_animation: None,
+            idle_since: Mutex::new(None),
+            clock: Arc::new(Local::now),
+            truncated_lines: Mutex::new(HashSet::new()),
+            unknown_time_zones: Mutex::new(HashSet::new()),
+            frame_pool: Mutex::new(Vec::new()),
         })
     }
 
+    /// Replaces the font with one loaded from `path`. If the file can't be read or
+    /// isn't a valid font, a warning is logged and the current font is kept.
+    pub fn with_font_path(mut self, path: &Path) -> Self {
+        let font = fs::read(path)
+            .context("could not read file")
+            .and_then(|data| Font::try_from_vec(data).context("not a valid TTF/OTF font"));
+        match font {
+            Ok(font) => self.font = Arc::new(font),
+            Err(e) => eprintln!(
+                "Warning: Could not load FONT_PATH {} ({:#}), using the embedded font",
+                path.display(),
+                e
+            ),
+        }
+        self
+    }
+
+    /// Loads `LOGO_IMAGE` to draw in a corner of every frame, keeping its
+    /// transparency. If the file can't be loaded, a warning is logged and no logo is drawn.
+    pub fn with_logo(mut self, logo: &Logo) -> Self {
+        let image = match image::open(&logo.path) {
+            Ok(image) => image.to_rgba8(),
+            Err(e) => {
+                eprintln!(
+                    "Warning: Could not load LOGO_IMAGE {} ({}), skipping the logo",
+                    logo.path, e
+                );
+                return self;
+            }
+        };
+
+        let width = ((image.width() as f32 * logo.scale).round() as u32).max(1);
+        let height = ((image.height() as f32 * logo.scale).round() as u32).max(1);
+        let image = if (width, height) == image.dimensions() {
+            image
+        } else {
+            image::imageops::resize(&image, width, height, FilterType::Triangle)
+        };
+        self.logo = Some((image, logo.position, logo.margin));
+        self
+    }
+
+    /// Sets per-line options, matched to `lines` by index.
+    pub fn with_line_options(mut self, line_options: Vec<LineOptions>) -> Self {
+        self.line_options = line_options;
+        self
+    }
+
+    /// Sets the chroma subsampling used by `generate_frame`.
+    pub fn with_jpeg_subsampling(mut self, subsampling: JpegSubsampling) -> Self {
+        self.jpeg_subsampling = subsampling;
+        self
+    }
+
+    /// Writes a restart marker every `interval` MCUs in `generate_frame`, so a
+    /// decoder can resync after a corrupted segment. 0 disables them.
+    pub fn with_jpeg_restart_interval(mut self, interval: u16) -> Self {
+        self.jpeg_restart_interval = interval;
+        self
+    }
+
+    /// Sets how fast `{ticker:...}` lines scroll, in pixels per second.
+    pub fn with_ticker_speed(mut self, px_per_sec: f32) -> Self {
+        self.ticker_px_per_sec = px_per_sec;
+        self
+    }
+
+    /// Reduces every frame to pure black and white for e-paper. `None` keeps full color.
+    pub fn with_monochrome(mut self, monochrome: Option<Monochrome>) -> Self {
+        self.monochrome = monochrome;
+        self
+    }
+
+    /// Replaces the wall clock used for time tokens, the analog clock, tickers and
+    /// anti-burn-in drift.
+    #[cfg(test)]
+    pub fn with_clock(
+        mut self,
+        clock: impl Fn() -> DateTime<Local> + Send + Sync + 'static,
+    ) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Caps resolved lines at `max_chars` characters, so a huge sensor state can't
+    /// make every frame lay out and measure megabytes of text.
+    pub fn with_max_line_length(mut self, max_chars: usize) -> Self {
+        self.max_line_length = max_chars.max(1);
+        self
+    }
+
+    /// Formats tokens without modifiers by their entity's `device_class`.
+    pub fn with_smart_format(mut self, enabled: bool) -> Self {
+        self.smart_format = enabled;
+        self
+    }
+
+    /// Draws text spans at fractional x positions instead of whole pixels.
+    pub fn with_subpixel_text(mut self, enabled: bool) -> Self {
+        self.subpixel_text = enabled;
+        self
+    }
+
+    /// Anti-aliasing of the clock's lines and circles: `samples`×`samples` per
+    /// pixel, 1 for hard edges.
+    pub fn with_widget_supersampling(mut self, samples: u32) -> Self {
+        self.widget_supersampling = samples;
+        self
+    }
+
+    /// Sets the default text color and the background fill.
+    pub fn with_colors(mut self, text: Rgb<u8>, background: Rgb<u8>) -> Self {
+        self.text_color = text;
+        self.background_color = background;
+        self
+    }
+
+    /// Fills the background with a gradient instead of the solid background color.
+    pub fn with_background_gradient(mut self, gradient: Option<BackgroundGradient>) -> Self {
+        self.background_gradient = gradient;
+        self
+    }
+
+    /// Scales each frame to `width`×`height` with Lanczos filtering when that differs
+    /// from the size it is drawn at, for smooth text at small output sizes.
+    pub fn with_output_size(mut self, width: u32, height: u32) -> Self {
+        self.output_size = (width, height);
+        self
+    }
+
+    /// Outlines text in `color`, on every line or only where `LINE_n_OUTLINE=on`.
+    pub fn with_outline(mut self, enabled: bool, color: Rgb<u8>) -> Self {
+        self.outline = enabled;
+        self.outline_color = color;
+        self
+    }
+
+    /// Text shown in the middle of the frame while every line resolves to nothing,
+    /// so an empty layout doesn't look like a crashed stream. `None` leaves the
+    /// frame blank.
+    pub fn with_empty_frame_text(mut self, text: Option<String>) -> Self {
+        self.empty_frame_text = text;
+        self
+    }
+
+    /// Logs every frame's resolved lines, skipping frames with the same text as
+    /// the last one logged.
+    pub fn with_trace_frames(mut self, enabled: bool) -> Self {
+        self.trace_frames = enabled;
+        self
+    }
+
+    /// Animates a dot once the sensor values haven't changed for `idle_after`,
+    /// so a static display still shows it is live. `None` never animates.
+    pub fn with_idle_animation(mut self, idle_after: Option<Duration>) -> Self {
+        self.idle_animation = idle_after;
+        self
+    }
+
+    /// Lays the flowing lines out in `cols` columns instead of a single centered list.
+    pub fn with_grid_cols(mut self, cols: u32) -> Self {
+        self.grid_cols = cols.max(1);
+        self
+    }
+
+    /// Draws an inset border of `width` pixels around the frame. Lines are laid out inside it.
+    pub fn with_border(mut self, width: u32, color: Rgb<u8>) -> Self {
+        self.border_width = width;
+        self.border_color = color;
+        self
+    }
+
+    /// Enables slowly drifting all content to avoid burn-in. `None` keeps it static.
+    pub fn with_anti_burnin(mut self, anti_burnin: Option<AntiBurnin>) -> Self {
+        self.anti_burnin = anti_burnin;
+        self
+    }
+
+    /// Sets the `SENSOR_x_REGEX_REPLACE` rules, keyed by entity id.
+    pub fn with_value_replacements(
+        mut self,
+        value_replacements: HashMap<String, Vec<ValueReplacement>>,
+    ) -> Self {
+        self.value_replacements = value_replacements;
+        self
+    }
+
+    /// Sets the named colors from `PALETTE`. Invalid colors, and color modifiers in
+    /// `lines` that reference unknown names, are reported and render in the default color.
+    pub fn with_palette(mut self, palette: &[(String, String)]) -> Self {
+        for (name, hex) in palette {
+            match parse_color(hex) {
+                Some(color) => {
+                    self.palette.insert(name.clone(), color);
+                }
+                None => eprintln!(
+                    "Warning: PALETTE color '{}' for '{}' is not #rrggbb or a color name",
+                    hex, name
+                ),
+            }
+        }
+
+        for template in &self.lines {
+            for caps in self.sensor_regex.captures_iter(template) {
+                let Some(spec) = caps.get(2) else { continue };
+                for modifier in parse_modifiers(spec.as_str()) {
+                    if modifier.name != "color" {
+                        continue;
+                    }
+                    let rules = parse_color_rules(modifier.arg.as_deref().unwrap_or(""));
+                    for rule in rules
+                        .iter()
+                        .filter(|r| self.resolve_color(&r.color).is_none())
+                    {
+                        eprintln!(
+                            "Warning: Unknown color '{}' in line '{}', using the default text color",
+                            rule.color, template
+                        );
+                    }
+                }
+            }
+        }
+
+        for (i, options) in self.line_options.iter().enumerate() {
+            for rule in options
+                .color_when
+                .iter()
+                .filter(|r| self.resolve_color(&r.rule.color).is_none())
+            {
+                eprintln!(
+                    "Warning: Unknown color '{}' in LINE_{}_COLOR_WHEN, the condition is ignored",
+                    rule.rule.color,
+                    i + 1
+                );
+            }
+        }
+        self
+    }
+
+    /// Scales every output pixel by `brightness` (0.0–1.0).
+    pub fn with_brightness(mut self, brightness: f32) -> Self {
+        self.brightness_lut = if brightness >= 1.0 {
+            None
+        } else {
+            let mut lut = [0u8; 256];
+            for (i, v) in lut.iter_mut().enumerate() {
+                *v = (i as f32 * brightness.max(0.0)).round() as u8;
+            }
+            Some(lut)
+        };
+        self
+    }
+
     fn get_decimal_separator(locale: &str) -> char {
         let l = locale.to_lowercase();
         // Common locales that use comma as decimal separator
@@ -68,29 +500,55 @@ impl ImageGenerator {
     }
 
     fn measure_text_width(&self, text: &str, scale: Scale) -> u32 {
-        let width = self
-            .font
+        self.measure_text_advance(text, scale).ceil() as u32
+    }
+
+    /// The exact horizontal advance of `text`, where the next text would start.
+    fn measure_text_advance(&self, text: &str, scale: Scale) -> f32 {
+        self.font
             .layout(text, scale, point(0.0, 0.0))
             .map(|g| g.position().x + g.unpositioned().h_metrics().advance_width)
             .last()
-            .unwrap_or(0.0);
-        width.ceil() as u32
+            .unwrap_or(0.0)
     }
 
-    fn resolve_line(&self, template: &str, sensor_values: &HashMap<String, String>) -> String {
+    /// Replaces the tokens in `template` without applying `MAX_LINE_LENGTH`.
+    fn substitute(&self, template: &str, sensor_values: &HashMap<String, String>) -> String {
         let mut result = template.to_string();
 
         // Replace Time
-        let now = Local::now();
+        let now = (self.clock)();
         result = self
             .time_regex
             .replace_all(&result, |caps: &regex::Captures| {
-                let fmt = &caps[1];
-                now.format(fmt).to_string()
+                self.format_time(now, &caps[1])
+            })
+            .to_string();
+
+        // Replace the connection health summary
+        if result.contains("{status}") {
+            let status = sensor_values
+                .get(STATUS_KEY)
+                .map(String::as_str)
+                .unwrap_or("HA ?");
+            result = result.replace("{status}", status);
+        }
+
+        // Replace arithmetic over entities, rounded to two decimals
+        result = self
+            .expr_regex
+            .replace_all(&result, |caps: &regex::Captures| {
+                match expr::evaluate(&caps[1], sensor_values) {
+                    // Adding 0.0 turns a rounded -0 into 0
+                    Some(value) => format!("{}", (value * 100.0).round() / 100.0 + 0.0)
+                        .replace('.', &self.decimal_separator.to_string()),
+                    None => "?".to_string(),
+                }
             })
             .to_string();
 
         // Replace Sensors
+        result = self.expand_templated_ids(&result, sensor_values);
         result = self
             .sensor_regex
             .replace_all(&result, |caps: &regex::Captures| {
@@ -98,123 +556,2409 @@ impl ImageGenerator {
                 let entity_id = format!("sensor.{}", &caps[1]);
                 let val = sensor_values
                     .get(&entity_id)
-                    .cloned()
+                    // `inf` and `NaN` parse as numbers but mean the sensor is broken
+                    .filter(|val| !is_non_finite(val))
+                    .map(|val| self.replace_value(&entity_id, val))
+                    .and_then(|val| match caps.get(2) {
+                        Some(spec) => {
+                            self.apply_modifiers(val, spec.as_str(), &entity_id, sensor_values, now)
+                        }
+                        None if self.smart_format => {
+                            Some(self.format_device_class(val, &entity_id, sensor_values))
+                        }
+                        None => Some(val),
+                    })
                     .unwrap_or_else(|| "?".to_string());
 
-                // Apply decimal separator if numeric
-                if val.parse::<f64>().is_ok() {
+                // Apply decimal separator if numeric, unless the token asks for `raw`
+                let modifiers = caps
+                    .get(2)
+                    .map(|spec| parse_modifiers(spec.as_str()))
+                    .unwrap_or_default();
+                let val = if modifiers.iter().any(|modifier| modifier.name == "raw") {
+                    val
+                } else if val.parse::<f64>().is_ok_and(f64::is_finite) {
                     val.replace('.', &self.decimal_separator.to_string())
                 } else {
-                    val
+                    format_grouped(&val, self.number_format).unwrap_or(val)
+                };
+
+                // Padding comes last, so it measures the value as displayed
+                match modifiers
+                    .iter()
+                    .find(|modifier| modifier.name == "pad")
+                    .and_then(|modifier| modifier.arg.as_deref())
+                {
+                    Some(width) => pad_value(&val, width, self.max_line_length),
+                    None => val,
                 }
             })
             .to_string();
 
-        result
+        // Composed characters map to single glyphs and measure correctly, no
+        // matter whether the state or template used combining accents
+        result.nfc().collect()
     }
 
-    fn draw_frame(&self, sensor_values: &HashMap<String, String>) -> RgbImage {
-        let mut image = RgbImage::new(self.width, self.height);
+    /// `LINE_n_TRANSFORM` steps for line `index`, empty when none are set.
+    fn line_transforms(&self, index: usize) -> &[TextTransform] {
+        self.line_options
+            .get(index)
+            .map_or(&[], |opts| opts.transforms.as_slice())
+    }
+
+    /// Formats `{time:FMT}`, or `{time:FMT@ZONE}` in an IANA zone such as
+    /// `America/New_York`. Unknown zones fall back to local time.
+    fn format_time(&self, now: DateTime<Local>, spec: &str) -> String {
+        // A zone name never contains spaces or strftime fields, so `%H @ home` stays a format
+        let Some((fmt, zone)) = spec
+            .rsplit_once('@')
+            .filter(|(_, zone)| !zone.is_empty() && !zone.contains([' ', '%']))
+        else {
+            return now.format(spec).to_string();
+        };
 
-        // Fill with black
-        for pixel in image.pixels_mut() {
-            *pixel = Rgb([0, 0, 0]);
+        match zone.parse::<Tz>() {
+            Ok(tz) => now.with_timezone(&tz).format(fmt).to_string(),
+            Err(_) => {
+                if self
+                    .unknown_time_zones
+                    .lock()
+                    .unwrap()
+                    .insert(zone.to_string())
+                {
+                    eprintln!(
+                        "Warning: Unknown time zone '{}' in {{time:{}}}, using local time",
+                        zone, spec
+                    );
+                }
+                now.format(fmt).to_string()
+            }
         }
+    }
 
-        let scale = Scale {
-            x: self.font_size,
-            y: self.font_size,
+    /// Shortens `text` to `max_line_length` characters, ending it with `…`. The
+    /// first truncation of each template is logged.
+    fn truncate(&self, template: &str, text: String) -> String {
+        let Some((cut, _)) = text.char_indices().nth(self.max_line_length) else {
+            return text;
         };
-        let white = Rgb([255, 255, 255]);
-        let line_height = self.font_size as i32;
-        let gap = (self.font_size * 0.25) as i32; // 25% gap
 
-        let total_lines = self.lines.len() as i32;
-        let total_content_height = total_lines * line_height + (total_lines - 1).max(0) * gap;
-        let start_y = (self.height as i32 - total_content_height) / 2;
+        self.log_truncation(template, text.chars().count());
+        let mut text = text;
+        text.truncate(cut);
+        text.push('…');
+        text
+    }
+
+    /// [`Self::truncate`] for a line drawn as colored spans, counting the whole line.
+    fn truncate_spans(
+        &self,
+        template: &str,
+        spans: Vec<(String, Rgb<u8>)>,
+    ) -> Vec<(String, Rgb<u8>)> {
+        let length: usize = spans.iter().map(|(text, _)| text.chars().count()).sum();
+        if length <= self.max_line_length {
+            return spans;
+        }
 
-        for (i, line_template) in self.lines.iter().enumerate() {
-            let text = self.resolve_line(line_template, sensor_values);
-            let text_width = self.measure_text_width(&text, scale);
-            let x = (self.width as i32 - text_width as i32) / 2;
-            let y = start_y + i as i32 * (line_height + gap);
+        self.log_truncation(template, length);
+        transform::apply(&[TextTransform::Truncate(self.max_line_length)], spans)
+    }
 
-            draw_text_mut(&mut image, white, x, y, scale, &self.font, &text);
+    fn log_truncation(&self, template: &str, length: usize) {
+        if self
+            .truncated_lines
+            .lock()
+            .unwrap()
+            .insert(template.to_string())
+        {
+            eprintln!(
+                "Warning: Line '{}' resolved to {} characters, truncating to MAX_LINE_LENGTH ({})",
+                template, length, self.max_line_length
+            );
         }
+    }
 
-        image
+    /// Turns templated tokens like `{sensor.${input_select.room}_temp}` into plain
+    /// ones by interpolating the referenced states. A token whose references have
+    /// no usable state yet renders as the `?` placeholder.
+    fn expand_templated_ids(
+        &self,
+        template: &str,
+        sensor_values: &HashMap<String, String>,
+    ) -> String {
+        self.templated_regex
+            .replace_all(template, |caps: &regex::Captures| {
+                if !caps[1].contains("${") {
+                    return caps[0].to_string();
+                }
+                match resolve_entity_refs(&caps[1], sensor_values) {
+                    Some(object_id) => format!("{{sensor.{}{}}}", object_id, &caps[2]),
+                    None => "?".to_string(),
+                }
+            })
+            .into_owned()
     }
 
-    pub fn generate_frame(&self, sensor_values: &HashMap<String, String>) -> Result<Vec<u8>> {
-        let image = self.draw_frame(sensor_values);
+    /// Runs the entity's regex replacements over its state, in order.
+    fn replace_value(&self, entity_id: &str, val: &str) -> String {
+        let mut val = val.to_string();
+        for rule in self.value_replacements.get(entity_id).into_iter().flatten() {
+            val = rule
+                .pattern
+                .replace_all(&val, rule.replacement.as_str())
+                .into_owned();
+        }
+        val
+    }
 
-        // Encode to JPEG
-        let mut buffer = Cursor::new(Vec::new());
-        image.write_to(&mut buffer, ImageOutputFormat::Jpeg(80))?;
+    /// Applies token modifiers to a sensor value. `None` renders the placeholder.
+    fn apply_modifiers(
+        &self,
+        val: String,
+        spec: &str,
+        entity_id: &str,
+        sensor_values: &HashMap<String, String>,
+        now: DateTime<Local>,
+    ) -> Option<String> {
+        let mut val = val;
+        for modifier in parse_modifiers(spec) {
+            val = match modifier.name.as_str() {
+                "relative" => humanize_relative(&val, now)?,
+                "max_today" | "min_today" => sensor_values
+                    .get(&history_key(entity_id, &modifier.name))?
+                    .clone(),
+                "avg" => {
+                    let spec = format!("avg={}", modifier.arg.as_deref().unwrap_or(""));
+                    sensor_values.get(&history_key(entity_id, &spec))?.clone()
+                }
+                // Non-numeric values pass through unchanged
+                "currency" => {
+                    let code = modifier.arg.as_deref().unwrap_or("USD");
+                    format_currency(&val, code, self.number_format).unwrap_or(val)
+                }
+                "percent" => format_percent(&val, self.number_format).unwrap_or(val),
+                "emoji" => {
+                    select_emoji(modifier.arg.as_deref().unwrap_or(""), &val).unwrap_or_default()
+                }
+                "trend" => {
+                    let tolerance = match modifier.arg.as_deref() {
+                        Some(arg) => arg.trim().parse().ok()?,
+                        None => 0.0,
+                    };
+                    let previous = sensor_values.get(&history_key(entity_id, "previous"));
+                    trend_arrow(&val, previous.map(String::as_str), tolerance)
+                        .unwrap_or_default()
+                        .to_string()
+                }
+                _ => val,
+            };
+        }
+        Some(val)
+    }
 
-        Ok(buffer.into_inner())
+    /// Formats `val` by the device class and unit the poller recorded for the entity.
+    /// Values of other entities pass through unchanged.
+    fn format_device_class(
+        &self,
+        val: String,
+        entity_id: &str,
+        sensor_values: &HashMap<String, String>,
+    ) -> String {
+        let Some(device_class) = sensor_values.get(&history_key(entity_id, "device_class")) else {
+            return val;
+        };
+        let unit = sensor_values.get(&history_key(entity_id, "unit"));
+        format_device_class(
+            &val,
+            device_class,
+            unit.map(String::as_str),
+            self.number_format,
+        )
+        .unwrap_or(val)
     }
 
-    pub fn generate_raw_frame(&self, sensor_values: &HashMap<String, String>) -> Vec<u8> {
-        let image = self.draw_frame(sensor_values);
-        image.into_raw()
+    /// Looks up a palette name, falling back to a literal `#rrggbb` color.
+    fn resolve_color(&self, name: &str) -> Option<Rgb<u8>> {
+        self.palette
+            .get(name)
+            .copied()
+            .or_else(|| parse_color(name))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Color chosen by a sensor token's `color=` modifier for the current value, if any.
+    fn token_color(
+        &self,
+        caps: &regex::Captures,
+        sensor_values: &HashMap<String, String>,
+    ) -> Option<Rgb<u8>> {
+        let spec = caps.get(2)?;
+        let value = sensor_values.get(&format!("sensor.{}", &caps[1]))?;
+        parse_modifiers(spec.as_str())
+            .into_iter()
+            .filter(|modifier| modifier.name == "color")
+            .find_map(|modifier| {
+                let rules = parse_color_rules(modifier.arg.as_deref()?);
+                self.resolve_color(select_color(&rules, value)?)
+            })
+    }
 
-    #[test]
-    fn test_image_generation() {
-        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
-        let lines = vec![
-            "Date: {time:%Y-%m-%d}".to_string(),
-            // Updated template to just entity ID
-            "Temp: {sensor.temp}°C".to_string(),
-        ];
-        let generator = ImageGenerator::new(font_data, lines, 48.0, "en_US", 640, 360)
-            .expect("Failed to create ImageGenerator");
+    /// Resolves a text line into runs of text and color. Sensor tokens with a
+    /// `color=` modifier get their own color; everything else inherits `line_color`.
+    /// `MAX_LINE_LENGTH` applies to the line as a whole.
+    fn text_spans(
+        &self,
+        template: &str,
+        sensor_values: &HashMap<String, String>,
+        line_color: Rgb<u8>,
+    ) -> Vec<(String, Rgb<u8>)> {
+        let expanded = &self.expand_templated_ids(template, sensor_values);
+        let mut spans: Vec<(String, Rgb<u8>)> = Vec::new();
+        let mut push = |text: String, color: Rgb<u8>| match spans.last_mut() {
+            Some((last, last_color)) if *last_color == color => last.push_str(&text),
+            _ if text.is_empty() => {}
+            _ => spans.push((text, color)),
+        };
 
-        let mut sensors = HashMap::new();
-        // Lookup still uses full entity ID
-        sensors.insert("sensor.temp".to_string(), "22.5".to_string());
+        let mut last_end = 0;
+        for caps in self.sensor_regex.captures_iter(expanded) {
+            let token = caps.get(0).unwrap();
+            push(
+                self.substitute(&expanded[last_end..token.start()], sensor_values),
+                line_color,
+            );
+            let color = self.token_color(&caps, sensor_values).unwrap_or(line_color);
+            push(self.substitute(token.as_str(), sensor_values), color);
+            last_end = token.end();
+        }
+        push(
+            self.substitute(&expanded[last_end..], sensor_values),
+            line_color,
+        );
 
-        let frame = generator
-            .generate_frame(&sensors)
-            .expect("Failed to generate frame");
+        self.truncate_spans(template, spans)
+    }
 
-        assert!(!frame.is_empty());
-        assert_eq!(frame[0], 0xFF);
-        assert_eq!(frame[1], 0xD8);
+    /// Content offset for anti-burn-in at `now`. Traces a Lissajous figure so the
+    /// drift covers the whole square rather than a single line or circle.
+    fn burnin_offset(&self, now: DateTime<Local>) -> (i32, i32) {
+        let Some(anti_burnin) = self.anti_burnin else {
+            return (0, 0);
+        };
+        let period_ms = anti_burnin.period.as_millis().max(1) as i64;
+        let phase = now.timestamp_millis().rem_euclid(period_ms) as f32 / period_ms as f32;
+        let angle = phase * std::f32::consts::TAU;
+        let amplitude = anti_burnin.amplitude as f32;
+        (
+            (amplitude * angle.sin()).round() as i32,
+            (amplitude * (2.0 * angle).sin()).round() as i32,
+        )
     }
 
-    #[test]
-    fn test_resolve_line_locale() {
-        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
-        let lines = vec![];
+    /// Whether the entity states have stayed the same for the idle threshold at
+    /// `now`. Each call records the states, so a change restarts the wait.
+    fn is_idle(&self, sensor_values: &HashMap<String, String>, now: DateTime<Local>) -> bool {
+        let Some(idle_after) = self.idle_animation else {
+            return false;
+        };
+        // Only entity states count: derived keys such as `sensor.x:last_updated`,
+        // running averages or `status` change without the shown values changing.
+        // Summing the entry hashes ignores the map's iteration order
+        let states = sensor_values
+            .iter()
+            .filter(|(key, _)| key.contains('.') && !key.contains(':'));
+        let hash = states.fold(0u64, |sum, entry| {
+            let mut hasher = DefaultHasher::new();
+            entry.hash(&mut hasher);
+            sum.wrapping_add(hasher.finish())
+        });
+        let mut idle_since = self.idle_since.lock().unwrap();
+        let since = match *idle_since {
+            Some((last, since)) if last == hash => since,
+            _ => idle_since.insert((hash, now)).1,
+        };
+        (now - since).to_std().is_ok_and(|idle| idle >= idle_after)
+    }
 
-        // Test US Locale (Dot)
-        let gen_us =
-            ImageGenerator::new(font_data, lines.clone(), 48.0, "en_US", 640, 360).unwrap();
-        let mut sensors = HashMap::new();
-        sensors.insert("sensor.temp".to_string(), "22.5".to_string());
-        // Updated template to just entity ID
-        assert_eq!(gen_us.resolve_line("{sensor.temp}", &sensors), "22.5");
+    /// Color for line `index`: the first matching `LINE_n_COLOR_WHEN` condition,
+    /// else green or red with HA health for lines showing `{status}`.
+    fn line_color(
+        &self,
+        index: usize,
+        sensor_values: &HashMap<String, String>,
+        default: Rgb<u8>,
+    ) -> Rgb<u8> {
+        let conditional = self
+            .line_options
+            .get(index)
+            .and_then(|opts| select_line_color(&opts.color_when, sensor_values))
+            .and_then(|name| self.resolve_color(name));
+        if let Some(color) = conditional {
+            return color;
+        }
 
-        // Test SV Locale (Comma)
-        let gen_sv =
-            ImageGenerator::new(font_data, lines.clone(), 48.0, "sv_SE", 640, 360).unwrap();
-        // Updated template to just entity ID
-        assert_eq!(gen_sv.resolve_line("{sensor.temp}", &sensors), "22,5");
+        if !self.lines[index].contains("{status}") {
+            return default;
+        }
+        match sensor_values.get(STATUS_KEY) {
+            Some(status) if status.starts_with("HA OK") => Rgb([0, 200, 0]),
+            Some(_) => Rgb([230, 0, 0]),
+            None => default,
+        }
+    }
 
-        // Test Non-numeric
-        sensors.insert("sensor.state".to_string(), "on".to_string());
-        assert_eq!(gen_sv.resolve_line("{sensor.state}", &sensors), "on");
+    /// Layer of line `index` from `LINE_n_LAYER`. By default clocks and QR codes
+    /// sit on layer 0 and text, tickers and the legend above them on layer 1.
+    fn line_layer(&self, index: usize, content: &LineContent) -> i32 {
+        let default = match content {
+            LineContent::Clock { .. } | LineContent::Qr { .. } => 0,
+            LineContent::Text { .. } | LineContent::Ticker { .. } | LineContent::Legend { .. } => 1,
+        };
+        self.line_options
+            .get(index)
+            .and_then(|opts| opts.layer)
+            .unwrap_or(default)
+    }
 
-        // Test IP (multiple dots, parses as float? "1.2.3.4" -> No)
-        sensors.insert("sensor.ip".to_string(), "192.168.1.1".to_string());
-        assert_eq!(gen_sv.resolve_line("{sensor.ip}", &sensors), "192.168.1.1");
+    /// Whether no line has anything to show: every text line is blank and every
+    /// legend is empty. Clocks, QR codes and tickers always show something.
+    fn is_blank(contents: &[LineContent]) -> bool {
+        contents.iter().all(|content| match content {
+            LineContent::Text { spans } => spans.iter().all(|(text, _)| text.trim().is_empty()),
+            LineContent::Legend { entries } => entries.is_empty(),
+            LineContent::Clock { .. } | LineContent::Qr { .. } | LineContent::Ticker { .. } => {
+                false
+            }
+        })
+    }
+
+    /// Returns the clock size if the line is an analog clock, e.g. `{clock:size=120}`.
+    fn clock_size(&self, template: &str) -> Option<u32> {
+        let caps = self.clock_regex.captures(template)?;
+        let default_size = (self.font_size * 2.5) as u32;
+        let size = caps
+            .get(1)
+            .and_then(|args| {
+                args.as_str()
+                    .split([':', ','])
+                    .filter_map(|arg| arg.trim().strip_prefix("size="))
+                    .find_map(|size| size.parse().ok())
+            })
+            .unwrap_or(default_size);
+        Some(size)
+    }
+
+    /// Turns a resolved line into a QR widget if it contains a
+    /// `{qr:DATA|size=150|ec=M}` token. Tokens inside DATA are already resolved.
+    fn qr_content(&self, text: String) -> Option<LineContent> {
+        let caps = self.qr_regex.captures(&text)?;
+
+        let mut size = (self.font_size * 3.0) as u32;
+        let mut ec_level = EcLevel::M;
+        for arg in caps[2].split('|').map(str::trim) {
+            if let Some(value) = arg.strip_prefix("size=") {
+                size = value.parse().unwrap_or(size);
+            } else if let Some(value) = arg.strip_prefix("ec=") {
+                ec_level = match value.to_uppercase().as_str() {
+                    "L" => EcLevel::L,
+                    "Q" => EcLevel::Q,
+                    "H" => EcLevel::H,
+                    _ => EcLevel::M,
+                };
+            }
+        }
+
+        Some(LineContent::Qr {
+            data: caps[1].to_string(),
+            size,
+            ec_level,
+            line: text,
+        })
+    }
+
+    /// Returns the text of a `{ticker:sensor.events}` line: the recent values the
+    /// poller collected, or the `?` placeholder before the first one arrives.
+    fn ticker_text(
+        &self,
+        template: &str,
+        sensor_values: &HashMap<String, String>,
+    ) -> Option<String> {
+        let caps = self.ticker_regex.captures(template)?;
+        let text = sensor_values
+            .get(&history_key(&caps[1], "ticker"))
+            .cloned()
+            .unwrap_or_else(|| "?".to_string());
+        Some(self.truncate(template, text))
+    }
+
+    /// Draws `text` scrolling through the `width` pixels starting at `left`. Copies
+    /// follow each other with a gap so the ticker loops seamlessly, and the text is
+    /// clipped to its cell.
+    #[allow(clippy::too_many_arguments)]
+    fn draw_ticker(
+        &self,
+        image: &mut RgbImage,
+        text: &str,
+        left: i32,
+        top: i32,
+        width: i32,
+        font_size: f32,
+        color: Rgb<u8>,
+        now: DateTime<Local>,
+    ) {
+        let scale = Scale::uniform(font_size);
+        let spacing = (font_size * 2.0) as u32;
+        let period = self.measure_text_width(text, scale) + spacing;
+        let offset = ticker_offset(now, self.ticker_px_per_sec, period);
+
+        // The strip includes the line gap so descenders are not cut off. It starts
+        // as a copy of the frame, so it keeps the background gradient and alert pulse.
+        let height = self.text_height(font_size) as u32;
+        let (strip_left, strip_top) = (left.max(0), top.max(0));
+        let mut strip = image::imageops::crop_imm(
+            image,
+            strip_left as u32,
+            strip_top as u32,
+            (width - (strip_left - left)).max(1) as u32,
+            height,
+        )
+        .to_image();
+        let mut canvas = self.canvas(&mut strip);
+        let mut x = -(offset as i32) - (strip_left - left);
+        while x < width {
+            canvas.text(x, top - strip_top, scale, &self.font, text, color);
+            x += period as i32;
+        }
+        image::imageops::replace(image, &strip, strip_left as i64, strip_top as i64);
+    }
+
+    /// The `PALETTE` names used by `color=` modifiers and `LINE_n_COLOR_WHEN`, in
+    /// the order they first appear, with their colors. Colors given as a name or
+    /// `#rrggbb` have no meaning to show and are left out.
+    fn legend_entries(&self) -> Vec<(String, Rgb<u8>)> {
+        let token_rules = self.lines.iter().flat_map(|template| {
+            self.sensor_regex
+                .captures_iter(template)
+                .filter_map(|caps| caps.get(2))
+                .flat_map(|spec| parse_modifiers(spec.as_str()))
+                .filter(|modifier| modifier.name == "color")
+                .flat_map(|modifier| parse_color_rules(modifier.arg.as_deref().unwrap_or("")))
+                .map(|rule| rule.color)
+                .collect::<Vec<_>>()
+        });
+        let line_rules = self
+            .line_options
+            .iter()
+            .flat_map(|opts| opts.color_when.iter().map(|rule| rule.rule.color.clone()));
+
+        let mut entries: Vec<(String, Rgb<u8>)> = Vec::new();
+        for name in token_rules.chain(line_rules) {
+            if entries.iter().any(|(known, _)| *known == name) {
+                continue;
+            }
+            if let Some(&color) = self.palette.get(&name) {
+                entries.push((name, color));
+            }
+        }
+        entries
+    }
+
+    /// Draws the legend entries centered in the cell at `left`/`width`, each a
+    /// square swatch followed by its label in `label_color`.
+    #[allow(clippy::too_many_arguments)]
+    fn draw_legend(
+        &self,
+        canvas: &mut Canvas,
+        entries: &[(String, Rgb<u8>)],
+        left: i32,
+        y: i32,
+        width: i32,
+        font_size: f32,
+        label_color: Rgb<u8>,
+        swatch_color: impl Fn(Rgb<u8>) -> Rgb<u8>,
+    ) {
+        let scale = Scale::uniform(font_size);
+        let swatch = ((font_size * 0.6).round() as i32).max(1);
+        let (label_gap, entry_gap) = (swatch / 2, font_size.round() as i32);
+        let widths: Vec<i32> = entries
+            .iter()
+            .map(|(label, _)| swatch + label_gap + self.measure_text_width(label, scale) as i32)
+            .collect();
+        let total = widths.iter().sum::<i32>() + (widths.len() as i32 - 1).max(0) * entry_gap;
+
+        let mut x = (left + (width - total) / 2).max(left);
+        let swatch_y = y + (self.text_height(font_size) - swatch) / 2;
+        for ((label, color), entry_width) in entries.iter().zip(widths) {
+            let rect = Rect::at(x, swatch_y).of_size(swatch as u32, swatch as u32);
+            canvas.rect(rect, swatch_color(*color));
+            let label_x = x + swatch + label_gap;
+            canvas.text(label_x, y, scale, &self.font, label, label_color);
+            x += entry_width + entry_gap;
+        }
+    }
+
+    /// Height of a line of text at `font_size`, from the top of the tallest glyph
+    /// (like `Å`) to the bottom of the deepest descender (like `g`).
+    fn text_height(&self, font_size: f32) -> i32 {
+        let metrics = self.font.v_metrics(Scale::uniform(font_size));
+        (metrics.ascent - metrics.descent).ceil() as i32
+    }
+
+    /// The background color under pixel (`x`, `y`), before any alert pulse.
+    fn background_at(&self, x: i32, y: i32) -> Rgb<u8> {
+        match self.background_gradient {
+            Some(gradient) if gradient.vertical => {
+                gradient.color_at(y.clamp(0, self.height as i32 - 1) as u32, self.height)
+            }
+            Some(gradient) => {
+                gradient.color_at(x.clamp(0, self.width as i32 - 1) as u32, self.width)
+            }
+            None => self.background_color,
+        }
+    }
+
+    /// A `width`×`height` buffer to draw into, reused when one is pooled. Its
+    /// previous contents are left as they are: the background fill covers every pixel.
+    fn frame_buffer(&self) -> RgbImage {
+        self.frame_pool
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| RgbImage::new(self.width, self.height))
+    }
+
+    /// Returns a drawn frame to the pool once it is encoded. Frames scaled to
+    /// another output size are dropped, they can't be drawn into.
+    fn recycle_frame(&self, image: RgbImage) {
+        if image.dimensions() != (self.width, self.height) {
+            return;
+        }
+        let mut pool = self.frame_pool.lock().unwrap();
+        if pool.len() < MAX_POOLED_FRAMES {
+            pool.push(image);
+        }
+    }
+
+    /// A canvas over `image` drawing shapes with `WIDGET_SUPERSAMPLING`.
+    fn canvas<'a>(&self, image: &'a mut RgbImage) -> Canvas<'a> {
+        Canvas::new(image).with_supersampling(self.widget_supersampling)
+    }
+
+    fn draw_frame(&self, sensor_values: &HashMap<String, String>) -> RgbImage {
+        let mut image = self.frame_buffer();
+        let now = (self.clock)();
+
+        // Fill with the background, pulsing red while the disconnect alert is raised
+        let alert = sensor_values.contains_key(ALERT_KEY);
+        let fill = |color: Rgb<u8>| {
+            if alert {
+                alert_background(color, now)
+            } else {
+                color
+            }
+        };
+        match self.background_gradient {
+            Some(gradient) => {
+                // One color per row or column, computed once
+                let len = if gradient.vertical {
+                    self.height
+                } else {
+                    self.width
+                };
+                let colors: Vec<Rgb<u8>> = (0..len)
+                    .map(|pos| fill(gradient.color_at(pos, len)))
+                    .collect();
+                for (x, y, pixel) in image.enumerate_pixels_mut() {
+                    *pixel = colors[if gradient.vertical { y } else { x } as usize];
+                }
+            }
+            None => {
+                let background = fill(self.background_color);
+                for pixel in image.pixels_mut() {
+                    *pixel = background;
+                }
+            }
+        }
+
+        // Inset border, one outline per pixel of width. Content stays inside it.
+        let border = self.border_width.min(self.width / 2).min(self.height / 2) as i32;
+        let mut canvas = self.canvas(&mut image);
+        for inset in 0..border {
+            let rect = Rect::at(inset, inset).of_size(
+                self.width - 2 * inset as u32,
+                self.height - 2 * inset as u32,
+            );
+            canvas.hollow_rect(rect, self.border_color);
+        }
+        let inner_height = self.height as i32 - 2 * border;
+
+        // Inside the border and below the content, so the values stay readable
+        if self.is_idle(sensor_values, now) {
+            let radius = (self.height.min(self.width) as f32 / 60.0).max(2.0);
+            let inner = (
+                self.width.saturating_sub(2 * border as u32),
+                self.height.saturating_sub(2 * border as u32),
+            );
+            let (x, y) = idle_dot_position(now, inner, radius);
+            canvas.disc(
+                (x + border as f32, y + border as f32),
+                radius,
+                blend(self.text_color, self.background_color, 0.4),
+            );
+        }
+
+        let text_color = self.text_color;
+        let gap = (self.font_size * 0.25) as i32; // 25% gap
+
+        let colors: Vec<Rgb<u8>> = (0..self.lines.len())
+            .map(|i| self.line_color(i, sensor_values, text_color))
+            .collect();
+        let contents = self.line_contents(sensor_values, &colors);
+        if self.trace_frames {
+            self.trace_lines(&self.drawn_lines(&contents));
+        }
+        let opacities: Vec<f32> = (0..self.lines.len())
+            .map(|i| {
+                self.line_options
+                    .get(i)
+                    .and_then(|opts| opts.opacity)
+                    .unwrap_or(1.0)
+            })
+            .collect();
+        let sizes: Vec<f32> = (0..self.lines.len())
+            .map(|i| {
+                self.line_options
+                    .get(i)
+                    .and_then(|opts| opts.font_size)
+                    .unwrap_or(self.font_size)
+            })
+            .collect();
+        let heights: Vec<i32> = contents
+            .iter()
+            .zip(&sizes)
+            .map(|(content, &font_size)| match content {
+                LineContent::Text { .. } | LineContent::Ticker { .. } => {
+                    self.text_height(font_size)
+                }
+                LineContent::Clock { size } | LineContent::Qr { size, .. } => *size as i32,
+                LineContent::Legend { .. } => self.text_height(font_size),
+            })
+            .collect();
+
+        // Absolutely positioned lines are taken out of the centered flow, and a
+        // legend without named threshold colors takes no space at all
+        let positions: Vec<_> = (0..self.lines.len())
+            .map(|i| self.line_options.get(i).and_then(|opts| opts.y))
+            .collect();
+
+        // Flow lines fill a grid row by row, each cell centered. With a single
+        // column this is the classic centered list.
+        let inner_width = self.width as i32 - 2 * border;
+        let cols = self.grid_cols.max(1) as usize;
+        let cell_width = inner_width / cols as i32;
+        let flow: Vec<usize> = (0..contents.len())
+            .filter(|&i| positions[i].is_none())
+            .filter(
+                |&i| !matches!(&contents[i], LineContent::Legend { entries } if entries.is_empty()),
+            )
+            .collect();
+        let row_heights: Vec<i32> = flow
+            .chunks(cols)
+            .map(|row| row.iter().map(|&i| heights[i]).max().unwrap_or(0))
+            .collect();
+        let total_content_height =
+            row_heights.iter().sum::<i32>() + (row_heights.len() as i32 - 1).max(0) * gap;
+        let (dx, dy) = self.burnin_offset(now);
+
+        // (left, width, top) of each line's cell
+        let mut cells = vec![(border, inner_width, 0); contents.len()];
+        let mut row_y = border + (inner_height - total_content_height) / 2;
+        for (row, row_height) in flow.chunks(cols).zip(&row_heights) {
+            for (col, &i) in row.iter().enumerate() {
+                let left = border + col as i32 * cell_width;
+                let width = if cols == 1 { inner_width } else { cell_width };
+                cells[i] = (left, width, row_y + (row_height - heights[i]) / 2);
+            }
+            row_y += row_height + gap;
+        }
+        for (i, cell) in cells.iter_mut().enumerate() {
+            if let Some(pos) = positions[i] {
+                cell.2 = border + pos.resolve(inner_height as u32) - heights[i] / 2;
+            }
+        }
+
+        // Stacking, bottom to top: background, border, logo, then the lines by
+        // layer. The logo drifts with the text so it is protected from burn-in too
+        if let Some((logo, position, margin)) = &self.logo {
+            let inset = border + *margin as i32;
+            let right = self.width as i32 - inset - logo.width() as i32;
+            let bottom = self.height as i32 - inset - logo.height() as i32;
+            let (x, y) = match position {
+                LogoPosition::TopLeft => (inset, inset),
+                LogoPosition::TopRight => (right, inset),
+                LogoPosition::BottomLeft => (inset, bottom),
+                LogoPosition::BottomRight => (right, bottom),
+            };
+            self.canvas(&mut image).overlay(logo, x + dx, y + dy);
+        }
+
+        // Lines on the same layer are drawn in order, so later ones end up on top
+        let mut order: Vec<usize> = (0..contents.len()).collect();
+        order.sort_by_key(|&i| self.line_layer(i, &contents[i]));
+        for i in order {
+            let content = &contents[i];
+            let (left, width, top) = cells[i];
+            let y = top + dy;
+            let cx = left + width / 2 + dx;
+            // Opacity blends toward the background behind the middle of the line
+            let background = fill(self.background_at(cx, y + heights[i] / 2));
+
+            match content {
+                LineContent::Text { spans } => {
+                    let scale = Scale::uniform(sizes[i]);
+                    let spans = bidi::visual_spans(
+                        spans.clone(),
+                        self.line_options.get(i).and_then(|opts| opts.direction),
+                    );
+                    // Where each span starts, at whole pixels unless SUBPIXEL_TEXT is on
+                    let starts: Vec<f32> = if self.subpixel_text {
+                        let widths: Vec<f32> = spans
+                            .iter()
+                            .map(|(text, _)| self.measure_text_advance(text, scale))
+                            .collect();
+                        let text_width: f32 = widths.iter().sum();
+                        let x = (left as f32 + (width as f32 - text_width) / 2.0).max(left as f32)
+                            + dx as f32;
+                        span_starts(x, &widths)
+                    } else {
+                        let widths: Vec<f32> = spans
+                            .iter()
+                            .map(|(text, _)| self.measure_text_width(text, scale) as f32)
+                            .collect();
+                        let text_width = widths.iter().sum::<f32>() as i32;
+                        let x = (left + (width - text_width) / 2).max(left) + dx;
+                        span_starts(x as f32, &widths)
+                    };
+                    let mut canvas = self.canvas(&mut image);
+                    let mut draw = |color, x: f32, y, text: &str| {
+                        if self.subpixel_text {
+                            canvas.text_subpixel(x, y, scale, &self.font, text, color);
+                        } else {
+                            canvas.text(x as i32, y, scale, &self.font, text, color);
+                        }
+                    };
+
+                    let outlined = self
+                        .line_options
+                        .get(i)
+                        .and_then(|opts| opts.outline)
+                        .unwrap_or(self.outline);
+                    if outlined {
+                        // The text stamped one pixel off in every direction, under the fill
+                        let color = blend(self.outline_color, background, opacities[i]);
+                        for (ox, oy) in OUTLINE_OFFSETS {
+                            for ((text, _), x) in spans.iter().zip(&starts) {
+                                draw(color, x + ox as f32, y + oy, text);
+                            }
+                        }
+                    }
+                    for ((text, color), x) in spans.iter().zip(&starts) {
+                        let color = blend(*color, background, opacities[i]);
+                        draw(color, *x, y, text);
+                    }
+                }
+                LineContent::Clock { size } => {
+                    let color = blend(text_color, background, opacities[i]);
+                    let mut canvas = self.canvas(&mut image);
+                    draw_clock(&mut canvas, cx, y + heights[i] / 2, *size, color, now);
+                }
+                LineContent::Qr {
+                    data,
+                    size,
+                    ec_level,
+                    ..
+                } => {
+                    let cy = y + heights[i] / 2;
+                    let mut canvas = self.canvas(&mut image);
+                    if let Err(e) = draw_qr(&mut canvas, cx, cy, *size, data, *ec_level) {
+                        eprintln!("Error rendering QR code: {}", e);
+                    }
+                }
+                LineContent::Ticker { text } => {
+                    let color = blend(colors[i], background, opacities[i]);
+                    let (left, size) = (left + dx, sizes[i]);
+                    self.draw_ticker(&mut image, text, left, y, width, size, color, now);
+                }
+                LineContent::Legend { entries } => {
+                    let label_color = blend(colors[i], background, opacities[i]);
+                    let swatch_color = |color| blend(color, background, opacities[i]);
+                    let (left, size) = (left + dx, sizes[i]);
+                    self.draw_legend(
+                        &mut self.canvas(&mut image),
+                        entries,
+                        left,
+                        y,
+                        width,
+                        size,
+                        label_color,
+                        swatch_color,
+                    );
+                }
+            }
+        }
+
+        if let Some(text) = &self.empty_frame_text {
+            if Self::is_blank(&contents) {
+                let scale = Scale::uniform(self.font_size);
+                let x = (self.width as i32 - self.measure_text_width(text, scale) as i32) / 2;
+                let y = (self.height as i32 - self.text_height(self.font_size)) / 2;
+                let (x, y) = (x.max(0) + dx, y + dy);
+                self.canvas(&mut image)
+                    .text(x, y, scale, &self.font, text, text_color);
+            }
+        }
+
+        // Scaled before brightness and dithering, which work on output pixels
+        let (out_width, out_height) = self.output_size;
+        if (out_width, out_height) != (self.width, self.height) {
+            image = image::imageops::resize(&image, out_width, out_height, FilterType::Lanczos3);
+        }
+
+        if let Some(lut) = &self.brightness_lut {
+            for channel in image.iter_mut() {
+                *channel = lut[*channel as usize];
+            }
+        }
+        if let Some(mode) = self.monochrome {
+            monochrome::apply(&mut image, mode);
+        }
+
+        image
+    }
+
+    /// What each line renders as, with text lines in `colors`.
+    fn line_contents(
+        &self,
+        sensor_values: &HashMap<String, String>,
+        colors: &[Rgb<u8>],
+    ) -> Vec<LineContent> {
+        self.lines
+            .iter()
+            .enumerate()
+            .map(|(i, template)| {
+                if let Some(size) = self.clock_size(template) {
+                    LineContent::Clock { size }
+                } else if template.contains(LEGEND_TOKEN) {
+                    LineContent::Legend {
+                        entries: self.legend_entries(),
+                    }
+                } else if let Some(text) = self.ticker_text(template, sensor_values) {
+                    LineContent::Ticker { text }
+                } else {
+                    let spans = self.text_spans(template, sensor_values, colors[i]);
+                    let text: String = spans.iter().map(|(text, _)| text.as_str()).collect();
+                    // QR payloads are encoded as written, without the line's transforms
+                    self.qr_content(text).unwrap_or_else(|| LineContent::Text {
+                        spans: transform::apply(self.line_transforms(i), spans),
+                    })
+                }
+            })
+            .collect()
+    }
+
+    /// The text of each line in `contents`: tickers show their recent values,
+    /// the legend its labels, QR lines their resolved template and clocks
+    /// their template.
+    fn drawn_lines(&self, contents: &[LineContent]) -> Vec<String> {
+        contents
+            .iter()
+            .zip(&self.lines)
+            .map(|(content, template)| match content {
+                LineContent::Text { spans } => {
+                    spans.iter().map(|(text, _)| text.as_str()).collect()
+                }
+                LineContent::Ticker { text } => text.clone(),
+                LineContent::Legend { entries } => {
+                    let labels: Vec<String> = entries
+                        .iter()
+                        .map(|(label, _)| format!("■ {}", label))
+                        .collect();
+                    labels.join("  ")
+                }
+                LineContent::Qr { line, .. } => line.clone(),
+                LineContent::Clock { .. } => template.clone(),
+            })
+            .collect()
+    }
+
+    /// The text of every line as it is currently drawn, see [`Self::drawn_lines`].
+    pub fn resolved_lines(&self, sensor_values: &HashMap<String, String>) -> Vec<String> {
+        let colors = vec![self.text_color; self.lines.len()];
+        self.drawn_lines(&self.line_contents(sensor_values, &colors))
+    }
+
+    /// Prints `lines` unless they are the same as the last traced ones. Returns
+    /// whether they were printed.
+    fn trace_lines(&self, lines: &[String]) -> bool {
+        let mut hasher = DefaultHasher::new();
+        lines.hash(&mut hasher);
+        let hash = hasher.finish();
+        if self.traced_lines.lock().unwrap().replace(hash) == Some(hash) {
+            return false;
+        }
+        println!("Frame: {:?}", lines);
+        true
+    }
+
+    /// Draws and JPEG-encodes a frame. A failed encode carries an `EncodeError` context.
+    pub fn generate_frame(&self, sensor_values: &HashMap<String, String>) -> Result<Vec<u8>> {
+        let image = self.draw_frame(sensor_values);
+        let jpeg = self.encode_jpeg(&image).context(EncodeError);
+        self.recycle_frame(image);
+        jpeg
+    }
+
+    fn encode_jpeg(&self, image: &RgbImage) -> Result<Vec<u8>> {
+        let sampling = match self.jpeg_subsampling {
+            // The image crate always encodes 4:2:0 without restart markers, keep
+            // using it for the default.
+            JpegSubsampling::Yuv420 if self.jpeg_restart_interval == 0 => {
+                let mut buffer = Cursor::new(Vec::new());
+                image.write_to(&mut buffer, ImageOutputFormat::Jpeg(80))?;
+                return Ok(buffer.into_inner());
+            }
+            JpegSubsampling::Yuv420 => SamplingFactor::R_4_2_0,
+            JpegSubsampling::Yuv422 => SamplingFactor::R_4_2_2,
+            JpegSubsampling::Yuv444 => SamplingFactor::R_4_4_4,
+        };
+
+        let mut buffer = Vec::new();
+        let mut encoder = Encoder::new(&mut buffer, 80);
+        encoder.set_sampling_factor(sampling);
+        encoder.set_restart_interval(self.jpeg_restart_interval);
+        // JPEG dimensions are 16 bit, refuse instead of writing a wrapped size
+        let (Ok(width), Ok(height)) = (image.width().try_into(), image.height().try_into()) else {
+            bail!("{}x{} is too large for JPEG", image.width(), image.height());
+        };
+        encoder.encode(image.as_raw(), width, height, ColorType::Rgb)?;
+
+        Ok(buffer)
+    }
+
+    /// Encodes the frame as PNG: 1-bit grayscale in monochrome mode, RGB otherwise.
+    pub fn generate_png(&self, sensor_values: &HashMap<String, String>) -> Result<Vec<u8>> {
+        let image = self.draw_frame(sensor_values);
+        let png = if self.monochrome.is_some() {
+            monochrome::encode_png_1bit(&image)
+        } else {
+            let mut buffer = Cursor::new(Vec::new());
+            image
+                .write_to(&mut buffer, ImageOutputFormat::Png)
+                .map(|()| buffer.into_inner())
+                .map_err(Into::into)
+        };
+        self.recycle_frame(image);
+        png
+    }
+
+    /// The frame as packed RGB24. The buffer is handed over to the caller (the
+    /// RTSP pipeline wraps it without copying), so it isn't reused.
+    pub fn generate_raw_frame(&self, sensor_values: &HashMap<String, String>) -> Vec<u8> {
+        let image = self.draw_frame(sensor_values);
+        image.into_raw()
+    }
+
+    /// Encodes a frame from [`Self::generate_raw_frame`] as JPEG.
+    pub fn encode_raw_frame(&self, raw: Vec<u8>) -> Result<Vec<u8>> {
+        let (width, height) = self.output_size;
+        let image = RgbImage::from_raw(width, height, raw)
+            .with_context(|| format!("Raw frame is not {}x{} RGB24", width, height))?;
+        let jpeg = self.encode_jpeg(&image).context(EncodeError);
+        self.recycle_frame(image);
+        jpeg
+    }
+}
+
+/// Offsets the outline is drawn at around the text, one pixel wide.
+const OUTLINE_OFFSETS: [(i32, i32); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+/// The x position of each span when drawn one after another from `x`.
+fn span_starts(x: f32, widths: &[f32]) -> Vec<f32> {
+    widths
+        .iter()
+        .scan(x, |next, width| {
+            let start = *next;
+            *next += width;
+            Some(start)
+        })
+        .collect()
+}
+
+/// Whether a state parses as a number that can't be shown, like `inf` or `NaN`.
+fn is_non_finite(value: &str) -> bool {
+    value.trim().parse::<f64>().is_ok_and(|n| !n.is_finite())
+}
+
+/// How far a ticker has scrolled at `now`, wrapping every `period` pixels.
+fn ticker_offset(now: DateTime<Local>, px_per_sec: f32, period: u32) -> u32 {
+    let scrolled = now.timestamp_millis() as f64 * px_per_sec as f64 / 1000.0;
+    (scrolled.max(0.0) as u64 % period.max(1) as u64) as u32
+}
+
+/// The background at `now` during a disconnect alert: fades to red and back
+/// once a second, so the display cannot be mistaken for a frozen one.
+fn alert_background(background: Rgb<u8>, now: DateTime<Local>) -> Rgb<u8> {
+    const ALERT_RED: Rgb<u8> = Rgb([220, 0, 0]);
+    let phase = now.timestamp_subsec_millis() as f32 / 1000.0;
+    let level = 0.5 - 0.5 * (phase * std::f32::consts::TAU).cos();
+    blend(ALERT_RED, background, level)
+}
+
+/// Center of the idle dot at `now`. It bounces between the edges, slowly and on
+/// different periods horizontally and vertically, so it visits the whole frame.
+fn idle_dot_position(now: DateTime<Local>, (width, height): (u32, u32), radius: f32) -> (f32, f32) {
+    let bounce = |period_ms: i64, len: u32| {
+        let phase = now.timestamp_millis().rem_euclid(period_ms) as f32 / period_ms as f32;
+        let travel = (len as f32 - 2.0 * radius).max(0.0);
+        radius + travel * (1.0 - (2.0 * phase - 1.0).abs())
+    };
+    (bounce(23_000, width), bounce(17_000, height))
+}
+
+/// Mixes `color` with `background`; `opacity` 1.0 keeps `color` unchanged.
+fn blend(color: Rgb<u8>, background: Rgb<u8>, opacity: f32) -> Rgb<u8> {
+    let mix = |c: u8, b: u8| (b as f32 + (c as f32 - b as f32) * opacity).round() as u8;
+    Rgb([
+        mix(color[0], background[0]),
+        mix(color[1], background[1]),
+        mix(color[2], background[2]),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LinePosition;
+    use crate::modifiers::parse_line_color_rules;
+
+    impl ImageGenerator {
+        /// The text `template` is drawn with, before the line's transforms.
+        fn resolve_line(&self, template: &str, sensor_values: &HashMap<String, String>) -> String {
+            self.text_spans(template, sensor_values, self.text_color)
+                .into_iter()
+                .map(|(text, _)| text)
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_image_generation() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec![
+            "Date: {time:%Y-%m-%d}".to_string(),
+            // Updated template to just entity ID
+            "Temp: {sensor.temp}°C".to_string(),
+        ];
+        let generator = ImageGenerator::new(font_data, lines, 48.0, "en_US", 640, 360)
+            .expect("Failed to create ImageGenerator");
+
+        let mut sensors = HashMap::new();
+        // Lookup still uses full entity ID
+        sensors.insert("sensor.temp".to_string(), "22.5".to_string());
+
+        let frame = generator
+            .generate_frame(&sensors)
+            .expect("Failed to generate frame");
+
+        assert!(!frame.is_empty());
+        assert_eq!(frame[0], 0xFF);
+        assert_eq!(frame[1], 0xD8);
+    }
+
+    #[test]
+    fn test_frame_buffer_reuse() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec!["Temp: {sensor.temp}".to_string()];
+        let generator = ImageGenerator::new(font_data, lines.clone(), 48.0, "en_US", 640, 360)
+            .unwrap()
+            .with_background_gradient(Some(BackgroundGradient {
+                from: Rgb([0, 0, 80]),
+                to: Rgb([0, 80, 0]),
+                vertical: true,
+            }));
+        let fresh = ImageGenerator::new(font_data, lines, 48.0, "en_US", 640, 360)
+            .unwrap()
+            .with_background_gradient(Some(BackgroundGradient {
+                from: Rgb([0, 0, 80]),
+                to: Rgb([0, 80, 0]),
+                vertical: true,
+            }));
+        let mut sensors = HashMap::new();
+        sensors.insert("sensor.temp".to_string(), "-22.5 and falling".to_string());
+
+        let first = generator.draw_frame(&sensors);
+        let pixels = first.as_raw().as_ptr();
+        generator.recycle_frame(first);
+
+        // The next frame is drawn into the same buffer, without traces of the last one
+        sensors.insert("sensor.temp".to_string(), "1".to_string());
+        let second = generator.draw_frame(&sensors);
+        assert_eq!(second.as_raw().as_ptr(), pixels);
+        assert_eq!(second, fresh.draw_frame(&sensors));
+        generator.recycle_frame(second);
+
+        // Encoding hands the buffer back
+        generator.generate_frame(&sensors).unwrap();
+        assert_eq!(generator.frame_pool.lock().unwrap().len(), 1);
+
+        // Frames scaled to another output size aren't pooled
+        let scaled =
+            ImageGenerator::new(font_data, vec!["Hi".to_string()], 48.0, "en_US", 640, 360)
+                .unwrap()
+                .with_output_size(320, 180);
+        scaled.generate_frame(&sensors).unwrap();
+        assert!(scaled.frame_pool.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_trace_frames_only_on_change() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec!["Temp: {sensor.temp}".to_string()];
+        let generator = ImageGenerator::new(font_data, lines, 48.0, "en_US", 320, 180)
+            .unwrap()
+            .with_trace_frames(true);
+        let mut sensors = HashMap::new();
+        sensors.insert("sensor.temp".to_string(), "21".to_string());
+
+        let frame = generator.resolved_lines(&sensors);
+        assert!(generator.trace_lines(&frame));
+        assert!(!generator.trace_lines(&frame));
+
+        sensors.insert("sensor.temp".to_string(), "22".to_string());
+        let changed = generator.resolved_lines(&sensors);
+        assert!(generator.trace_lines(&changed));
+        assert!(generator.trace_lines(&frame));
+
+        // Drawing traces the frame, so the same text isn't printed again
+        generator.draw_frame(&HashMap::from([(
+            "sensor.temp".to_string(),
+            "21".to_string(),
+        )]));
+        assert!(!generator.trace_lines(&frame));
+    }
+
+    #[test]
+    fn test_font_path_falls_back_to_embedded() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec!["Hello".to_string()];
+        let bad_font =
+            std::env::temp_dir().join(format!("ha-streamer-bad-{}.ttf", std::process::id()));
+        fs::write(&bad_font, b"not a font").unwrap();
+
+        for path in [bad_font.as_path(), Path::new("/nonexistent/font.ttf")] {
+            let generator = ImageGenerator::new(font_data, lines.clone(), 48.0, "en_US", 320, 180)
+                .unwrap()
+                .with_font_path(path);
+            let image = generator.draw_frame(&HashMap::new());
+            assert!(image.iter().any(|&channel| channel > 0));
+        }
+
+        fs::remove_file(bad_font).unwrap();
+    }
+
+    #[test]
+    fn test_unit_symbols_render() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        // Integrations use both the micro sign (U+00B5) and Greek mu (U+03BC)
+        let symbols = ['°', 'µ', '²', '³', 'μ', 'Ω'];
+        let lines = vec!["21.5°C 3µg/m³ 12m² ±5 €".to_string()];
+        let generator = ImageGenerator::new(font_data, lines, 48.0, "en_US", 640, 360).unwrap();
+
+        // Glyph 0 is .notdef, which renders as an empty box or nothing
+        for symbol in symbols {
+            assert_ne!(generator.font.glyph(symbol).id().0, 0, "missing {}", symbol);
+            let scale = Scale::uniform(48.0);
+            assert!(generator.measure_text_width(&symbol.to_string(), scale) > 0);
+        }
+
+        let frame = generator.generate_frame(&HashMap::new()).unwrap();
+        assert_eq!(&frame[..2], &[0xFF, 0xD8]);
+        assert!(generator.draw_frame(&HashMap::new()).iter().any(|&c| c > 0));
+    }
+
+    #[test]
+    fn test_jpeg_subsampling_444() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec!["Temp: {sensor.temp}°C".to_string()];
+        let generator = ImageGenerator::new(font_data, lines, 48.0, "en_US", 640, 360)
+            .unwrap()
+            .with_jpeg_subsampling(JpegSubsampling::Yuv444);
+
+        let frame = generator.generate_frame(&HashMap::new()).unwrap();
+
+        assert_eq!(&frame[..2], &[0xFF, 0xD8]);
+        let decoded = image::load_from_memory(&frame).unwrap();
+        assert_eq!(decoded.width(), 640);
+        assert_eq!(decoded.height(), 360);
+    }
+
+    #[test]
+    fn test_jpeg_restart_markers() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec!["Temp: {sensor.temp}°C".to_string()];
+        let has_marker = |frame: &[u8], marker: u8| frame.windows(2).any(|w| w == [0xFF, marker]);
+
+        let generator =
+            ImageGenerator::new(font_data, lines.clone(), 48.0, "en_US", 640, 360).unwrap();
+        let frame = generator.generate_frame(&HashMap::new()).unwrap();
+        assert!(!has_marker(&frame, 0xDD));
+
+        let generator = ImageGenerator::new(font_data, lines, 48.0, "en_US", 640, 360)
+            .unwrap()
+            .with_jpeg_restart_interval(8);
+        let frame = generator.generate_frame(&HashMap::new()).unwrap();
+
+        // A DRI segment plus RST0..RST7 markers in the scan
+        assert!(has_marker(&frame, 0xDD));
+        assert!((0xD0..=0xD7).all(|rst| has_marker(&frame, rst)));
+        let decoded = image::load_from_memory(&frame).unwrap();
+        assert_eq!(decoded.width(), 640);
+    }
+
+    #[test]
+    fn test_absolute_line_position() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec!["I".to_string()];
+        let generator = ImageGenerator::new(font_data, lines, 40.0, "en_US", 200, 200)
+            .unwrap()
+            .with_line_options(vec![LineOptions {
+                y: Some(LinePosition::Pixels(20)),
+                ..Default::default()
+            }]);
+
+        let image = generator.draw_frame(&HashMap::new());
+        let lit_rows: Vec<u32> = (0..200)
+            .filter(|&y| (0..200).any(|x| image.get_pixel(x, y)[0] > 128))
+            .collect();
+
+        // The glyph is drawn around y=20 instead of the vertical center
+        assert!(!lit_rows.is_empty());
+        assert!(*lit_rows.last().unwrap() < 60);
+    }
+
+    #[test]
+    fn test_text_and_background_colors() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec!["Hello".to_string()];
+        let generator = ImageGenerator::new(font_data, lines, 48.0, "en_US", 320, 180)
+            .unwrap()
+            .with_colors(Rgb([255, 255, 0]), Rgb([0, 0, 128]));
+
+        let image = generator.draw_frame(&HashMap::new());
+        assert_eq!(*image.get_pixel(0, 0), Rgb([0, 0, 128]));
+        assert!(image.pixels().any(|p| *p == Rgb([255, 255, 0])));
+    }
+
+    #[test]
+    fn test_grid_layout() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec!["I".to_string(), "I".to_string(), "I".to_string()];
+        let generator = ImageGenerator::new(font_data, lines, 40.0, "en_US", 400, 200)
+            .unwrap()
+            .with_grid_cols(2);
+
+        let image = generator.draw_frame(&HashMap::new());
+        let lit = |x0: u32, x1: u32, y0: u32, y1: u32| {
+            (x0..x1).any(|x| (y0..y1).any(|y| image.get_pixel(x, y)[0] > 128))
+        };
+
+        // Two cells on the first row, one on the left of the second
+        assert!(lit(0, 200, 0, 100) && lit(200, 400, 0, 100));
+        assert!(lit(0, 200, 100, 200) && !lit(200, 400, 100, 200));
+        // Nothing is drawn in the middle column, where a single-column list would be
+        assert!(!lit(190, 210, 0, 200));
+    }
+
+    #[test]
+    fn test_border() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let generator = ImageGenerator::new(font_data, vec![], 48.0, "en_US", 100, 80)
+            .unwrap()
+            .with_border(3, Rgb([255, 0, 0]));
+
+        let image = generator.draw_frame(&HashMap::new());
+
+        for inset in 0..3 {
+            assert_eq!(*image.get_pixel(inset, 40), Rgb([255, 0, 0]));
+            assert_eq!(*image.get_pixel(50, 79 - inset), Rgb([255, 0, 0]));
+        }
+        assert_eq!(*image.get_pixel(3, 40), Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn test_burnin_offset() {
+        use chrono::TimeZone;
+
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let generator = ImageGenerator::new(font_data, vec![], 48.0, "en_US", 64, 64).unwrap();
+        let start = Local.timestamp_opt(0, 0).unwrap();
+        assert_eq!(generator.burnin_offset(start), (0, 0));
+
+        let generator = generator.with_anti_burnin(Some(AntiBurnin {
+            amplitude: 4,
+            period: std::time::Duration::from_secs(400),
+        }));
+        assert_eq!(generator.burnin_offset(start), (0, 0));
+        // An eighth and a quarter of the way through the cycle
+        assert_eq!(
+            generator.burnin_offset(start + chrono::Duration::seconds(50)),
+            (3, 4)
+        );
+        assert_eq!(
+            generator.burnin_offset(start + chrono::Duration::seconds(100)),
+            (4, 0)
+        );
+        // Back to the start after a full period
+        assert_eq!(
+            generator.burnin_offset(start + chrono::Duration::seconds(400)),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn test_line_opacity() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec!["Footnote".to_string()];
+        let generator = ImageGenerator::new(font_data, lines, 48.0, "en_US", 320, 180)
+            .unwrap()
+            .with_line_options(vec![LineOptions {
+                opacity: Some(0.25),
+                ..Default::default()
+            }]);
+
+        let max = *generator.draw_frame(&HashMap::new()).iter().max().unwrap();
+        assert_eq!(max, 64);
+        assert_eq!(
+            blend(Rgb([255, 0, 100]), Rgb([0, 0, 0]), 1.0),
+            Rgb([255, 0, 100])
+        );
+    }
+
+    #[test]
+    fn test_line_transform() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec![
+            "  door {sensor.door}  ".to_string(),
+            "{qr:https://example.com/a}".to_string(),
+        ];
+        let pipeline = crate::transform::parse_pipeline("trim|upper|truncate:8").unwrap();
+        let generator = ImageGenerator::new(font_data, lines, 32.0, "en_US", 320, 180)
+            .unwrap()
+            .with_line_options(vec![
+                LineOptions {
+                    transforms: pipeline.clone(),
+                    ..Default::default()
+                },
+                LineOptions {
+                    transforms: pipeline,
+                    ..Default::default()
+                },
+            ]);
+        let plain = ImageGenerator::new(
+            font_data,
+            vec![
+                "DOOR OPE…".to_string(),
+                "{qr:https://example.com/a}".to_string(),
+            ],
+            32.0,
+            "en_US",
+            320,
+            180,
+        )
+        .unwrap();
+
+        let mut sensors = HashMap::new();
+        sensors.insert("sensor.door".to_string(), "open".to_string());
+        // QR payloads are encoded as written
+        assert_eq!(
+            generator.resolved_lines(&sensors),
+            vec!["DOOR OPE…", "{qr:https://example.com/a}"]
+        );
+        assert_eq!(generator.draw_frame(&sensors), plain.draw_frame(&sensors));
+    }
+
+    #[test]
+    fn test_brightness() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec!["Hello".to_string()];
+        let full = ImageGenerator::new(font_data, lines.clone(), 48.0, "en_US", 320, 180).unwrap();
+        let dimmed = ImageGenerator::new(font_data, lines, 48.0, "en_US", 320, 180)
+            .unwrap()
+            .with_brightness(0.5);
+
+        let max_full = *full.draw_frame(&HashMap::new()).iter().max().unwrap();
+        let max_dimmed = *dimmed.draw_frame(&HashMap::new()).iter().max().unwrap();
+
+        assert_eq!(max_full, 255);
+        assert_eq!(max_dimmed, 128);
+    }
+
+    #[test]
+    fn test_line_color_when() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec!["Temp {sensor.temp}".to_string(), "{status}".to_string()];
+        let generator = ImageGenerator::new(font_data, lines, 48.0, "en_US", 320, 180)
+            .unwrap()
+            .with_line_options(vec![
+                LineOptions {
+                    color_when: parse_line_color_rules(
+                        "sensor.alarm==on:alarm,sensor.alarm==test:nope",
+                    )
+                    .unwrap(),
+                    ..Default::default()
+                },
+                LineOptions {
+                    color_when: parse_line_color_rules("sensor.alarm==on:blue").unwrap(),
+                    ..Default::default()
+                },
+            ])
+            .with_palette(&[("alarm".to_string(), "#ff0000".to_string())]);
+
+        let white = Rgb([255, 255, 255]);
+        let mut sensors = HashMap::new();
+        sensors.insert(STATUS_KEY.to_string(), "HA OK (1/1)".to_string());
+        assert_eq!(generator.line_color(0, &sensors, white), white);
+        assert_eq!(generator.line_color(1, &sensors, white), Rgb([0, 200, 0]));
+
+        sensors.insert("sensor.alarm".to_string(), "on".to_string());
+        assert_eq!(generator.line_color(0, &sensors, white), Rgb([255, 0, 0]));
+        assert_eq!(generator.line_color(1, &sensors, white), Rgb([0, 0, 255]));
+
+        // An unknown color name falls back to the line's usual color
+        sensors.insert("sensor.alarm".to_string(), "test".to_string());
+        assert_eq!(generator.line_color(0, &sensors, white), white);
+    }
+
+    #[test]
+    fn test_legend() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec![
+            "{sensor.temp:color=alert>25,ok,#0000ff}".to_string(),
+            "{legend}".to_string(),
+        ];
+        let palette = vec![
+            ("ok".to_string(), "#00ff00".to_string()),
+            ("alert".to_string(), "#ff0000".to_string()),
+            ("unused".to_string(), "#ffff00".to_string()),
+        ];
+        let generator = ImageGenerator::new(font_data, lines.clone(), 32.0, "en_US", 320, 180)
+            .unwrap()
+            .with_line_options(vec![
+                LineOptions::default(),
+                LineOptions {
+                    color_when: parse_line_color_rules(
+                        "sensor.alarm==on:alert,sensor.door==on:red",
+                    )
+                    .unwrap(),
+                    ..Default::default()
+                },
+            ])
+            .with_palette(&palette);
+
+        // Palette names in first-use order; plain colors and unused names are left out
+        assert_eq!(
+            generator.legend_entries(),
+            vec![
+                ("alert".to_string(), Rgb([255, 0, 0])),
+                ("ok".to_string(), Rgb([0, 255, 0])),
+            ]
+        );
+        let sensors = HashMap::new();
+        assert_eq!(generator.resolved_lines(&sensors)[1], "■ alert  ■ ok");
+
+        // Both swatches are drawn, although the value shows neither color
+        let image = generator.draw_frame(&sensors);
+        assert!(image.pixels().any(|p| *p == Rgb([255, 0, 0])));
+        assert!(image.pixels().any(|p| *p == Rgb([0, 255, 0])));
+
+        // Without named threshold colors the legend draws nothing
+        let plain = ImageGenerator::new(font_data, lines, 32.0, "en_US", 320, 180).unwrap();
+        let without_legend = ImageGenerator::new(
+            font_data,
+            vec!["{sensor.temp:color=alert>25,ok,#0000ff}".to_string()],
+            32.0,
+            "en_US",
+            320,
+            180,
+        )
+        .unwrap();
+        assert_eq!(plain.resolved_lines(&sensors)[1], "");
+        assert_eq!(
+            plain.draw_frame(&sensors),
+            without_legend.draw_frame(&sensors)
+        );
+    }
+
+    #[test]
+    fn test_empty_frame_text() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec!["{sensor.note}".to_string(), "{legend}".to_string()];
+        let blank = ImageGenerator::new(font_data, lines.clone(), 32.0, "en_US", 320, 180)
+            .unwrap()
+            .with_colors(Rgb([255, 255, 255]), Rgb([0, 0, 0]));
+        let generator = ImageGenerator::new(font_data, lines, 32.0, "en_US", 320, 180)
+            .unwrap()
+            .with_colors(Rgb([255, 255, 255]), Rgb([0, 0, 0]))
+            .with_empty_frame_text(Some("No data".to_string()));
+
+        // Every line resolves to nothing: the text is drawn instead of a blank frame
+        let mut sensors = HashMap::new();
+        sensors.insert("sensor.note".to_string(), " ".to_string());
+        assert!(blank
+            .draw_frame(&sensors)
+            .pixels()
+            .all(|p| *p == Rgb([0, 0, 0])));
+        let image = generator.draw_frame(&sensors);
+        assert!(image.pixels().any(|p| *p == Rgb([255, 255, 255])));
+        assert_eq!(*image.get_pixel(5, 5), Rgb([0, 0, 0]));
+
+        // As soon as one line shows something, the frame is the usual one
+        sensors.insert("sensor.note".to_string(), "Hi".to_string());
+        assert_eq!(generator.draw_frame(&sensors), blank.draw_frame(&sensors));
+    }
+
+    #[test]
+    fn test_idle_animation() {
+        use chrono::TimeZone;
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let start = Local.with_ymd_and_hms(2024, 3, 9, 7, 5, 30).unwrap();
+        let generator = ImageGenerator::new(font_data, vec![], 32.0, "en_US", 320, 180)
+            .unwrap()
+            .with_colors(Rgb([255, 255, 255]), Rgb([0, 0, 0]))
+            .with_idle_animation(Some(Duration::from_secs(60)));
+        let later = |secs| start + chrono::Duration::seconds(secs);
+
+        let mut sensors = HashMap::new();
+        sensors.insert("sensor.temp".to_string(), "21".to_string());
+        assert!(!generator.is_idle(&sensors, start));
+        assert!(!generator.is_idle(&sensors, later(59)));
+        assert!(generator.is_idle(&sensors, later(60)));
+
+        // A change restarts the wait
+        sensors.insert("sensor.temp".to_string(), "22".to_string());
+        assert!(!generator.is_idle(&sensors, later(61)));
+        assert!(generator.is_idle(&sensors, later(121)));
+
+        // Derived values aren't watched states, they don't restart it
+        sensors.insert(
+            "sensor.temp:last_updated".to_string(),
+            "2024-03-09T07:07:31+00:00".to_string(),
+        );
+        sensors.insert(STATUS_KEY.to_string(), "HA OK".to_string());
+        assert!(generator.is_idle(&sensors, later(122)));
+
+        // The dot moves from frame to frame and stays inside the frame
+        let first = idle_dot_position(later(121), (320, 180), 3.0);
+        let second = idle_dot_position(later(122), (320, 180), 3.0);
+        assert_ne!(first, second);
+        for secs in 0..120 {
+            let (x, y) = idle_dot_position(later(secs), (320, 180), 3.0);
+            assert!((3.0..=317.0).contains(&x) && (3.0..=177.0).contains(&y));
+        }
+
+        // Drawn dimmed once idle, never without IDLE_ANIMATION
+        let idle = ImageGenerator::new(font_data, vec![], 32.0, "en_US", 320, 180)
+            .unwrap()
+            .with_colors(Rgb([255, 255, 255]), Rgb([0, 0, 0]))
+            .with_idle_animation(Some(Duration::ZERO))
+            .draw_frame(&sensors);
+        assert!(idle.pixels().any(|p| *p != Rgb([0, 0, 0])));
+        assert!(idle.pixels().all(|p| p[0] <= 102));
+        let off = ImageGenerator::new(font_data, vec![], 32.0, "en_US", 320, 180).unwrap();
+        assert!(off
+            .draw_frame(&sensors)
+            .pixels()
+            .all(|p| *p == Rgb([0, 0, 0])));
+    }
+
+    #[test]
+    fn test_token_colors() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec!["T {sensor.temp:color=warn>25,#0000ff} C".to_string()];
+        let palette = vec![
+            ("warn".to_string(), "#ff0000".to_string()),
+            ("bad".to_string(), "reddish".to_string()),
+        ];
+        let generator = ImageGenerator::new(font_data, lines, 48.0, "sv_SE", 640, 360)
+            .unwrap()
+            .with_palette(&palette);
+        assert!(!generator.palette.contains_key("bad"));
+
+        let white = Rgb([255, 255, 255]);
+        let mut sensors = HashMap::new();
+        sensors.insert("sensor.temp".to_string(), "26.5".to_string());
+        assert_eq!(
+            generator.text_spans(&generator.lines[0], &sensors, white),
+            vec![
+                ("T ".to_string(), white),
+                ("26,5".to_string(), Rgb([255, 0, 0])),
+                (" C".to_string(), white),
+            ]
+        );
+
+        sensors.insert("sensor.temp".to_string(), "20".to_string());
+        assert_eq!(
+            generator.text_spans(&generator.lines[0], &sensors, white)[1],
+            ("20".to_string(), Rgb([0, 0, 255]))
+        );
+
+        // Unknown names and missing values inherit the line color
+        let spans = generator.text_spans("{sensor.temp:color=nope} {sensor.x}", &sensors, white);
+        assert_eq!(spans, vec![("20 ?".to_string(), white)]);
+    }
+
+    #[test]
+    fn test_templated_entity_ids() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let generator = ImageGenerator::new(font_data, vec![], 48.0, "en_US", 320, 180).unwrap();
+        let template = "{sensor.${input_select.room}_temp} C";
+
+        let mut sensors = HashMap::new();
+        assert_eq!(generator.resolve_line(template, &sensors), "? C");
+
+        sensors.insert("input_select.room".to_string(), "Living Room".to_string());
+        sensors.insert("sensor.living_room_temp".to_string(), "21.5".to_string());
+        sensors.insert("sensor.kitchen_temp".to_string(), "19.0".to_string());
+        assert_eq!(generator.resolve_line(template, &sensors), "21.5 C");
+
+        sensors.insert("input_select.room".to_string(), "kitchen".to_string());
+        assert_eq!(generator.resolve_line(template, &sensors), "19.0 C");
+    }
+
+    #[test]
+    fn test_ticker() {
+        use chrono::TimeZone;
+
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec!["{ticker:sensor.events}".to_string()];
+        let generator = ImageGenerator::new(font_data, lines, 48.0, "en_US", 320, 180)
+            .unwrap()
+            .with_ticker_speed(100.0);
+
+        let mut sensors = HashMap::new();
+        assert_eq!(
+            generator
+                .ticker_text(&generator.lines[0], &sensors)
+                .as_deref(),
+            Some("?")
+        );
+        sensors.insert(
+            "sensor.events:ticker".to_string(),
+            "Door opened · Alarm off".to_string(),
+        );
+        assert_eq!(
+            generator
+                .ticker_text(&generator.lines[0], &sensors)
+                .as_deref(),
+            Some("Door opened · Alarm off")
+        );
+        assert_eq!(generator.ticker_text("Plain text", &sensors), None);
+
+        // 100 px/s moves 25 px in 250 ms and wraps after one period
+        let start = Local.timestamp_opt(0, 0).unwrap();
+        assert_eq!(ticker_offset(start, 100.0, 400), 0);
+        assert_eq!(
+            ticker_offset(start + chrono::Duration::milliseconds(250), 100.0, 400),
+            25
+        );
+        assert_eq!(
+            ticker_offset(start + chrono::Duration::seconds(5), 100.0, 400),
+            100
+        );
+
+        // Text longer than the cell is clipped to it
+        let generator = ImageGenerator::new(
+            font_data,
+            vec![generator.lines[0].clone(), String::new()],
+            48.0,
+            "en_US",
+            320,
+            180,
+        )
+        .unwrap()
+        .with_grid_cols(2);
+        let frame = generator.draw_frame(&sensors);
+        assert!(frame
+            .enumerate_pixels()
+            .any(|(x, _, p)| x < 160 && p[0] > 0));
+        assert!(frame
+            .enumerate_pixels()
+            .all(|(x, _, p)| x < 160 || p[0] == 0));
+    }
+
+    #[test]
+    fn test_long_values_are_truncated() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec!["Log: {sensor.log}".to_string()];
+        let generator = ImageGenerator::new(font_data, lines, 48.0, "en_US", 320, 180)
+            .unwrap()
+            .with_max_line_length(16);
+
+        let mut sensors = HashMap::new();
+        sensors.insert("sensor.log".to_string(), "é".repeat(10_000));
+        let line = generator.resolve_line(&generator.lines[0], &sensors);
+        assert_eq!(line, format!("Log: {}…", "é".repeat(11)));
+
+        sensors.insert("sensor.log".to_string(), "short".to_string());
+        assert_eq!(
+            generator.resolve_line(&generator.lines[0], &sensors),
+            "Log: short"
+        );
+
+        // The default limit still renders a frame quickly
+        let generator =
+            ImageGenerator::new(font_data, generator.lines.clone(), 48.0, "en_US", 320, 180)
+                .unwrap();
+        sensors.insert("sensor.log".to_string(), "x".repeat(10_000));
+        assert_eq!(
+            generator
+                .resolve_line(&generator.lines[0], &sensors)
+                .chars()
+                .count(),
+            513
+        );
+        generator.draw_frame(&sensors);
+    }
+
+    #[test]
+    fn test_colored_lines_are_truncated_as_a_whole() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec!["Temp {sensor.temp:color=#ff0000} and {sensor.log}".to_string()];
+        let generator = ImageGenerator::new(font_data, lines, 48.0, "en_US", 320, 180)
+            .unwrap()
+            .with_max_line_length(13);
+
+        let white = Rgb([255, 255, 255]);
+        let red = Rgb([255, 0, 0]);
+        let mut sensors = HashMap::new();
+        sensors.insert("sensor.temp".to_string(), "21".to_string());
+        sensors.insert("sensor.log".to_string(), "x".repeat(100));
+
+        // Each slice fits on its own, but the line is cut once, in the last span it reaches
+        assert_eq!(
+            generator.text_spans(&generator.lines[0], &sensors, white),
+            vec![
+                ("Temp ".to_string(), white),
+                ("21".to_string(), red),
+                (" and x…".to_string(), white),
+            ]
+        );
+        assert_eq!(
+            *generator.truncated_lines.lock().unwrap(),
+            HashSet::from([generator.lines[0].clone()])
+        );
+    }
+
+    #[test]
+    fn test_monochrome_frame() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec!["Hello".to_string()];
+        let generator = ImageGenerator::new(font_data, lines, 48.0, "en_US", 320, 180)
+            .unwrap()
+            .with_colors(Rgb([255, 200, 0]), Rgb([40, 40, 40]))
+            .with_monochrome(Some(Monochrome::Threshold));
+
+        let frame = generator.draw_frame(&HashMap::new());
+        assert!(frame.iter().all(|&c| c == 0 || c == 255));
+        assert!(frame.contains(&255));
+
+        let png = generator.generate_png(&HashMap::new()).unwrap();
+        let decoded = image::load_from_memory(&png).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::L8);
+        assert_eq!(decoded.to_rgb8(), frame);
+    }
+
+    #[test]
+    fn test_logo() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let path =
+            std::env::temp_dir().join(format!("ha-streamer-logo-{}.png", std::process::id()));
+        RgbaImage::from_pixel(20, 10, image::Rgba([255, 0, 0, 255]))
+            .save(&path)
+            .unwrap();
+        let logo = Logo {
+            path: path.to_string_lossy().into_owned(),
+            position: LogoPosition::BottomRight,
+            scale: 0.5,
+            margin: 4,
+        };
+
+        let generator = ImageGenerator::new(font_data, vec![], 48.0, "en_US", 100, 50)
+            .unwrap()
+            .with_logo(&logo);
+        let frame = generator.draw_frame(&HashMap::new());
+        // Scaled to 10x5 and placed 4px from the bottom right corner
+        assert_eq!(*frame.get_pixel(86, 41), Rgb([255, 0, 0]));
+        assert_eq!(*frame.get_pixel(95, 45), Rgb([255, 0, 0]));
+        assert_eq!(*frame.get_pixel(96, 45), Rgb([0, 0, 0]));
+        assert_eq!(*frame.get_pixel(85, 41), Rgb([0, 0, 0]));
+        std::fs::remove_file(&path).unwrap();
+
+        // A missing file leaves the frame without a logo
+        let generator = ImageGenerator::new(font_data, vec![], 48.0, "en_US", 100, 50)
+            .unwrap()
+            .with_logo(&logo);
+        assert!(generator.logo.is_none());
+    }
+
+    #[test]
+    fn test_layers() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let path =
+            std::env::temp_dir().join(format!("ha-streamer-layers-{}.png", std::process::id()));
+        RgbaImage::from_pixel(320, 180, image::Rgba([255, 0, 0, 255]))
+            .save(&path)
+            .unwrap();
+        let logo = Logo {
+            path: path.to_string_lossy().into_owned(),
+            position: LogoPosition::TopLeft,
+            scale: 1.0,
+            margin: 0,
+        };
+        // A QR code and green text stacked at the same spot, over a logo filling the frame
+        let lines = vec![
+            "{qr:https://example.com|size=160}".to_string(),
+            "{sensor.temp:color=#00ff00}".to_string(),
+        ];
+        let centered = |layer| LineOptions {
+            y: Some(LinePosition::Percent(50.0)),
+            layer,
+            ..Default::default()
+        };
+        let mut sensors = HashMap::new();
+        sensors.insert("sensor.temp".to_string(), "88".to_string());
+        let render = |qr_layer| {
+            ImageGenerator::new(font_data, lines.clone(), 32.0, "en_US", 320, 180)
+                .unwrap()
+                .with_logo(&logo)
+                .with_line_options(vec![centered(qr_layer), centered(None)])
+                .draw_frame(&sensors)
+        };
+        let green = |frame: &RgbImage| frame.pixels().any(|p| *p == Rgb([0, 255, 0]));
+
+        // By default text is drawn over widgets, which are drawn over the logo
+        let frame = render(None);
+        assert!(green(&frame));
+        assert_eq!(*frame.get_pixel(5, 5), Rgb([255, 0, 0]));
+        assert_ne!(*frame.get_pixel(160, 90), Rgb([255, 0, 0]));
+
+        // Raising the QR code above the text hides the text behind it
+        let frame = render(Some(2));
+        assert!(!green(&frame));
+        assert_eq!(*frame.get_pixel(5, 5), Rgb([255, 0, 0]));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_time_tokens_with_fixed_clock() {
+        use chrono::TimeZone;
+
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let now = Local.with_ymd_and_hms(2024, 3, 9, 7, 5, 30).unwrap();
+        let generator = ImageGenerator::new(font_data, vec![], 48.0, "en_US", 320, 180)
+            .unwrap()
+            .with_clock(move || now);
+
+        let mut sensors = HashMap::new();
+        assert_eq!(generator.resolve_line("{time:%H:%M}", &sensors), "07:05");
+        assert_eq!(
+            generator.resolve_line("{time:%Y-%m-%d} {time:%S}s", &sensors),
+            "2024-03-09 30s"
+        );
+
+        let earlier = now - chrono::Duration::minutes(3);
+        sensors.insert("sensor.last_seen".to_string(), earlier.to_rfc3339());
+        assert_eq!(
+            generator.resolve_line("{sensor.last_seen:relative}", &sensors),
+            "3 minutes ago"
+        );
+    }
+
+    #[test]
+    fn test_time_tokens_in_other_zones() {
+        use chrono::{TimeZone, Utc};
+
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let now = Utc
+            .with_ymd_and_hms(2024, 3, 9, 12, 5, 0)
+            .unwrap()
+            .with_timezone(&Local);
+        let generator = ImageGenerator::new(font_data, vec![], 48.0, "en_US", 320, 180)
+            .unwrap()
+            .with_clock(move || now);
+        let sensors = HashMap::new();
+
+        assert_eq!(
+            generator.resolve_line(
+                "NY {time:%H:%M@America/New_York} TYO {time:%H:%M@Asia/Tokyo}",
+                &sensors
+            ),
+            "NY 07:05 TYO 21:05"
+        );
+        assert_eq!(
+            generator.resolve_line("{time:%H:%M@UTC}", &sensors),
+            "12:05"
+        );
+
+        // Unknown zones and plain `@` text use local time
+        let local = now.format("%H:%M").to_string();
+        assert_eq!(
+            generator.resolve_line("{time:%H:%M@Mars/Olympus}", &sensors),
+            local
+        );
+        assert_eq!(
+            generator.resolve_line("{time:%H:%M @ home}", &sensors),
+            format!("{} @ home", local)
+        );
+    }
+
+    #[test]
+    fn test_render_resolution_is_scaled_to_output() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec!["22.5".to_string()];
+        let generator = ImageGenerator::new(font_data, lines, 96.0, "en_US", 256, 128)
+            .unwrap()
+            .with_output_size(64, 32);
+        let sensors = HashMap::new();
+
+        let raw = generator.generate_raw_frame(&sensors);
+        assert_eq!(raw.len(), 64 * 32 * 3);
+        let jpeg = image::load_from_memory(&generator.generate_frame(&sensors).unwrap()).unwrap();
+        assert_eq!((jpeg.width(), jpeg.height()), (64, 32));
+
+        // The text keeps its layout, a quarter of the size
+        let lit = |x: u32, y: u32| raw[((y * 64 + x) * 3) as usize] > 128;
+        assert!((0..64).any(|x| lit(x, 16)));
+        assert!(!(0..64).any(|x| lit(x, 0)));
+    }
+
+    #[test]
+    fn test_background_gradient_fill() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let generator = |vertical: bool| {
+            ImageGenerator::new(font_data, vec![], 24.0, "en_US", 11, 21)
+                .unwrap()
+                .with_background_gradient(Some(BackgroundGradient {
+                    from: Rgb([0, 100, 200]),
+                    to: Rgb([0, 0, 0]),
+                    vertical,
+                }))
+        };
+
+        let image = generator(true).draw_frame(&HashMap::new());
+        assert_eq!(*image.get_pixel(0, 0), Rgb([0, 100, 200]));
+        assert_eq!(*image.get_pixel(10, 0), Rgb([0, 100, 200]));
+        assert_eq!(*image.get_pixel(5, 10), Rgb([0, 50, 100]));
+        assert_eq!(*image.get_pixel(5, 20), Rgb([0, 0, 0]));
+
+        let image = generator(false).draw_frame(&HashMap::new());
+        assert_eq!(*image.get_pixel(0, 20), Rgb([0, 100, 200]));
+        assert_eq!(*image.get_pixel(5, 0), Rgb([0, 50, 100]));
+        assert_eq!(*image.get_pixel(10, 10), Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn test_disconnect_alert_pulses_background() {
+        use chrono::TimeZone;
+
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let at = |millis: i64| {
+            let now = Local.with_ymd_and_hms(2024, 3, 9, 7, 5, 30).unwrap()
+                + chrono::Duration::milliseconds(millis);
+            ImageGenerator::new(font_data, vec![], 48.0, "en_US", 32, 32)
+                .unwrap()
+                .with_clock(move || now)
+        };
+        let mut sensors = HashMap::new();
+        let corner = |generator: &ImageGenerator, sensors: &HashMap<String, String>| {
+            generator.generate_raw_frame(sensors)[..3].to_vec()
+        };
+
+        // Normal rendering without the alert
+        assert_eq!(corner(&at(500), &sensors), vec![0, 0, 0]);
+
+        sensors.insert(ALERT_KEY.to_string(), "on".to_string());
+        assert_eq!(corner(&at(0), &sensors), vec![0, 0, 0]);
+        assert_eq!(corner(&at(500), &sensors), vec![220, 0, 0]);
+        assert_eq!(corner(&at(250), &sensors), vec![110, 0, 0]);
+    }
+
+    #[test]
+    fn test_subpixel_text_option() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let line = "T {sensor.a:color=red}{sensor.b:color=yellow}".to_string();
+        let generator = |subpixel: bool| {
+            ImageGenerator::new(font_data, vec![line.clone()], 11.0, "en_US", 64, 16)
+                .unwrap()
+                .with_subpixel_text(subpixel)
+        };
+        let mut sensors = HashMap::new();
+        sensors.insert("sensor.a".to_string(), "21".to_string());
+        sensors.insert("sensor.b".to_string(), ".5".to_string());
+
+        let whole = generator(false).generate_raw_frame(&sensors);
+        let subpixel = generator(true).generate_raw_frame(&sensors);
+        assert_ne!(whole, subpixel);
+    }
+
+    #[test]
+    fn test_mixed_line_sizes_do_not_overlap() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec![
+            "{sensor.title:color=#ff0000}".to_string(),
+            "{sensor.body:color=#00ff00}".to_string(),
+        ];
+        let generator = ImageGenerator::new(font_data, lines, 36.0, "en_US", 400, 200)
+            .unwrap()
+            .with_line_options(vec![
+                LineOptions {
+                    font_size: Some(72.0),
+                    ..Default::default()
+                },
+                LineOptions::default(),
+            ]);
+        let mut sensors = HashMap::new();
+        // Accents and descenders reach the edges of each line
+        sensors.insert("sensor.title".to_string(), "Ågjy".to_string());
+        sensors.insert("sensor.body".to_string(), "ÅÉgjy".to_string());
+
+        let image = generator.draw_frame(&sensors);
+        let rows = |channel: usize| -> Vec<u32> {
+            (0..200)
+                .filter(|&y| (0..400).any(|x| image.get_pixel(x, y)[channel] > 0))
+                .collect()
+        };
+        let (title, body) = (rows(0), rows(1));
+
+        assert!(!title.is_empty() && !body.is_empty());
+        assert!(title.last() < body.first());
+        // Both lines fit inside the frame
+        assert!(title[0] > 0 && *body.last().unwrap() < 199);
+    }
+
+    #[test]
+    fn test_line_outline_overrides() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let red = Rgb([255, 0, 0]);
+        let lines = vec!["I".to_string(), "I".to_string()];
+        let generator = |outline: bool, overrides: [Option<bool>; 2]| {
+            ImageGenerator::new(font_data, lines.clone(), 24.0, "en_US", 40, 80)
+                .unwrap()
+                .with_colors(Rgb([255, 255, 255]), Rgb([0, 0, 128]))
+                .with_outline(outline, red)
+                .with_line_options(
+                    overrides
+                        .iter()
+                        .map(|&outline| LineOptions {
+                            outline,
+                            ..Default::default()
+                        })
+                        .collect(),
+                )
+        };
+        // Rows of the top and bottom half with any red outline pixels
+        let outlined = |generator: ImageGenerator| {
+            let image = generator.draw_frame(&HashMap::new());
+            let has_red = |rows: std::ops::Range<u32>| {
+                rows.flat_map(|y| (0..40).map(move |x| (x, y)))
+                    .any(|(x, y)| image.get_pixel(x, y)[0] > 128 && image.get_pixel(x, y)[1] < 64)
+            };
+            (has_red(0..40), has_red(40..80))
+        };
+
+        assert_eq!(outlined(generator(false, [None, None])), (false, false));
+        assert_eq!(outlined(generator(true, [None, None])), (true, true));
+        assert_eq!(
+            outlined(generator(true, [None, Some(false)])),
+            (true, false)
+        );
+        assert_eq!(
+            outlined(generator(false, [Some(true), None])),
+            (true, false)
+        );
+    }
+
+    #[test]
+    fn test_grouped_numbers_and_raw_tokens() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let generator =
+            |locale: &str| ImageGenerator::new(font_data, vec![], 24.0, locale, 96, 32).unwrap();
+        let mut sensors = HashMap::new();
+        sensors.insert("sensor.meter".to_string(), "1.234.567".to_string());
+        sensors.insert("sensor.temp".to_string(), "22,5".to_string());
+        sensors.insert("sensor.build".to_string(), "2.1".to_string());
+
+        let us = generator("en_US");
+        assert_eq!(us.resolve_line("{sensor.meter}", &sensors), "1,234,567");
+        assert_eq!(us.resolve_line("{sensor.temp}", &sensors), "22,5");
+        let de = generator("de_DE");
+        assert_eq!(de.resolve_line("{sensor.meter}", &sensors), "1.234.567");
+        assert_eq!(de.resolve_line("{sensor.temp}", &sensors), "22,5");
+        assert_eq!(de.resolve_line("v{sensor.build}", &sensors), "v2,1");
+
+        // `raw` shows the state exactly as Home Assistant sent it
+        assert_eq!(de.resolve_line("v{sensor.build:raw}", &sensors), "v2.1");
+        assert_eq!(us.resolve_line("{sensor.meter:raw}", &sensors), "1.234.567");
+    }
+
+    #[test]
+    fn test_expr_tokens() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let generator = ImageGenerator::new(font_data, vec![], 24.0, "de_DE", 96, 32).unwrap();
+        let mut sensors = HashMap::new();
+        sensors.insert("sensor.power_a".to_string(), "120.25".to_string());
+        sensors.insert("sensor.power_b".to_string(), "80".to_string());
+        sensors.insert("sensor.mode".to_string(), "eco".to_string());
+
+        let line = |template| generator.resolve_line(template, &sensors);
+        assert_eq!(
+            line("Sum {expr: sensor.power_a + sensor.power_b} W"),
+            "Sum 200,25 W"
+        );
+        assert_eq!(line("{expr:sensor.power_b / 3}"), "26,67");
+        assert_eq!(line("{expr:(sensor.power_b - 80) * -1}"), "0");
+        assert_eq!(line("{expr:sensor.power_a / (sensor.power_b - 80)}"), "?");
+        assert_eq!(line("{expr:sensor.power_a + sensor.mode}"), "?");
+    }
+
+    #[test]
+    fn test_non_finite_values_show_placeholder() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let generator = ImageGenerator::new(font_data, vec![], 24.0, "de_DE", 96, 32).unwrap();
+
+        let mut sensors = HashMap::new();
+        for raw in ["inf", "NaN", "-inf", " infinity"] {
+            sensors.insert("sensor.temp".to_string(), raw.to_string());
+            assert_eq!(generator.resolve_line("T {sensor.temp}", &sensors), "T ?");
+            assert_eq!(
+                generator.resolve_line("{sensor.temp:percent}", &sensors),
+                "?"
+            );
+        }
+
+        sensors.insert("sensor.temp".to_string(), "21.5".to_string());
+        assert_eq!(
+            generator.resolve_line("T {sensor.temp}", &sensors),
+            "T 21,5"
+        );
+    }
+
+    #[test]
+    fn test_decomposed_text_is_normalized() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let render = |line: &str| {
+            let generator =
+                ImageGenerator::new(font_data, vec![line.to_string()], 24.0, "en_US", 96, 32)
+                    .unwrap();
+            let mut sensors = HashMap::new();
+            // "é" as "e" followed by a combining acute accent
+            sensors.insert("sensor.name".to_string(), "Caf\u{65}\u{301}".to_string());
+            (
+                generator.resolve_line(line, &sensors),
+                generator.generate_raw_frame(&sensors),
+            )
+        };
+
+        let (text, frame) = render("{sensor.name}");
+        assert_eq!(text, "Caf\u{e9}");
+        assert_eq!((text, frame), render("Caf\u{e9}"));
+    }
+
+    #[test]
+    fn test_smart_format() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let generator = ImageGenerator::new(font_data, vec![], 48.0, "en_US", 320, 180)
+            .unwrap()
+            .with_smart_format(true);
+
+        let mut sensors = HashMap::new();
+        sensors.insert("sensor.temp".to_string(), "21.46".to_string());
+        sensors.insert(
+            history_key("sensor.temp", "device_class"),
+            "temperature".to_string(),
+        );
+        sensors.insert(history_key("sensor.temp", "unit"), "°C".to_string());
+        sensors.insert("sensor.mode".to_string(), "eco".to_string());
+
+        assert_eq!(
+            generator.resolve_line("Out {sensor.temp}", &sensors),
+            "Out 21.5 °C"
+        );
+        // Explicit modifiers and entities without a device class are left alone
+        assert_eq!(
+            generator.resolve_line("{sensor.temp:percent}", &sensors),
+            "21.46%"
+        );
+        assert_eq!(generator.resolve_line("{sensor.mode}", &sensors), "eco");
+    }
+
+    #[test]
+    fn test_emoji_modifier() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let generator = ImageGenerator::new(font_data, vec![], 48.0, "sv_SE", 320, 180).unwrap();
+        let template = "{sensor.temp:emoji=<10:🥶,<28:😊,else:🥵} {sensor.temp}°";
+
+        let mut sensors = HashMap::new();
+        for (value, expected) in [
+            ("4.5", "🥶 4,5°"),
+            ("21", "😊 21°"),
+            ("31.2", "🥵 31,2°"),
+            ("unknown", "🥵 unknown°"),
+        ] {
+            sensors.insert("sensor.temp".to_string(), value.to_string());
+            assert_eq!(generator.resolve_line(template, &sensors), expected);
+        }
+
+        // Without `else`, values matching no rule show nothing
+        assert_eq!(
+            generator.resolve_line("[{sensor.temp:emoji=<10:🥶}]", &sensors),
+            "[]"
+        );
+    }
+
+    #[test]
+    fn test_pad_modifier() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let generator = ImageGenerator::new(font_data, vec![], 48.0, "sv_SE", 320, 180).unwrap();
+        let template = "[{sensor.temp:pad=6}] [{sensor.temp:pad=-6}]";
+
+        let mut sensors = HashMap::new();
+        let mut widths = Vec::new();
+        for (value, expected) in [
+            ("9.5", "[   9,5] [9,5   ]"),
+            ("-12.3", "[ -12,3] [-12,3 ]"),
+            ("100", "[   100] [100   ]"),
+        ] {
+            sensors.insert("sensor.temp".to_string(), value.to_string());
+            let line = generator.resolve_line(template, &sensors);
+            assert_eq!(line, expected);
+            widths.push(line.chars().count());
+        }
+        assert!(widths.iter().all(|&width| width == widths[0]));
+
+        // Missing values and other modifiers are padded as displayed
+        sensors.clear();
+        assert_eq!(
+            generator.resolve_line("{sensor.temp:pad=3}", &sensors),
+            "  ?"
+        );
+        sensors.insert("sensor.hum".to_string(), "45.5".to_string());
+        assert_eq!(
+            generator.resolve_line("{sensor.hum:percent:pad=8}", &sensors),
+            "  45,5 %"
+        );
+    }
+
+    #[test]
+    fn test_resolve_line_locale() {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec![];
+
+        // Test US Locale (Dot)
+        let gen_us =
+            ImageGenerator::new(font_data, lines.clone(), 48.0, "en_US", 640, 360).unwrap();
+        let mut sensors = HashMap::new();
+        sensors.insert("sensor.temp".to_string(), "22.5".to_string());
+        // Updated template to just entity ID
+        assert_eq!(gen_us.resolve_line("{sensor.temp}", &sensors), "22.5");
+
+        // Test SV Locale (Comma)
+        let gen_sv =
+            ImageGenerator::new(font_data, lines.clone(), 48.0, "sv_SE", 640, 360).unwrap();
+        // Updated template to just entity ID
+        assert_eq!(gen_sv.resolve_line("{sensor.temp}", &sensors), "22,5");
+
+        // Test Non-numeric
+        sensors.insert("sensor.state".to_string(), "on".to_string());
+        assert_eq!(gen_sv.resolve_line("{sensor.state}", &sensors), "on");
+
+        // Test IP (multiple dots, parses as float? "1.2.3.4" -> No)
+        sensors.insert("sensor.ip".to_string(), "192.168.1.1".to_string());
+        assert_eq!(gen_sv.resolve_line("{sensor.ip}", &sensors), "192.168.1.1");
+
+        // Relative time modifier
+        let hour_ago = (Local::now() - chrono::Duration::hours(2)).to_rfc3339();
+        sensors.insert("sensor.last_seen".to_string(), hour_ago);
+        assert_eq!(
+            gen_sv.resolve_line("Seen {sensor.last_seen:relative}", &sensors),
+            "Seen 2 hours ago"
+        );
+        sensors.insert("sensor.bad_ts".to_string(), "unknown".to_string());
+        assert_eq!(
+            gen_sv.resolve_line("{sensor.bad_ts:relative}", &sensors),
+            "?"
+        );
+
+        // Daily extremes are looked up from values written by the poller
+        sensors.insert("sensor.temp:max_today".to_string(), "25.5".to_string());
+        assert_eq!(
+            gen_sv.resolve_line("{sensor.temp:max_today} {sensor.temp:min_today}", &sensors),
+            "25,5 ?"
+        );
+
+        // Averages are looked up from values written by the poller
+        sensors.insert("sensor.temp:avg=60s".to_string(), "21.7".to_string());
+        assert_eq!(
+            gen_sv.resolve_line("{sensor.temp:avg=60s}", &sensors),
+            "21,7"
+        );
+        assert_eq!(gen_sv.resolve_line("{sensor.temp:avg=5m}", &sensors), "?");
+
+        // Locale-aware money and percentages
+        sensors.insert("sensor.cost".to_string(), "1234.5".to_string());
+        assert_eq!(
+            gen_us.resolve_line("{sensor.cost:currency=USD}", &sensors),
+            "$1,234.50"
+        );
+        assert_eq!(
+            gen_sv.resolve_line("{sensor.cost:currency=EUR}", &sensors),
+            "1 234,50 €"
+        );
+        assert_eq!(
+            gen_sv.resolve_line("{sensor.state:currency=EUR}", &sensors),
+            "on"
+        );
+
+        // Regex replacements run before modifiers
+        let gen_replace = ImageGenerator::new(font_data, lines.clone(), 48.0, "en_US", 640, 360)
+            .unwrap()
+            .with_value_replacements(HashMap::from([(
+                "sensor.player".to_string(),
+                vec![ValueReplacement {
+                    pattern: Regex::new(r"\s*\(.*\)").unwrap(),
+                    replacement: String::new(),
+                }],
+            )]));
+        sensors.insert("sensor.player".to_string(), "Playing (Spotify)".to_string());
+        assert_eq!(
+            gen_replace.resolve_line("{sensor.player}", &sensors),
+            "Playing"
+        );
+
+        // Connection health summary
+        assert_eq!(gen_sv.resolve_line("{status}", &sensors), "HA ?");
+        sensors.insert(STATUS_KEY.to_string(), "HA OK (2/2)".to_string());
+        assert_eq!(gen_sv.resolve_line("{status}", &sensors), "HA OK (2/2)");
 
         // Test simple version number "1.2" parses as float -> "1,2".
         // This is a trade-off. "Version 1.2" might become "Version 1,2".
@@ -1,29 +1,73 @@
-use axum::{body::Body, extract::State, response::Response, routing::get, Router};
-use std::{
-    collections::HashMap,
-    net::SocketAddr,
-    sync::{Arc, RwLock},
-    time::Duration,
+use anyhow::Context;
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::get,
+    Json, Router,
 };
-use tokio::time::sleep;
+use axum_server::tls_rustls::RustlsConfig;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures_core::Stream;
+use std::{fmt::Write, net::SocketAddr, path::Path, sync::Arc, time::Duration};
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower_http::{
+    compression::CompressionLayer, limit::RequestBodyLimitLayer, timeout::TimeoutLayer,
+};
+
+mod bidi;
+
+mod check;
 
 mod config;
 
+mod demo;
+
+mod expr;
+
 mod ha_client;
 
 mod image_gen;
 
+mod modifiers;
+
+mod monochrome;
+
+mod output;
+
+mod poller;
+
+mod render;
+
+mod renderer;
+
 mod rtsp;
 
+mod smoothing;
+
 mod state;
 
-use config::Config;
+mod transform;
+
+mod widgets;
+
+use config::{Config, TlsFiles};
 
 use ha_client::HaClient;
 
-use image_gen::ImageGenerator;
+use image_gen::{EncodeError, ImageGenerator};
 
-use state::AppState;
+use poller::Poller;
+
+use renderer::FrameFormat;
+
+use state::{history_key, AppState};
+
+/// Limits for the non-streaming HTTP routes.
+const MAX_REQUEST_BODY_BYTES: usize = 16 * 1024;
+const MAX_CONCURRENT_REQUESTS: usize = 32;
 
 #[tokio::main]
 
@@ -32,13 +76,38 @@ async fn main() -> anyhow::Result<()> {
 
     // env_logger::init(); // Skipped for simplicity, can add later
 
+    config::load_config_document()?;
     let config = Config::from_env()?;
 
-    println!("Starting ha-sensor-streamer...");
+    // `render` draws a single frame to a PNG and exits
+    if let Some(args) = render::parse_args(std::env::args().skip(1)) {
+        let image_gen = Arc::new(build_image_generator(&config)?);
+        return render::run(&config, image_gen, &args?).await;
+    }
+    // `RENDER_FRAMES=N` renders N frames, reports the timing and exits
+    if let Some(count) = config.render_frames {
+        let image_gen = Arc::new(build_image_generator(&config)?);
+        return render::run_frames(&config, image_gen, count).await;
+    }
+    // `check` fetches each watched entity once, prints the results and exits
+    if check::requested(std::env::args().skip(1)) {
+        let all_ok = check::run(&config, &HaClient::new(&config)).await?;
+        std::process::exit(if all_ok { 0 } else { 1 });
+    }
+
+    println!(
+        "Starting ha-sensor-streamer {} ({})...",
+        env!("CARGO_PKG_VERSION"),
+        env!("GIT_HASH")
+    );
 
     println!("Mode: {}", config.stream_format);
 
-    println!("Connecting to Home Assistant at {}", config.ha_base_url);
+    if config.demo {
+        println!("DEMO MODE: Home Assistant is not contacted, showing synthetic sensor values");
+    } else {
+        println!("Connecting to Home Assistant at {}", config.ha_base_url);
+    }
 
     let sensors_to_watch = config.get_required_sensors();
     if sensors_to_watch.is_empty() {
@@ -47,60 +116,44 @@ async fn main() -> anyhow::Result<()> {
         println!("Watching sensors: {:?}", sensors_to_watch);
     }
 
-    // Shared state for the latest sensor values.
-    let sensor_values = Arc::new(RwLock::new(HashMap::new()));
-
     // Initialize components
 
     let ha_client = HaClient::new(&config);
 
-    // Embed font at compile time
-
-    let font_data = include_bytes!("../assets/Lato-Regular.ttf");
-
-    let image_gen = Arc::new(ImageGenerator::new(
-        font_data,
-        config.lines.clone(),
-        config.font_size,
-        &config.locale,
-        config.video_width,
-        config.video_height,
-    )?);
+    let image_gen = Arc::new(build_image_generator(&config)?);
+
+    // Shared state for the latest sensor values and their fetch health.
+    let mut app_state = AppState::new(config.clone(), image_gen);
+    if config.shared_renderer {
+        let format = if config.stream_format == "rtsp" {
+            FrameFormat::Raw
+        } else {
+            FrameFormat::Jpeg
+        };
+        app_state.latest_frame = Some(renderer::spawn(&app_state, format)?);
+    }
 
     // 1. Spawn Background Polling Task
 
-    let sensor_values_clone = sensor_values.clone();
-    let sensors_list = sensors_to_watch.clone();
-    let ha_client_clone = ha_client.clone();
-
-    if !sensors_list.is_empty() {
-        tokio::spawn(async move {
-            loop {
-                for entity_id in &sensors_list {
-                    match ha_client_clone.fetch_sensor_state(entity_id).await {
-                        Ok(val) => {
-                            if let Ok(mut lock) = sensor_values_clone.write() {
-                                lock.insert(entity_id.clone(), val);
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("Error fetching sensor state for {}: {}", entity_id, e);
-                            // Optionally update state to "Error" or keep old value
-                        }
-                    }
-                }
-                sleep(Duration::from_secs(10)).await; // Poll every 10 seconds
-            }
-        });
+    if config.demo {
+        tokio::spawn(demo::run(app_state.clone()));
+    } else if !sensors_to_watch.is_empty() {
+        let poller = Poller::new(&config, ha_client.clone(), app_state.clone());
+        tokio::spawn(poller.run());
     }
 
-    let app_state = AppState {
-        sensor_values,
-
-        image_gen,
-
-        config: config.clone(),
-    };
+    // Optional file or pipe output, next to whichever stream server runs below.
+    // On `docker stop` the frame being written is finished before exiting.
+    if let Some(output) = config.output.clone() {
+        if let Some(handle) = output::spawn(output, app_state.clone()) {
+            tokio::spawn(async move {
+                shutdown_signal().await;
+                println!("Shutting down, finishing the frame output...");
+                let _ = tokio::task::spawn_blocking(move || handle.stop()).await;
+                std::process::exit(0);
+            });
+        }
+    }
 
     if config.stream_format == "rtsp" {
         // Run RTSP Server (Blocking)
@@ -113,128 +166,654 @@ async fn main() -> anyhow::Result<()> {
 
         // rtsp::run_rtsp_server blocks.
 
-        tokio::task::spawn_blocking(move || {
-            if let Err(e) = rtsp::run_rtsp_server(&config, app_state) {
-                eprintln!("RTSP Server error: {}", e);
-            }
-        })
-        .await?;
+        // Errors are returned from main so the process exits non-zero and the
+        // container gets restarted.
+        if let Some(port) = config.snapshot_port {
+            let addr = SocketAddr::from(([0, 0, 0, 0], port));
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("Failed to listen on SNAPSHOT_PORT {}", port))?;
+            println!("Snapshots available at http://{}/snapshot", addr);
+            let app = snapshot_app(&config, app_state.clone());
+            tokio::spawn(async move {
+                if let Err(e) = axum::serve(listener, app).await {
+                    eprintln!("Snapshot server error: {}", e);
+                }
+            });
+        }
+
+        tokio::task::spawn_blocking(move || rtsp::run_rtsp_server(&config, app_state))
+            .await?
+            .context("RTSP Server error")?;
     } else {
         // Run MJPEG Server (Axum)
 
+        // Only the non-streaming routes are compressed and time limited; MJPEG frames are
+        // already compressed, must reach the client as soon as they are produced, and the
+        // stream intentionally runs forever.
+        let mut limited = Router::new()
+            .route("/", get(|| async { Redirect::temporary("/preview") }))
+            .route("/favicon.ico", get(favicon))
+            .route("/snapshot", get(snapshot))
+            .route("/snapshot.jpg", get(snapshot))
+            .route("/snapshot.png", get(snapshot_png))
+            .route("/preview", get(preview_page))
+            .route("/version", get(version_info));
+        if config.debug_endpoints {
+            limited = limited.route("/debug/frame", get(debug_frame));
+        }
+        let limited = limited
+            .layer(CompressionLayer::new())
+            .layer(RequestBodyLimitLayer::new(MAX_REQUEST_BODY_BYTES))
+            .layer(TimeoutLayer::new(Duration::from_secs(
+                config.http_timeout_secs,
+            )))
+            .layer(GlobalConcurrencyLimitLayer::new(MAX_CONCURRENT_REQUESTS));
+
         let app = Router::new()
             .route("/stream", get(mjpeg_stream))
+            .route("/frame", get(long_poll_frame))
+            .merge(limited)
             .with_state(app_state);
 
         let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
+        let scheme = if config.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        };
 
-        println!("MJPEG Server listening on http://{}", addr);
+        println!("MJPEG Server listening on {}://{}", scheme, addr);
+        println!("Preview page available at {}://{}/preview", scheme, addr);
 
-        let listener = tokio::net::TcpListener::bind(addr).await?;
+        match &config.tls {
+            Some(tls) => serve_tls(app, addr, tls).await?,
+            None => {
+                let listener = tokio::net::TcpListener::bind(addr).await?;
 
-        axum::serve(listener, app).await?;
+                axum::serve(listener, app).await?;
+            }
+        }
     }
 
     Ok(())
 }
 
-// MJPEG Stream Handler
-
-async fn mjpeg_stream(State(state): State<AppState>) -> Response {
-    let fps = state.config.video_fps;
-
-    let stream = async_stream::stream! {
-
-        let mut interval = tokio::time::interval(Duration::from_millis(1000 / fps));
-
-
-
-                loop {
-
-
-
-                    interval.tick().await;
-
-
+/// The still frame routes alone, served on `SNAPSHOT_PORT` next to the RTSP
+/// stream for NVRs that fetch a snapshot URL even for RTSP cameras.
+fn snapshot_app(config: &Config, app_state: AppState) -> Router {
+    Router::new()
+        .route("/snapshot", get(snapshot))
+        .route("/snapshot.jpg", get(snapshot))
+        .route("/snapshot.png", get(snapshot_png))
+        .layer(TimeoutLayer::new(Duration::from_secs(
+            config.http_timeout_secs,
+        )))
+        .layer(GlobalConcurrencyLimitLayer::new(MAX_CONCURRENT_REQUESTS))
+        .with_state(app_state)
+}
 
+/// Resolves on Ctrl+C or SIGTERM, which `docker stop` sends.
+async fn shutdown_signal() {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("Failed to install the SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
 
+/// Serves `app` over HTTPS with the `TLS_CERT_PATH` / `TLS_KEY_PATH` files.
+/// Clients may negotiate HTTP/2 or HTTP/1.1; `/stream` is an ordinary streaming
+/// response on both.
+async fn serve_tls(app: Router, addr: SocketAddr, tls: &TlsFiles) -> anyhow::Result<()> {
+    // Only the ring provider is built in, install it before rustls looks for one
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let rustls_config = RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to load the TLS certificate {} and key {}",
+                tls.cert_path, tls.key_path
+            )
+        })?;
+
+    axum_server::bind_rustls(addr, rustls_config)
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
 
+/// Builds the renderer from the configuration.
+fn build_image_generator(config: &Config) -> anyhow::Result<ImageGenerator> {
+    // Embed font at compile time
 
+    let font_data = include_bytes!("../assets/Lato-Regular.ttf");
 
-                    // Get current sensor values
+    // Sizes and positions are in render pixels; the frame is scaled to the video size
+    let (render_width, render_height) = config
+        .render_resolution
+        .unwrap_or((config.video_width, config.video_height));
+    let mut image_gen = ImageGenerator::new(
+        font_data,
+        config.lines.clone(),
+        config.font_size,
+        &config.locale,
+        render_width,
+        render_height,
+    )?
+    .with_output_size(config.video_width, config.video_height)
+    .with_jpeg_subsampling(config.jpeg_subsampling)
+    .with_jpeg_restart_interval(config.jpeg_restart_interval)
+    .with_line_options(config.line_options.clone())
+    .with_brightness(config.brightness)
+    .with_ticker_speed(config.ticker_px_per_sec)
+    .with_max_line_length(config.max_line_length)
+    .with_smart_format(config.smart_format)
+    .with_subpixel_text(config.subpixel_text)
+    .with_widget_supersampling(config.widget_supersampling)
+    .with_colors(config.text_color, config.background_color)
+    .with_background_gradient(config.background_gradient)
+    .with_outline(config.text_outline, config.outline_color)
+    .with_empty_frame_text(config.empty_frame_text.clone())
+    .with_trace_frames(config.trace_frames)
+    .with_grid_cols(config.grid_cols)
+    .with_border(config.border_width, config.border_color)
+    .with_anti_burnin(config.anti_burnin)
+    .with_idle_animation(config.idle_animation)
+    .with_monochrome(config.monochrome)
+    .with_value_replacements(config.sensor_replacements.clone())
+    .with_palette(&config.palette);
+
+    // A broken FONT_PATH only logs a warning and keeps the embedded font
+    if let Some(font_path) = &config.font_path {
+        image_gen = image_gen.with_font_path(Path::new(font_path));
+    }
+    // Likewise a missing LOGO_IMAGE only skips the logo
+    if let Some(logo) = &config.logo {
+        image_gen = image_gen.with_logo(logo);
+    }
 
-            let val_map = {
+    Ok(image_gen)
+}
 
-                let lock = state.sensor_values.read().unwrap();
+// Preview Page Handler
+
+const PREVIEW_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>ha-sensor-streamer</title>
+<style>
+body { margin: 0; background: #111; color: #ccc; font-family: sans-serif; text-align: center; }
+img { max-width: 100%; margin-top: 2em; border: 1px solid #333; }
+</style>
+</head>
+<body>
+<img src="/stream" alt="Sensor stream">
+<p>Stream URL: <code>/stream</code></p>
+</body>
+</html>
+"#;
+
+async fn preview_page() -> Html<&'static str> {
+    Html(PREVIEW_HTML)
+}
 
-                lock.clone()
+// Favicon Handler
 
-            };
+const FAVICON: &[u8] = include_bytes!("../assets/favicon.ico");
 
+/// Served so browsers stop re-requesting a missing icon on every visit.
+async fn favicon() -> Response {
+    Response::builder()
+        .header("Content-Type", "image/x-icon")
+        .header("Cache-Control", "public, max-age=86400")
+        .body(Body::from(FAVICON))
+        .unwrap()
+}
 
+// Version Handler
 
-            // Check if we need to regenerate (if time or sensor changed)
+async fn version_info(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_hash": env!("GIT_HASH"),
+        "stream_format": state.config.stream_format,
+        "ha_reachable": state.ha_reachable(),
+    }))
+}
 
-                        // We use a cheap formatting check for time
+// Snapshot Handler
 
-                        // We only need to regenerate if the *displayed* time changes.
+/// A single current frame as `image/jpeg`, for consumers that poll a still image
+/// URL (e.g. Home Assistant's generic camera) instead of reading a multipart stream.
+async fn snapshot(State(state): State<AppState>) -> Response {
+    let val_map = state.sensor_values.read().unwrap().clone();
+    image_response(state.image_gen.generate_frame(&val_map), "image/jpeg")
+}
 
-            // Since we don't know the format logic here perfectly without duplicating image_gen logic,
+/// The current frame as PNG. In `MONOCHROME` mode this is a 1-bit image, which
+/// e-paper displays can show without any conversion.
+async fn snapshot_png(State(state): State<AppState>) -> Response {
+    let val_map = state.sensor_values.read().unwrap().clone();
+    image_response(state.image_gen.generate_png(&val_map), "image/png")
+}
 
-            // we'll just regenerate every second (approx) or if sensor changes.
+// Long Poll Handler
+
+/// A single JPEG, but only once it differs from the one the client already has.
+/// The client sends the `ETag` of its last frame as `If-None-Match`; the request
+/// is held until the content changes or `FRAME_MAX_WAIT_SECS` pass, which answers
+/// `304 Not Modified`. Dashboards that poll it in a loop only download frames
+/// that show something new.
+async fn long_poll_frame(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let known = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|tag| tag.to_str().ok())
+        .map(str::to_string);
+    let deadline = tokio::time::Instant::now() + state.config.frame_max_wait;
+    // With the shared renderer, wake up for each published frame instead of rendering
+    let mut frames = state.latest_frame.as_ref().map(|frames| frames.subscribe());
+
+    loop {
+        let jpeg = match &mut frames {
+            Some(frames) => frames.borrow_and_update().clone(),
+            None => {
+                let val_map = state.sensor_values.read().unwrap().clone();
+                match state.image_gen.generate_frame(&val_map) {
+                    Ok(jpeg) => Bytes::from(jpeg),
+                    Err(e) => return image_response(Err(e), "image/jpeg"),
+                }
+            }
+        };
+        let tag = frame_tag(&jpeg);
+        if known.as_deref() != Some(tag.as_str()) {
+            return Response::builder()
+                .header("Content-Type", "image/jpeg")
+                .header("Cache-Control", NO_CACHE)
+                .header("ETag", tag)
+                .body(Body::from(jpeg))
+                .unwrap();
+        }
 
-            // Actually, simplest robust way: Just generate it.
+        let next = match &mut frames {
+            Some(frames) => tokio::time::timeout_at(deadline, frames.changed())
+                .await
+                .is_ok_and(|changed| changed.is_ok()),
+            None => {
+                let frame_duration = state.config.video_fps.frame_duration();
+                tokio::time::timeout_at(deadline, tokio::time::sleep(frame_duration))
+                    .await
+                    .is_ok()
+            }
+        };
+        if !next {
+            return Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header("Cache-Control", NO_CACHE)
+                .header("ETag", tag)
+                .body(Body::empty())
+                .unwrap();
+        }
+    }
+}
 
-            // If FPS is 5, generating 5 JPEGs/sec of simple text is trivial for Rust.
+/// A quoted entity tag identifying the content of a frame.
+fn frame_tag(jpeg: &[u8]) -> String {
+    use std::hash::{Hash, Hasher};
 
-            // Let's stick to simple generation for now to ensure correctness of custom formats (like seconds).
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    jpeg.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
 
+fn image_response(image: anyhow::Result<Vec<u8>>, content_type: &str) -> Response {
+    match image {
+        Ok(bytes) => Response::builder()
+            .header("Content-Type", content_type)
+            .header("Cache-Control", NO_CACHE)
+            .body(Body::from(bytes))
+            .unwrap(),
+        Err(e) => {
+            eprintln!("Error generating frame: {}", e);
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty())
+                .unwrap()
+        }
+    }
+}
 
+// Debug Handler
+
+/// The resolved text of every line next to the frame rendered from the same
+/// values, as a `data:` URI, so a monitoring page needs only one request. Each
+/// watched entity is listed with its state and when Home Assistant last changed
+/// and updated it, to tell a stale sensor from a stale stream.
+async fn debug_frame(State(state): State<AppState>) -> Response {
+    let val_map = state.sensor_values.read().unwrap().clone();
+    let image_gen = &state.image_gen;
+    let lines = image_gen.resolved_lines(&val_map);
+    let entities: serde_json::Map<String, serde_json::Value> = state
+        .config
+        .get_required_sensors()
+        .into_iter()
+        .map(|entity_id| {
+            let value = |name: &str| val_map.get(&history_key(&entity_id, name));
+            let entity = serde_json::json!({
+                "state": val_map.get(&entity_id),
+                "last_changed": value("last_changed"),
+                "last_updated": value("last_updated"),
+            });
+            (entity_id, entity)
+        })
+        .collect();
+    let jpeg = match image_gen.generate_frame(&val_map) {
+        Ok(jpeg) => jpeg,
+        Err(e) => return image_response(Err(e), "image/jpeg"),
+    };
 
-            // Optimization: If the user wants 30FPS, we should probably optimize.
+    let mut response = Json(serde_json::json!({
+        "lines": lines,
+        "entities": entities,
+        "frame": format!("data:image/jpeg;base64,{}", BASE64_STANDARD.encode(jpeg)),
+    }))
+    .into_response();
+    response
+        .headers_mut()
+        .insert("Cache-Control", HeaderValue::from_static(NO_CACHE));
+    response
+}
 
-            // For 5FPS, it's fine.
+// MJPEG Stream Handler
 
+/// Frames change constantly, so neither the stream nor snapshots may be cached.
+const NO_CACHE: &str = "no-cache, no-store, must-revalidate";
 
+async fn mjpeg_stream(State(state): State<AppState>) -> Response {
+    let body = Body::from_stream(mjpeg_frames(state));
 
-            match state.image_gen.generate_frame(&val_map) {
+    Response::builder()
+        .header("Content-Type", "multipart/x-mixed-replace; boundary=frame")
+        .header("Cache-Control", NO_CACHE)
+        .body(body)
+        .unwrap()
+}
 
-                Ok(jpeg_bytes) => {
+/// Endless stream of multipart parts for one viewer.
+///
+/// The body is pull-based: a frame is only generated once hyper has written the
+/// previous one, so at most one frame per client is in flight and a slow viewer
+/// never builds up a backlog. When it falls behind, the missed ticks are dropped
+/// and the next frame it gets shows the latest values. Once the client
+/// disconnects, the body and with it this stream are dropped, ending the loop.
+fn mjpeg_frames(state: AppState) -> impl Stream<Item = Result<Bytes, std::io::Error>> {
+    let frame_duration = state.config.video_fps.frame_duration();
+
+    async_stream::stream! {
+        // With the shared renderer, forward whatever frame is newest once the
+        // previous one has been written
+        if let Some(frames) = &state.latest_frame {
+            let mut frames = frames.subscribe();
+            frames.mark_changed();
+            while frames.changed().await.is_ok() {
+                let jpeg = frames.borrow_and_update().clone();
+                yield Ok(multipart_frame(&jpeg));
+            }
+            return;
+        }
 
-                    let frame_header = format!(
+        let mut interval = tokio::time::interval(frame_duration);
+        // If the client or encoding falls behind, wait a full frame instead of bursting to catch up
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
-                        "--frame\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+        let mut last_good: Option<Bytes> = None;
+        loop {
+            interval.tick().await;
 
-                        jpeg_bytes.len()
+            // Regenerating every frame keeps custom time formats (like seconds) correct
+            // and is cheap at typical frame rates.
+            let val_map = state.sensor_values.read().unwrap().clone();
 
-                    );
+            if let Some(part) = frame_part(state.image_gen.generate_frame(&val_map), &mut last_good) {
+                yield Ok(part);
+            }
+        }
+    }
+}
 
+/// The multipart part to send for a generated frame. A frame that could not be
+/// drawn is skipped, one that could not be encoded is replaced by the last good one.
+fn frame_part(frame: anyhow::Result<Vec<u8>>, last_good: &mut Option<Bytes>) -> Option<Bytes> {
+    match frame {
+        Ok(jpeg_bytes) => {
+            let part = multipart_frame(&jpeg_bytes);
+            *last_good = Some(part.clone());
+            Some(part)
+        }
+        Err(e) => {
+            eprintln!("Error generating frame: {:#}", e);
+            if e.is::<EncodeError>() {
+                last_good.clone()
+            } else {
+                None
+            }
+        }
+    }
+}
 
+/// Assembles one multipart part (boundary, headers, JPEG, trailing CRLF) into a
+/// single pre-sized buffer, so each frame costs one allocation and one write.
+fn multipart_frame(jpeg: &[u8]) -> Bytes {
+    const HEADER_PREFIX: &str = "--frame\r\nContent-Type: image/jpeg\r\nContent-Length: ";
+
+    // 20 digits covers any usize length
+    let mut buf = BytesMut::with_capacity(HEADER_PREFIX.len() + 20 + 4 + jpeg.len() + 2);
+    buf.put_slice(HEADER_PREFIX.as_bytes());
+    write!(buf, "{}\r\n\r\n", jpeg.len()).expect("writing to BytesMut cannot fail");
+    buf.put_slice(jpeg);
+    buf.put_slice(b"\r\n");
+    buf.freeze()
+}
 
-                    yield Ok::<_, std::io::Error>(axum::body::Bytes::from(frame_header));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::testing::test_state;
+    use serial_test::serial;
+
+    #[tokio::test]
+    #[serial]
+    async fn test_slow_viewer_gets_no_backlog() {
+        use futures_util::StreamExt;
+
+        let frames = mjpeg_frames(test_state(&[("VIDEO_FPS", "20")], &[], (64, 64)));
+        futures_util::pin_mut!(frames);
+        frames.next().await.unwrap().unwrap();
+
+        // Stall for several frame intervals, like a congested connection
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        // One frame is due immediately, but the missed ticks are not replayed
+        let start = std::time::Instant::now();
+        frames.next().await.unwrap().unwrap();
+        frames.next().await.unwrap().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
 
-                    yield Ok(axum::body::Bytes::from(jpeg_bytes));
+    #[test]
+    #[serial]
+    fn test_encode_failure_repeats_last_frame() {
+        let state = test_state(&[("VIDEO_FPS", "20")], &[], (64, 64));
+        let values = state.sensor_values.read().unwrap().clone();
+
+        // Wider than JPEG allows: drawing works, encoding fails every time
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let too_wide = ImageGenerator::new(font_data, vec![], 24.0, "en_US", 70_000, 1).unwrap();
+        let err = too_wide.generate_frame(&values).unwrap_err();
+        assert!(err.is::<EncodeError>());
+
+        // Nothing to repeat before the first good frame
+        let mut last_good = None;
+        assert_eq!(frame_part(Err(err), &mut last_good), None);
+
+        // Then the viewer keeps getting the last good frame, but not for other failures
+        let first = frame_part(state.image_gen.generate_frame(&values), &mut last_good);
+        assert!(first.is_some());
+        let err = too_wide.generate_frame(&values).unwrap_err();
+        assert_eq!(frame_part(Err(err), &mut last_good), first);
+        let err = anyhow::anyhow!("Failed to draw the frame");
+        assert_eq!(frame_part(Err(err), &mut last_good), None);
+    }
 
-                    yield Ok(axum::body::Bytes::from("\r\n"));
+    #[test]
+    fn test_favicon_decodes() {
+        let icon = image::load_from_memory(FAVICON).unwrap();
+        assert_eq!((icon.width(), icon.height()), (16, 16));
+    }
 
-                }
+    #[tokio::test]
+    #[serial]
+    async fn test_snapshot_is_plain_jpeg() {
+        let response = snapshot(State(test_state(&[("VIDEO_FPS", "5")], &[], (64, 64)))).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Content-Type"], "image/jpeg");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..2], &[0xFF, 0xD8]);
+
+        let response = snapshot_png(State(test_state(&[("VIDEO_FPS", "5")], &[], (64, 64)))).await;
+        assert_eq!(response.headers()["Content-Type"], "image/png");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..4], b"\x89PNG");
+    }
 
-                Err(e) => {
+    #[tokio::test]
+    #[serial]
+    async fn test_snapshot_app_serves_stills_only() {
+        use tower::ServiceExt;
+
+        let state = test_state(&[("VIDEO_FPS", "5")], &[], (64, 64));
+        let app = snapshot_app(&state.config, state.clone());
+        let get = |uri| {
+            axum::http::Request::builder()
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(get("/snapshot")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Content-Type"], "image/jpeg");
+        let response = app.clone().oneshot(get("/snapshot.png")).await.unwrap();
+        assert_eq!(response.headers()["Content-Type"], "image/png");
+
+        let response = app.oneshot(get("/stream")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
 
-                    eprintln!("Error generating frame: {}", e);
+    #[tokio::test]
+    #[serial]
+    async fn test_debug_frame_has_lines_and_image() {
+        let mut state = test_state(&[("VIDEO_FPS", "5")], &[], (64, 64));
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec!["Temp {sensor.temp}".to_string()];
+        state.config.lines = lines.clone();
+        state.image_gen =
+            Arc::new(ImageGenerator::new(font_data, lines, 16.0, "en_US", 64, 64).unwrap());
+        {
+            let mut values = state.sensor_values.write().unwrap();
+            values.insert("sensor.temp".to_string(), "21.5".to_string());
+            values.insert(
+                history_key("sensor.temp", "last_changed"),
+                "2024-03-09T07:00:00+00:00".to_string(),
+            );
+        }
 
+        let response = debug_frame(State(state)).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["lines"], serde_json::json!(["Temp 21.5"]));
+        assert_eq!(
+            json["entities"],
+            serde_json::json!({
+                "sensor.temp": {
+                    "state": "21.5",
+                    "last_changed": "2024-03-09T07:00:00+00:00",
+                    "last_updated": null,
                 }
+            })
+        );
+        let frame = json["frame"].as_str().unwrap();
+        let jpeg = BASE64_STANDARD
+            .decode(frame.strip_prefix("data:image/jpeg;base64,").unwrap())
+            .unwrap();
+        assert_eq!(&jpeg[..2], &[0xFF, 0xD8]);
+    }
 
+    #[tokio::test]
+    #[serial]
+    async fn test_frame_long_poll_waits_for_changes() {
+        let mut state = test_state(&[("VIDEO_FPS", "20")], &[], (64, 64));
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = vec!["{sensor.temp}".to_string()];
+        state.image_gen =
+            Arc::new(ImageGenerator::new(font_data, lines, 24.0, "en_US", 64, 32).unwrap());
+        state.config.frame_max_wait = Duration::from_millis(200);
+        let poll = |state: &AppState, tag: Option<&HeaderValue>| {
+            let mut headers = HeaderMap::new();
+            if let Some(tag) = tag {
+                headers.insert(header::IF_NONE_MATCH, tag.clone());
             }
+            long_poll_frame(State(state.clone()), headers)
+        };
+
+        // A new client gets the current frame right away
+        let first = poll(&state, None).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(first.headers()["Content-Type"], "image/jpeg");
+        let tag = first.headers()["ETag"].clone();
+
+        // Nothing changed: held until the wait runs out
+        let started = std::time::Instant::now();
+        let unchanged = poll(&state, Some(&tag)).await;
+        assert_eq!(unchanged.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(unchanged.headers()["ETag"], tag);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        // A new value ends the wait early
+        state.config.frame_max_wait = Duration::from_secs(5);
+        let values = state.sensor_values.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            values
+                .write()
+                .unwrap()
+                .insert("sensor.temp".to_string(), "21".to_string());
+        });
+        let started = std::time::Instant::now();
+        let changed = poll(&state, Some(&tag)).await;
+        assert_eq!(changed.status(), StatusCode::OK);
+        assert_ne!(changed.headers()["ETag"], tag);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
 
-        }
-
-    };
-
-    let body = Body::from_stream(stream);
+    #[test]
+    fn test_multipart_frame() {
+        let frame = multipart_frame(&[0xFF, 0xD8, 0xFF, 0xD9]);
 
-    Response::builder()
-        .header("Content-Type", "multipart/x-mixed-replace; boundary=frame")
-        .body(body)
-        .unwrap()
+        assert_eq!(
+            &frame[..],
+            b"--frame\r\nContent-Type: image/jpeg\r\nContent-Length: 4\r\n\r\n\xFF\xD8\xFF\xD9\r\n"
+        );
+    }
 }
@@ -0,0 +1,757 @@
+//! Parsing and helpers for sensor token modifiers, e.g. `{sensor.temp:round=1:unit}`.
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Duration, Local};
+use std::collections::HashMap;
+
+/// Names of all supported modifiers.
+pub const MODIFIER_NAMES: &[&str] = &[
+    "relative",
+    "color",
+    "max_today",
+    "min_today",
+    "currency",
+    "percent",
+    "avg",
+    "raw",
+    "emoji",
+    "trend",
+    "pad",
+];
+
+/// Modifiers computed by the poller from today's recorded history.
+pub const HISTORY_MODIFIERS: &[&str] = &["max_today", "min_today"];
+
+/// A single `name` or `name=arg` modifier following a sensor token.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Modifier {
+    pub name: String,
+    pub arg: Option<String>,
+}
+
+/// Splits a modifier list on `:`. Segments that don't start with a known modifier
+/// name belong to the previous modifier's argument, so arguments may contain colons.
+pub fn parse_modifiers(spec: &str) -> Vec<Modifier> {
+    let mut modifiers: Vec<Modifier> = Vec::new();
+
+    for segment in spec.split(':') {
+        let (name, arg) = match segment.split_once('=') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (segment, None),
+        };
+        let is_name = MODIFIER_NAMES.contains(&name.trim());
+
+        match modifiers.last_mut() {
+            Some(last) if !is_name && last.arg.is_some() => {
+                let arg = last.arg.as_mut().unwrap();
+                arg.push(':');
+                arg.push_str(segment);
+            }
+            _ => modifiers.push(Modifier {
+                name: name.trim().to_string(),
+                arg: arg.map(|a| a.to_string()),
+            }),
+        }
+    }
+
+    modifiers
+}
+
+/// One rule of a `color=` modifier, e.g. `warn>25`. Rules without a condition always match.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColorRule {
+    /// Palette name or `#rrggbb` hex code.
+    pub color: String,
+    pub condition: Option<(String, String)>,
+}
+
+impl ColorRule {
+    fn matches(&self, value: &str) -> bool {
+        let Some((op, operand)) = &self.condition else {
+            return true;
+        };
+        match op.as_str() {
+            "=" => return value.trim() == operand,
+            "!=" => return value.trim() != operand,
+            _ => {}
+        }
+        let (Ok(value), Ok(operand)) = (value.trim().parse::<f64>(), operand.parse::<f64>()) else {
+            return false;
+        };
+        match op.as_str() {
+            ">" => value > operand,
+            "<" => value < operand,
+            ">=" => value >= operand,
+            "<=" => value <= operand,
+            _ => false,
+        }
+    }
+}
+
+/// Parses the argument of a `color=` modifier: comma-separated rules such as
+/// `warn>25,ok<10` or `#ff8800=on`.
+pub fn parse_color_rules(arg: &str) -> Vec<ColorRule> {
+    arg.split(',')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .filter_map(|rule| match rule.find(['>', '<', '=']) {
+            Some(pos) => Some(ColorRule {
+                color: rule[..pos].trim().to_string(),
+                condition: Some(parse_condition(&rule[pos..])?),
+            }),
+            None => Some(ColorRule {
+                color: rule.to_string(),
+                condition: None,
+            }),
+        })
+        .collect()
+}
+
+/// Splits a condition such as `>=25` into its operator and operand. `None` if it
+/// doesn't start with an operator.
+fn parse_condition(condition: &str) -> Option<(String, String)> {
+    [">=", "<=", ">", "<", "="].into_iter().find_map(|op| {
+        let operand = condition.strip_prefix(op)?;
+        Some((op.to_string(), operand.trim().to_string()))
+    })
+}
+
+/// Parses the argument of an `emoji=` modifier, e.g. `<10:🥶,<28:😊,else:🥵`.
+/// Used to reject broken rules when the config is read.
+pub fn parse_emoji_rules(arg: &str) -> Result<Vec<ColorRule>> {
+    arg.split(',')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(parse_emoji_rule)
+        .collect()
+}
+
+fn parse_emoji_rule(rule: &str) -> Result<ColorRule> {
+    let Some((condition, emoji)) = rule.split_once(':') else {
+        bail!("'{}' has no emoji, expected e.g. <10:🥶", rule);
+    };
+    let condition = match condition.trim() {
+        "else" => None,
+        condition => Some(parse_condition(condition).with_context(|| {
+            format!(
+                "'{}' has no comparison, expected e.g. <10:🥶 or else:🥵",
+                rule
+            )
+        })?),
+    };
+    Ok(ColorRule {
+        color: emoji.trim().to_string(),
+        condition,
+    })
+}
+
+/// Picks the emoji of an `emoji=` modifier for `value`, e.g. `<10:🥶,<28:😊,else:🥵`.
+/// Conditions are those of `color=` rules, written before the emoji; `else`
+/// matches anything, including non-numeric values. Rules that don't parse are
+/// skipped. `None` if no rule matches.
+pub fn select_emoji(arg: &str, value: &str) -> Option<String> {
+    let rules: Vec<ColorRule> = arg
+        .split(',')
+        .filter_map(|rule| parse_emoji_rule(rule.trim()).ok())
+        .collect();
+    select_color(&rules, value).map(str::to_string)
+}
+
+/// Returns the color of the first rule matching `value`.
+pub fn select_color<'a>(rules: &'a [ColorRule], value: &str) -> Option<&'a str> {
+    rules
+        .iter()
+        .find(|rule| rule.matches(value))
+        .map(|rule| rule.color.as_str())
+}
+
+/// The `trend` arrow for a change from `previous` to `value`: `↑` or `↓`, or `→`
+/// while the change is within `tolerance` or there is no earlier value yet.
+/// `None` for non-numeric values.
+pub fn trend_arrow(value: &str, previous: Option<&str>, tolerance: f64) -> Option<&'static str> {
+    let value = value.trim().parse::<f64>().ok()?;
+    let Some(previous) = previous.and_then(|previous| previous.trim().parse::<f64>().ok()) else {
+        return Some("→");
+    };
+    let change = value - previous;
+    Some(if change.abs() <= tolerance {
+        "→"
+    } else if change > 0.0 {
+        "↑"
+    } else {
+        "↓"
+    })
+}
+
+/// Pads `value` with spaces to `width` characters for `pad=WIDTH`: right-aligned,
+/// or left-aligned for a negative width such as `pad=-6`. Widths beyond
+/// `max_width` are capped there. Longer values and widths that aren't a number
+/// leave `value` unchanged.
+pub fn pad_value(value: &str, width: &str, max_width: usize) -> String {
+    let Ok(width) = width.trim().parse::<i64>() else {
+        return value.to_string();
+    };
+    let padded = (width.unsigned_abs() as usize).min(max_width);
+    let fill = " ".repeat(padded.saturating_sub(value.chars().count()));
+    if width < 0 {
+        format!("{}{}", value, fill)
+    } else {
+        format!("{}{}", fill, value)
+    }
+}
+
+/// One condition of `LINE_n_COLOR_WHEN`, e.g. `sensor.alarm==on:#ff0000`: the line
+/// takes `rule.color` while the entity's state matches `rule`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineColorRule {
+    pub entity_id: String,
+    pub rule: ColorRule,
+}
+
+/// Parses comma-separated `ENTITY OP VALUE:COLOR` conditions. Operators are `==`
+/// (or `=`) and `!=` comparing text, and `>`, `<`, `>=`, `<=` comparing numbers.
+pub fn parse_line_color_rules(value: &str) -> Result<Vec<LineColorRule>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| {
+            let Some((condition, color)) = rule.rsplit_once(':') else {
+                bail!(
+                    "'{}' has no color, expected e.g. sensor.alarm==on:red",
+                    rule
+                );
+            };
+            let Some(pos) = condition.find(['=', '!', '>', '<']) else {
+                bail!(
+                    "'{}' has no comparison, expected e.g. sensor.alarm==on:red",
+                    rule
+                );
+            };
+            let rest = &condition[pos..];
+            let (op, op_len) = ["==", "!=", ">=", "<=", ">", "<", "="]
+                .into_iter()
+                .find(|op| rest.starts_with(op))
+                .map(|op| (op.replace("==", "="), op.len()))
+                .with_context(|| format!("Unknown comparison in '{}'", rule))?;
+            let entity_id = condition[..pos].trim();
+            if !entity_id.contains('.') {
+                bail!("'{}' is not an entity id in '{}'", entity_id, rule);
+            }
+
+            Ok(LineColorRule {
+                entity_id: entity_id.to_string(),
+                rule: ColorRule {
+                    color: color.trim().to_string(),
+                    condition: Some((op, rest[op_len..].trim().to_string())),
+                },
+            })
+        })
+        .collect()
+}
+
+/// Returns the color of the first rule whose entity matches. Entities without a
+/// value never match.
+pub fn select_line_color<'a>(
+    rules: &'a [LineColorRule],
+    sensor_values: &HashMap<String, String>,
+) -> Option<&'a str> {
+    rules
+        .iter()
+        .find(|r| {
+            sensor_values
+                .get(&r.entity_id)
+                .is_some_and(|value| r.rule.matches(value))
+        })
+        .map(|r| r.rule.color.as_str())
+}
+
+/// Locale conventions used by the `currency` and `percent` modifiers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NumberFormat {
+    pub decimal: char,
+    pub group: char,
+    /// Whether symbols follow the number with a space (`12,34 €`) or lead it (`$12.34`).
+    pub symbol_after: bool,
+}
+
+impl NumberFormat {
+    pub fn for_locale(locale: &str, decimal: char) -> Self {
+        let l = locale.to_lowercase();
+        // Comma-decimal locales group with a space or a dot
+        let space_grouped = [
+            "sv", "fi", "no", "nb", "nn", "fr", "pl", "cs", "sk", "ru", "uk", "hu", "bg", "et",
+            "lv", "lt",
+        ];
+
+        if decimal == ',' {
+            let group = if space_grouped.iter().any(|&p| l.starts_with(p)) {
+                ' '
+            } else {
+                '.'
+            };
+            NumberFormat {
+                decimal,
+                group,
+                symbol_after: true,
+            }
+        } else {
+            NumberFormat {
+                decimal,
+                group: ',',
+                symbol_after: false,
+            }
+        }
+    }
+
+    /// Formats `value` with `decimals` fraction digits and thousands grouping.
+    fn format(&self, value: f64, decimals: usize) -> String {
+        let fixed = format!("{:.*}", decimals, value.abs());
+        let (int_part, frac_part) = match fixed.split_once('.') {
+            Some((int_part, frac_part)) => (int_part, Some(frac_part)),
+            None => (fixed.as_str(), None),
+        };
+
+        let mut result = String::new();
+        if value < 0.0 && fixed.chars().any(|c| c.is_ascii_digit() && c != '0') {
+            result.push('-');
+        }
+        for (i, digit) in int_part.chars().enumerate() {
+            if i > 0 && (int_part.len() - i) % 3 == 0 {
+                result.push(self.group);
+            }
+            result.push(digit);
+        }
+        if let Some(frac_part) = frac_part {
+            result.push(self.decimal);
+            result.push_str(frac_part);
+        }
+        result
+    }
+}
+
+/// Symbol and number of fraction digits for an ISO 4217 currency code.
+fn currency_symbol(code: &str) -> (&str, usize) {
+    match code {
+        "USD" => ("$", 2),
+        "EUR" => ("€", 2),
+        "GBP" => ("£", 2),
+        "JPY" => ("¥", 0),
+        "SEK" | "NOK" | "DKK" => ("kr", 2),
+        "ISK" => ("kr", 0),
+        "CHF" => ("CHF", 2),
+        "PLN" => ("zł", 2),
+        "CZK" => ("Kč", 2),
+        "CAD" => ("C$", 2),
+        "AUD" => ("A$", 2),
+        _ => (code, 2),
+    }
+}
+
+/// Formats a numeric state as money, e.g. `$1,234.50` or `1 234,50 kr`.
+/// Returns `None` if the value is not a number.
+pub fn format_currency(value: &str, code: &str, format: NumberFormat) -> Option<String> {
+    let number: f64 = value.trim().parse().ok().filter(|n: &f64| n.is_finite())?;
+    let code = code.trim().to_uppercase();
+    let (symbol, decimals) = currency_symbol(&code);
+    let amount = format.format(number, decimals);
+
+    Some(if format.symbol_after {
+        format!("{} {}", amount, symbol)
+    } else if let Some(amount) = amount.strip_prefix('-') {
+        format!("-{}{}", symbol, amount)
+    } else {
+        format!("{}{}", symbol, amount)
+    })
+}
+
+/// Formats a numeric state that is already a percentage, keeping its precision,
+/// e.g. `45.5` becomes `45.5%` or `45,5 %`. Returns `None` if the value is not a number.
+pub fn format_percent(value: &str, format: NumberFormat) -> Option<String> {
+    let value = value.trim();
+    let number: f64 = value.parse().ok().filter(|n: &f64| n.is_finite())?;
+    let decimals = value.split_once('.').map_or(0, |(_, frac)| frac.len());
+    let amount = format.format(number, decimals);
+
+    Some(if format.symbol_after {
+        format!("{} %", amount)
+    } else {
+        format!("{}%", amount)
+    })
+}
+
+/// Reads a number written with thousands grouping, such as `1.234.567`,
+/// `1,234,567.89` or `1 234,5`, and returns it with its number of fraction digits.
+/// A single `.` or `,` group like `1.234` is ambiguous and not read as grouping.
+fn parse_grouped_number(value: &str) -> Option<(f64, usize)> {
+    let value = value.trim();
+    let (sign, digits) = match value.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", value),
+    };
+    let group = digits.chars().find(|c| !c.is_ascii_digit())?;
+    if !matches!(group, '.' | ',' | ' ' | '\u{a0}' | '\u{202f}') {
+        return None;
+    }
+
+    // A trailing separator other than the grouping one starts the fraction
+    let (int_part, frac) = match digits
+        .char_indices()
+        .rev()
+        .find(|(_, c)| !c.is_ascii_digit())
+    {
+        Some((pos, decimal)) if decimal != group => {
+            if !matches!(decimal, '.' | ',') {
+                return None;
+            }
+            (&digits[..pos], Some(&digits[pos + decimal.len_utf8()..]))
+        }
+        _ => (digits, None),
+    };
+
+    let groups: Vec<&str> = int_part.split(group).collect();
+    let well_formed = groups.len() >= 2
+        && (1..=3).contains(&groups[0].len())
+        && groups[1..].iter().all(|g| g.len() == 3)
+        && groups.iter().all(|g| g.chars().all(|c| c.is_ascii_digit()))
+        && frac.is_none_or(|f| !f.is_empty() && f.chars().all(|c| c.is_ascii_digit()));
+    let unambiguous = groups.len() >= 3 || frac.is_some() || !matches!(group, '.' | ',');
+    if !well_formed || !unambiguous {
+        return None;
+    }
+
+    let frac = frac.unwrap_or("");
+    let number = format!("{}{}.{}", sign, groups.concat(), frac);
+    Some((number.trim_end_matches('.').parse().ok()?, frac.len()))
+}
+
+/// Re-formats a number that arrived with thousands grouping (e.g. `1.234.567` from a
+/// German-localized integration) the `LOCALE` way. Returns `None` for anything else.
+pub fn format_grouped(value: &str, format: NumberFormat) -> Option<String> {
+    let (number, decimals) = parse_grouped_number(value)?;
+    Some(format.format(number, decimals))
+}
+
+/// Fraction digits and usual unit for a Home Assistant `device_class`.
+fn device_class_format(device_class: &str) -> Option<(usize, &'static str)> {
+    Some(match device_class {
+        "temperature" => (1, "°C"),
+        "humidity" | "moisture" | "battery" => (0, "%"),
+        "power" => (0, "W"),
+        "energy" => (2, "kWh"),
+        "voltage" => (1, "V"),
+        "current" => (2, "A"),
+        "pressure" | "atmospheric_pressure" => (0, "hPa"),
+        "illuminance" => (0, "lx"),
+        "carbon_dioxide" | "carbon_monoxide" => (0, "ppm"),
+        "pm1" | "pm25" | "pm10" => (0, "µg/m³"),
+        "wind_speed" | "speed" => (1, "km/h"),
+        "precipitation" => (1, "mm"),
+        "gas" | "water" => (2, "m³"),
+        _ => return None,
+    })
+}
+
+/// `SMART_FORMAT`: rounds a numeric state for its `device_class` and appends the
+/// entity's unit (or the class's usual one), e.g. a temperature of `21.46` becomes
+/// `21.5 °C`. Returns `None` for other classes and non-numeric states.
+pub fn format_device_class(
+    value: &str,
+    device_class: &str,
+    unit: Option<&str>,
+    format: NumberFormat,
+) -> Option<String> {
+    let (decimals, default_unit) = device_class_format(device_class)?;
+    let number: f64 = value.trim().parse().ok().filter(|n: &f64| n.is_finite())?;
+    let amount = format.format(number, decimals);
+
+    Some(match unit.unwrap_or(default_unit) {
+        "" => amount,
+        "%" if !format.symbol_after => format!("{}%", amount),
+        unit => format!("{} {}", amount, unit),
+    })
+}
+
+/// Parses an `avg=` window such as `60s`, `5m`, `1h` or a plain number of seconds.
+pub fn parse_window(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => value.split_at(pos),
+        None => (value, "s"),
+    };
+    let number: i64 = number.parse().ok()?;
+    let window = match unit.trim() {
+        "s" => Duration::seconds(number),
+        "m" | "min" => Duration::minutes(number),
+        "h" => Duration::hours(number),
+        _ => return None,
+    };
+    (number > 0).then_some(window)
+}
+
+/// Renders an RFC 3339 timestamp as a compact relative duration, e.g. `3 minutes ago`.
+/// Returns `None` if the value is not a timestamp.
+pub fn humanize_relative(value: &str, now: DateTime<Local>) -> Option<String> {
+    let timestamp = DateTime::parse_from_rfc3339(value.trim()).ok()?;
+    let delta = now.signed_duration_since(timestamp);
+    let (magnitude, future) = if delta < Duration::zero() {
+        (-delta, true)
+    } else {
+        (delta, false)
+    };
+
+    let secs = magnitude.num_seconds();
+    if secs < 45 {
+        return Some("just now".to_string());
+    }
+
+    let (amount, unit) = if secs < 3600 {
+        ((secs + 30) / 60, "minute")
+    } else if secs < 86_400 {
+        ((secs + 1800) / 3600, "hour")
+    } else {
+        ((secs + 43_200) / 86_400, "day")
+    };
+    let plural = if amount == 1 { "" } else { "s" };
+
+    Some(if future {
+        format!("in {} {}{}", amount, unit, plural)
+    } else {
+        format!("{} {}{} ago", amount, unit, plural)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_parse_modifiers() {
+        assert_eq!(
+            parse_modifiers("relative"),
+            vec![Modifier {
+                name: "relative".to_string(),
+                arg: None
+            }]
+        );
+
+        // Colons inside an argument are kept with that argument
+        let mods = parse_modifiers("other=on:Open,off:Closed:relative");
+        assert_eq!(mods.len(), 2);
+        assert_eq!(mods[0].arg.as_deref(), Some("on:Open,off:Closed"));
+        assert_eq!(mods[1].name, "relative");
+    }
+
+    #[test]
+    fn test_color_rules() {
+        let rules = parse_color_rules("warn>=25, ok<10, #888888");
+        assert_eq!(rules.len(), 3);
+        assert_eq!(
+            rules[0].condition,
+            Some((">=".to_string(), "25".to_string()))
+        );
+
+        assert_eq!(select_color(&rules, "25"), Some("warn"));
+        assert_eq!(select_color(&rules, "3.5"), Some("ok"));
+        assert_eq!(select_color(&rules, "18"), Some("#888888"));
+        assert_eq!(select_color(&rules, "unavailable"), Some("#888888"));
+
+        let rules = parse_color_rules("ok=on");
+        assert_eq!(select_color(&rules, "on"), Some("ok"));
+        assert_eq!(select_color(&rules, "off"), None);
+    }
+
+    #[test]
+    fn test_select_emoji() {
+        let arg = "<10:🥶,<28:😊,else:🥵";
+        let emoji = |value| select_emoji(arg, value);
+
+        assert_eq!(emoji("-3.5").as_deref(), Some("🥶"));
+        assert_eq!(emoji("10").as_deref(), Some("😊"));
+        assert_eq!(emoji(" 27.9 ").as_deref(), Some("😊"));
+        assert_eq!(emoji("28").as_deref(), Some("🥵"));
+        // Non-numeric values only match `else`, or nothing without one
+        assert_eq!(emoji("unavailable").as_deref(), Some("🥵"));
+        assert_eq!(select_emoji("<10:🥶,>=28:🥵", "unavailable"), None);
+        assert_eq!(select_emoji("<10:🥶,>=28:🥵", "15"), None);
+        assert_eq!(select_emoji("=on:💡,else:🌑", "on").as_deref(), Some("💡"));
+
+        // Empty and non-ASCII conditions are skipped instead of panicking
+        assert_eq!(select_emoji(":🥶,else:🌑", "5").as_deref(), Some("🌑"));
+        assert_eq!(select_emoji("🥶:<10", "5"), None);
+        assert!(parse_emoji_rules(":🥶").is_err());
+        assert!(parse_emoji_rules("🥶:<10").is_err());
+        assert!(parse_emoji_rules("<10").is_err());
+        assert_eq!(parse_emoji_rules("<10:🥶, else:🥵").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_trend_arrow() {
+        // Changes within the tolerance are neutral
+        assert_eq!(trend_arrow("21.2", Some("21.0"), 0.5), Some("→"));
+        assert_eq!(trend_arrow("20.8", Some("21.0"), 0.5), Some("→"));
+        assert_eq!(trend_arrow("22.0", Some("21.0"), 0.5), Some("↑"));
+        assert_eq!(trend_arrow("20.0", Some("21.0"), 0.5), Some("↓"));
+        assert_eq!(trend_arrow("21.2", Some("21.0"), 0.0), Some("↑"));
+
+        assert_eq!(trend_arrow("21.0", None, 0.5), Some("→"));
+        assert_eq!(trend_arrow("21.0", Some("unavailable"), 0.5), Some("→"));
+        assert_eq!(trend_arrow("unavailable", Some("21.0"), 0.5), None);
+    }
+
+    #[test]
+    fn test_pad_value() {
+        assert_eq!(pad_value("9.5", "6", 512), "   9.5");
+        assert_eq!(pad_value("-12.3", "6", 512), " -12.3");
+        assert_eq!(pad_value("9.5", "-6", 512), "9.5   ");
+        assert_eq!(pad_value("°C", "3", 512), " °C");
+
+        assert_eq!(pad_value("1234.56", "6", 512), "1234.56");
+        assert_eq!(pad_value("9.5", "wide", 512), "9.5");
+        // A huge width is capped instead of allocating gigabytes per frame
+        assert_eq!(pad_value("9.5", "10000000000", 512).len(), 512);
+    }
+
+    #[test]
+    fn test_line_color_rules() {
+        let rules = parse_line_color_rules(
+            "sensor.alarm==on:#ff0000, sensor.temp >= 25 : orange, binary_sensor.door!=off:warn",
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[1].entity_id, "sensor.temp");
+        assert_eq!(
+            rules[1].rule.condition,
+            Some((">=".to_string(), "25".to_string()))
+        );
+
+        let mut values = HashMap::new();
+        assert_eq!(select_line_color(&rules, &values), None);
+        values.insert("sensor.temp".to_string(), "26".to_string());
+        values.insert("binary_sensor.door".to_string(), "off".to_string());
+        assert_eq!(select_line_color(&rules, &values), Some("orange"));
+        values.insert("sensor.alarm".to_string(), "on".to_string());
+        assert_eq!(select_line_color(&rules, &values), Some("#ff0000"));
+        values.insert("sensor.alarm".to_string(), "off".to_string());
+        values.insert("binary_sensor.door".to_string(), "on".to_string());
+        values.insert("sensor.temp".to_string(), "20".to_string());
+        assert_eq!(select_line_color(&rules, &values), Some("warn"));
+
+        assert!(parse_line_color_rules("sensor.alarm==on").is_err());
+        assert!(parse_line_color_rules("sensor.alarm:red").is_err());
+        assert!(parse_line_color_rules("alarm==on:red").is_err());
+    }
+
+    #[test]
+    fn test_format_currency_and_percent() {
+        let us = NumberFormat::for_locale("en_US", '.');
+        let de = NumberFormat::for_locale("de_DE", ',');
+        let sv = NumberFormat::for_locale("sv_SE", ',');
+
+        assert_eq!(format_currency("1234.5", "usd", us).unwrap(), "$1,234.50");
+        assert_eq!(format_currency("-12.345", "USD", us).unwrap(), "-$12.35");
+        assert_eq!(format_currency("12.34", "EUR", de).unwrap(), "12,34 €");
+        assert_eq!(
+            format_currency("1234567", "SEK", sv).unwrap(),
+            "1 234 567,00 kr"
+        );
+        assert_eq!(format_currency("1500.4", "JPY", us).unwrap(), "¥1,500");
+        assert_eq!(format_currency("unavailable", "EUR", de), None);
+
+        assert_eq!(format_percent("45", us).unwrap(), "45%");
+        assert_eq!(format_percent("45.5", de).unwrap(), "45,5 %");
+        assert_eq!(format_percent("on", de), None);
+    }
+
+    #[test]
+    fn test_format_grouped() {
+        let us = NumberFormat::for_locale("en_US", '.');
+        let de = NumberFormat::for_locale("de_DE", ',');
+        let sv = NumberFormat::for_locale("sv_SE", ',');
+
+        assert_eq!(format_grouped("1.234.567", us).unwrap(), "1,234,567");
+        assert_eq!(format_grouped("1.234.567", de).unwrap(), "1.234.567");
+        assert_eq!(format_grouped("1.234,5", us).unwrap(), "1,234.5");
+        assert_eq!(
+            format_grouped("-1,234,567.89", de).unwrap(),
+            "-1.234.567,89"
+        );
+        assert_eq!(format_grouped("1 234,50", us).unwrap(), "1,234.50");
+        assert_eq!(format_grouped("12,345,678", sv).unwrap(), "12 345 678");
+
+        // A single `.` or `,` group could just as well be a decimal
+        assert_eq!(format_grouped("1.234", us), None);
+        assert_eq!(format_grouped("1,234", de), None);
+        // Not grouped numbers at all
+        assert_eq!(format_grouped("22,5", de), None);
+        assert_eq!(format_grouped("1.23.456", us), None);
+        assert_eq!(format_grouped("1,234.567.8", us), None);
+        assert_eq!(format_grouped("192.168.1.10", us), None);
+        assert_eq!(format_grouped("1.234.567 kWh", us), None);
+        assert_eq!(format_grouped("v1.234.567", us), None);
+    }
+
+    #[test]
+    fn test_format_device_class() {
+        let us = NumberFormat::for_locale("en_US", '.');
+        let sv = NumberFormat::for_locale("sv_SE", ',');
+
+        assert_eq!(
+            format_device_class("21.46", "temperature", Some("°C"), us).unwrap(),
+            "21.5 °C"
+        );
+        assert_eq!(
+            format_device_class("21.46", "temperature", Some("°F"), sv).unwrap(),
+            "21,5 °F"
+        );
+        assert_eq!(
+            format_device_class("1234.7", "power", None, us).unwrap(),
+            "1,235 W"
+        );
+        assert_eq!(
+            format_device_class("45.2", "humidity", Some("%"), us).unwrap(),
+            "45%"
+        );
+        assert_eq!(
+            format_device_class("45.2", "humidity", Some("%"), sv).unwrap(),
+            "45 %"
+        );
+        assert_eq!(format_device_class("on", "temperature", None, us), None);
+        assert_eq!(format_device_class("3", "enum", None, us), None);
+    }
+
+    #[test]
+    fn test_parse_window() {
+        assert_eq!(parse_window("60s"), Some(Duration::seconds(60)));
+        assert_eq!(parse_window("5m"), Some(Duration::minutes(5)));
+        assert_eq!(parse_window("1h"), Some(Duration::hours(1)));
+        assert_eq!(parse_window("90"), Some(Duration::seconds(90)));
+        assert_eq!(parse_window("0s"), None);
+        assert_eq!(parse_window("soon"), None);
+    }
+
+    #[test]
+    fn test_humanize_relative() {
+        let now = Local.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let ago = |d: Duration| (now - d).to_rfc3339();
+
+        assert_eq!(
+            humanize_relative(&ago(Duration::seconds(10)), now).unwrap(),
+            "just now"
+        );
+        assert_eq!(
+            humanize_relative(&ago(Duration::minutes(3)), now).unwrap(),
+            "3 minutes ago"
+        );
+        assert_eq!(
+            humanize_relative(&ago(Duration::hours(1)), now).unwrap(),
+            "1 hour ago"
+        );
+        assert_eq!(
+            humanize_relative(&ago(Duration::days(-2)), now).unwrap(),
+            "in 2 days"
+        );
+        assert_eq!(humanize_relative("unknown", now), None);
+    }
+}
@@ -0,0 +1,109 @@
+use crate::config::Monochrome;
+use anyhow::Result;
+use image::{Rgb, RgbImage};
+
+const BLACK: Rgb<u8> = Rgb([0, 0, 0]);
+const WHITE: Rgb<u8> = Rgb([255, 255, 255]);
+
+/// Reduces `image` to pure black and white in place.
+pub fn apply(image: &mut RgbImage, mode: Monochrome) {
+    match mode {
+        Monochrome::Threshold => {
+            for pixel in image.pixels_mut() {
+                *pixel = if luma(*pixel) >= 128.0 { WHITE } else { BLACK };
+            }
+        }
+        Monochrome::FloydSteinberg => floyd_steinberg(image),
+    }
+}
+
+/// Perceived brightness (BT.601 weights), 0–255.
+fn luma(pixel: Rgb<u8>) -> f32 {
+    0.299 * pixel[0] as f32 + 0.587 * pixel[1] as f32 + 0.114 * pixel[2] as f32
+}
+
+/// Thresholds each pixel and spreads the rounding error to the unvisited
+/// neighbours, so gradients become dot patterns instead of flat areas.
+fn floyd_steinberg(image: &mut RgbImage) {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let mut levels: Vec<f32> = image.pixels().map(|p| luma(*p)).collect();
+
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            let white = levels[i] >= 128.0;
+            let error = levels[i] - if white { 255.0 } else { 0.0 };
+            image.put_pixel(x as u32, y as u32, if white { WHITE } else { BLACK });
+
+            let mut spread = |dx: isize, dy: usize, weight: f32| {
+                let nx = x as isize + dx;
+                if nx >= 0 && (nx as usize) < width && y + dy < height {
+                    levels[(y + dy) * width + nx as usize] += error * weight;
+                }
+            };
+            spread(1, 0, 7.0 / 16.0);
+            spread(-1, 1, 3.0 / 16.0);
+            spread(0, 1, 5.0 / 16.0);
+            spread(1, 1, 1.0 / 16.0);
+        }
+    }
+}
+
+/// Encodes a black-and-white frame as a 1-bit grayscale PNG, an eighth of the
+/// size of 8-bit gray and readable by most e-paper firmware.
+pub fn encode_png_1bit(image: &RgbImage) -> Result<Vec<u8>> {
+    let (width, height) = image.dimensions();
+    let row_bytes = (width as usize).div_ceil(8);
+    let mut data = vec![0u8; row_bytes * height as usize];
+    for (x, y, pixel) in image.enumerate_pixels() {
+        if pixel[0] >= 128 {
+            data[y as usize * row_bytes + x as usize / 8] |= 0x80 >> (x % 8);
+        }
+    }
+
+    let mut buffer = Vec::new();
+    let mut encoder = png::Encoder::new(&mut buffer, width, height);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::One);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish()?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_threshold_has_no_grays() {
+        let mut image = RgbImage::from_fn(16, 4, |x, _| Rgb([x as u8 * 16, x as u8 * 16, 200]));
+        apply(&mut image, Monochrome::Threshold);
+
+        assert!(image.pixels().all(|p| *p == BLACK || *p == WHITE));
+        assert_eq!(*image.get_pixel(0, 0), BLACK);
+        assert_eq!(*image.get_pixel(15, 0), WHITE);
+    }
+
+    #[test]
+    fn test_floyd_steinberg_keeps_average_brightness() {
+        let mut image = RgbImage::from_pixel(32, 32, Rgb([64, 64, 64]));
+        apply(&mut image, Monochrome::FloydSteinberg);
+
+        let white = image.pixels().filter(|p| **p == WHITE).count();
+        assert!(image.pixels().all(|p| *p == BLACK || *p == WHITE));
+        // A quarter gray comes out as roughly a quarter white dots
+        assert!((200..=312).contains(&white), "{} white pixels", white);
+    }
+
+    #[test]
+    fn test_png_1bit_round_trip() {
+        let mut image =
+            RgbImage::from_fn(13, 3, |x, y| if (x + y) % 3 == 0 { WHITE } else { BLACK });
+        apply(&mut image, Monochrome::Threshold);
+        let png = encode_png_1bit(&image).unwrap();
+
+        let decoded = image::load_from_memory(&png).unwrap().to_rgb8();
+        assert_eq!(decoded, image);
+    }
+}
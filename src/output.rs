@@ -0,0 +1,208 @@
+//! Writes frames to a file or named pipe, for tooling that doesn't speak MJPEG or RTSP.
+
+use crate::config::FrameOutput;
+use crate::state::AppState;
+use anyhow::{Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long `OutputHandle::stop` waits for the frame being written. A pipe
+/// without a reader can block forever, so shutdown doesn't wait on it longer.
+const STOP_GRACE: Duration = Duration::from_secs(5);
+
+/// The running output thread.
+pub struct OutputHandle {
+    stop: Arc<AtomicBool>,
+    stopped: mpsc::Receiver<()>,
+}
+
+impl OutputHandle {
+    /// Lets the frame being written finish, closes a pipe and returns, so a
+    /// reader never sees a partial frame. Gives up after `STOP_GRACE`.
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if self.stopped.recv_timeout(STOP_GRACE).is_err() {
+            eprintln!("Frame output did not finish within {:?}", STOP_GRACE);
+        }
+    }
+}
+
+/// Starts writing frames at `VIDEO_FPS` on a dedicated thread. Writes to a pipe
+/// block until the reader catches up, so they must stay off the async runtime.
+pub fn spawn(output: FrameOutput, state: AppState) -> Option<OutputHandle> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let (stopped_tx, stopped) = mpsc::channel();
+    let thread_stop = stop.clone();
+    let result = thread::Builder::new()
+        .name("frame-output".to_string())
+        .spawn(move || {
+            run(output, state, &thread_stop);
+            let _ = stopped_tx.send(());
+        });
+    match result {
+        Ok(_) => Some(OutputHandle { stop, stopped }),
+        Err(e) => {
+            eprintln!("Error starting frame output: {}", e);
+            None
+        }
+    }
+}
+
+fn run(output: FrameOutput, state: AppState, stop: &AtomicBool) {
+    match &output {
+        FrameOutput::File(path) => println!("Writing the latest frame to {}", path.display()),
+        FrameOutput::Pipe(path) => println!(
+            "Writing raw RGB24 {}x{} frames to {}",
+            state.config.video_width,
+            state.config.video_height,
+            path.display()
+        ),
+    }
+
+    let frame_duration = state.config.video_fps.frame_duration();
+    let mut pipe = None;
+    let mut failing = false;
+    let mut next_frame = Instant::now();
+
+    // A stop request is only checked between frames, never during a write
+    while !stop.load(Ordering::Relaxed) {
+        thread::sleep(next_frame.saturating_duration_since(Instant::now()));
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+        // After a slow write, wait a full frame instead of bursting to catch up
+        next_frame = (next_frame + frame_duration).max(Instant::now());
+
+        // Log the first failure and the recovery, not every frame in between
+        match write_frame(&output, &state, &mut pipe) {
+            Ok(()) if failing => {
+                println!("Frame output is working again");
+                failing = false;
+            }
+            Ok(()) => {}
+            Err(e) if !failing => {
+                eprintln!("Error writing frame output: {:#}", e);
+                failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+
+    // Closing the pipe tells the reader the last frame was complete
+    if let Some(mut pipe) = pipe {
+        let _ = pipe.flush();
+    }
+}
+
+/// Renders and writes one frame. A pipe is (re)opened as needed, which blocks
+/// until a reader connects; after a write error it is dropped so the next frame
+/// waits for a new reader.
+fn write_frame(output: &FrameOutput, state: &AppState, pipe: &mut Option<File>) -> Result<()> {
+    let values = state.sensor_values.read().unwrap().clone();
+
+    match output {
+        FrameOutput::File(path) => {
+            let jpeg = state.image_gen.generate_frame(&values)?;
+            write_atomically(path, &jpeg)
+        }
+        FrameOutput::Pipe(path) => {
+            if pipe.is_none() {
+                let file = OpenOptions::new()
+                    .write(true)
+                    .open(path)
+                    .with_context(|| format!("Cannot open {}", path.display()))?;
+                *pipe = Some(file);
+            }
+
+            let frame = state.image_gen.generate_raw_frame(&values);
+            let result = pipe.as_mut().unwrap().write_all(&frame);
+            if result.is_err() {
+                *pipe = None;
+            }
+            result.with_context(|| format!("Reader of {} went away", path.display()))
+        }
+    }
+}
+
+/// Writes to a temporary file next to `path` and renames it into place, so readers
+/// never see a partially written frame.
+fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, data).with_context(|| format!("Cannot write {:?}", tmp))?;
+    fs::rename(&tmp, path).with_context(|| format!("Cannot replace {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::testing::test_state;
+    use serial_test::serial;
+
+    #[test]
+    #[serial]
+    fn test_write_frame() {
+        let state = test_state(&[], &[], (64, 32));
+        let dir = std::env::temp_dir().join(format!("ha-streamer-output-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // The JPEG replaces the previous frame without leaving the temporary file behind
+        let jpeg_path = dir.join("frame.jpg");
+        let output = FrameOutput::File(jpeg_path.clone());
+        write_frame(&output, &state, &mut None).unwrap();
+        write_frame(&output, &state, &mut None).unwrap();
+        assert_eq!(&fs::read(&jpeg_path).unwrap()[..2], &[0xFF, 0xD8]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // Raw frames are appended back-to-back through the open handle
+        let raw_path = dir.join("frames.raw");
+        File::create(&raw_path).unwrap();
+        let output = FrameOutput::Pipe(raw_path.clone());
+        let mut pipe = None;
+        write_frame(&output, &state, &mut pipe).unwrap();
+        write_frame(&output, &state, &mut pipe).unwrap();
+        assert_eq!(fs::metadata(&raw_path).unwrap().len(), 2 * 64 * 32 * 3);
+
+        assert!(write_frame(&FrameOutput::Pipe(dir.join("missing")), &state, &mut None).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_stop_never_leaves_partial_frames() {
+        let state = test_state(&[("VIDEO_FPS", "30")], &[], (320, 180));
+        let dir = std::env::temp_dir().join(format!("ha-streamer-stop-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // Stop at different points of the frame cycle, likely catching a write in progress
+        for delay_ms in [1, 17, 40, 75] {
+            let jpeg_path = dir.join("frame.jpg");
+            let handle = spawn(FrameOutput::File(jpeg_path.clone()), state.clone()).unwrap();
+            thread::sleep(Duration::from_millis(delay_ms));
+            handle.stop();
+            // Either no frame yet or a complete JPEG, and no temporary file
+            if let Ok(jpeg) = fs::read(&jpeg_path) {
+                assert_eq!(&jpeg[..2], &[0xFF, 0xD8]);
+                assert_eq!(&jpeg[jpeg.len() - 2..], &[0xFF, 0xD9]);
+            }
+            assert!(fs::read_dir(&dir)
+                .unwrap()
+                .all(|entry| entry.unwrap().file_name() == "frame.jpg"));
+
+            // A regular file stands in for the pipe; it only ever holds whole frames
+            let raw_path = dir.join("frames.raw");
+            File::create(&raw_path).unwrap();
+            let handle = spawn(FrameOutput::Pipe(raw_path.clone()), state.clone()).unwrap();
+            thread::sleep(Duration::from_millis(delay_ms));
+            handle.stop();
+            assert_eq!(fs::metadata(&raw_path).unwrap().len() % (320 * 180 * 3), 0);
+            fs::remove_file(&raw_path).unwrap();
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
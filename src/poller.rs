@@ -0,0 +1,944 @@
+use crate::config::{Config, HaTransport};
+use crate::ha_client::{EntityState, EventStream, HaClient, RateLimited, StateChange};
+use crate::modifiers::parse_window;
+use crate::smoothing::{SampleWindow, Smoother};
+use crate::state::{
+    history_key, resolve_entity_refs, status_summary, AppState, SensorHealth, ALERT_KEY, STATUS_KEY,
+};
+use anyhow::Result;
+use chrono::{Local, NaiveDate, NaiveTime};
+use futures_util::stream::{self, StreamExt};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::time::{interval, sleep, sleep_until, MissedTickBehavior};
+
+/// How long to poll before trying to reopen a failed event stream.
+const EVENT_STREAM_RETRY: Duration = Duration::from_secs(60);
+
+/// Background task that keeps `AppState::sensor_values` up to date.
+pub struct Poller {
+    ha_client: HaClient,
+    state: AppState,
+    sensors: Vec<String>,
+    /// Entity id templates such as `sensor.${input_select.room}_temp`.
+    templated_sensors: Vec<String>,
+    /// What the templates currently resolve to, minus entities already in `sensors`.
+    templated_targets: Vec<String>,
+    grace: Duration,
+    concurrency: usize,
+    breaker_threshold: u32,
+    breaker_retry: Duration,
+    smoothers: HashMap<String, Smoother>,
+    poll_interval: Duration,
+    poll_jitter: f64,
+    poll_stagger: bool,
+    /// Entities shown with `max_today` / `min_today`.
+    history_sensors: Vec<String>,
+    history_refresh: Duration,
+    /// Today's recorded states per entity, refreshed every `history_refresh`.
+    history: HashMap<String, Vec<String>>,
+    history_day: Option<NaiveDate>,
+    /// `avg=` tokens as (entity id, window as written, window).
+    averages: Vec<(String, String, Duration)>,
+    /// Recent samples of entities shown with `avg=`, long enough for their widest window.
+    sample_windows: HashMap<String, SampleWindow>,
+    /// Recent distinct values of entities shown in a `{ticker:...}` line.
+    tickers: HashMap<String, RecentValues>,
+    /// Entities shown with `trend`, whose last different value is kept.
+    trend_sensors: Vec<String>,
+    /// `_PRIMARY_ATTR`: attributes shown instead of the state, keyed by entity id.
+    primary_attrs: HashMap<String, String>,
+    alert_after: Option<Duration>,
+    /// Since when no watched entity could be fetched.
+    down_since: Option<Instant>,
+    transport: HaTransport,
+}
+
+/// The last few distinct values of an entity, newest first.
+struct RecentValues {
+    capacity: usize,
+    values: VecDeque<String>,
+}
+
+impl RecentValues {
+    /// Separates the values in the ticker text.
+    const SEPARATOR: &'static str = "  ·  ";
+
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            values: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Records `value` unless it repeats the newest one.
+    fn push(&mut self, value: &str) {
+        if self.values.front().is_some_and(|newest| newest == value) {
+            return;
+        }
+        self.values.push_front(value.to_string());
+        self.values.truncate(self.capacity);
+    }
+
+    fn text(&self) -> String {
+        let values: Vec<&str> = self.values.iter().map(String::as_str).collect();
+        values.join(Self::SEPARATOR)
+    }
+}
+
+impl Poller {
+    pub fn new(config: &Config, ha_client: HaClient, state: AppState) -> Self {
+        let smoothers = config
+            .sensor_smoothing
+            .iter()
+            .map(|(entity_id, method)| (entity_id.clone(), Smoother::new(*method)))
+            .collect();
+
+        // Windows were validated when the config was loaded
+        let averages: Vec<(String, String, Duration)> = config
+            .get_average_windows()
+            .into_iter()
+            .filter_map(|(entity_id, spec)| {
+                let window = parse_window(&spec)?.to_std().ok()?;
+                Some((entity_id, spec, window))
+            })
+            .collect();
+        let mut sample_windows: HashMap<String, SampleWindow> = HashMap::new();
+        for (entity_id, _, window) in &averages {
+            let retain = sample_windows
+                .get(entity_id)
+                .map_or(*window, |existing| existing.retain().max(*window));
+            sample_windows.insert(entity_id.clone(), SampleWindow::new(retain));
+        }
+
+        let tickers = config
+            .get_ticker_sensors()
+            .into_iter()
+            .map(|(entity_id, count)| (entity_id, RecentValues::new(count)))
+            .collect();
+
+        Self {
+            ha_client,
+            state,
+            sensors: config.get_required_sensors(),
+            templated_sensors: config.get_templated_sensors(),
+            templated_targets: Vec::new(),
+            grace: Duration::from_secs(config.sensor_grace_secs),
+            concurrency: config.fetch_concurrency,
+            breaker_threshold: config.circuit_breaker_threshold,
+            breaker_retry: Duration::from_secs(config.circuit_breaker_retry_secs),
+            smoothers,
+            poll_interval: Duration::from_secs(config.poll_interval_secs),
+            poll_jitter: config.poll_jitter,
+            poll_stagger: config.poll_stagger,
+            history_sensors: config.get_history_sensors(),
+            history_refresh: Duration::from_secs(config.history_refresh_secs.max(1)),
+            history: HashMap::new(),
+            history_day: None,
+            averages,
+            sample_windows,
+            tickers,
+            trend_sensors: config.get_trend_sensors(),
+            primary_attrs: config.sensor_primary_attrs.clone(),
+            alert_after: config.alert_after,
+            down_since: None,
+            transport: config.ha_transport,
+        }
+    }
+
+    pub async fn run(mut self) {
+        let mut next_poll = tokio::time::Instant::now();
+        let mut history = interval(self.history_refresh);
+        history.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let has_history = !self.history_sensors.is_empty();
+
+        // With `HA_TRANSPORT=sse`, polling only runs until the stream is up and
+        // while it is down
+        let use_sse = self.transport == HaTransport::Sse;
+        let mut events: Option<EventStream> = None;
+        let mut next_connect = tokio::time::Instant::now();
+
+        loop {
+            tokio::select! {
+                _ = sleep_until(next_poll), if events.is_none() => {
+                    self.poll_once().await;
+                    // Jitter keeps instances started together from polling in lockstep
+                    let delay = jittered(self.poll_interval, self.poll_jitter, fastrand::f64());
+                    next_poll = tokio::time::Instant::now() + delay;
+                }
+                _ = sleep_until(next_connect), if use_sse && events.is_none() => {
+                    match self.ha_client.open_event_stream().await {
+                        Ok(stream) => {
+                            println!("Receiving state changes from the Home Assistant event stream");
+                            // Catch up on changes made while the stream was down
+                            self.poll_once().await;
+                            events = Some(stream);
+                        }
+                        Err(e) => {
+                            eprintln!(
+                                "Error opening the Home Assistant event stream: {} (polling instead, retrying in {}s)",
+                                e,
+                                EVENT_STREAM_RETRY.as_secs()
+                            );
+                            next_connect = tokio::time::Instant::now() + EVENT_STREAM_RETRY;
+                        }
+                    }
+                }
+                change = next_change(&mut events) => match change {
+                    Ok(Some(change)) => self.apply_change(change).await,
+                    result => {
+                        match result {
+                            Err(e) => eprintln!("Home Assistant event stream failed: {}, polling instead", e),
+                            _ => eprintln!("Home Assistant closed the event stream, polling instead"),
+                        }
+                        events = None;
+                        next_poll = tokio::time::Instant::now();
+                        next_connect = next_poll + EVENT_STREAM_RETRY;
+                    }
+                },
+                _ = history.tick(), if has_history => self.refresh_history().await,
+            }
+        }
+    }
+
+    /// Applies a state change from the event stream if the entity is watched.
+    async fn apply_change(&mut self, change: StateChange) {
+        let watched = self.sensors.contains(&change.entity_id)
+            || self.templated_targets.contains(&change.entity_id);
+        // A removed entity is picked up as missing by the next poll
+        let (true, Some(new_state)) = (watched, change.new_state) else {
+            return;
+        };
+        self.apply(vec![(change.entity_id, Ok(new_state))]);
+
+        // As in `poll_once`, a changed input may point a template at a new entity
+        let added = self.update_templated_targets();
+        if !added.is_empty() {
+            let results = self.fetch_states(&added).await;
+            self.apply(results);
+        }
+    }
+
+    /// Re-fetches today's history for the entities shown with `max_today` / `min_today`.
+    async fn refresh_history(&mut self) {
+        let today = Local::now().date_naive();
+        let start = today
+            .and_time(NaiveTime::MIN)
+            .and_local_timezone(Local)
+            .earliest()
+            .unwrap_or_else(Local::now);
+
+        let client = &self.ha_client;
+        let results = fetch_all(
+            &self.history_sensors,
+            self.concurrency,
+            |entity_id| async move { client.fetch_history(&entity_id, start).await },
+        )
+        .await;
+
+        if self.history_day != Some(today) {
+            self.history.clear();
+            self.history_day = Some(today);
+        }
+        for (entity_id, result) in results {
+            match result {
+                Ok(series) => {
+                    self.history.insert(entity_id, series);
+                }
+                Err(e) => eprintln!("Error fetching history for {}: {}", entity_id, e),
+            }
+        }
+
+        let mut values = self.state.sensor_values.write().unwrap();
+        self.update_daily_extremes(&mut values);
+    }
+
+    /// Writes today's high and low for each history entity, combining the cached
+    /// history with the live value. Non-numeric states are skipped.
+    fn update_daily_extremes(&self, values: &mut HashMap<String, String>) {
+        let today = Local::now().date_naive();
+
+        for entity_id in &self.history_sensors {
+            let current = values.get(entity_id).cloned();
+            let cached = self
+                .history
+                .get(entity_id)
+                .filter(|_| self.history_day == Some(today));
+            let numeric: Vec<(f64, &String)> = cached
+                .into_iter()
+                .flatten()
+                .chain(current.as_ref())
+                .filter_map(|state| {
+                    let n = state.trim().parse::<f64>().ok()?;
+                    n.is_finite().then_some((n, state))
+                })
+                .collect();
+
+            let max = numeric.iter().max_by(|a, b| a.0.total_cmp(&b.0));
+            let min = numeric.iter().min_by(|a, b| a.0.total_cmp(&b.0));
+            for (modifier, extreme) in [("max_today", max), ("min_today", min)] {
+                let key = history_key(entity_id, modifier);
+                match extreme {
+                    Some((_, state)) => values.insert(key, state.trim().to_string()),
+                    None => values.remove(&key),
+                };
+            }
+        }
+    }
+
+    pub async fn poll_once(&mut self) {
+        let entity_ids: Vec<String> = self
+            .sensors
+            .iter()
+            .chain(&self.templated_targets)
+            .cloned()
+            .collect();
+        let results = self.fetch_states(&entity_ids).await;
+        self.apply(results);
+
+        // A changed input may point a templated token at a new entity. Fetch it right
+        // away rather than a cycle later, but only once, so templates can never keep
+        // a cycle going.
+        let added = self.update_templated_targets();
+        if !added.is_empty() {
+            let results = self.fetch_states(&added).await;
+            self.apply(results);
+        }
+    }
+
+    /// Fetches the current state of each entity not held back by its circuit breaker.
+    async fn fetch_states(&self, entity_ids: &[String]) -> Vec<(String, Result<EntityState>)> {
+        // Entities behind an open circuit breaker sit this cycle out
+        let now = Instant::now();
+        let due: Vec<String> = {
+            let health = self.state.sensor_health.read().unwrap();
+            entity_ids
+                .iter()
+                .filter(|id| health.get(*id).is_none_or(|h| h.should_fetch(now)))
+                .cloned()
+                .collect()
+        };
+
+        // Optionally spread the cycle's requests over the first half of the interval
+        let stagger = if self.poll_stagger {
+            self.poll_interval / 2
+        } else {
+            Duration::ZERO
+        };
+
+        let client = &self.ha_client;
+        fetch_all(&due, self.concurrency, |entity_id| async move {
+            if !stagger.is_zero() {
+                sleep(stagger.mul_f64(fastrand::f64())).await;
+            }
+            client.fetch_sensor_state(&entity_id).await
+        })
+        .await
+    }
+
+    /// Re-resolves the templated entity ids against the latest values. Entities no
+    /// template points at anymore are forgotten; the newly targeted ones are returned.
+    fn update_templated_targets(&mut self) -> Vec<String> {
+        let mut targets: Vec<String> = {
+            let values = self.state.sensor_values.read().unwrap();
+            self.templated_sensors
+                .iter()
+                .filter_map(|template| resolve_entity_refs(template, &values))
+                .filter(|entity_id| !self.sensors.contains(entity_id))
+                .collect()
+        };
+        targets.sort();
+        targets.dedup();
+
+        let dropped: Vec<&String> = self
+            .templated_targets
+            .iter()
+            .filter(|id| !targets.contains(id))
+            .collect();
+        if !dropped.is_empty() {
+            let mut health = self.state.sensor_health.write().unwrap();
+            let mut values = self.state.sensor_values.write().unwrap();
+            for entity_id in dropped {
+                health.remove(entity_id);
+                values.remove(entity_id);
+            }
+        }
+
+        let added = targets
+            .iter()
+            .filter(|id| !self.templated_targets.contains(id))
+            .cloned()
+            .collect();
+        self.templated_targets = targets;
+        added
+    }
+
+    /// Applies a whole cycle of results under a single write lock, so readers see
+    /// either the previous cycle or this one, never a mix.
+    fn apply(&mut self, results: Vec<(String, Result<EntityState>)>) {
+        let now = Instant::now();
+        let mut health = self.state.sensor_health.write().unwrap();
+        let mut values = self.state.sensor_values.write().unwrap();
+
+        for (entity_id, result) in results {
+            let entry = health.entry(entity_id.clone()).or_default();
+
+            match result {
+                Ok(entity) => {
+                    if entry.consecutive_failures >= self.breaker_threshold {
+                        println!("{} is responding again, resuming normal polling", entity_id);
+                    }
+                    entry.last_good = Some(now);
+                    entry.consecutive_failures = 0;
+                    entry.retry_at = None;
+                    // Kept for `SMART_FORMAT` and `/debug/frame`
+                    for (name, attribute) in [
+                        ("device_class", entity.device_class),
+                        ("unit", entity.unit),
+                        ("last_changed", entity.last_changed),
+                        ("last_updated", entity.last_updated),
+                    ] {
+                        let key = history_key(&entity_id, name);
+                        match attribute {
+                            Some(attribute) => values.insert(key, attribute),
+                            None => values.remove(&key),
+                        };
+                    }
+                    // Attribute-only sensors often have a state such as `unknown`
+                    let primary = self
+                        .primary_attrs
+                        .get(&entity_id)
+                        .and_then(|name| entity.attributes.get(name))
+                        .filter(|attribute| !attribute.is_null());
+                    let val = match primary {
+                        Some(Value::String(text)) => text.clone(),
+                        Some(attribute) => attribute.to_string(),
+                        None => entity.state,
+                    };
+                    if let Some(samples) = self.sample_windows.get_mut(&entity_id) {
+                        samples.push(now, &val);
+                    }
+                    // `unavailable` and `unknown` tell nothing about what happened
+                    let informative = !matches!(val.as_str(), "unavailable" | "unknown");
+                    if let Some(ticker) = self.tickers.get_mut(&entity_id).filter(|_| informative) {
+                        ticker.push(&val);
+                        values.insert(history_key(&entity_id, "ticker"), ticker.text());
+                    }
+                    let val = match self.smoothers.get_mut(&entity_id) {
+                        Some(smoother) => smoother.push(val),
+                        None => val,
+                    };
+                    let previous = values.insert(entity_id.clone(), val.clone());
+                    if self.trend_sensors.contains(&entity_id) {
+                        if let Some(previous) = previous.filter(|previous| *previous != val) {
+                            values.insert(history_key(&entity_id, "previous"), previous);
+                        }
+                    }
+                }
+                Err(e) if e.downcast_ref::<RateLimited>().is_some() => {
+                    // Expected under load: not a failure, just wait as long as asked
+                    let retry_after = e.downcast_ref::<RateLimited>().unwrap().retry_after;
+                    println!(
+                        "Home Assistant is rate limiting {}, next fetch in {}s",
+                        entity_id,
+                        retry_after.as_secs()
+                    );
+                    entry.retry_at = Some(now + retry_after);
+                    if entry.grace_expired(now, self.grace) {
+                        values.remove(&entity_id);
+                    }
+                }
+                Err(e) => {
+                    entry.last_failure = Some(now);
+                    entry.consecutive_failures += 1;
+
+                    // Log until the breaker opens, then stay quiet until it recovers
+                    if entry.consecutive_failures < self.breaker_threshold {
+                        eprintln!("Error fetching sensor state for {}: {}", entity_id, e);
+                    } else {
+                        if entry.consecutive_failures == self.breaker_threshold {
+                            eprintln!(
+                                "Error fetching sensor state for {}: {} ({} failures in a row, retrying every {}s)",
+                                entity_id,
+                                e,
+                                entry.consecutive_failures,
+                                self.breaker_retry.as_secs()
+                            );
+                        }
+                        entry.retry_at = Some(now + self.breaker_retry);
+                    }
+
+                    // Keep showing the last good value until the grace period runs out
+                    if entry.grace_expired(now, self.grace) {
+                        values.remove(&entity_id);
+                    }
+                }
+            }
+        }
+
+        self.update_daily_extremes(&mut values);
+        for (entity_id, spec, window) in &self.averages {
+            let key = history_key(entity_id, &format!("avg={}", spec));
+            match self.sample_windows[entity_id].mean(now, *window) {
+                Some(mean) => values.insert(key, mean),
+                None => values.remove(&key),
+            };
+        }
+
+        let tracked: Vec<&SensorHealth> = self
+            .sensors
+            .iter()
+            .chain(&self.templated_targets)
+            .filter_map(|id| health.get(id))
+            .collect();
+        values.insert(
+            STATUS_KEY.to_string(),
+            status_summary(tracked.iter().copied()),
+        );
+
+        // Raise the alert once every entity has been failing for long enough
+        let down = !tracked.is_empty() && !tracked.iter().any(|entry| entry.is_ok());
+        self.down_since = if down {
+            self.down_since.or(Some(now))
+        } else {
+            None
+        };
+        match (self.alert_after, self.down_since) {
+            (Some(after), Some(since)) if now.duration_since(since) >= after => {
+                if !values.contains_key(ALERT_KEY) {
+                    eprintln!(
+                        "Home Assistant unreachable for {}s, raising the disconnect alert",
+                        now.duration_since(since).as_secs()
+                    );
+                }
+                values.insert(ALERT_KEY.to_string(), "on".to_string());
+            }
+            _ => {
+                if values.remove(ALERT_KEY).is_some() {
+                    println!("Home Assistant is reachable again, clearing the disconnect alert");
+                }
+            }
+        }
+    }
+}
+
+/// Waits for the next event on `events`, forever if there is no stream.
+async fn next_change(events: &mut Option<EventStream>) -> Result<Option<StateChange>> {
+    match events {
+        Some(stream) => stream.next_change().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Scales `interval` by a random factor in `1 ± jitter`, given `random` in `[0, 1)`.
+fn jittered(interval: Duration, jitter: f64, random: f64) -> Duration {
+    interval.mul_f64((1.0 + jitter * (2.0 * random - 1.0)).max(0.0))
+}
+
+/// Fetches every entity exactly once, running at most `concurrency` requests at a time.
+/// Results are returned in the order of `entity_ids`.
+async fn fetch_all<T, F, Fut>(
+    entity_ids: &[String],
+    concurrency: usize,
+    fetch: F,
+) -> Vec<(String, Result<T>)>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    stream::iter(entity_ids.iter().cloned())
+        .map(|entity_id| {
+            let fut = fetch(entity_id.clone());
+            async move { (entity_id, fut.await) }
+        })
+        .buffered(concurrency.max(1))
+        .collect()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::testing::test_state;
+    use serial_test::serial;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn fetched(value: impl ToString) -> EntityState {
+        EntityState {
+            state: value.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Builds a poller from the current environment plus the given extra variables.
+    fn test_poller(vars: &[(&str, &str)]) -> (Poller, AppState) {
+        let state = test_state(vars, &[], (64, 64));
+        let poller = Poller::new(&state.config, HaClient::new(&state.config), state.clone());
+        (poller, state)
+    }
+
+    #[test]
+    fn test_jittered_interval() {
+        let interval = Duration::from_secs(10);
+        assert_eq!(jittered(interval, 0.1, 0.0), Duration::from_secs(9));
+        assert_eq!(jittered(interval, 0.1, 0.5), interval);
+        assert_eq!(jittered(interval, 0.0, 0.9), interval);
+        for _ in 0..100 {
+            let delay = jittered(interval, 0.1, fastrand::f64());
+            assert!(delay >= Duration::from_secs(9) && delay <= Duration::from_secs(11));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_all_respects_concurrency() {
+        let ids: Vec<String> = (0..8).map(|i| format!("sensor.s{}", i)).collect();
+        let active = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+
+        let results = fetch_all(&ids, 3, |entity_id| {
+            let (active, peak) = (&active, &peak);
+            async move {
+                let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                sleep(Duration::from_millis(10)).await;
+                active.fetch_sub(1, Ordering::SeqCst);
+                Ok(entity_id)
+            }
+        })
+        .await;
+
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+        let returned: Vec<&String> = results.iter().map(|(id, _)| id).collect();
+        assert_eq!(returned, ids.iter().collect::<Vec<_>>());
+        assert!(results.iter().all(|(id, val)| val.as_ref().unwrap() == id));
+    }
+
+    #[test]
+    #[serial]
+    fn test_apply_is_atomic_for_readers() {
+        let (mut poller, state) = test_poller(&[]);
+        let ids: Vec<String> = (0..20).map(|i| format!("sensor.s{}", i)).collect();
+
+        let reader_values = state.sensor_values.clone();
+        let reader = std::thread::spawn(move || {
+            for _ in 0..2000 {
+                let values = reader_values.read().unwrap();
+                let mut distinct: Vec<&String> = values
+                    .iter()
+                    .filter(|(key, _)| key.as_str() != STATUS_KEY)
+                    .map(|(_, val)| val)
+                    .collect();
+                distinct.dedup();
+                // A half-applied cycle would mix values from two rounds
+                assert!(distinct.len() <= 1, "observed a partial update");
+            }
+        });
+
+        for round in 0..500 {
+            let results = ids
+                .iter()
+                .map(|id| (id.clone(), Ok(fetched(round))))
+                .collect();
+            poller.apply(results);
+        }
+
+        reader.join().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_daily_extremes_include_live_value() {
+        let (mut poller, state) = test_poller(&[("LINE_1", "{sensor.out:max_today}")]);
+        let id = "sensor.out".to_string();
+        poller.history_day = Some(Local::now().date_naive());
+        poller.history.insert(
+            id.clone(),
+            vec!["12.5", "unavailable", "18.0", "9.5"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+        );
+
+        poller.apply(vec![(id.clone(), Ok(fetched("14.0")))]);
+        let values = state.sensor_values.read().unwrap().clone();
+        assert_eq!(values[&history_key(&id, "max_today")], "18.0");
+        assert_eq!(values[&history_key(&id, "min_today")], "9.5");
+
+        poller.apply(vec![(id.clone(), Ok(fetched("19.5")))]);
+        let values = state.sensor_values.read().unwrap().clone();
+        assert_eq!(values[&history_key(&id, "max_today")], "19.5");
+    }
+
+    #[test]
+    #[serial]
+    fn test_previous_value_for_trend() {
+        let (mut poller, state) = test_poller(&[("LINE_1", "{sensor.out:trend=0.5}")]);
+        let id = "sensor.out".to_string();
+        let key = history_key(&id, "previous");
+
+        poller.apply(vec![(id.clone(), Ok(fetched("14.0")))]);
+        assert!(!state.sensor_values.read().unwrap().contains_key(&key));
+
+        // Repeated values keep the last different one
+        poller.apply(vec![(id.clone(), Ok(fetched("14.5")))]);
+        poller.apply(vec![(id.clone(), Ok(fetched("14.5")))]);
+        assert_eq!(state.sensor_values.read().unwrap()[&key], "14.0");
+    }
+
+    #[test]
+    #[serial]
+    fn test_average_window() {
+        let (mut poller, state) = test_poller(&[("LINE_1", "{sensor.power:avg=60s}")]);
+        let id = "sensor.power".to_string();
+        let key = history_key(&id, "avg=60s");
+
+        poller.apply(vec![(id.clone(), Ok(fetched("unavailable")))]);
+        assert!(!state.sensor_values.read().unwrap().contains_key(&key));
+
+        for sample in ["100", "200", "300"] {
+            poller.apply(vec![(id.clone(), Ok(fetched(sample)))]);
+        }
+        assert_eq!(state.sensor_values.read().unwrap()[&key], "200");
+    }
+
+    #[test]
+    #[serial]
+    fn test_ticker_keeps_recent_changes() {
+        let (mut poller, state) = test_poller(&[("LINE_1", "{ticker:sensor.events|count=2}")]);
+
+        for event in ["Door opened", "Door opened", "Alarm off", "Door closed"] {
+            poller.apply(vec![("sensor.events".to_string(), Ok(fetched(event)))]);
+        }
+
+        let values = state.sensor_values.read().unwrap();
+        assert_eq!(values["sensor.events:ticker"], "Door closed  ·  Alarm off");
+        assert_eq!(values["sensor.events"], "Door closed");
+    }
+
+    #[test]
+    #[serial]
+    fn test_ticker_skips_unavailable_states() {
+        let (mut poller, state) = test_poller(&[("LINE_1", "{ticker:sensor.events}")]);
+
+        poller.apply(vec![("sensor.events".to_string(), Ok(fetched("unknown")))]);
+        assert!(!state
+            .sensor_values
+            .read()
+            .unwrap()
+            .contains_key("sensor.events:ticker"));
+
+        for event in ["Door opened", "unavailable", "Door closed", "unknown"] {
+            poller.apply(vec![("sensor.events".to_string(), Ok(fetched(event)))]);
+        }
+
+        let values = state.sensor_values.read().unwrap();
+        assert_eq!(
+            values["sensor.events:ticker"],
+            "Door closed  ·  Door opened"
+        );
+        assert_eq!(values["sensor.events"], "unknown");
+    }
+
+    #[test]
+    #[serial]
+    fn test_templated_targets_follow_input() {
+        let (mut poller, state) = test_poller(&[
+            ("LINE_1", "{sensor.${input_select.room}_temp}"),
+            ("LINE_2", "{sensor.kitchen_temp}"),
+        ]);
+        assert_eq!(
+            poller.sensors,
+            vec!["input_select.room", "sensor.kitchen_temp"]
+        );
+
+        // Nothing to resolve until the input has a state
+        assert!(poller.update_templated_targets().is_empty());
+
+        poller.apply(vec![
+            ("input_select.room".to_string(), Ok(fetched("Living Room"))),
+            ("sensor.kitchen_temp".to_string(), Ok(fetched("19"))),
+        ]);
+        assert_eq!(
+            poller.update_templated_targets(),
+            vec!["sensor.living_room_temp"]
+        );
+        poller.apply(vec![(
+            "sensor.living_room_temp".to_string(),
+            Ok(fetched("21")),
+        )]);
+        assert!(poller.update_templated_targets().is_empty());
+
+        // Switching to an entity that is polled anyway drops the old target
+        poller.apply(vec![(
+            "input_select.room".to_string(),
+            Ok(fetched("Kitchen")),
+        )]);
+        assert!(poller.update_templated_targets().is_empty());
+        assert!(poller.templated_targets.is_empty());
+        let values = state.sensor_values.read().unwrap();
+        assert!(!values.contains_key("sensor.living_room_temp"));
+        assert!(!state
+            .sensor_health
+            .read()
+            .unwrap()
+            .contains_key("sensor.living_room_temp"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_circuit_breaker_opens_and_recovers() {
+        let (mut poller, state) = test_poller(&[("CIRCUIT_BREAKER_THRESHOLD", "3")]);
+        let id = "sensor.typo".to_string();
+        let fail = || vec![(id.clone(), Err(anyhow::anyhow!("404")))];
+
+        poller.apply(fail());
+        poller.apply(fail());
+        assert!(state.sensor_health.read().unwrap()[&id].retry_at.is_none());
+
+        poller.apply(fail());
+        let health = state.sensor_health.read().unwrap()[&id].clone();
+        assert_eq!(health.consecutive_failures, 3);
+        assert!(!health.should_fetch(Instant::now()));
+
+        poller.apply(vec![(id.clone(), Ok(fetched("1")))]);
+        let health = state.sensor_health.read().unwrap()[&id].clone();
+        assert_eq!(health.consecutive_failures, 0);
+        assert!(health.should_fetch(Instant::now()));
+    }
+
+    #[test]
+    #[serial]
+    fn test_rate_limit_backs_off_without_failing() {
+        let (mut poller, state) = test_poller(&[("CIRCUIT_BREAKER_THRESHOLD", "1")]);
+        let id = "sensor.temp".to_string();
+        poller.apply(vec![(id.clone(), Ok(fetched("21")))]);
+
+        let limited = RateLimited {
+            retry_after: Duration::from_secs(120),
+        };
+        poller.apply(vec![(id.clone(), Err(limited.into()))]);
+
+        let health = state.sensor_health.read().unwrap()[&id].clone();
+        assert_eq!(health.consecutive_failures, 0);
+        assert!(health.is_ok());
+        assert!(!health.should_fetch(Instant::now() + Duration::from_secs(119)));
+        assert!(health.should_fetch(Instant::now() + Duration::from_secs(121)));
+        assert_eq!(state.sensor_values.read().unwrap()[&id], "21");
+    }
+
+    #[test]
+    #[serial]
+    fn test_disconnect_alert() {
+        let (mut poller, state) = test_poller(&[
+            ("LINE_1", "{sensor.temp}"),
+            ("ALERT_ON_DISCONNECT", "on"),
+            ("ALERT_AFTER_SECS", "0"),
+        ]);
+        let id = "sensor.temp".to_string();
+        let alert = || state.sensor_values.read().unwrap().contains_key(ALERT_KEY);
+
+        poller.apply(vec![(id.clone(), Ok(fetched("21")))]);
+        assert!(!alert());
+
+        poller.apply(vec![(
+            id.clone(),
+            Err(anyhow::anyhow!("connection refused")),
+        )]);
+        assert!(alert());
+
+        poller.apply(vec![(id.clone(), Ok(fetched("21")))]);
+        assert!(!alert());
+    }
+
+    #[test]
+    #[serial]
+    fn test_primary_attribute_replaces_state() {
+        let (mut poller, state) = test_poller(&[
+            ("LINE_1", "{sensor.weather} {sensor.mode}"),
+            ("SENSOR_WEATHER_PRIMARY_ATTR", "temperature"),
+            ("SENSOR_MODE_PRIMARY_ATTR", "preset"),
+        ]);
+        let attribute_only = |state: &str, attributes: serde_json::Value| EntityState {
+            state: state.to_string(),
+            attributes: serde_json::from_value(attributes).unwrap(),
+            ..Default::default()
+        };
+
+        poller.apply(vec![
+            (
+                "sensor.weather".to_string(),
+                Ok(attribute_only(
+                    "unknown",
+                    serde_json::json!({"temperature": 21.5, "humidity": 40}),
+                )),
+            ),
+            (
+                "sensor.mode".to_string(),
+                Ok(attribute_only("on", serde_json::json!({"preset": "eco"}))),
+            ),
+        ]);
+        let values = state.sensor_values.read().unwrap().clone();
+        assert_eq!(values["sensor.weather"], "21.5");
+        assert_eq!(values["sensor.mode"], "eco");
+
+        // Without the attribute the state is shown
+        poller.apply(vec![(
+            "sensor.mode".to_string(),
+            Ok(attribute_only("off", serde_json::json!({"preset": null}))),
+        )]);
+        assert_eq!(state.sensor_values.read().unwrap()["sensor.mode"], "off");
+    }
+
+    #[test]
+    #[serial]
+    fn test_attributes_are_kept_for_formatting() {
+        let (mut poller, state) = test_poller(&[]);
+        let id = "sensor.temp".to_string();
+        let entity = EntityState {
+            state: "21.46".to_string(),
+            device_class: Some("temperature".to_string()),
+            unit: Some("°C".to_string()),
+            last_changed: Some("2024-03-09T07:00:00+00:00".to_string()),
+            last_updated: Some("2024-03-09T07:05:00+00:00".to_string()),
+            ..Default::default()
+        };
+
+        poller.apply(vec![(id.clone(), Ok(entity))]);
+        let values = state.sensor_values.read().unwrap().clone();
+        assert_eq!(values[&history_key(&id, "device_class")], "temperature");
+        assert_eq!(values[&history_key(&id, "unit")], "°C");
+        assert_eq!(
+            values[&history_key(&id, "last_changed")],
+            "2024-03-09T07:00:00+00:00"
+        );
+        assert_eq!(
+            values[&history_key(&id, "last_updated")],
+            "2024-03-09T07:05:00+00:00"
+        );
+
+        poller.apply(vec![(id.clone(), Ok(fetched("21.5")))]);
+        let values = state.sensor_values.read().unwrap().clone();
+        assert!(!values.contains_key(&history_key(&id, "unit")));
+        assert!(!values.contains_key(&history_key(&id, "last_changed")));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_event_stream_changes_update_watched_entities() {
+        let (mut poller, state) = test_poller(&[("LINE_1", "{sensor.temp}")]);
+        let change = |entity_id: &str, value: &str| StateChange {
+            entity_id: entity_id.to_string(),
+            new_state: Some(fetched(value)),
+        };
+
+        poller.apply_change(change("sensor.temp", "22")).await;
+        poller.apply_change(change("sensor.other", "1")).await;
+
+        let values = state.sensor_values.read().unwrap().clone();
+        assert_eq!(values["sensor.temp"], "22");
+        assert!(!values.contains_key("sensor.other"));
+        assert_eq!(values[STATUS_KEY], "HA OK (1/1)");
+    }
+}
@@ -0,0 +1,220 @@
+//! `ha-sensor-streamer render --out frame.png [--set sensor.temp=22.5 ...]`: draws
+//! a single frame to a PNG and exits, to iterate on layout, colors and fonts
+//! without a streaming client.
+//!
+//! `RENDER_FRAMES=N` renders N stream frames instead and reports how long they
+//! took, to measure throughput on the target hardware.
+
+use crate::config::Config;
+use crate::demo::demo_values;
+use crate::ha_client::HaClient;
+use crate::image_gen::ImageGenerator;
+use crate::poller::Poller;
+use crate::state::{AppState, STATUS_KEY};
+use anyhow::{bail, Context, Result};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, PartialEq)]
+pub struct RenderArgs {
+    pub out: PathBuf,
+    /// `--set entity=value` mock values. When given, Home Assistant is not contacted.
+    pub values: Vec<(String, String)>,
+}
+
+/// Parses the arguments after the program name. `None` unless the first one is `render`.
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Option<Result<RenderArgs>> {
+    let mut args = args.into_iter();
+    if args.next()? != "render" {
+        return None;
+    }
+    Some(parse_options(args))
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> Result<RenderArgs> {
+    let mut render = RenderArgs {
+        out: PathBuf::from("frame.png"),
+        values: Vec::new(),
+    };
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .with_context(|| format!("{} needs a value", arg))?;
+        match arg.as_str() {
+            "--out" => render.out = PathBuf::from(value),
+            "--set" => {
+                let (entity_id, state) = value
+                    .split_once('=')
+                    .with_context(|| format!("--set expects entity=value (got '{}')", value))?;
+                render
+                    .values
+                    .push((entity_id.trim().to_string(), state.to_string()));
+            }
+            other => bail!("unknown render option '{}', expected --out or --set", other),
+        }
+    }
+    Ok(render)
+}
+
+/// Fills in the values (mocks, demo values or one live fetch), renders the frame
+/// and writes it as PNG.
+pub async fn run(config: &Config, image_gen: Arc<ImageGenerator>, args: &RenderArgs) -> Result<()> {
+    let state = AppState::new(config.clone(), image_gen);
+    fill_values(config, &state, &args.values).await;
+
+    let values = state.sensor_values.read().unwrap().clone();
+    let png = state.image_gen.generate_png(&values)?;
+    std::fs::write(&args.out, png)
+        .with_context(|| format!("Failed to write {}", args.out.display()))?;
+    println!(
+        "Wrote a {}x{} frame to {}",
+        config.video_width,
+        config.video_height,
+        args.out.display()
+    );
+    Ok(())
+}
+
+/// Renders `count` frames the way the configured stream does (raw RGB for RTSP,
+/// JPEG otherwise), discards them and prints the total and per-frame time.
+pub async fn run_frames(config: &Config, image_gen: Arc<ImageGenerator>, count: u32) -> Result<()> {
+    let state = AppState::new(config.clone(), image_gen);
+    fill_values(config, &state, &[]).await;
+
+    let elapsed = render_frames(&state, count)?;
+    println!(
+        "Rendered {} {}x{} frames in {:.2}s, {:.2} ms/frame",
+        count,
+        config.video_width,
+        config.video_height,
+        elapsed.as_secs_f64(),
+        elapsed.as_secs_f64() * 1000.0 / count as f64
+    );
+    Ok(())
+}
+
+fn render_frames(state: &AppState, count: u32) -> Result<Duration> {
+    let values = state.sensor_values.read().unwrap().clone();
+    let image_gen = &state.image_gen;
+    let raw = state.config.stream_format == "rtsp";
+
+    let start = Instant::now();
+    for _ in 0..count {
+        if raw {
+            image_gen.generate_raw_frame(&values);
+        } else {
+            image_gen.generate_frame(&values)?;
+        }
+    }
+    Ok(start.elapsed())
+}
+
+/// Mock values when given, else the demo values or a single fetch from Home Assistant.
+async fn fill_values(config: &Config, state: &AppState, mocks: &[(String, String)]) {
+    if !mocks.is_empty() {
+        let mut values = state.sensor_values.write().unwrap();
+        values.extend(mocks.iter().cloned());
+    } else if config.demo {
+        let mut values = state.sensor_values.write().unwrap();
+        for (entity_id, value) in demo_values(Duration::ZERO) {
+            values.insert(entity_id.to_string(), value);
+        }
+        values.insert(STATUS_KEY.to_string(), "DEMO".to_string());
+    } else if !config.get_required_sensors().is_empty() {
+        println!("Fetching sensors from {}", config.ha_base_url);
+        let mut poller = Poller::new(config, HaClient::new(config), state.clone());
+        poller.poll_once().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::testing::{test_config, test_image_gen};
+    use serial_test::serial;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert!(parse_args(args(&[])).is_none());
+        assert!(parse_args(args(&["check"])).is_none());
+
+        assert_eq!(
+            parse_args(args(&["render"])).unwrap().unwrap(),
+            RenderArgs {
+                out: PathBuf::from("frame.png"),
+                values: vec![],
+            }
+        );
+        assert_eq!(
+            parse_args(args(&[
+                "render",
+                "--out",
+                "/tmp/x.png",
+                "--set",
+                "sensor.temp=22.5",
+                "--set",
+                "sensor.mode=a=b",
+            ]))
+            .unwrap()
+            .unwrap(),
+            RenderArgs {
+                out: PathBuf::from("/tmp/x.png"),
+                values: vec![
+                    ("sensor.temp".to_string(), "22.5".to_string()),
+                    ("sensor.mode".to_string(), "a=b".to_string()),
+                ],
+            }
+        );
+
+        assert!(parse_args(args(&["render", "--out"])).unwrap().is_err());
+        assert!(parse_args(args(&["render", "--set", "22.5"]))
+            .unwrap()
+            .is_err());
+        assert!(parse_args(args(&["render", "--size", "2"]))
+            .unwrap()
+            .is_err());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_render_writes_png() {
+        let config = test_config(&[]);
+        let image_gen = test_image_gen(&["{sensor.temp}"], (64, 32));
+        let out =
+            std::env::temp_dir().join(format!("ha-streamer-render-{}.png", std::process::id()));
+        let args = RenderArgs {
+            out: out.clone(),
+            values: vec![("sensor.temp".to_string(), "22.5".to_string())],
+        };
+
+        // Mock values never reach out to Home Assistant
+        run(&config, image_gen, &args).await.unwrap();
+
+        let png = image::open(&out).unwrap();
+        assert_eq!((png.width(), png.height()), (64, 32));
+        std::fs::remove_file(&out).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_render_frames() {
+        let mut config = test_config(&[]);
+        let image_gen = test_image_gen(&["{sensor.temp}"], (64, 32));
+
+        for stream_format in ["mjpeg", "rtsp"] {
+            config.stream_format = stream_format.to_string();
+            let state = AppState::new(config.clone(), image_gen.clone());
+            assert!(render_frames(&state, 5).is_ok());
+        }
+
+        // Frames too large to encode fail instead of reporting a bogus time
+        config.stream_format = "mjpeg".to_string();
+        let image_gen = test_image_gen(&[], (70000, 1));
+        assert!(render_frames(&AppState::new(config, image_gen), 1).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! `SHARED_RENDERER=on`: one thread draws each frame and every MJPEG viewer or the
+//! RTSP stream picks up the latest one, instead of each rendering its own copy.
+
+use crate::state::AppState;
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+use tokio::sync::watch;
+
+/// The most recently rendered frame. Consumers `subscribe` to it; a frame that
+/// is replaced before a slow consumer reads it is simply skipped.
+pub type LatestFrame = Arc<watch::Sender<Bytes>>;
+
+/// Encoding of the published frames, matching the active stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameFormat {
+    Jpeg,
+    /// Packed RGB24, as pushed into the RTSP pipeline.
+    Raw,
+}
+
+/// Renders the first frame right away, so consumers never see an empty one, and
+/// then keeps rendering at `VIDEO_FPS` on a dedicated thread.
+pub fn spawn(state: &AppState, format: FrameFormat) -> Result<LatestFrame> {
+    let mut last_drawn = None;
+    let first = render(state, format, &mut last_drawn)?.expect("Nothing was drawn before");
+    let (frames, _) = watch::channel(first);
+    let frames = Arc::new(frames);
+
+    let (thread_frames, thread_state) = (frames.clone(), state.clone());
+    thread::Builder::new()
+        .name("renderer".to_string())
+        .spawn(move || run(&thread_frames, &thread_state, format, last_drawn))
+        .context("Failed to start the renderer thread")?;
+
+    Ok(frames)
+}
+
+fn run(
+    frames: &LatestFrame,
+    state: &AppState,
+    format: FrameFormat,
+    mut last_drawn: Option<Vec<u8>>,
+) {
+    let frame_duration = state.config.video_fps.frame_duration();
+    let mut next_frame = Instant::now();
+
+    loop {
+        thread::sleep(next_frame.saturating_duration_since(Instant::now()));
+        // After a slow frame, wait a full frame instead of bursting to catch up
+        next_frame = (next_frame + frame_duration).max(Instant::now());
+
+        // Nobody is watching, e.g. no MJPEG viewer is connected
+        if frames.receiver_count() == 0 {
+            continue;
+        }
+
+        match render(state, format, &mut last_drawn) {
+            Ok(Some(frame)) => {
+                frames.send_replace(frame);
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("Error generating frame: {}", e),
+        }
+    }
+}
+
+/// Draws a frame and encodes it, or returns `None` if it looks exactly like
+/// `last_drawn`. Comparing the drawn pixels rather than the sensor values keeps
+/// clocks and animations moving, while a static screen costs no encoding and
+/// wakes no consumer.
+fn render(
+    state: &AppState,
+    format: FrameFormat,
+    last_drawn: &mut Option<Vec<u8>>,
+) -> Result<Option<Bytes>> {
+    let values = state.sensor_values.read().unwrap().clone();
+    let raw = state.image_gen.generate_raw_frame(&values);
+    if last_drawn.as_ref() == Some(&raw) {
+        return Ok(None);
+    }
+
+    let frame = match format {
+        FrameFormat::Jpeg => state.image_gen.encode_raw_frame(raw.clone())?.into(),
+        FrameFormat::Raw => Bytes::from(raw.clone()),
+    };
+    *last_drawn = Some(raw);
+    Ok(Some(frame))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::testing::test_state;
+    use serial_test::serial;
+
+    #[tokio::test]
+    #[serial]
+    async fn test_renderer_publishes_latest_values() {
+        let state = test_state(&[("VIDEO_FPS", "20")], &["{sensor.temp}"], (64, 32));
+
+        let frames = spawn(&state, FrameFormat::Raw).unwrap();
+        let mut rx = frames.subscribe();
+        let placeholder = rx.borrow_and_update().clone();
+        assert_eq!(placeholder.len(), 64 * 32 * 3);
+
+        state
+            .sensor_values
+            .write()
+            .unwrap()
+            .insert("sensor.temp".to_string(), "21".to_string());
+        let expected = Bytes::from(
+            state
+                .image_gen
+                .generate_raw_frame(&state.sensor_values.read().unwrap()),
+        );
+
+        // Within a few frames the new value is published
+        let updated = tokio::time::timeout(std::time::Duration::from_secs(2), async {
+            loop {
+                rx.changed().await.unwrap();
+                if *rx.borrow_and_update() == expected {
+                    break;
+                }
+            }
+        })
+        .await;
+        assert!(updated.is_ok());
+        assert_ne!(placeholder, expected);
+    }
+
+    #[test]
+    #[serial]
+    fn test_unchanged_frames_are_not_encoded_again() {
+        let state = test_state(&[], &["{sensor.temp}"], (64, 32));
+        let set_temp = |temp: &str| {
+            state
+                .sensor_values
+                .write()
+                .unwrap()
+                .insert("sensor.temp".to_string(), temp.to_string());
+        };
+        set_temp("21");
+
+        let mut last_drawn = None;
+        let first = render(&state, FrameFormat::Jpeg, &mut last_drawn).unwrap();
+        assert_eq!(&first.unwrap()[..2], &[0xFF, 0xD8]);
+        assert!(render(&state, FrameFormat::Jpeg, &mut last_drawn)
+            .unwrap()
+            .is_none());
+
+        set_temp("22");
+        assert!(render(&state, FrameFormat::Jpeg, &mut last_drawn)
+            .unwrap()
+            .is_some());
+        assert!(render(&state, FrameFormat::Jpeg, &mut last_drawn)
+            .unwrap()
+            .is_none());
+    }
+}
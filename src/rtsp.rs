@@ -1,11 +1,14 @@
+use crate::config::{ColorRange, FrameRate, TlsFiles};
 use crate::state::AppState;
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use gstreamer as gst;
 use gstreamer_app as gst_app;
 use gstreamer_rtsp_server as gst_rtsp_server;
 use gstreamer_rtsp_server::prelude::*;
 use gstreamer_video as gst_video;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 pub fn run_rtsp_server(config: &crate::config::Config, app_state: AppState) -> Result<()> {
     gst::init()?;
@@ -18,16 +21,7 @@ pub fn run_rtsp_server(config: &crate::config::Config, app_state: AppState) -> R
         .context("Could not get mount points")?;
     let factory = gst_rtsp_server::RTSPMediaFactory::new();
 
-    // Define the pipeline
-    // appsrc -> videoconvert -> x264enc -> rtph264pay
-    // We use speed-preset=ultrafast and tune=zerolatency for real-time performance
-    let pipeline_str = "appsrc name=src format=time is-live=true do-timestamp=true \
-        ! videoconvert \
-        ! x264enc speed-preset=ultrafast tune=zerolatency \
-        ! rtph264pay name=pay0 pt=96"
-        .to_string();
-
-    factory.set_launch(&pipeline_str);
+    factory.set_launch(&pipeline_launch(config.rtsp_color_range));
     factory.set_shared(true); // Share the pipeline among clients?
                               // Actually, for appsrc, sharing is tricky if we don't manage the push loop centrally.
                               // If shared=false (default), every client gets its own appsrc and its own generation loop.
@@ -37,7 +31,14 @@ pub fn run_rtsp_server(config: &crate::config::Config, app_state: AppState) -> R
     // Clone state for the closure
     let state = Arc::new(app_state);
 
+    // A fatal pipeline error stops the main loop so the process can exit non-zero
+    let main_loop = gst::glib::MainLoop::new(None, false);
+    let fatal_error: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+    let (watch_loop, watch_error) = (main_loop.clone(), fatal_error.clone());
+
     factory.connect_media_configure(move |_factory, media| {
+        watch_pipeline_bus(media, watch_loop.clone(), watch_error.clone());
+
         let element = media.element();
         let appsrc_element = element
             .downcast_ref::<gst::Bin>()
@@ -52,68 +53,448 @@ pub fn run_rtsp_server(config: &crate::config::Config, app_state: AppState) -> R
         // Setup the video info
         let width = state.config.video_width as i32;
         let height = state.config.video_height as i32;
-        let fps = state.config.video_fps as i32;
+        let fps = state.config.video_fps;
 
         let video_info =
             gst_video::VideoInfo::builder(gst_video::VideoFormat::Rgb, width as u32, height as u32)
-                .fps(gst::Fraction::new(fps, 1))
+                .fps(gst::Fraction::new(fps.num as i32, fps.den as i32))
                 .build()
                 .expect("Failed to create video info");
 
         appsrc.set_caps(Some(&video_info.to_caps().unwrap()));
         appsrc.set_format(gst::Format::Time);
 
-        // We need to keep a mutable state for the timestamp/frame count inside the callback
-        // The callback is called from GStreamer threads.
+        // Every media gets its own clock starting at PTS 0. With a shared factory a new
+        // media is only configured once all previous clients are gone, so reconnecting
+        // clients always start on a fresh, continuous timeline.
         let state_clone = state.clone();
-        let mut timestamp = 0u64;
-        let frame_duration = 1_000_000_000 / (fps as u64); // duration in ns
+        let mut clock = FrameClock::new(fps, Instant::now());
+        let latest_frame = state.latest_frame.as_ref().map(|frames| frames.subscribe());
 
         let callbacks = gst_app::AppSrcCallbacks::builder()
             .need_data(move |appsrc, _hint| {
-                // Check current sensor value
-                let val_map = {
-                    let lock = state_clone.sensor_values.read().unwrap();
-                    lock.clone()
+                // Take the shared renderer's latest frame, or render one here
+                let raw_bytes = match &latest_frame {
+                    Some(frames) => frames.borrow().clone(),
+                    None => {
+                        let val_map = {
+                            let lock = state_clone.sensor_values.read().unwrap();
+                            lock.clone()
+                        };
+                        // Note: ImageGenerator now returns raw RGB bytes for RTSP efficiency.
+                        state_clone.image_gen.generate_raw_frame(&val_map).into()
+                    }
                 };
 
-                // Generate frame
-                // Note: ImageGenerator now returns raw RGB bytes for RTSP efficiency.
-                let raw_bytes = state_clone.image_gen.generate_raw_frame(&val_map);
-
                 // Create buffer
                 let mut buffer = gst::Buffer::from_slice(raw_bytes);
 
                 // Set timestamps
-                let pts = timestamp;
+                let (pts, duration) = clock.next_frame(Instant::now());
                 {
                     let buffer_ref = buffer.get_mut().unwrap();
                     buffer_ref.set_pts(gst::ClockTime::from_nseconds(pts));
-                    buffer_ref.set_duration(gst::ClockTime::from_nseconds(frame_duration));
+                    buffer_ref.set_duration(gst::ClockTime::from_nseconds(duration));
                 }
 
                 // Push buffer
                 let _ = appsrc.push_buffer(buffer);
-                timestamp += frame_duration;
             })
             .build();
 
         appsrc.set_callbacks(callbacks);
     });
 
+    let scheme = match &config.rtsp_tls {
+        Some(tls) => {
+            server.set_auth(Some(&tls_auth(tls)?));
+            factory.add_role_from_structure(
+                &gst::Structure::builder(ANONYMOUS_ROLE)
+                    .field(gst_rtsp_server::RTSP_PERM_MEDIA_FACTORY_ACCESS, true)
+                    .field(gst_rtsp_server::RTSP_PERM_MEDIA_FACTORY_CONSTRUCT, true)
+                    .build(),
+            );
+            println!("RTSP TLS enabled with certificate {}", tls.cert_path);
+            "rtsps"
+        }
+        None => {
+            println!("RTSP TLS disabled, set RTSP_TLS_CERT and RTSP_TLS_KEY to serve rtsps://");
+            "rtsp"
+        }
+    };
+
     mounts.add_factory("/stream", factory);
 
+    if let Some(max_clients) = config.rtsp_max_clients {
+        limit_clients(&server, max_clients);
+    }
+
+    // Frames are pushed at a fixed cadence and the caps carry the exact rate
     println!(
-        "RTSP Server listening on rtsp://0.0.0.0:{}/stream",
-        config.port
+        "RTSP frame rate: {} fps (constant, {} ms per frame)",
+        config.video_fps,
+        config.video_fps.frame_duration().as_millis()
+    );
+
+    println!(
+        "RTSP Server listening on {}://0.0.0.0:{}/stream",
+        scheme, config.port
     );
 
     // Attach the server to the default main context
     server.attach(None)?;
 
     // Run the main loop
-    let main_loop = gst::glib::MainLoop::new(None, false);
     main_loop.run();
 
+    if let Some(error) = fatal_error.lock().unwrap().take() {
+        bail!("RTSP pipeline failed: {}", error);
+    }
+
     Ok(())
 }
+
+/// Role of every client on an RTSPS server, which authenticates nobody.
+const ANONYMOUS_ROLE: &str = "anonymous";
+
+/// Makes the server negotiate TLS with every client using the `RTSP_TLS_CERT` /
+/// `RTSP_TLS_KEY` files. Any client that completes the handshake may play.
+fn tls_auth(tls: &TlsFiles) -> Result<gst_rtsp_server::RTSPAuth> {
+    let certificate =
+        gst_rtsp_server::gio::TlsCertificate::from_files(&tls.cert_path, &tls.key_path)
+            .with_context(|| {
+                format!(
+                    "Failed to load the RTSP TLS certificate {} and key {}",
+                    tls.cert_path, tls.key_path
+                )
+            })?;
+
+    let auth = gst_rtsp_server::RTSPAuth::new();
+    auth.set_tls_certificate(Some(&certificate));
+    // Once a server has an auth, clients without a token are turned away
+    let mut token = gst_rtsp_server::RTSPToken::builder()
+        .field(
+            gst_rtsp_server::RTSP_TOKEN_MEDIA_FACTORY_ROLE,
+            ANONYMOUS_ROLE,
+        )
+        .build();
+    auth.set_default_token(Some(&mut token));
+    Ok(auth)
+}
+
+/// The media pipeline: appsrc -> videoconvert -> x264enc -> rtph264pay.
+///
+/// The caps after `videoconvert` pin the YUV color range, and x264enc writes it
+/// into the stream's VUI so players expand the levels correctly. We use
+/// speed-preset=ultrafast and tune=zerolatency for real-time performance.
+fn pipeline_launch(color_range: ColorRange) -> String {
+    format!(
+        "appsrc name=src format=time is-live=true do-timestamp=true \
+         ! videoconvert \
+         ! video/x-raw,format=I420,colorimetry={} \
+         ! x264enc speed-preset=ultrafast tune=zerolatency \
+         ! rtph264pay name=pay0 pt=96",
+        color_range.colorimetry()
+    )
+}
+
+/// Presentation timestamps for one media's frames. Timestamps are derived from the
+/// frame index with exact rational math, so rates like 30000/1001 don't drift the
+/// way summing a rounded frame duration would.
+///
+/// Guarantees:
+/// - PTS lie on the exact frame grid, start at 0 and strictly increase.
+/// - Normally each `need_data` gets the next frame. If calls were missed (a stalled
+///   pipeline or client), the index catches up with the time elapsed since the
+///   media started, so PTS stay in step with the time shown in the frame.
+/// - Elapsed time comes from the monotonic clock, so changes of the system time
+///   (NTP steps, DST) have no effect.
+/// - The math is 128-bit and saturates at `u64::MAX` ns (about 584 years) instead
+///   of wrapping.
+struct FrameClock {
+    fps: FrameRate,
+    start: Instant,
+    frame: u64,
+}
+
+impl FrameClock {
+    fn new(fps: FrameRate, start: Instant) -> Self {
+        Self {
+            fps,
+            start,
+            frame: 0,
+        }
+    }
+
+    fn pts_of(&self, frame: u64) -> u64 {
+        let pts = frame as u128 * 1_000_000_000 * self.fps.den as u128 / self.fps.num as u128;
+        pts.min(u64::MAX as u128) as u64
+    }
+
+    /// Index of the frame due `elapsed` after the start.
+    fn frame_at(&self, elapsed: Duration) -> u64 {
+        let frame =
+            elapsed.as_nanos() * self.fps.num as u128 / (1_000_000_000 * self.fps.den as u128);
+        frame.min(u64::MAX as u128) as u64
+    }
+
+    /// Returns the PTS and duration (in ns) of the frame pushed at `now`.
+    fn next_frame(&mut self, now: Instant) -> (u64, u64) {
+        let due = self.frame_at(now.saturating_duration_since(self.start));
+        let frame = self.frame.max(due);
+        self.frame = frame.saturating_add(1);
+        let pts = self.pts_of(frame);
+        (pts, self.pts_of(self.frame) - pts)
+    }
+}
+
+/// The RTSP clients being served, out of at most `max`.
+struct ClientSlots {
+    active: AtomicUsize,
+    max: usize,
+}
+
+impl ClientSlots {
+    fn new(max: usize) -> Self {
+        Self {
+            active: AtomicUsize::new(0),
+            max,
+        }
+    }
+
+    /// Takes a slot, or returns `false` when all of them are in use.
+    fn try_acquire(&self) -> bool {
+        self.active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+                (active < self.max).then_some(active + 1)
+            })
+            .is_ok()
+    }
+
+    fn release(&self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Serves at most `max_clients` RTSP clients at once. A client connecting beyond
+/// that gets `503 Service Unavailable` to its DESCRIBE and takes no slot, so it
+/// can only play once it reconnects after another client left.
+fn limit_clients(server: &gst_rtsp_server::RTSPServer, max_clients: usize) {
+    let slots = Arc::new(ClientSlots::new(max_clients));
+    server.connect_client_connected(move |_server, client| {
+        if slots.try_acquire() {
+            let slots = slots.clone();
+            client.connect_closed(move |_client| slots.release());
+            return;
+        }
+        eprintln!(
+            "Rejecting an RTSP client, RTSP_MAX_CLIENTS ({}) are already connected",
+            max_clients
+        );
+        client.connect_pre_describe_request(|_client, _ctx| {
+            gst_rtsp_server::gst_rtsp::RTSPStatusCode::ServiceUnavailable
+        });
+    });
+}
+
+/// Logs errors, warnings and EOS from a media pipeline. Errors are fatal: the encoder
+/// or caps negotiation cannot recover on its own, so the main loop is stopped and the
+/// error handed back to `run_rtsp_server`, letting the container restart us.
+///
+/// The media owns the bus watch, so this uses a sync handler that passes every
+/// message on unchanged.
+fn watch_pipeline_bus(
+    media: &gst_rtsp_server::RTSPMedia,
+    main_loop: gst::glib::MainLoop,
+    fatal_error: Arc<Mutex<Option<String>>>,
+) {
+    let Some(bus) = media
+        .element()
+        .parent()
+        .and_then(|parent| parent.downcast::<gst::Pipeline>().ok())
+        .and_then(|pipeline| pipeline.bus())
+    else {
+        eprintln!("Could not get the RTSP pipeline bus, pipeline errors will not be reported");
+        return;
+    };
+
+    bus.set_sync_handler(move |_bus, msg| {
+        use gst::MessageView;
+
+        let source = msg
+            .src()
+            .map(|s| s.path_string().to_string())
+            .unwrap_or_default();
+        match msg.view() {
+            MessageView::Error(err) => {
+                let error = format!(
+                    "{} from {} ({})",
+                    err.error(),
+                    source,
+                    err.debug().as_deref().unwrap_or("no details")
+                );
+                eprintln!("RTSP pipeline error: {}", error);
+                fatal_error.lock().unwrap().get_or_insert(error);
+                main_loop.quit();
+            }
+            MessageView::Warning(warning) => {
+                eprintln!("RTSP pipeline warning: {} from {}", warning.error(), source);
+            }
+            MessageView::Eos(_) => println!("RTSP pipeline reached end of stream"),
+            _ => {}
+        }
+
+        gst::BusSyncReply::Pass
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::testing::{test_config, test_image_gen};
+    use serial_test::serial;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_frame_clock_starts_at_zero_and_does_not_drift() {
+        let ntsc = FrameRate {
+            num: 30000,
+            den: 1001,
+        };
+        let start = Instant::now();
+        let mut clock = FrameClock::new(ntsc, start);
+        assert_eq!(clock.next_frame(start), (0, 33_366_666));
+
+        let mut last_end = 33_366_666;
+        for _ in 1..30000 {
+            let (pts, duration) = clock.next_frame(start);
+            // Each frame starts exactly where the previous one ended
+            assert_eq!(pts, last_end);
+            last_end = pts + duration;
+        }
+        // 30000 frames take exactly 1001 seconds
+        assert_eq!(last_end, 1_001_000_000_000);
+
+        // A new media (e.g. after all clients reconnect) starts over at 0
+        assert_eq!(FrameClock::new(ntsc, start).next_frame(start).0, 0);
+    }
+
+    #[test]
+    fn test_frame_clock_catches_up_after_missed_frames() {
+        let fps = FrameRate { num: 5, den: 1 };
+        let start = Instant::now();
+        let mut clock = FrameClock::new(fps, start);
+        assert_eq!(clock.next_frame(start).0, 0);
+
+        // No need_data for 10 s: the next frame is the one due now, not frame 1
+        let later = start + Duration::from_secs(10);
+        assert_eq!(clock.next_frame(later), (10_000_000_000, 200_000_000));
+        // Calls faster than real time still advance by one frame each
+        assert_eq!(clock.next_frame(later).0, 10_200_000_000);
+        assert_eq!(clock.next_frame(later).0, 10_400_000_000);
+    }
+
+    #[test]
+    fn test_frame_clock_long_running() {
+        let ntsc = FrameRate {
+            num: 30000,
+            den: 1001,
+        };
+        let start = Instant::now();
+
+        // A year in, timestamps are still exact
+        let year = Duration::from_secs(365 * 24 * 3600);
+        let mut clock = FrameClock::new(ntsc, start);
+        let (pts, duration) = clock.next_frame(start + year);
+        assert!(pts <= year.as_nanos() as u64);
+        assert!(year.as_nanos() as u64 - pts < duration);
+
+        // Near the end of the u64 range they saturate instead of wrapping
+        let mut clock = FrameClock::new(ntsc, start);
+        clock.frame = u64::MAX - 1;
+        let (pts, duration) = clock.next_frame(start);
+        assert_eq!((pts, duration), (u64::MAX, 0));
+        assert_eq!(clock.next_frame(start).0, u64::MAX);
+    }
+
+    #[test]
+    fn test_client_slots() {
+        let slots = ClientSlots::new(2);
+        assert!(slots.try_acquire());
+        assert!(slots.try_acquire());
+        assert!(!slots.try_acquire());
+
+        // A client leaving frees its slot for the next one
+        slots.release();
+        assert!(slots.try_acquire());
+        assert!(!slots.try_acquire());
+    }
+
+    #[test]
+    fn test_tls_auth_reports_unreadable_files() {
+        let tls = TlsFiles {
+            cert_path: "/nonexistent/cert.pem".to_string(),
+            key_path: "/nonexistent/key.pem".to_string(),
+        };
+
+        let err = tls_auth(&tls).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Failed to load the RTSP TLS certificate /nonexistent/cert.pem"));
+    }
+
+    #[test]
+    fn test_pipeline_tags_color_range() {
+        assert!(pipeline_launch(ColorRange::Limited).contains("colorimetry=bt709 "));
+        assert!(pipeline_launch(ColorRange::Full).contains("colorimetry=1:3:5:1 "));
+    }
+
+    /// Pulls a few frames from a live server and checks they decode at the configured size.
+    /// Needs GStreamer with the x264, rtsp and decoder plugins installed.
+    #[test]
+    #[serial]
+    #[ignore = "requires GStreamer runtime plugins"]
+    fn test_rtsp_stream_decodes() {
+        let mut config = test_config(&[("VIDEO_WIDTH", "320"), ("VIDEO_HEIGHT", "240")]);
+
+        // Grab a free port for the server
+        config.port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let image_gen = test_image_gen(&[], (config.video_width, config.video_height));
+        let state = AppState::new(config.clone(), image_gen);
+        let server_config = config.clone();
+        std::thread::spawn(move || run_rtsp_server(&server_config, state));
+        std::thread::sleep(std::time::Duration::from_secs(1));
+
+        let pipeline = gst::parse::launch(&format!(
+            "rtspsrc location=rtsp://127.0.0.1:{}/stream latency=0 \
+             ! rtph264depay ! h264parse ! decodebin ! videoconvert \
+             ! video/x-raw,format=RGB ! appsink name=sink",
+            config.port
+        ))
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        let sink = pipeline
+            .by_name("sink")
+            .unwrap()
+            .downcast::<gst_app::AppSink>()
+            .unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+
+        for _ in 0..3 {
+            let sample = sink
+                .try_pull_sample(gst::ClockTime::from_seconds(10))
+                .expect("no frame received from RTSP stream");
+            let info = gst_video::VideoInfo::from_caps(sample.caps().unwrap()).unwrap();
+            assert_eq!(info.width(), 320);
+            assert_eq!(info.height(), 240);
+        }
+
+        pipeline.set_state(gst::State::Null).unwrap();
+    }
+}
@@ -0,0 +1,227 @@
+use anyhow::{bail, Result};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How a noisy numeric sensor is smoothed before it is stored for display.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SmoothingMethod {
+    /// Median of the last `n` samples.
+    Median(usize),
+    /// Exponential moving average with the given weight for the newest sample.
+    Ema(f64),
+}
+
+impl SmoothingMethod {
+    /// Parses `median3`, `median:5`, `ema` or `ema:0.3`.
+    pub fn parse(value: &str) -> Result<Self> {
+        let value = value.trim().to_lowercase();
+
+        if let Some(window) = value.strip_prefix("median") {
+            let window = window.trim_start_matches(':');
+            let window = if window.is_empty() {
+                3
+            } else {
+                window.parse::<usize>()?
+            };
+            if window == 0 {
+                bail!("median window must be at least 1");
+            }
+            return Ok(SmoothingMethod::Median(window));
+        }
+
+        if let Some(alpha) = value.strip_prefix("ema") {
+            let alpha = alpha.trim_start_matches(':');
+            let alpha = if alpha.is_empty() {
+                0.3
+            } else {
+                alpha.parse::<f64>()?
+            };
+            if !(alpha > 0.0 && alpha <= 1.0) {
+                bail!("ema weight must be in (0, 1]");
+            }
+            return Ok(SmoothingMethod::Ema(alpha));
+        }
+
+        bail!("unknown smoothing method '{}'", value)
+    }
+}
+
+/// Per-entity smoothing state, fed with every successfully fetched value.
+pub struct Smoother {
+    method: SmoothingMethod,
+    samples: VecDeque<(f64, String)>,
+    ema: Option<f64>,
+}
+
+impl Smoother {
+    pub fn new(method: SmoothingMethod) -> Self {
+        Self {
+            method,
+            samples: VecDeque::new(),
+            ema: None,
+        }
+    }
+
+    /// Adds a raw value and returns the value to display.
+    /// Non-numeric values, including `inf` and `NaN`, bypass smoothing and reset the history.
+    pub fn push(&mut self, raw: String) -> String {
+        let Some(value) = raw.parse::<f64>().ok().filter(|n| n.is_finite()) else {
+            self.samples.clear();
+            self.ema = None;
+            return raw;
+        };
+
+        match self.method {
+            SmoothingMethod::Median(window) => {
+                self.samples.push_back((value, raw));
+                while self.samples.len() > window {
+                    self.samples.pop_front();
+                }
+
+                // Return an actual sample so the sensor's own formatting is kept
+                let mut sorted: Vec<&(f64, String)> = self.samples.iter().collect();
+                sorted.sort_by(|a, b| a.0.total_cmp(&b.0));
+                sorted[(sorted.len() - 1) / 2].1.clone()
+            }
+            SmoothingMethod::Ema(alpha) => {
+                let smoothed = match self.ema {
+                    Some(prev) => alpha * value + (1.0 - alpha) * prev,
+                    None => value,
+                };
+                self.ema = Some(smoothed);
+
+                let decimals = raw.split_once('.').map_or(0, |(_, frac)| frac.len());
+                format!("{:.*}", decimals, smoothed)
+            }
+        }
+    }
+}
+
+/// Timestamped numeric samples of one entity, retained for `avg=` windows.
+pub struct SampleWindow {
+    retain: Duration,
+    /// (fetched at, value, decimals in the raw state)
+    samples: VecDeque<(Instant, f64, usize)>,
+}
+
+impl SampleWindow {
+    pub fn new(retain: Duration) -> Self {
+        Self {
+            retain,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn retain(&self) -> Duration {
+        self.retain
+    }
+
+    /// Records a fetched value and drops samples older than the retention.
+    /// Non-numeric values are ignored.
+    pub fn push(&mut self, now: Instant, raw: &str) {
+        if let Ok(value) = raw.trim().parse::<f64>() {
+            if value.is_finite() {
+                let decimals = raw.split_once('.').map_or(0, |(_, frac)| frac.trim().len());
+                self.samples.push_back((now, value, decimals));
+            }
+        }
+        while self
+            .samples
+            .front()
+            .is_some_and(|(at, _, _)| now.duration_since(*at) > self.retain)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Mean of the samples from the last `window`, formatted with the samples' precision.
+    pub fn mean(&self, now: Instant, window: Duration) -> Option<String> {
+        let recent: Vec<_> = self
+            .samples
+            .iter()
+            .filter(|(at, _, _)| now.duration_since(*at) <= window)
+            .collect();
+        if recent.is_empty() {
+            return None;
+        }
+
+        let mean = recent.iter().map(|(_, value, _)| value).sum::<f64>() / recent.len() as f64;
+        let decimals = recent.iter().map(|(_, _, d)| *d).max().unwrap_or(0);
+        Some(format!("{:.*}", decimals, mean))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_methods() {
+        assert_eq!(
+            SmoothingMethod::parse("median3").unwrap(),
+            SmoothingMethod::Median(3)
+        );
+        assert_eq!(
+            SmoothingMethod::parse("median:5").unwrap(),
+            SmoothingMethod::Median(5)
+        );
+        assert_eq!(
+            SmoothingMethod::parse("EMA:0.5").unwrap(),
+            SmoothingMethod::Ema(0.5)
+        );
+        assert!(SmoothingMethod::parse("median0").is_err());
+        assert!(SmoothingMethod::parse("ema:2").is_err());
+        assert!(SmoothingMethod::parse("mean").is_err());
+    }
+
+    #[test]
+    fn test_median_and_ema() {
+        let mut median = Smoother::new(SmoothingMethod::Median(3));
+        assert_eq!(median.push("22.4".to_string()), "22.4");
+        assert_eq!(median.push("22.5".to_string()), "22.4");
+        assert_eq!(median.push("22.4".to_string()), "22.4");
+        assert_eq!(median.push("22.5".to_string()), "22.5");
+        assert_eq!(median.push("30.0".to_string()), "22.5");
+
+        // Non-numeric values pass through untouched
+        assert_eq!(median.push("unavailable".to_string()), "unavailable");
+        assert_eq!(median.push("21.0".to_string()), "21.0");
+
+        let mut ema = Smoother::new(SmoothingMethod::Ema(0.5));
+        assert_eq!(ema.push("20.0".to_string()), "20.0");
+        assert_eq!(ema.push("22.0".to_string()), "21.0");
+        assert_eq!(ema.push("22".to_string()), "22");
+
+        // A non-finite reading doesn't poison the average
+        assert_eq!(ema.push("inf".to_string()), "inf");
+        assert_eq!(ema.push("20.0".to_string()), "20.0");
+    }
+
+    #[test]
+    fn test_sample_window_mean() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut window = SampleWindow::new(Duration::from_secs(60));
+        assert_eq!(window.mean(start, Duration::from_secs(60)), None);
+
+        window.push(at(0), "100");
+        window.push(at(10), "unavailable");
+        window.push(at(20), "200");
+        window.push(at(30), "150.4");
+        assert_eq!(
+            window.mean(at(30), Duration::from_secs(60)).unwrap(),
+            "150.1"
+        );
+        assert_eq!(
+            window.mean(at(30), Duration::from_secs(15)).unwrap(),
+            "175.2"
+        );
+
+        // Samples older than the retention are dropped
+        window.push(at(85), "0");
+        assert_eq!(
+            window.mean(at(85), Duration::from_secs(600)).unwrap(),
+            "75.2"
+        );
+    }
+}
@@ -1,11 +1,255 @@
-use crate::config::Config;
+use crate::config::{Config, ENTITY_REF_PATTERN};
 use crate::image_gen::ImageGenerator;
+use crate::renderer::LatestFrame;
+use regex::Regex;
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::{Duration, Instant};
+
+/// Key in `AppState::sensor_values` holding the `{status}` summary. Entity ids
+/// always contain a dot, so it cannot clash with a real entity.
+pub const STATUS_KEY: &str = "status";
+
+/// Key in `AppState::sensor_values` that is present while the disconnect alert
+/// is raised (`ALERT_ON_DISCONNECT`).
+pub const ALERT_KEY: &str = "alert";
+
+/// Key in `AppState::sensor_values` for a value derived from an entity's history,
+/// e.g. `sensor.temp:max_today`.
+pub fn history_key(entity_id: &str, modifier: &str) -> String {
+    format!("{}:{}", entity_id, modifier)
+}
+
+static ENTITY_REF_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(ENTITY_REF_PATTERN).expect("Invalid entity reference regex"));
+
+/// Replaces each `${domain.object_id}` in `template` with that entity's state,
+/// slugified the way Home Assistant builds entity ids (`Living Room` becomes
+/// `living_room`). Returns `None` while a referenced entity has no usable state.
+///
+/// References are expanded in a single pass and a slug never contains `$`, so a
+/// state cannot inject further references.
+pub fn resolve_entity_refs(template: &str, values: &HashMap<String, String>) -> Option<String> {
+    let mut missing = false;
+    let resolved = ENTITY_REF_REGEX.replace_all(template, |caps: &regex::Captures| {
+        match values.get(&caps[1]).map(|state| slugify(state)) {
+            Some(slug) if !slug.is_empty() && slug != "unknown" && slug != "unavailable" => slug,
+            _ => {
+                missing = true;
+                String::new()
+            }
+        }
+    });
+    (!missing).then(|| resolved.into_owned())
+}
+
+/// Lowercases `text` and joins its alphanumeric runs with `_`.
+fn slugify(text: &str) -> String {
+    text.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(str::to_ascii_lowercase)
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+/// Fetch bookkeeping for a single entity.
+#[derive(Clone, Debug, Default)]
+pub struct SensorHealth {
+    pub last_good: Option<Instant>,
+    pub last_failure: Option<Instant>,
+    pub consecutive_failures: u32,
+    /// The entity is not fetched again before this instant (circuit breaker / back-off).
+    pub retry_at: Option<Instant>,
+}
+
+impl SensorHealth {
+    /// Returns true once the last good value is older than `grace` (or there never was one).
+    pub fn grace_expired(&self, now: Instant, grace: Duration) -> bool {
+        match self.last_good {
+            Some(last_good) => now.duration_since(last_good) > grace,
+            None => true,
+        }
+    }
+
+    /// Whether the entity should be fetched in a cycle starting at `now`.
+    pub fn should_fetch(&self, now: Instant) -> bool {
+        self.retry_at.is_none_or(|at| now >= at)
+    }
+
+    /// True when the most recent fetch succeeded.
+    pub fn is_ok(&self) -> bool {
+        match (self.last_good, self.last_failure) {
+            (Some(good), Some(failure)) => good >= failure,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct AppState {
     pub sensor_values: Arc<RwLock<HashMap<String, String>>>,
+    pub sensor_health: Arc<RwLock<HashMap<String, SensorHealth>>>,
     pub image_gen: Arc<ImageGenerator>,
     pub config: Config,
+    /// Frames from the shared renderer, `None` unless `SHARED_RENDERER=on`.
+    pub latest_frame: Option<LatestFrame>,
+}
+
+/// Summarizes entity health for the `{status}` token, e.g. `HA OK (3/3)`.
+pub fn status_summary<'a>(health: impl Iterator<Item = &'a SensorHealth>) -> String {
+    let (mut ok, mut total) = (0, 0);
+    for entry in health {
+        total += 1;
+        if entry.is_ok() {
+            ok += 1;
+        }
+    }
+
+    let label = if ok == total {
+        "OK"
+    } else if ok == 0 {
+        "DOWN"
+    } else {
+        "DEGRADED"
+    };
+    format!("HA {} ({}/{})", label, ok, total)
+}
+
+impl AppState {
+    pub fn new(config: Config, image_gen: Arc<ImageGenerator>) -> Self {
+        Self {
+            sensor_values: Arc::new(RwLock::new(HashMap::new())),
+            sensor_health: Arc::new(RwLock::new(HashMap::new())),
+            image_gen,
+            config,
+            latest_frame: None,
+        }
+    }
+
+    /// Whether Home Assistant answered the latest fetch of at least one entity.
+    /// `None` until any entity has been fetched.
+    pub fn ha_reachable(&self) -> Option<bool> {
+        let health = self.sensor_health.read().unwrap();
+        if health.is_empty() {
+            return None;
+        }
+        Some(health.values().any(SensorHealth::is_ok))
+    }
+}
+
+/// Fixtures for tests that need a `Config` or an `AppState`. They set and then
+/// remove env vars, so the calling test must be `#[serial]`.
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+
+    /// A `Config` for a local Home Assistant, with `vars` set while it is read.
+    pub fn test_config(vars: &[(&str, &str)]) -> Config {
+        std::env::set_var("HA_BASE_URL", "http://localhost:8123");
+        std::env::set_var("HA_LONG_LIVED_TOKEN", "test_token");
+        for (key, value) in vars {
+            std::env::set_var(key, value);
+        }
+        let config = Config::from_env().unwrap();
+        std::env::remove_var("HA_BASE_URL");
+        std::env::remove_var("HA_LONG_LIVED_TOKEN");
+        for (key, _) in vars {
+            std::env::remove_var(key);
+        }
+        config
+    }
+
+    /// A generator drawing `lines` into a `width`x`height` frame.
+    pub fn test_image_gen(lines: &[&str], (width, height): (u32, u32)) -> Arc<ImageGenerator> {
+        let font_data = include_bytes!("../assets/Lato-Regular.ttf");
+        let lines = lines.iter().map(|line| line.to_string()).collect();
+        Arc::new(ImageGenerator::new(font_data, lines, 24.0, "en_US", width, height).unwrap())
+    }
+
+    /// An `AppState` for [`test_config`] that draws `lines` into a `width`x`height` frame.
+    pub fn test_state(vars: &[(&str, &str)], lines: &[&str], size: (u32, u32)) -> AppState {
+        AppState::new(test_config(vars), test_image_gen(lines, size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grace_expired() {
+        let grace = Duration::from_secs(30);
+        let start = Instant::now();
+
+        assert!(SensorHealth::default().grace_expired(start, grace));
+
+        let health = SensorHealth {
+            last_good: Some(start),
+            last_failure: Some(start + Duration::from_secs(5)),
+            ..Default::default()
+        };
+        assert!(!health.grace_expired(start + Duration::from_secs(10), grace));
+        assert!(!health.grace_expired(start + grace, grace));
+        assert!(health.grace_expired(start + Duration::from_secs(31), grace));
+        assert!(!health.is_ok());
+        assert!(SensorHealth {
+            last_good: Some(start + Duration::from_secs(6)),
+            ..health
+        }
+        .is_ok());
+    }
+
+    #[test]
+    fn test_resolve_entity_refs() {
+        let mut values = HashMap::new();
+        values.insert("input_select.room".to_string(), "Living Room".to_string());
+        values.insert("input_select.floor".to_string(), "unavailable".to_string());
+        values.insert(
+            "input_text.evil".to_string(),
+            "${input_text.evil}".to_string(),
+        );
+
+        assert_eq!(
+            resolve_entity_refs("sensor.${input_select.room}_temp", &values).as_deref(),
+            Some("sensor.living_room_temp")
+        );
+        assert_eq!(
+            resolve_entity_refs("sensor.plain", &values).as_deref(),
+            Some("sensor.plain")
+        );
+        assert_eq!(
+            resolve_entity_refs("sensor.${input_select.floor}", &values),
+            None
+        );
+        assert_eq!(
+            resolve_entity_refs("sensor.${input_select.none}", &values),
+            None
+        );
+        // A state that looks like a reference is not expanded again
+        assert_eq!(
+            resolve_entity_refs("sensor.${input_text.evil}", &values).as_deref(),
+            Some("sensor.input_text_evil")
+        );
+    }
+
+    #[test]
+    fn test_status_summary() {
+        let now = Instant::now();
+        let ok = SensorHealth {
+            last_good: Some(now),
+            ..Default::default()
+        };
+        let failing = SensorHealth {
+            last_failure: Some(now),
+            ..Default::default()
+        };
+
+        assert_eq!(status_summary([&ok, &ok].into_iter()), "HA OK (2/2)");
+        assert_eq!(
+            status_summary([&ok, &failing, &ok].into_iter()),
+            "HA DEGRADED (2/3)"
+        );
+        assert_eq!(status_summary([&failing].into_iter()), "HA DOWN (0/1)");
+    }
 }
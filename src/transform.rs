@@ -0,0 +1,250 @@
+//! `LINE_n_TRANSFORM=upper|trim|truncate:20`: text shaping applied in order to a
+//! line after its tokens are substituted.
+
+use anyhow::{bail, Context, Result};
+use image::Rgb;
+use regex::Regex;
+
+/// One step of a `LINE_n_TRANSFORM` pipeline.
+#[derive(Clone, Debug)]
+pub enum TextTransform {
+    Upper,
+    Lower,
+    /// Strips leading and trailing whitespace.
+    Trim,
+    /// Keeps the first `n` characters and ends the line with `…`.
+    Truncate(usize),
+    /// `replace:PATTERN=>REPLACEMENT`, a regular expression over the whole line.
+    Replace(Regex, String),
+    /// `map:FROM=TO,FROM=TO`: swaps a line that is exactly `FROM` for `TO`.
+    Map(Vec<(String, String)>),
+}
+
+impl PartialEq for TextTransform {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (TextTransform::Upper, TextTransform::Upper)
+            | (TextTransform::Lower, TextTransform::Lower)
+            | (TextTransform::Trim, TextTransform::Trim) => true,
+            (TextTransform::Truncate(a), TextTransform::Truncate(b)) => a == b,
+            (TextTransform::Replace(a, with_a), TextTransform::Replace(b, with_b)) => {
+                a.as_str() == b.as_str() && with_a == with_b
+            }
+            (TextTransform::Map(a), TextTransform::Map(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Parses steps separated by `|`, each a name with an optional `:` argument.
+/// Arguments are kept as written, so `replace:\s+=> ` replaces with a space.
+pub fn parse_pipeline(value: &str) -> Result<Vec<TextTransform>> {
+    value
+        .split('|')
+        .filter(|step| !step.trim().is_empty())
+        .map(parse_step)
+        .collect()
+}
+
+fn parse_step(step: &str) -> Result<TextTransform> {
+    let (name, arg) = match step.split_once(':') {
+        Some((name, arg)) => (name.trim(), Some(arg)),
+        None => (step.trim(), None),
+    };
+    Ok(match (name.to_lowercase().as_str(), arg) {
+        ("upper", None) => TextTransform::Upper,
+        ("lower", None) => TextTransform::Lower,
+        ("trim", None) => TextTransform::Trim,
+        ("truncate", Some(len)) => TextTransform::Truncate(
+            len.trim()
+                .parse()
+                .with_context(|| format!("truncate needs a length (got '{}')", len))?,
+        ),
+        ("replace", Some(rule)) => {
+            let (pattern, replacement) = rule
+                .split_once("=>")
+                .context("replace expects pattern=>replacement")?;
+            TextTransform::Replace(Regex::new(pattern)?, replacement.to_string())
+        }
+        ("map", Some(pairs)) => TextTransform::Map(
+            pairs
+                .split(',')
+                .map(|pair| {
+                    let (from, to) = pair
+                        .split_once('=')
+                        .with_context(|| format!("map expects FROM=TO (got '{}')", pair))?;
+                    Ok((from.trim().to_string(), to.trim().to_string()))
+                })
+                .collect::<Result<_>>()?,
+        ),
+        ("upper" | "lower" | "trim", Some(_)) => bail!("{} takes no argument", name),
+        ("truncate" | "replace" | "map", None) => bail!("{} needs an argument", name),
+        _ => bail!(
+            "unknown transform '{}', expected upper, lower, trim, truncate, replace or map",
+            name
+        ),
+    })
+}
+
+/// Runs `transforms` over a line drawn as colored spans. Case changes, `trim` and
+/// `truncate` keep each span's color; `replace` and `map` work on the whole line
+/// and, when they change it, leave a single span in the first span's color.
+pub fn apply(
+    transforms: &[TextTransform],
+    mut spans: Vec<(String, Rgb<u8>)>,
+) -> Vec<(String, Rgb<u8>)> {
+    for transform in transforms {
+        match transform {
+            TextTransform::Upper => {
+                for (text, _) in &mut spans {
+                    *text = text.to_uppercase();
+                }
+            }
+            TextTransform::Lower => {
+                for (text, _) in &mut spans {
+                    *text = text.to_lowercase();
+                }
+            }
+            TextTransform::Trim => {
+                while spans
+                    .first()
+                    .is_some_and(|(text, _)| text.trim().is_empty())
+                {
+                    spans.remove(0);
+                }
+                while spans.last().is_some_and(|(text, _)| text.trim().is_empty()) {
+                    spans.pop();
+                }
+                if let Some((text, _)) = spans.first_mut() {
+                    *text = text.trim_start().to_string();
+                }
+                if let Some((text, _)) = spans.last_mut() {
+                    *text = text.trim_end().to_string();
+                }
+            }
+            TextTransform::Truncate(len) => {
+                let mut left = *len;
+                for i in 0..spans.len() {
+                    let text = &mut spans[i].0;
+                    match text.char_indices().nth(left) {
+                        Some((cut, _)) => {
+                            text.truncate(cut);
+                            text.push('…');
+                            spans.truncate(i + 1);
+                            break;
+                        }
+                        None => left -= text.chars().count(),
+                    }
+                }
+            }
+            TextTransform::Replace(pattern, replacement) => {
+                spans = replace_line(spans, |line| {
+                    pattern.replace_all(line, replacement.as_str()).to_string()
+                });
+            }
+            TextTransform::Map(pairs) => {
+                spans = replace_line(spans, |line| {
+                    pairs
+                        .iter()
+                        .find(|(from, _)| from == line)
+                        .map_or(line.to_string(), |(_, to)| to.clone())
+                });
+            }
+        }
+    }
+    spans
+}
+
+/// Runs `replace` over the joined text of `spans`, merging them only if it changed.
+fn replace_line(
+    spans: Vec<(String, Rgb<u8>)>,
+    replace: impl Fn(&str) -> String,
+) -> Vec<(String, Rgb<u8>)> {
+    let line: String = spans.iter().map(|(text, _)| text.as_str()).collect();
+    let replaced = replace(&line);
+    match spans.first() {
+        Some(&(_, color)) if replaced != line => vec![(replaced, color)],
+        _ => spans,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: Rgb<u8> = Rgb([255, 255, 255]);
+    const RED: Rgb<u8> = Rgb([255, 0, 0]);
+
+    fn spans(list: &[(&str, Rgb<u8>)]) -> Vec<(String, Rgb<u8>)> {
+        list.iter()
+            .map(|(text, color)| (text.to_string(), *color))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_pipeline() {
+        assert_eq!(
+            parse_pipeline("upper | trim|truncate:20").unwrap(),
+            vec![
+                TextTransform::Upper,
+                TextTransform::Trim,
+                TextTransform::Truncate(20)
+            ]
+        );
+        assert_eq!(
+            parse_pipeline("map:on=Open, off=Closed").unwrap(),
+            vec![TextTransform::Map(vec![
+                ("on".to_string(), "Open".to_string()),
+                ("off".to_string(), "Closed".to_string()),
+            ])]
+        );
+        assert_eq!(
+            parse_pipeline(r"replace:\s+=> ").unwrap(),
+            vec![TextTransform::Replace(
+                Regex::new(r"\s+").unwrap(),
+                " ".to_string()
+            )]
+        );
+        assert!(parse_pipeline("").unwrap().is_empty());
+
+        assert!(parse_pipeline("shout").is_err());
+        assert!(parse_pipeline("truncate").is_err());
+        assert!(parse_pipeline("truncate:many").is_err());
+        assert!(parse_pipeline("upper:1").is_err());
+        assert!(parse_pipeline("replace:(=>x").is_err());
+        assert!(parse_pipeline("map:on").is_err());
+    }
+
+    #[test]
+    fn test_pipeline_runs_in_order() {
+        let line = spans(&[("  Door: ", WHITE), ("open", RED), (" since 7:00  ", WHITE)]);
+
+        let shaped = apply(
+            &parse_pipeline("trim|upper|truncate:9").unwrap(),
+            line.clone(),
+        );
+        assert_eq!(shaped, spans(&[("DOOR: ", WHITE), ("OPE…", RED)]));
+
+        // The order matters: truncating first counts the leading spaces
+        let shaped = apply(&parse_pipeline("truncate:9|trim|upper").unwrap(), line);
+        assert_eq!(shaped, spans(&[("DOOR: ", WHITE), ("O…", RED)]));
+    }
+
+    #[test]
+    fn test_whole_line_transforms() {
+        let pipeline = parse_pipeline("map:on=Open,off=Closed|replace:^(\\w)\\w+=>$1").unwrap();
+
+        assert_eq!(
+            apply(&pipeline, spans(&[("o", WHITE), ("n", RED)])),
+            spans(&[("O", WHITE)])
+        );
+        // Unchanged lines keep their colors
+        let line = spans(&[("-", WHITE), ("?", RED)]);
+        assert_eq!(apply(&pipeline, line.clone()), line);
+
+        assert_eq!(
+            apply(&pipeline, spans(&[("off", RED)])),
+            spans(&[("C", RED)])
+        );
+    }
+}
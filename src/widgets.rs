@@ -0,0 +1,456 @@
+//! Graphical widgets drawn into a frame alongside the text lines, and the
+//! `Canvas` primitives they and the text lines are drawn with.
+
+use chrono::{DateTime, Local, Timelike};
+use image::{Rgb, RgbImage, RgbaImage};
+use imageproc::drawing::{draw_filled_rect_mut, draw_hollow_rect_mut, draw_text_mut};
+use imageproc::pixelops::weighted_sum;
+use imageproc::rect::Rect;
+use qrcode::{Color, EcLevel, QrCode};
+use rusttype::{point, Font, Scale};
+use std::f32::consts::PI;
+
+/// Samples per pixel along each axis for anti-aliased shapes.
+const DEFAULT_SUPERSAMPLING: u32 = 4;
+
+/// A frame being drawn into. Lines and circles are anti-aliased by sampling each
+/// pixel they touch on a `supersampling`×`supersampling` grid; rectangles and
+/// text are drawn by imageproc and rusttype. Coordinates are pixel centers, as in
+/// imageproc.
+pub struct Canvas<'a> {
+    image: &'a mut RgbImage,
+    supersampling: u32,
+}
+
+impl<'a> Canvas<'a> {
+    pub fn new(image: &'a mut RgbImage) -> Self {
+        Self {
+            image,
+            supersampling: DEFAULT_SUPERSAMPLING,
+        }
+    }
+
+    /// Samples per pixel along each axis; 1 draws hard, aliased edges.
+    pub fn with_supersampling(mut self, samples: u32) -> Self {
+        self.supersampling = samples.max(1);
+        self
+    }
+
+    /// A line `width` pixels wide from `start` to `end`, with round ends.
+    pub fn line(&mut self, start: (f32, f32), end: (f32, f32), width: f32, color: Rgb<u8>) {
+        let half = width / 2.0;
+        let (dx, dy) = (end.0 - start.0, end.1 - start.1);
+        let len_sq = (dx * dx + dy * dy).max(f32::EPSILON);
+        let bounds = (
+            start.0.min(end.0) - half,
+            start.1.min(end.1) - half,
+            start.0.max(end.0) + half,
+            start.1.max(end.1) + half,
+        );
+        self.fill_shape(
+            bounds,
+            color,
+            |_, _| true,
+            |x, y| {
+                // Distance to the closest point of the segment
+                let t = (((x - start.0) * dx + (y - start.1) * dy) / len_sq).clamp(0.0, 1.0);
+                let (px, py) = (start.0 + t * dx - x, start.1 + t * dy - y);
+                px * px + py * py <= half * half
+            },
+        );
+    }
+
+    /// A circle outline `width` pixels wide, centered on its `radius`.
+    pub fn circle(&mut self, center: (f32, f32), radius: f32, width: f32, color: Rgb<u8>) {
+        let outer = radius + width / 2.0;
+        let inner = (radius - width / 2.0).max(0.0);
+        let distance = |x: f32, y: f32| ((x - center.0).powi(2) + (y - center.1).powi(2)).sqrt();
+        // Samples lie within 0.71px of their pixel's center, so pixels more than
+        // 1px from the ring can't touch it
+        self.fill_shape(
+            square_around(center, outer),
+            color,
+            |x, y| (inner - 1.0..=outer + 1.0).contains(&distance(x, y)),
+            |x, y| (inner..=outer).contains(&distance(x, y)),
+        );
+    }
+
+    /// A filled circle.
+    pub fn disc(&mut self, center: (f32, f32), radius: f32, color: Rgb<u8>) {
+        self.fill_shape(
+            square_around(center, radius),
+            color,
+            |_, _| true,
+            |x, y| (x - center.0).powi(2) + (y - center.1).powi(2) <= radius * radius,
+        );
+    }
+
+    pub fn rect(&mut self, rect: Rect, color: Rgb<u8>) {
+        draw_filled_rect_mut(self.image, rect, color);
+    }
+
+    pub fn hollow_rect(&mut self, rect: Rect, color: Rgb<u8>) {
+        draw_hollow_rect_mut(self.image, rect, color);
+    }
+
+    /// Text with its top-left corner at (`x`, `y`).
+    pub fn text(&mut self, x: i32, y: i32, scale: Scale, font: &Font, text: &str, color: Rgb<u8>) {
+        draw_text_mut(self.image, color, x, y, scale, font, text);
+    }
+
+    /// Like `text`, but starting at a fractional `x`. rusttype rasterizes each
+    /// glyph at its exact offset, so spans drawn one after another keep the same
+    /// spacing as a single run of text.
+    pub fn text_subpixel(
+        &mut self,
+        x: f32,
+        y: i32,
+        scale: Scale,
+        font: &Font,
+        text: &str,
+        color: Rgb<u8>,
+    ) {
+        let ascent = font.v_metrics(scale).ascent;
+        let (width, height) = (self.image.width() as i32, self.image.height() as i32);
+        for glyph in font.layout(text, scale, point(x, y as f32 + ascent)) {
+            let Some(bb) = glyph.pixel_bounding_box() else {
+                continue;
+            };
+            glyph.draw(|gx, gy, coverage| {
+                let (px, py) = (bb.min.x + gx as i32, bb.min.y + gy as i32);
+                if (0..width).contains(&px) && (0..height).contains(&py) {
+                    self.blend_pixel(px as u32, py as u32, color, coverage);
+                }
+            });
+        }
+    }
+
+    /// Composites `overlay` with its top-left corner at (`x`, `y`), blending by
+    /// its alpha channel. Parts outside the frame are skipped.
+    pub fn overlay(&mut self, overlay: &RgbaImage, x: i32, y: i32) {
+        let (width, height) = (self.image.width() as i32, self.image.height() as i32);
+        for (ox, oy, pixel) in overlay.enumerate_pixels() {
+            let (px, py) = (x + ox as i32, y + oy as i32);
+            if px < 0 || py < 0 || px >= width || py >= height {
+                continue;
+            }
+            let alpha = pixel[3] as u32;
+            let target = self.image.get_pixel_mut(px as u32, py as u32);
+            for c in 0..3 {
+                target[c] = ((pixel[c] as u32 * alpha + target[c] as u32 * (255 - alpha) + 127)
+                    / 255) as u8;
+            }
+        }
+    }
+
+    /// Blends `color` into every pixel within `bounds` (min x, min y, max x, max y)
+    /// by the share of its samples for which `inside` holds. Pixels whose center
+    /// fails `near` are skipped without sampling.
+    fn fill_shape(
+        &mut self,
+        bounds: (f32, f32, f32, f32),
+        color: Rgb<u8>,
+        near: impl Fn(f32, f32) -> bool,
+        inside: impl Fn(f32, f32) -> bool,
+    ) {
+        let (width, height) = (self.image.width() as i32, self.image.height() as i32);
+        let x_range = (bounds.0.floor() as i32).max(0)..=(bounds.2.ceil() as i32).min(width - 1);
+        let y_range = (bounds.1.floor() as i32).max(0)..=(bounds.3.ceil() as i32).min(height - 1);
+        let n = self.supersampling;
+        let offsets: Vec<f32> = (0..n).map(|i| (i as f32 + 0.5) / n as f32 - 0.5).collect();
+
+        for py in y_range {
+            for px in x_range.clone() {
+                if !near(px as f32, py as f32) {
+                    continue;
+                }
+                let hits = offsets
+                    .iter()
+                    .flat_map(|oy| offsets.iter().map(move |ox| (*ox, *oy)))
+                    .filter(|(ox, oy)| inside(px as f32 + ox, py as f32 + oy))
+                    .count();
+                if hits > 0 {
+                    let coverage = hits as f32 / (n * n) as f32;
+                    self.blend_pixel(px as u32, py as u32, color, coverage);
+                }
+            }
+        }
+    }
+
+    fn blend_pixel(&mut self, x: u32, y: u32, color: Rgb<u8>, coverage: f32) {
+        let pixel = self.image.get_pixel_mut(x, y);
+        *pixel = weighted_sum(*pixel, color, 1.0 - coverage, coverage);
+    }
+}
+
+/// Bounds of the square enclosing a circle.
+fn square_around(center: (f32, f32), radius: f32) -> (f32, f32, f32, f32) {
+    (
+        center.0 - radius,
+        center.1 - radius,
+        center.0 + radius,
+        center.1 + radius,
+    )
+}
+
+/// Draws an analog clock face showing `now`, centered on (`cx`, `cy`).
+pub fn draw_clock(
+    canvas: &mut Canvas,
+    cx: i32,
+    cy: i32,
+    size: u32,
+    color: Rgb<u8>,
+    now: DateTime<Local>,
+) {
+    let radius = size as f32 / 2.0 - 1.0;
+    if radius < 2.0 {
+        return;
+    }
+    let center = (cx as f32, cy as f32);
+
+    canvas.circle(center, radius, 1.0, color);
+
+    // Hour ticks
+    for i in 0..12 {
+        let angle = i as f32 / 12.0 * 2.0 * PI;
+        let inner = if i % 3 == 0 { 0.8 } else { 0.88 };
+        canvas.line(
+            hand_point(center, angle, radius * inner),
+            hand_point(center, angle, radius * 0.95),
+            1.0,
+            color,
+        );
+    }
+
+    let seconds = now.second() as f32;
+    let minutes = now.minute() as f32 + seconds / 60.0;
+    let hours = (now.hour() % 12) as f32 + minutes / 60.0;
+
+    let hands = [
+        (hours / 12.0, 0.5, 3.0),
+        (minutes / 60.0, 0.75, 2.0),
+        (seconds / 60.0, 0.85, 1.0),
+    ];
+    for (fraction, length, width) in hands {
+        let angle = fraction * 2.0 * PI;
+        canvas.line(
+            center,
+            hand_point(center, angle, radius * length),
+            width,
+            color,
+        );
+    }
+
+    canvas.disc(center, (radius * 0.05).max(1.0), color);
+}
+
+/// Draws `data` as a QR code of roughly `size` pixels (including a quiet zone),
+/// dark modules on a white square, centered on (`cx`, `cy`).
+pub fn draw_qr(
+    canvas: &mut Canvas,
+    cx: i32,
+    cy: i32,
+    size: u32,
+    data: &str,
+    ec_level: EcLevel,
+) -> Result<(), qrcode::types::QrError> {
+    const QUIET_ZONE: usize = 2;
+
+    let code = QrCode::with_error_correction_level(data, ec_level)?;
+    let modules = code.width();
+    let total = modules + 2 * QUIET_ZONE;
+    let module_px = (size as usize / total).max(1) as u32;
+    let side = module_px * total as u32;
+    let left = cx - side as i32 / 2;
+    let top = cy - side as i32 / 2;
+
+    canvas.rect(
+        Rect::at(left, top).of_size(side, side),
+        Rgb([255, 255, 255]),
+    );
+
+    for (i, color) in code.to_colors().into_iter().enumerate() {
+        if color == Color::Dark {
+            let x = left + ((i % modules + QUIET_ZONE) as u32 * module_px) as i32;
+            let y = top + ((i / modules + QUIET_ZONE) as u32 * module_px) as i32;
+            canvas.rect(Rect::at(x, y).of_size(module_px, module_px), Rgb([0, 0, 0]));
+        }
+    }
+
+    Ok(())
+}
+
+/// Point at `length` from `center`, `angle` radians clockwise from 12 o'clock.
+fn hand_point(center: (f32, f32), angle: f32, length: f32) -> (f32, f32) {
+    (
+        center.0 + length * angle.sin(),
+        center.1 - length * angle.cos(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const WHITE: Rgb<u8> = Rgb([255, 255, 255]);
+
+    #[test]
+    fn test_line() {
+        let mut image = RgbImage::new(20, 20);
+        Canvas::new(&mut image).line((2.0, 10.0), (17.0, 10.0), 3.0, WHITE);
+
+        // Fully covered along the middle and one pixel to each side, nothing beyond
+        for y in 9..=11 {
+            assert_eq!(*image.get_pixel(10, y), WHITE);
+        }
+        assert_eq!(*image.get_pixel(10, 8), Rgb([0, 0, 0]));
+        assert_eq!(*image.get_pixel(10, 12), Rgb([0, 0, 0]));
+        assert_eq!(*image.get_pixel(19, 10), Rgb([0, 0, 0]));
+
+        // A diagonal has partially covered pixels along its edges
+        let mut image = RgbImage::new(20, 20);
+        Canvas::new(&mut image).line((2.0, 2.0), (17.0, 12.0), 1.0, WHITE);
+        assert!(image.get_pixel(2, 2)[0] > 128);
+        assert!(image.pixels().any(|p| p[0] > 0 && p[0] < 255));
+    }
+
+    #[test]
+    fn test_circle_and_disc() {
+        let mut image = RgbImage::new(40, 40);
+        Canvas::new(&mut image).circle((20.0, 20.0), 10.0, 1.0, WHITE);
+
+        // The outline passes through the axis points, the middle stays empty
+        for (x, y) in [(30, 20), (10, 20), (20, 30), (20, 10)] {
+            assert_eq!(*image.get_pixel(x, y), WHITE);
+        }
+        assert_eq!(*image.get_pixel(20, 20), Rgb([0, 0, 0]));
+        // Between the axes the edge is anti-aliased
+        assert!(image.pixels().any(|p| p[0] > 0 && p[0] < 255));
+
+        // Skipping the pixels away from the ring doesn't change what is drawn
+        let mut sampled = RgbImage::new(40, 40);
+        Canvas::new(&mut sampled).fill_shape(
+            (9.5, 9.5, 30.5, 30.5),
+            WHITE,
+            |_, _| true,
+            |x, y| (9.5..=10.5).contains(&((x - 20.0).powi(2) + (y - 20.0).powi(2)).sqrt()),
+        );
+        assert_eq!(image, sampled);
+
+        let mut image = RgbImage::new(40, 40);
+        Canvas::new(&mut image).disc((20.0, 20.0), 5.0, WHITE);
+        assert_eq!(*image.get_pixel(20, 20), WHITE);
+        assert_eq!(*image.get_pixel(24, 20), WHITE);
+        assert_eq!(*image.get_pixel(27, 20), Rgb([0, 0, 0]));
+        // Half of the pixel at the edge is inside
+        assert!((100..=155).contains(&image.get_pixel(25, 20)[0]));
+    }
+
+    #[test]
+    fn test_supersampling_off_draws_hard_edges() {
+        let mut image = RgbImage::new(40, 40);
+        Canvas::new(&mut image)
+            .with_supersampling(1)
+            .circle((20.0, 20.0), 10.0, 1.0, WHITE);
+
+        assert!(image.pixels().all(|p| p[0] == 0 || p[0] == 255));
+        assert_eq!(*image.get_pixel(30, 20), WHITE);
+    }
+
+    #[test]
+    fn test_rects() {
+        let mut image = RgbImage::new(10, 10);
+        let mut canvas = Canvas::new(&mut image);
+        canvas.rect(Rect::at(1, 1).of_size(3, 3), WHITE);
+        canvas.hollow_rect(Rect::at(5, 5).of_size(4, 4), Rgb([255, 0, 0]));
+
+        assert_eq!(*image.get_pixel(3, 3), WHITE);
+        assert_eq!(*image.get_pixel(4, 4), Rgb([0, 0, 0]));
+        assert_eq!(*image.get_pixel(5, 7), Rgb([255, 0, 0]));
+        assert_eq!(*image.get_pixel(6, 6), Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn test_subpixel_spans_keep_single_run_spacing() {
+        let font = Font::try_from_bytes(include_bytes!("../assets/Lato-Regular.ttf")).unwrap();
+        let scale = Scale::uniform(11.0);
+        let blank = || RgbImage::new(48, 16);
+
+        // At whole pixels it matches imageproc
+        let mut ours = blank();
+        Canvas::new(&mut ours).text_subpixel(3.0, 1, scale, &font, "21.5", WHITE);
+        let mut theirs = blank();
+        Canvas::new(&mut theirs).text(3, 1, scale, &font, "21.5", WHITE);
+        assert_eq!(ours, theirs);
+        assert!(ours.pixels().any(|p| p[0] > 0));
+
+        // Two spans drawn back to back look exactly like one run of text
+        let advance = font
+            .layout("2", scale, point(0.0, 0.0))
+            .map(|g| g.unpositioned().h_metrics().advance_width)
+            .sum::<f32>();
+        let mut run = blank();
+        Canvas::new(&mut run).text_subpixel(3.4, 1, scale, &font, "21", WHITE);
+        let mut spans = blank();
+        let mut canvas = Canvas::new(&mut spans);
+        canvas.text_subpixel(3.4, 1, scale, &font, "2", WHITE);
+        canvas.text_subpixel(3.4 + advance, 1, scale, &font, "1", WHITE);
+        assert_eq!(run, spans);
+        assert_ne!(run, ours);
+    }
+
+    #[test]
+    fn test_clock_hands() {
+        let mut image = RgbImage::new(100, 100);
+        let three_oclock = Local.with_ymd_and_hms(2024, 1, 1, 3, 0, 0).unwrap();
+        draw_clock(
+            &mut Canvas::new(&mut image),
+            50,
+            50,
+            100,
+            WHITE,
+            three_oclock,
+        );
+
+        // Hour hand points right, minute and second hands point up
+        assert_eq!(image.get_pixel(70, 50)[0], 255);
+        assert_eq!(image.get_pixel(50, 20)[0], 255);
+        // Nothing is drawn between the hands in the lower left
+        assert_eq!(image.get_pixel(35, 65)[0], 0);
+    }
+
+    #[test]
+    fn test_overlay_respects_alpha() {
+        let mut image = RgbImage::from_pixel(10, 10, Rgb([0, 0, 200]));
+        let mut logo = RgbaImage::from_pixel(4, 4, image::Rgba([255, 0, 0, 255]));
+        logo.put_pixel(1, 0, image::Rgba([255, 0, 0, 128]));
+        logo.put_pixel(2, 0, image::Rgba([255, 0, 0, 0]));
+        Canvas::new(&mut image).overlay(&logo, 8, 0);
+
+        assert_eq!(*image.get_pixel(8, 0), Rgb([255, 0, 0]));
+        assert_eq!(*image.get_pixel(9, 0), Rgb([128, 0, 100]));
+        assert_eq!(*image.get_pixel(7, 0), Rgb([0, 0, 200]));
+    }
+
+    #[test]
+    fn test_qr_has_finder_pattern() {
+        let mut image = RgbImage::new(200, 200);
+        let mut canvas = Canvas::new(&mut image);
+        draw_qr(
+            &mut canvas,
+            100,
+            100,
+            150,
+            "https://example.com",
+            EcLevel::M,
+        )
+        .unwrap();
+
+        // Version 2 code: 25 modules + 4 quiet = 29 modules of 5px, 145px square
+        let left = 100 - 145 / 2;
+        assert_eq!(image.get_pixel(left as u32 + 1, 100)[0], 255); // quiet zone
+        assert_eq!(image.get_pixel(left as u32 + 12, 40)[0], 0); // finder pattern
+        assert_eq!(image.get_pixel(5, 5)[0], 0); // outside the code
+    }
+}